@@ -0,0 +1,137 @@
+//! A neutral form of the [`Operation`]s, to bridge them into other diff tooling.
+use std::ops::Range;
+
+use crate::delta::Operation;
+
+/// What a [`Change`] does.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ChangeKind {
+    /// the bytes are taken from the original buffer as is.
+    Equal,
+    /// the bytes are new.
+    Insert,
+    /// the bytes are dropped from the original buffer.
+    Delete,
+    /// the bytes of the range are dropped and new bytes take their place.
+    Replace,
+}
+
+/// A change in the shape most diff crates use, a kind, a range and the bytes, if any.
+///
+/// the `range` is in the same coordinates as the [`Operation`] it came from, the new buffer for
+/// inserts, removes and replaces and the original buffer for copies, only inserts and replaces
+/// carry `bytes`, the range of a replace covers the replaced bytes.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{Change, ChangeKind, Operation};
+///
+/// let op = Operation::Insert { offset: 12, buffer: b"box".to_vec() };
+/// let change = Change::from(op.clone());
+/// assert_eq!(change.kind, ChangeKind::Insert);
+/// assert_eq!(change.range, 12..15);
+/// assert_eq!(Operation::from(change), op);
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub range: Range<usize>,
+    pub bytes: Vec<u8>,
+}
+
+impl From<Operation> for Change {
+    fn from(op: Operation) -> Self {
+        match op {
+            Operation::Insert { offset, buffer } => Change {
+                kind: ChangeKind::Insert,
+                range: offset..offset + buffer.len(),
+                bytes: buffer,
+            },
+            Operation::Remove { offset, len } => Change {
+                kind: ChangeKind::Delete,
+                range: offset..offset + len,
+                bytes: Vec::new(),
+            },
+            Operation::Copy { src_offset, len } => Change {
+                kind: ChangeKind::Equal,
+                range: src_offset..src_offset + len,
+                bytes: Vec::new(),
+            },
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => Change {
+                kind: ChangeKind::Replace,
+                range: offset..offset + old_len,
+                bytes: buffer,
+            },
+        }
+    }
+}
+
+impl From<Change> for Operation {
+    fn from(change: Change) -> Self {
+        let Change { kind, range, bytes } = change;
+        match kind {
+            ChangeKind::Insert => Operation::Insert {
+                offset: range.start,
+                buffer: bytes,
+            },
+            ChangeKind::Delete => Operation::Remove {
+                offset: range.start,
+                len: range.len(),
+            },
+            ChangeKind::Equal => Operation::Copy {
+                src_offset: range.start,
+                len: range.len(),
+            },
+            ChangeKind::Replace => Operation::Replace {
+                offset: range.start,
+                old_len: range.len(),
+                buffer: bytes,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let ops = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"hi, do".to_vec(),
+            },
+            Operation::Remove { offset: 6, len: 15 },
+            Operation::Insert {
+                offset: 16,
+                buffer: b"about rustlang?".to_vec(),
+            },
+            Operation::Remove { offset: 31, len: 5 },
+            Operation::Copy {
+                src_offset: 4,
+                len: 8,
+            },
+        ];
+        let changes: Vec<Change> = ops.iter().cloned().map(Change::from).collect();
+        assert_eq!(
+            changes.iter().map(|c| c.kind).collect::<Vec<_>>(),
+            vec![
+                ChangeKind::Insert,
+                ChangeKind::Delete,
+                ChangeKind::Insert,
+                ChangeKind::Delete,
+                ChangeKind::Equal,
+            ]
+        );
+        assert_eq!(changes[1].range, 6..21);
+        assert_eq!(changes[2].bytes, b"about rustlang?");
+        assert_eq!(changes[4].range, 4..12);
+        let back: Vec<Operation> = changes.into_iter().map(Operation::from).collect();
+        assert_eq!(back, ops);
+    }
+}
@@ -1,23 +1,65 @@
 //! The [`Delta`] module.
 //!
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
-use std::io;
+use std::io::{self, BufRead, BufReader, Read};
+use std::iter;
 use std::mem;
+use std::ops::Range;
+use std::str::Utf8Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::vec;
 
-use blake2::{Blake2b, Digest};
-use log::trace;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use log::{trace, warn};
 
-use crate::hash::{CryptoHash, IndexedSignature, RollingHasher};
-use crate::window::Window;
+use crate::differ::{self, Cancellable, CostModel, Differ, Event};
+use crate::hash::{BlockHash, Chunking, CryptoHash, CryptoHasher, IndexedSignature};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::observer::{NoopObserver, Observer, ObserverEvent};
+use crate::window::{Frames, Window};
 
 /// Operation to be done to upgrade from original version of the buffer to new version.
-#[derive(Clone, Eq, PartialEq)]
+///
+/// the offsets of the operations calculated by [`Delta::diff`] are positions in the new buffer,
+/// an insert starts at `offset` in the new buffer, and a remove skips `len` bytes of the
+/// original buffer right where the new buffer is at `offset`, the bytes between operations are
+/// the same in both buffers.
+///
+/// see [`crate::to_original_offsets`] to get the same operations with offsets in the original
+/// buffer instead, and [`crate::to_new_offsets`] to go back.
+#[derive(Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operation {
     /// Insertation Operation to be performed by inserting the `buffer` at the `offset`.
-    Insert { buffer: Vec<u8>, offset: usize },
+    Insert {
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialize::bytes"))]
+        buffer: Vec<u8>,
+        offset: usize,
+    },
     /// Removeal Operation to be performed by removing the `len` bytes from the `buffer` starting
     /// at `offset` and going back.
     Remove { offset: usize, len: usize },
+    /// Copy Operation to be performed by copying `len` bytes from the original buffer starting
+    /// at `src_offset`.
+    ///
+    /// a list of operations that contains any copy is explicit, the new buffer is only made of
+    /// the copied and inserted bytes, nothing is implicitly taken from the original buffer.
+    Copy { src_offset: usize, len: usize },
+    /// Replacement Operation, the same as an [`Operation::Remove`] of `old_len` bytes and an
+    /// [`Operation::Insert`] of the `buffer` at the same `offset`, see
+    /// [`crate::coalesce_replacements`].
+    Replace {
+        offset: usize,
+        old_len: usize,
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialize::bytes"))]
+        buffer: Vec<u8>,
+    },
 }
 
 /// Debug formtaing for easier debugging in tests.
@@ -28,46 +70,293 @@ impl fmt::Debug for Operation {
                 write!(f, "({}, {})", offset, String::from_utf8_lossy(buffer))
             }
             Operation::Remove { len, offset } => write!(f, "({}, {})", offset, len),
+            Operation::Copy { src_offset, len } => write!(f, "(={}, {})", src_offset, len),
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => write!(
+                f,
+                "({}, {} => {})",
+                offset,
+                old_len,
+                String::from_utf8_lossy(buffer)
+            ),
         }
     }
 }
 
+/// An offset in a buffer, used by the [`Operation`] constructors so it can't be mixed up with
+/// a [`ByteLen`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Offset(pub usize);
+
+/// A number of bytes, used by the [`Operation`] constructors so it can't be mixed up with an
+/// [`Offset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ByteLen(pub usize);
+
+impl From<usize> for Offset {
+    fn from(offset: usize) -> Self {
+        Self(offset)
+    }
+}
+
+impl From<Offset> for usize {
+    fn from(offset: Offset) -> Self {
+        offset.0
+    }
+}
+
+impl From<usize> for ByteLen {
+    fn from(len: usize) -> Self {
+        Self(len)
+    }
+}
+
+impl From<ByteLen> for usize {
+    fn from(len: ByteLen) -> Self {
+        len.0
+    }
+}
+
 impl Operation {
+    /// Create a new [`Operation::Insert`], returns [`None`] if the `buffer` is empty.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Offset, Operation};
+    ///
+    /// assert!(Operation::insert(Offset(12), b"box".to_vec()).is_some());
+    /// assert!(Operation::insert(Offset(12), Vec::new()).is_none());
+    /// ```
+    pub fn insert(offset: Offset, buffer: Vec<u8>) -> Option<Self> {
+        if buffer.is_empty() {
+            None
+        } else {
+            Some(Operation::Insert {
+                offset: offset.0,
+                buffer,
+            })
+        }
+    }
+
+    /// Create a new [`Operation::Remove`], returns [`None`] if `len` is zero.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{ByteLen, Offset, Operation};
+    ///
+    /// let op = Operation::remove(Offset(15), ByteLen(3));
+    /// assert_eq!(op, Some(Operation::Remove { offset: 15, len: 3 }));
+    /// ```
+    ///
+    /// the arguments can't be swapped by mistake:
+    /// ```compile_fail
+    /// use rsdiff::{ByteLen, Offset, Operation};
+    ///
+    /// let op = Operation::remove(ByteLen(3), Offset(15));
+    /// ```
+    pub fn remove(offset: Offset, len: ByteLen) -> Option<Self> {
+        if len.0 == 0 {
+            None
+        } else {
+            Some(Operation::Remove {
+                offset: offset.0,
+                len: len.0,
+            })
+        }
+    }
+
+    /// Create a new [`Operation::Copy`], returns [`None`] if `len` is zero.
+    ///
+    /// the arguments can't be swapped by mistake:
+    /// ```compile_fail
+    /// use rsdiff::{ByteLen, Offset, Operation};
+    ///
+    /// let op = Operation::copy(ByteLen(12), Offset(0));
+    /// ```
+    pub fn copy(src_offset: Offset, len: ByteLen) -> Option<Self> {
+        if len.0 == 0 {
+            None
+        } else {
+            Some(Operation::Copy {
+                src_offset: src_offset.0,
+                len: len.0,
+            })
+        }
+    }
+
     pub fn is_insert(&self) -> bool {
-        matches!(self, Operation::Insert {..})
+        matches!(self, Operation::Insert { .. })
     }
 
     pub fn is_remove(&self) -> bool {
-        matches!(self, Operation::Remove {..})
+        matches!(self, Operation::Remove { .. })
+    }
+
+    pub fn is_copy(&self) -> bool {
+        matches!(self, Operation::Copy { .. })
+    }
+
+    pub fn is_replace(&self) -> bool {
+        matches!(self, Operation::Replace { .. })
     }
 
+    /// The operation offset, for [`Operation::Copy`] this is the `src_offset` in the original
+    /// buffer.
     pub fn offset(&self) -> usize {
         match self {
             Operation::Insert { offset, .. } => *offset,
             Operation::Remove { offset, .. } => *offset,
+            Operation::Copy { src_offset, .. } => *src_offset,
+            Operation::Replace { offset, .. } => *offset,
         }
     }
 
+    /// The operation length, for [`Operation::Replace`] this is the length of the new bytes,
+    /// the removed ones are its `old_len`.
     pub fn len(&self) -> usize {
         match self {
             Operation::Insert { buffer, .. } => buffer.len(),
             Operation::Remove { len, .. } => *len,
+            Operation::Copy { len, .. } => *len,
+            Operation::Replace { buffer, .. } => buffer.len(),
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        match self {
+            Operation::Replace {
+                old_len, buffer, ..
+            } => *old_len == 0 && buffer.is_empty(),
+            _ => self.len() == 0,
+        }
     }
 
-    /// Current Operation buffer, returns [`None`] if the operation is [`Operation::Remove`].
+    /// Current Operation buffer, returns [`None`] if the operation is not [`Operation::Insert`]
+    /// or [`Operation::Replace`].
     pub fn buffer(&self) -> Option<&[u8]> {
         match self {
-            Operation::Insert { buffer, .. } => Some(&buffer),
+            Operation::Insert { buffer, .. } | Operation::Replace { buffer, .. } => Some(buffer),
             _ => None,
         }
     }
+
+    /// Current Operation buffer as text, returns [`None`] if the operation is not
+    /// [`Operation::Insert`] or [`Operation::Replace`].
+    ///
+    /// unlike the [`fmt::Display`] impl this does not replace invalid UTF-8 sequences, so it
+    /// could be used to detect binary inserts.
+    pub fn buffer_str(&self) -> Option<Result<&str, Utf8Error>> {
+        self.buffer().map(std::str::from_utf8)
+    }
+
+    /// Where the bytes of the [`Operation::buffer`] are in the new buffer, so they could be
+    /// sliced out of it instead of copied, like with `bytes::Bytes::slice` on a received buffer.
+    ///
+    /// ### Example
+    /// ```
+    /// let new = "i saw a red box";
+    /// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", new).unwrap();
+    /// for op in &ops {
+    ///     if let Some(range) = op.buffer_range() {
+    ///         assert_eq!(op.buffer(), Some(&new.as_bytes()[range]));
+    ///     }
+    /// }
+    /// ```
+    pub fn buffer_range(&self) -> Option<Range<usize>> {
+        let len = self.buffer()?.len();
+        Some(self.offset()..self.offset() + len)
+    }
+
+    /// Does this operation change any byte of the `range` of the new buffer?
+    ///
+    /// the inserted bytes are in the new buffer, so an insert (or a replace) affects the ranges
+    /// they overlap, the removed ones are not, a remove only affects a range if it happened
+    /// between two bytes of it. a copy takes the bytes as is, so it never affects a range.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Operation;
+    ///
+    /// let op = Operation::Insert { offset: 12, buffer: b"box".to_vec() };
+    /// assert!(op.affects(10..13));
+    /// assert!(!op.affects(0..12));
+    /// let op = Operation::Remove { offset: 15, len: 3 };
+    /// assert!(op.affects(10..20));
+    /// assert!(!op.affects(15..20));
+    /// ```
+    pub fn affects(&self, range: Range<usize>) -> bool {
+        match self {
+            Operation::Copy { .. } => false,
+            Operation::Remove { offset, .. } => range.start < *offset && *offset < range.end,
+            Operation::Replace { offset, buffer, .. } if buffer.is_empty() => {
+                range.start < *offset && *offset < range.end
+            }
+            Operation::Insert { offset, buffer } | Operation::Replace { offset, buffer, .. } => {
+                !range.is_empty() && *offset < range.end && range.start < offset + buffer.len()
+            }
+        }
+    }
+
+    /// A stable one line description of the operation for golden tests, unlike [`fmt::Debug`]
+    /// and [`fmt::Display`] it never changes and the buffers are hex, so any bytes are fine.
+    ///
+    /// buffers longer than 16 bytes only show their first 16 bytes, followed by `..`.
+    ///
+    /// see [`crate::snapshot_operations`] for a whole list.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Operation;
+    ///
+    /// let op = Operation::Insert { offset: 12, buffer: b"box".to_vec() };
+    /// assert_eq!(op.snapshot_string(), "insert offset=12 len=3 buffer=626f78");
+    /// let op = Operation::Remove { offset: 15, len: 3 };
+    /// assert_eq!(op.snapshot_string(), "remove offset=15 len=3");
+    /// ```
+    pub fn snapshot_string(&self) -> String {
+        let hex = |buffer: &[u8]| {
+            let mut hex: String = buffer
+                .iter()
+                .take(SNAPSHOT_BYTES)
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            if buffer.len() > SNAPSHOT_BYTES {
+                hex.push_str("..");
+            }
+            hex
+        };
+        match self {
+            Operation::Insert { offset, buffer } => format!(
+                "insert offset={} len={} buffer={}",
+                offset,
+                buffer.len(),
+                hex(buffer)
+            ),
+            Operation::Remove { offset, len } => format!("remove offset={} len={}", offset, len),
+            Operation::Copy { src_offset, len } => {
+                format!("copy src_offset={} len={}", src_offset, len)
+            }
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => format!(
+                "replace offset={} old_len={} len={} buffer={}",
+                offset,
+                old_len,
+                buffer.len(),
+                hex(buffer)
+            ),
+        }
+    }
 }
 
+/// How many bytes of a buffer [`Operation::snapshot_string`] shows.
+const SNAPSHOT_BYTES: usize = 16;
+
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -76,9 +365,23 @@ impl fmt::Display for Operation {
                 "+ {}..{} = {}",
                 offset,
                 offset + buffer.len(),
-                String::from_utf8_lossy(&buffer)
+                String::from_utf8_lossy(buffer)
             ),
             Operation::Remove { offset, len } => write!(f, "- {}..-{}", offset, len),
+            Operation::Copy { src_offset, len } => {
+                write!(f, "= {}..{}", src_offset, src_offset + len)
+            }
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => write!(
+                f,
+                "~ {}..-{} = {}",
+                offset,
+                old_len,
+                String::from_utf8_lossy(buffer)
+            ),
         }
     }
 }
@@ -93,8 +396,201 @@ pub struct Delta {
     sig: IndexedSignature,
     /// The [`Operation`]s calculated by calling [`Delta::diff`] on the new buffer.
     ops: Vec<Operation>,
+    /// The blocks of the original buffer matched so far, in the order they were found.
+    matches: Vec<BlockMatch>,
+    /// The hash of the last buffer diffed by [`Delta::diff_cached`] and its result.
+    cache: Option<(CryptoHash, Vec<Operation>, Vec<BlockMatch>)>,
+    /// Where the operations of the current diff start, diffing again appends to them.
+    first_op: usize,
+    /// Picks between the blocks that match, see [`Delta::with_cost_model`].
+    cost_model: Option<CostModel>,
+}
+
+/// A block from the original buffer found in the new buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockMatch {
+    /// The index of the block in the original buffer.
+    pub(crate) index: usize,
+    /// Where the block starts in the new buffer.
+    pub(crate) offset: usize,
+}
+
+/// An [`Operation`] tagged with the order it was generated in, see
+/// [`Delta::sequenced_operations`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SequencedOperation {
+    /// Starts at zero and goes up by one for every operation of the same [`Delta`].
+    pub id: usize,
+    /// The operation itself.
+    pub op: Operation,
+}
+
+/// Same as [`Operation`] but the inserted bytes are a [`Bytes`] slice of the new buffer instead
+/// of a copy, see [`Delta::diff_bytes`].
+#[cfg(feature = "bytes")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytesOperation {
+    /// See [`Operation::Insert`].
+    Insert { offset: usize, buffer: Bytes },
+    /// See [`Operation::Remove`].
+    Remove { offset: usize, len: usize },
+    /// See [`Operation::Copy`].
+    Copy { src_offset: usize, len: usize },
+    /// See [`Operation::Replace`].
+    Replace {
+        offset: usize,
+        old_len: usize,
+        buffer: Bytes,
+    },
+}
+
+#[cfg(feature = "bytes")]
+impl BytesOperation {
+    /// The same `op` of the `new` buffer, with its inserted bytes sliced out of `new`.
+    fn slice(op: Operation, new: &Bytes) -> Self {
+        // only inserts and replaces have a range, the others don't use it.
+        let range = op.buffer_range().unwrap_or(0..0);
+        debug_assert_eq!(op.buffer().unwrap_or(&[]), &new[range.clone()]);
+        match op {
+            Operation::Insert { offset, .. } => Self::Insert {
+                offset,
+                buffer: new.slice(range),
+            },
+            Operation::Remove { offset, len } => Self::Remove { offset, len },
+            Operation::Copy { src_offset, len } => Self::Copy { src_offset, len },
+            Operation::Replace {
+                offset, old_len, ..
+            } => Self::Replace {
+                offset,
+                old_len,
+                buffer: new.slice(range),
+            },
+        }
+    }
+}
+
+/// Copies the inserted bytes, for the places that only take an [`Operation`].
+#[cfg(feature = "bytes")]
+impl From<BytesOperation> for Operation {
+    fn from(op: BytesOperation) -> Self {
+        match op {
+            BytesOperation::Insert { offset, buffer } => Operation::Insert {
+                offset,
+                buffer: buffer.to_vec(),
+            },
+            BytesOperation::Remove { offset, len } => Operation::Remove { offset, len },
+            BytesOperation::Copy { src_offset, len } => Operation::Copy { src_offset, len },
+            BytesOperation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => Operation::Replace {
+                offset,
+                old_len,
+                buffer: buffer.to_vec(),
+            },
+        }
+    }
+}
+
+/// Hints about how well the block size fits the data, see [`Delta::diff_report`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DiffReport {
+    /// The diff looks bad, most of the new buffer did not match the original.
+    pub suspicious: bool,
+    /// Why it looks bad.
+    pub reason: Option<String>,
+}
+
+/// Errors of the convenience diff functions like [`crate::diff`].
+#[derive(Debug)]
+pub enum DiffError {
+    /// Reading the new buffer failed.
+    Io(io::Error),
+    /// Blocks of this size can't be used, like zero.
+    InvalidBlockSize(usize),
+    /// The signature has no blocks for an original buffer that is not empty, it was never
+    /// calculated.
+    EmptySignature,
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::Io(e) => write!(f, "io error: {}", e),
+            DiffError::InvalidBlockSize(size) => write!(f, "invalid block size {}", size),
+            DiffError::EmptySignature => write!(f, "empty signature"),
+        }
+    }
+}
+
+impl Error for DiffError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DiffError::Io(e) => Some(e),
+            DiffError::InvalidBlockSize(_) | DiffError::EmptySignature => None,
+        }
+    }
+}
+
+impl From<io::Error> for DiffError {
+    fn from(e: io::Error) -> Self {
+        // the checks of the signature in `Delta::diff` come back wrapped in an io error.
+        if e.get_ref().is_some_and(|inner| inner.is::<DiffError>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast().expect("checked above");
+        }
+        DiffError::Io(e)
+    }
+}
+
+impl From<DiffError> for io::Error {
+    fn from(e: DiffError) -> Self {
+        match e {
+            DiffError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidInput, e),
+        }
+    }
+}
+
+/// Make sure the `sig` can be diffed against, it could have been decoded from anywhere.
+pub(crate) fn check_signature(sig: &IndexedSignature) -> Result<(), DiffError> {
+    if sig.block_size == 0 || matches!(sig.chunking, Chunking::Strided(0)) {
+        return Err(DiffError::InvalidBlockSize(0));
+    }
+    if sig.blocks.is_empty() && sig.original_buffer_len > 0 {
+        return Err(DiffError::EmptySignature);
+    }
+    Ok(())
+}
+
+/// Options for [`Delta::diff_with_options`], the default is the same as [`Delta::diff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiffOptions {
+    /// After this many bytes without a match, skip a whole block without looking for matches.
+    ///
+    /// on inputs that barely match, the window slides one byte at a time and checks for a
+    /// match at every byte, this bounds how long it takes, but any match inside the skipped
+    /// blocks is missed so the delta could be bigger.
+    pub resync_after: Option<usize>,
+    /// Only match blocks at most this many blocks away from the one right after the last
+    /// matched block (the first block before any match).
+    ///
+    /// meant for buffers with local edits, a block that repeats far away can't be picked over
+    /// the nearby one, which would remove (or copy) everything in between and fragment the
+    /// delta, but a big move or remove turns into inserts.
+    pub match_window: Option<usize>,
 }
 
+/// Less than this percent of the new buffer matched is suspicious.
+const MIN_MATCH_PERCENT: usize = 10;
+
+/// An insert longer than this many blocks is suspicious.
+const MAX_INSERT_BLOCKS: usize = 64;
+
+/// [`Delta::diff_with_hint`] doesn't reserve more than this many operations, whatever the hint.
+const MAX_RESERVED_OPS: usize = 4096;
+
 impl Delta {
     /// Create new [`Delta`].
     /// ### Example
@@ -116,8 +612,43 @@ impl Delta {
         Self {
             sig: signature,
             ops: Vec::new(),
+            matches: Vec::new(),
+            cache: None,
+            first_op: 0,
+            cost_model: None,
         }
     }
+
+    /// Use the `cost` of the operations to choose between the blocks of the original buffer
+    /// that match at the same place, the cheapest one wins, or none of them if the matched bytes
+    /// are cheaper to insert.
+    ///
+    /// without a cost model the last matching block wins, with one every block with the same
+    /// hashes is considered (only the blocks after the last match for a non explicit diff), the
+    /// cost of a block is the cost of the copy of it in an explicit diff, otherwise the cost of
+    /// the remove of the bytes skipped to get to it. line signatures ignore the cost model.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, Operation, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "abcdefghijklmnop");
+    /// signature.calculate();
+    /// // removes are so expensive that inserting the block is cheaper.
+    /// let mut delta = Delta::new(signature.to_indexed()).with_cost_model(|op| match op {
+    ///     Operation::Remove { len, .. } => len * 10,
+    ///     op => op.len(),
+    /// });
+    /// delta.diff("mnop").unwrap();
+    /// assert_eq!(delta.operations()[0], Operation::Insert { offset: 0, buffer: b"mnop".to_vec() });
+    /// ```
+    pub fn with_cost_model<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&Operation) -> usize + Send + Sync + 'static,
+    {
+        self.cost_model = Some(CostModel(Arc::new(cost)));
+        self
+    }
     /// Get the operations calculated so far.
     ///
     /// see [`Delta::into_operations`] if you don't need the [`Delta`] anymore.
@@ -132,162 +663,2876 @@ impl Delta {
         self.ops
     }
 
-    /// Calculate the diff between the original and modified buffers.
+    /// Consume `Self` and returns the new buffer as a [`Manifest`] of literals and references
+    /// to the matched blocks by their crypto hash.
     ///
-    /// Retuns Err in case if there is any IO operation failled.
-    pub fn diff(&mut self, buf: impl AsRef<[u8]>) -> io::Result<()> {
-        trace!("starting new diff");
-        let block_size = self.sig.block_size;
-        trace!("block_size = {}", block_size);
-        let original_buf_len = self.sig.original_buffer_len;
-        trace!("original_buf_len = {}", original_buf_len);
-        let mut window = Window::new(buf, block_size)?;
-        let mut hasher = RollingHasher::new();
-        let mut ins_buffer = Vec::new();
-        let mut last_matching_block_idx = -1;
-        trace!("last_matching_block_idx = {}", last_matching_block_idx);
-        hasher.update(window.frame().0);
-        trace!("start diff loop..");
-        while window.has_frame() {
-            let block_idx = self.find_match(hasher.digest(), &window, last_matching_block_idx);
-            trace!("block_idx = {:?}", block_idx);
-            trace!("current total bytes read: {}", window.bytes_read());
-            if let Some(block_idx) = block_idx {
-                if !ins_buffer.is_empty() {
-                    trace!(
-                        "insert buffer is not empty, add insert op with len: {}",
-                        ins_buffer.len()
-                    );
-                    self.add_insert_op(
-                        window.bytes_read() - ins_buffer.len(),
-                        mem::replace(&mut ins_buffer, Vec::new()),
-                    );
-                }
-                trace!("check if the current block id is greater than last matched one");
-                if block_idx as isize > last_matching_block_idx + 1 {
-                    trace!("okay, it is greater, add a remove op");
-                    let block_len = block_idx as isize - last_matching_block_idx - 1;
-                    let len = block_size as isize * block_len;
-                    self.add_remove_op(window.bytes_read(), len as usize);
-                }
-                trace!(
-                    "update last matched block id ({}) with the current matched block id ({})",
-                    last_matching_block_idx,
-                    block_idx
-                );
-                last_matching_block_idx = block_idx as isize;
-                trace!("move a block forword with block_size = {}", block_size);
-                for _ in 0..block_size {
-                    trace!("current total bytes read: {}", window.bytes_read());
-                    if window.on_boundry() && window.frame_size() == 0 {
-                        trace!("we hit the bounds and current frame size is zero; break");
-                        break;
-                    }
-                    trace!("move the window one byte forword ..");
-                    let (tail, head) = window.move_forword()?;
-                    if let Some(tail) = tail {
-                        trace!("rolling out the hash ..");
-                        hasher.remove(tail);
-                    }
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, ManifestEntry, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff("i saw a red box").unwrap();
+    /// let manifest = delta.into_manifest();
+    /// assert_eq!(manifest.block_refs().count(), 3);
+    /// assert_eq!(manifest.entries[3], ManifestEntry::Literal(b"box".to_vec()));
+    /// ```
+    pub fn into_manifest(self) -> Manifest {
+        let hashes: HashMap<usize, CryptoHash> = self
+            .sig
+            .blocks
+            .values()
+            .flatten()
+            .map(|(idx, block)| (*idx, block.crypto_hash))
+            .collect();
+        // everything in the new buffer is either inserted or matched, by where it starts.
+        let mut entries: Vec<(usize, ManifestEntry)> = self
+            .matches
+            .iter()
+            .map(|m| (m.offset, ManifestEntry::BlockRef(hashes[&m.index])))
+            .collect();
+        for op in self.ops {
+            if let Operation::Insert { offset, buffer } = op {
+                entries.push((offset, ManifestEntry::Literal(buffer)));
+            }
+        }
+        entries.sort_by_key(|(offset, _)| *offset);
+        Manifest {
+            entries: entries.into_iter().map(|(_, entry)| entry).collect(),
+        }
+    }
 
-                    if let Some(head) = head {
-                        trace!("rolling in the hash ..");
-                        hasher.insert(head);
-                    }
-                }
-                trace!(
-                    "moved a block, current total bytes read so far: {}",
-                    window.bytes_read()
-                );
-            } else {
-                trace!("no match found, moving the window forword one byte ..");
-                let (tail, head) = window.move_forword()?;
-                trace!("current total bytes read: {}", window.bytes_read());
-                if let Some(tail) = tail {
-                    trace!("rolling out the hash ..");
-                    hasher.remove(tail);
-                    trace!("add the current tail to the insert buffer ..");
-                    ins_buffer.push(tail);
-                }
-                if let Some(head) = head {
-                    trace!("rolling in the hash ..");
-                    hasher.insert(head);
+    /// Same as [`Delta::operations`] but each operation is tagged with a sequence id, for
+    /// logging them somewhere and keeping track of their order.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff("i saw a red box").unwrap();
+    /// for sequenced in delta.sequenced_operations() {
+    ///     println!("#{} {}", sequenced.id, sequenced.op);
+    /// }
+    /// ```
+    pub fn sequenced_operations(&self) -> Vec<SequencedOperation> {
+        self.ops
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(id, op)| SequencedOperation { id, op })
+            .collect()
+    }
+
+    /// The regions that did not change, as `(original_range, new_range)` pairs.
+    ///
+    /// this is the complement of the operations, which only describe the changes, neighbour
+    /// blocks that matched one after the other are merged into a single span.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(5, "hello there, do you know rust?");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff("hi, do you know about rustlang?").unwrap();
+    /// // " you know " is the same in both.
+    /// assert_eq!(delta.matched_spans(), vec![(15..25, 6..16)]);
+    /// ```
+    pub fn matched_spans(&self) -> Vec<(Range<usize>, Range<usize>)> {
+        let mut spans: Vec<(Range<usize>, Range<usize>)> = Vec::with_capacity(self.matches.len());
+        for m in &self.matches {
+            let offset = self.sig.block_offset(m.index);
+            let len = self.sig.block_len(m.index);
+            match spans.last_mut() {
+                Some((original, new)) if original.end == offset && new.end == m.offset => {
+                    original.end += len;
+                    new.end += len;
                 }
+                _ => spans.push((offset..offset + len, m.offset..m.offset + len)),
             }
         }
+        spans
+    }
 
-        trace!("diff loop ended.");
-        trace!("current total bytes read: {}", window.bytes_read());
-        trace!(
-            "check the insert buffer for any remaining bytes, len = {}",
-            ins_buffer.len()
-        );
-        if !ins_buffer.is_empty() {
-            self.add_insert_op(window.bytes_read() - ins_buffer.len(), ins_buffer);
+    /// The ranges of the original buffer that are not reused, the complement of the original
+    /// ranges of [`Delta::matched_spans`].
+    ///
+    /// these are the bytes the new buffer replaced, someone that has the original buffer but not
+    /// the inserts knows what it has to fetch.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(5, "hello there, do you know rust?");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff("hi, do you know about rustlang?").unwrap();
+    /// // all but " you know ".
+    /// assert_eq!(delta.required_ranges(), vec![0..15, 25..30]);
+    /// ```
+    pub fn required_ranges(&self) -> Vec<Range<usize>> {
+        let mut matched: Vec<_> = self
+            .matched_spans()
+            .into_iter()
+            .map(|(original, _)| original)
+            .collect();
+        matched.sort_unstable_by_key(|range| range.start);
+        let mut ranges = Vec::with_capacity(matched.len() + 1);
+        let mut cursor = 0;
+        for range in matched {
+            if range.start > cursor {
+                ranges.push(cursor..range.start);
+            }
+            cursor = cmp::max(cursor, range.end);
         }
-
-        let original_block_count = (original_buf_len + block_size - 1) / block_size;
-        trace!("checking if the last matched block is less than the original block count which means a remove op should be added!");
-        trace!("original block count = {}", original_block_count);
-        trace!("last matching block = {}", last_matching_block_idx + 1);
-        if last_matching_block_idx + 1 < original_block_count as isize {
-            let block_len = (last_matching_block_idx + 1) * block_size as isize;
-            let len = original_buf_len as isize - block_len;
-            self.add_remove_op(window.bytes_read(), len as usize);
+        if cursor < self.sig.original_buffer_len {
+            ranges.push(cursor..self.sig.original_buffer_len);
         }
-        Ok(())
+        ranges
     }
 
-    fn add_insert_op(&mut self, offset: usize, buffer: Vec<u8>) {
-        trace!(
-            "Insert: at {} with len {} and buf = {} {:?}",
-            offset,
-            buffer.len(),
-            String::from_utf8_lossy(&buffer),
-            buffer
-        );
-        self.ops.push(Operation::Insert { offset, buffer });
+    /// Check the operations calculated so far for signs of a badly chosen block size, like a
+    /// new buffer that barely matched the original or a very long literal insert.
+    ///
+    /// the same checks are logged as warnings at the end of [`Delta::diff`].
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff("i saw a red box").unwrap();
+    /// assert!(!delta.diff_report().suspicious);
+    /// ```
+    pub fn diff_report(&self) -> DiffReport {
+        let matched: usize = self
+            .matches
+            .iter()
+            .map(|m| self.sig.block_len(m.index))
+            .sum();
+        let inserts = self.ops.iter().filter_map(Operation::buffer);
+        let inserted: usize = inserts.clone().map(<[u8]>::len).sum();
+        let longest_insert = inserts.map(<[u8]>::len).max().unwrap_or(0);
+        let max_insert = MAX_INSERT_BLOCKS * self.sig.block_size;
+        let reason = if longest_insert > max_insert {
+            Some(format!(
+                "a literal insert of {} bytes is longer than {} blocks, try a smaller block size",
+                longest_insert, MAX_INSERT_BLOCKS
+            ))
+        } else if inserted > 0 && matched * 100 < (matched + inserted) * MIN_MATCH_PERCENT {
+            Some(format!(
+                "only {} of {} bytes matched the original, try a smaller block size",
+                matched,
+                matched + inserted
+            ))
+        } else {
+            None
+        };
+        DiffReport {
+            suspicious: reason.is_some(),
+            reason,
+        }
     }
 
-    fn add_remove_op(&mut self, offset: usize, len: usize) {
-        trace!("Remove: at {} with len {}", offset, len,);
-        self.ops.push(Operation::Remove { offset, len });
+    /// Calculate the diff between the original and modified buffers.
+    ///
+    /// the window that slides over `buf` is always created with the block size of the
+    /// signature, it is not public on purpose so a window with another block size can't be
+    /// passed in and silently produce garbage.
+    ///
+    /// ```compile_fail
+    /// let window = rsdiff::window::Window::new("i saw a red box", 8);
+    /// ```
+    ///
+    /// Retuns Err in case if there is any IO operation failled, or if the signature can't be
+    /// diffed against, like one with a block size of zero or one that was never calculated, the
+    /// error then wraps a [`DiffError`].
+    pub fn diff(&mut self, buf: impl AsRef<[u8]>) -> io::Result<()> {
+        self.diff_with_options(buf, DiffOptions::default())
     }
 
-    /// Try to find a matched block from the original buffer signature.
-    /// if so, it will try to find if the current block index is the same as the one we matched.
-    /// if so, it is not modified, but if it fails these condations, it means there is a
-    /// modification happened in this block.
-    fn find_match<B: AsRef<[u8]>>(
-        &self,
-        weak_hash: u32,
-        window: &Window<B>,
-        last_matching_block_idx: isize,
-    ) -> Option<usize> {
-        trace!("weak_hash of the current frame = 0x{:0x}", weak_hash);
-        match self.sig.blocks.get(&weak_hash) {
-            Some((idx, block)) => {
-                trace!("found a match with the weak hash !!!");
-                let mut blake2 = Blake2b::new();
-                let (front, back) = window.frame();
-                blake2.update(front);
-                blake2.update(back);
-                let result = blake2.finalize();
-                let crypto_hash = CryptoHash::new(&result[..32]);
-                trace!("comparing the crypto hash");
-                let crypto_match = block.crypto_hash == crypto_hash;
-                let new_idx = *idx as isize > last_matching_block_idx;
-                trace!("crypto_match ? {}", crypto_match);
-                trace!("new_idx ? {}", new_idx);
-                if crypto_match && new_idx {
-                    trace!("all matched !!!");
-                    Some(*idx)
-                } else {
-                    trace!("crypto hash did not match, skip ..");
-                    None
-                }
+    /// Same as [`Delta::diff`] but the operations replace the ones calculated so far, and they
+    /// are remembered along with the hash of `buf`, diffing the same buffer again returns them
+    /// right away.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff_cached("i saw a red box").unwrap();
+    /// let ops = delta.operations().to_vec();
+    /// // nothing is calculated this time.
+    /// delta.diff_cached("i saw a red box").unwrap();
+    /// assert_eq!(delta.operations(), &ops[..]);
+    /// ```
+    pub fn diff_cached(&mut self, buf: impl AsRef<[u8]>) -> io::Result<()> {
+        let buf = buf.as_ref();
+        let hash = CryptoHash::calculate(buf);
+        if let Some((cached, ops, matches)) = &self.cache {
+            if cached.ct_eq(&hash) {
+                trace!("same buffer as the last one, using the cached operations");
+                self.ops = ops.clone();
+                self.matches = matches.clone();
+                self.first_op = 0;
+                return Ok(());
+            }
+        }
+        self.ops.clear();
+        self.matches.clear();
+        self.diff(buf)?;
+        self.cache = Some((hash, self.ops.clone(), self.matches.clone()));
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but with more control over how the diff is done, see
+    /// [`DiffOptions`].
+    pub fn diff_with_options(
+        &mut self,
+        buf: impl AsRef<[u8]>,
+        options: DiffOptions,
+    ) -> io::Result<()> {
+        self.diff_inner(buf.as_ref(), options, &mut NoopObserver, None)?;
+        if let Some(reason) = self.diff_report().reason {
+            warn!("{}", reason);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but every matched block becomes an [`Operation::Copy`] from the
+    /// original buffer, so the operations are only copies and inserts, and the new buffer is
+    /// made of them alone, in order.
+    ///
+    /// a block can be copied from anywhere in the original buffer, even more than once, where
+    /// [`Delta::diff`] only matches the blocks after the last one and removes what was skipped.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta, Operation};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff_with_copy("a red fox, i saw").unwrap();
+    /// assert_eq!(
+    ///     delta.operations(),
+    ///     &[
+    ///         Operation::Insert { offset: 0, buffer: b"a ".to_vec() },
+    ///         Operation::Copy { src_offset: 8, len: 4 },
+    ///         Operation::Insert { offset: 6, buffer: b"fox, ".to_vec() },
+    ///         Operation::Copy { src_offset: 0, len: 4 },
+    ///         Operation::Insert { offset: 15, buffer: b"w".to_vec() },
+    ///     ]
+    /// );
+    /// ```
+    pub fn diff_with_copy(&mut self, buf: impl AsRef<[u8]>) -> io::Result<()> {
+        let buf = buf.as_ref();
+        check_signature(&self.sig)?;
+        self.first_op = self.ops.len();
+        if let Chunking::Lines(_) = self.sig.chunking {
+            let first_match = self.matches.len();
+            let lines = buf.split_inclusive(|b| *b == b'\n').map(Ok);
+            self.diff_lines(lines, &mut NoopObserver, None)?;
+            // the same lines match, only the operations around them change.
+            self.ops.truncate(self.first_op);
+            let matches = self.matches.split_off(first_match);
+            self.push_matched_operations(buf, matches, true);
+        } else {
+            let differ = Differ::explicit(buf, self.sig.block_size)?;
+            self.diff_copies(differ, &mut NoopObserver, None)?;
+        }
+        if let Some(reason) = self.diff_report().reason {
+            warn!("{}", reason);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but the new buffer is read from the `reader` as the diff goes,
+    /// so it never has to be in memory as a whole, only about two blocks of it are, or a line
+    /// for line signatures, the operations are the same as diffing the whole buffer.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff_reader(&b"i saw a red box"[..]).unwrap();
+    /// let mut expected = Delta::new(signature.to_indexed());
+    /// expected.diff("i saw a red box").unwrap();
+    /// assert_eq!(delta.operations(), expected.operations());
+    /// ```
+    pub fn diff_reader<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        check_signature(&self.sig)?;
+        self.first_op = self.ops.len();
+        let block_size = self.sig.block_size;
+        match self.sig.chunking {
+            Chunking::Lines(_) => {
+                let mut reader = BufReader::new(reader);
+                let lines = iter::from_fn(|| {
+                    let mut line = Vec::new();
+                    match reader.read_until(b'\n', &mut line) {
+                        Ok(0) => None,
+                        Ok(_) => Some(Ok(line)),
+                        Err(e) => Some(Err(e)),
+                    }
+                });
+                self.diff_lines(lines, &mut NoopObserver, None)?;
+            }
+            Chunking::Parts(_) => {
+                let differ = Differ::from_reader(reader, block_size)?.into_explicit();
+                self.diff_copies(differ, &mut NoopObserver, None)?;
+            }
+            _ => {
+                let differ = Differ::from_reader(reader, block_size)?
+                    .with_cost_model(self.cost_model.clone());
+                self.run(differ, &mut NoopObserver, None)?;
+            }
+        }
+        if let Some(reason) = self.diff_report().reason {
+            warn!("{}", reason);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but the length of `buf` is known ahead, like from a header, so
+    /// the operations are reserved up front and a buffer that was cut short is caught.
+    ///
+    /// fails with an [`io::ErrorKind::UnexpectedEof`] error if `buf` is shorter than
+    /// `expected_len`, or an [`io::ErrorKind::InvalidData`] error if it is longer or the
+    /// operations don't produce `expected_len` bytes, nothing is diffed if the length is wrong.
+    ///
+    /// ### Example
+    /// ```
+    /// use std::io;
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// let err = delta.diff_with_hint("i saw a red", 15).unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    /// delta.diff_with_hint("i saw a red box", 15).unwrap();
+    /// ```
+    pub fn diff_with_hint(&mut self, buf: impl AsRef<[u8]>, expected_len: usize) -> io::Result<()> {
+        let buf = buf.as_ref();
+        if buf.len() != expected_len {
+            let kind = if buf.len() < expected_len {
+                io::ErrorKind::UnexpectedEof
+            } else {
+                io::ErrorKind::InvalidData
+            };
+            return Err(io::Error::new(
+                kind,
+                format!("expected {} bytes, got {}", expected_len, buf.len()),
+            ));
+        }
+        // a guess, most blocks match and the ones that don't come in runs.
+        self.ops.reserve(cmp::min(
+            expected_len / self.sig.block_size.max(1) / 4 + 1,
+            MAX_RESERVED_OPS,
+        ));
+        self.diff(buf)?;
+        let ops = &self.ops[self.first_op..];
+        let patched_len = if ops.iter().any(Operation::is_copy) {
+            ops.iter()
+                .filter(|op| !op.is_remove())
+                .map(Operation::len)
+                .sum()
+        } else {
+            (self.sig.original_buffer_len + crate::inserted_bytes(ops))
+                .checked_sub(crate::removed_bytes(ops))
+                .unwrap_or(usize::MAX)
+        };
+        if patched_len != expected_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the operations produce {} bytes instead of {}",
+                    patched_len, expected_len
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but the first `known_matched_prefix` bytes of `new` are known to
+    /// be the same as the original buffer, so they are not matched again, which is handy for
+    /// buffers that only grow like logs.
+    ///
+    /// the whole blocks of the prefix are taken as matched and the diff starts right after
+    /// them, if the prefix is correct and the original buffer has no repeated blocks (or weak
+    /// hash collisions) the result is the same as [`Delta::diff`]. if the prefix is wrong the
+    /// result is undefined, the operations won't produce `new`.
+    ///
+    /// only fixed size blocks can skip the prefix, any other signature is diffed as a whole.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "first line\n");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff_from("first line\nsecond line\n", 11).unwrap();
+    /// assert_eq!(rsdiff::inserted_bytes(delta.operations()), 12);
+    /// ```
+    pub fn diff_from(
+        &mut self,
+        new: impl AsRef<[u8]>,
+        known_matched_prefix: usize,
+    ) -> io::Result<()> {
+        let new = new.as_ref();
+        check_signature(&self.sig)?;
+        if self.sig.chunking != Chunking::Fixed {
+            return self.diff(new);
+        }
+        let prefix = cmp::min(
+            known_matched_prefix,
+            cmp::min(new.len(), self.sig.original_buffer_len),
+        );
+        let block_size = self.sig.block_size;
+        let blocks = prefix / block_size;
+        let start = blocks * block_size;
+        trace!("skipping {} known blocks", blocks);
+        self.matches.extend((0..blocks).map(|index| BlockMatch {
+            index,
+            offset: index * block_size,
+        }));
+        let differ =
+            Differ::new_at(new, block_size, start, start)?.with_cost_model(self.cost_model.clone());
+        self.first_op = self.ops.len();
+        self.run(differ, &mut NoopObserver, None)?;
+        if let Some(reason) = self.diff_report().reason {
+            warn!("{}", reason);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but the `observer` sees what happens while matching, see
+    /// [`ObserverEvent`].
+    pub fn diff_observed<O: Observer>(
+        &mut self,
+        buf: impl AsRef<[u8]>,
+        observer: &mut O,
+    ) -> io::Result<()> {
+        self.diff_inner(buf.as_ref(), DiffOptions::default(), observer, None)
+    }
+
+    /// Same as [`Delta::diff`] but stops as soon as the `cancelled` flag is set, from another
+    /// thread or a timer, with an [`io::ErrorKind::Interrupted`] error.
+    ///
+    /// the flag is checked for every byte of `buf`, or every line of a line signature, the
+    /// operations of a cancelled diff are dropped.
+    ///
+    /// ### Example
+    /// ```
+    /// use std::io;
+    /// use std::sync::atomic::AtomicBool;
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// let cancelled = AtomicBool::new(true);
+    /// let err = delta.diff_cancellable("i saw a red box", &cancelled).unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    /// assert!(delta.operations().is_empty());
+    /// ```
+    pub fn diff_cancellable(
+        &mut self,
+        buf: impl AsRef<[u8]>,
+        cancelled: &AtomicBool,
+    ) -> io::Result<()> {
+        let (first_op, first_match) = (self.ops.len(), self.matches.len());
+        let result = self.diff_inner(
+            buf.as_ref(),
+            DiffOptions::default(),
+            &mut NoopObserver,
+            Some(cancelled),
+        );
+        if result.is_err() {
+            self.ops.truncate(first_op);
+            self.matches.truncate(first_match);
+        }
+        result
+    }
+
+    /// Same as [`Delta::diff`] but the operations are calculated lazily, one at a time as the
+    /// iterator is advanced, so they can be streamed somewhere without holding all of them.
+    ///
+    /// the operations are not kept in the delta, and the iterator stops after the first error.
+    /// only signatures of fixed or strided blocks are diffed lazily, the others are diffed
+    /// right away and their operations handed out one by one.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// let ops: Vec<_> = delta
+    ///     .iter_operations(b"i saw a red box")
+    ///     .collect::<std::io::Result<_>>()
+    ///     .unwrap();
+    /// let expected = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box");
+    /// assert_eq!(ops, expected.unwrap());
+    /// assert!(delta.operations().is_empty());
+    /// ```
+    pub fn iter_operations<'a>(
+        &'a mut self,
+        buf: &'a [u8],
+    ) -> impl Iterator<Item = io::Result<Operation>> + 'a {
+        let mut state = match check_signature(&self.sig) {
+            Err(e) => LazyOps::Failed(e.into()),
+            Ok(()) => match self.sig.chunking {
+                Chunking::Fixed | Chunking::Strided(_) => {
+                    match Differ::new(buf, self.sig.block_size) {
+                        Ok(differ) => LazyOps::Lazy(
+                            differ.with_cost_model(self.cost_model.clone()),
+                            &mut self.sig,
+                        ),
+                        Err(e) => LazyOps::Failed(e),
+                    }
+                }
+                _ => {
+                    let (first_op, first_match) = (self.first_op, self.matches.len());
+                    let start = self.ops.len();
+                    let result = self.diff(buf);
+                    let ops: Vec<_> = self.ops.drain(start..).collect();
+                    self.matches.truncate(first_match);
+                    self.first_op = first_op;
+                    match result {
+                        Ok(()) => LazyOps::Eager(ops.into_iter()),
+                        Err(e) => LazyOps::Failed(e),
+                    }
+                }
+            },
+        };
+        iter::from_fn(move || match &mut state {
+            LazyOps::Lazy(differ, sig) => loop {
+                match differ.next_event(&mut **sig, &mut NoopObserver) {
+                    Ok(Some(Event::Op(op))) => return Some(Ok(op)),
+                    Ok(Some(Event::Match(_))) => continue,
+                    Ok(None) => {
+                        state = LazyOps::Done;
+                        return None;
+                    }
+                    Err(e) => {
+                        state = LazyOps::Done;
+                        return Some(Err(e));
+                    }
+                }
+            },
+            LazyOps::Eager(ops) => ops.next().map(Ok),
+            LazyOps::Failed(_) => match mem::replace(&mut state, LazyOps::Done) {
+                LazyOps::Failed(e) => Some(Err(e)),
+                _ => unreachable!(),
+            },
+            LazyOps::Done => None,
+        })
+    }
+
+    /// Same as [`Delta::iter_operations`] but all at once, and the inserted bytes are
+    /// [`Bytes`] slices of `buf` instead of copies, so they share its memory, behind the `bytes`
+    /// feature.
+    ///
+    /// the operations are not kept in the delta.
+    ///
+    /// ### Example
+    /// ```
+    /// use bytes::Bytes;
+    /// use rsdiff::{BytesOperation, Delta, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// let new = Bytes::from_static(b"i saw a red box");
+    /// let ops = delta.diff_bytes(new.clone()).unwrap();
+    /// assert_eq!(
+    ///     ops[0],
+    ///     BytesOperation::Insert { offset: 12, buffer: new.slice(12..15) }
+    /// );
+    /// ```
+    #[cfg(feature = "bytes")]
+    pub fn diff_bytes(&mut self, buf: Bytes) -> io::Result<Vec<BytesOperation>> {
+        self.iter_operations(&buf)
+            .map(|op| op.map(|op| BytesOperation::slice(op, &buf)))
+            .collect()
+    }
+
+    fn diff_inner<O: Observer>(
+        &mut self,
+        buf: &[u8],
+        options: DiffOptions,
+        observer: &mut O,
+        cancelled: Option<&AtomicBool>,
+    ) -> io::Result<()> {
+        check_signature(&self.sig)?;
+        self.first_op = self.ops.len();
+        match self.sig.chunking {
+            Chunking::Lines(_) => {
+                let lines = buf.split_inclusive(|b| *b == b'\n').map(Ok);
+                return self.diff_lines(lines, observer, cancelled);
+            }
+            Chunking::Parts(_) => {
+                let differ = Differ::explicit(buf, self.sig.block_size)?.with_options(options);
+                return self.diff_copies(differ, observer, cancelled);
+            }
+            _ => {}
+        }
+        let differ = Differ::new(buf, self.sig.block_size)?
+            .with_options(options)
+            .with_cost_model(self.cost_model.clone());
+        self.run(differ, observer, cancelled)
+    }
+
+    /// Run the `differ` to the end, collecting its operations and matches.
+    fn run<W: Frames, O: Observer>(
+        &mut self,
+        mut differ: Differ<W>,
+        observer: &mut O,
+        cancelled: Option<&AtomicBool>,
+    ) -> io::Result<()> {
+        while let Some(event) = next_event(&mut differ, &mut self.sig, observer, cancelled)? {
+            match event {
+                Event::Op(Operation::Insert { offset, buffer }) => {
+                    self.add_insert_op(offset, buffer)
+                }
+                Event::Op(Operation::Remove { offset, len }) => self.add_remove_op(offset, len),
+                Event::Op(op) => self.ops.push(op),
+                Event::Match(m) => self.matches.push(m),
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but `buf` is split into about `segments` pieces, each one diffed
+    /// on its own thread, the matched blocks become [`Operation::Copy`]s as they can come from
+    /// anywhere in the original buffer.
+    ///
+    /// every piece reads `block_size - 1` bytes into the next one, so a block that crosses the
+    /// boundary is still matched, and the operations of the overlap are merged so its bytes are
+    /// only there once. the pieces are at least a block long, a line signature is diffed on a
+    /// single thread using [`Delta::diff`].
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta, Operation};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// delta.diff_parallel("i saw a red box", 2).unwrap();
+    /// assert_eq!(
+    ///     delta.operations(),
+    ///     &[
+    ///         Operation::Copy { src_offset: 0, len: 12 },
+    ///         Operation::Insert { offset: 12, buffer: b"box".to_vec() },
+    ///     ]
+    /// );
+    /// ```
+    pub fn diff_parallel(&mut self, buf: impl AsRef<[u8]>, segments: usize) -> io::Result<()> {
+        let buf = buf.as_ref();
+        check_signature(&self.sig)?;
+        if let Chunking::Lines(_) = self.sig.chunking {
+            return self.diff(buf);
+        }
+        self.first_op = self.ops.len();
+        let block_size = self.sig.block_size;
+        let segment_len = cmp::max(buf.len().div_ceil(segments.max(1)), block_size);
+        let bounds: Vec<_> = (0..cmp::max(buf.len(), 1))
+            .step_by(segment_len)
+            .map(|start| start..cmp::min(start + segment_len, buf.len()))
+            .collect();
+        trace!("diffing {} segments of {} bytes", bounds.len(), segment_len);
+        let sig = &self.sig;
+        let cost_model = &self.cost_model;
+        let results: Vec<io::Result<Vec<Event>>> = thread::scope(|scope| {
+            let handles: Vec<_> = bounds
+                .iter()
+                .map(|bounds| {
+                    let overlap = cmp::min(bounds.end + block_size - 1, buf.len());
+                    let segment = &buf[bounds.start..overlap];
+                    scope.spawn(move || {
+                        let mut differ = Differ::explicit(segment, block_size)?
+                            .with_cost_model(cost_model.clone());
+                        let mut source = sig;
+                        let mut events = Vec::new();
+                        while let Some(event) = differ.next_event(&mut source, &mut NoopObserver)? {
+                            events.push(event);
+                        }
+                        Ok(events)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("diff thread panicked"))
+                .collect()
+        });
+        // how much of `buf` the operations cover so far, the overlap of a segment with the next
+        // one is covered by whichever gets there first.
+        let mut covered = 0;
+        let count = bounds.len();
+        for (i, (bounds, events)) in bounds.into_iter().zip(results).enumerate() {
+            let last = i + 1 == count;
+            for event in events? {
+                let (start, end) = match &event {
+                    Event::Op(Operation::Insert { offset, buffer }) => {
+                        let start = bounds.start + offset;
+                        (start, start + buffer.len())
+                    }
+                    Event::Match(m) => {
+                        let start = bounds.start + m.offset;
+                        (start, start + self.sig.block_len(m.index))
+                    }
+                    Event::Op(op) => unreachable!("explicit diffs only insert, got {:?}", op),
+                };
+                // the rest is the next segment's, unless it's a block crossing into it.
+                let end = match event {
+                    Event::Op(_) if !last => cmp::min(end, bounds.end),
+                    _ => end,
+                };
+                if start >= end || end <= covered || (!last && start >= bounds.end) {
+                    continue;
+                }
+                let trim = covered.saturating_sub(start);
+                let start = start + trim;
+                match event {
+                    Event::Op(_) => match self.ops[self.first_op..].last_mut() {
+                        Some(Operation::Insert { offset, buffer })
+                            if *offset + buffer.len() == start =>
+                        {
+                            buffer.extend_from_slice(&buf[start..end])
+                        }
+                        _ => self.add_insert_op(start, buf[start..end].to_vec()),
+                    },
+                    Event::Match(m) => {
+                        let src_offset = self.sig.block_offset(m.index) + trim;
+                        let len = end - start;
+                        match self.ops[self.first_op..].last_mut() {
+                            Some(Operation::Copy {
+                                src_offset: last,
+                                len: last_len,
+                            }) if *last + *last_len == src_offset => *last_len += len,
+                            _ => self.ops.push(Operation::Copy { src_offset, len }),
+                        }
+                        if trim == 0 {
+                            self.matches.push(BlockMatch {
+                                index: m.index,
+                                offset: start,
+                            });
+                        }
+                    }
+                }
+                covered = end;
+            }
+        }
+        // without any copy these are plain inserts, so the original buffer has to go.
+        if !self.ops[self.first_op..].iter().any(Operation::is_copy)
+            && self.sig.original_buffer_len > 0
+        {
+            self.add_remove_op(buf.len(), self.sig.original_buffer_len);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but makes sure there is at most `max_ops` operations.
+    ///
+    /// when there are too many operations, the nearby changes are merged together by treating
+    /// the unchanged bytes between them as literals, starting with the smallest gaps, which
+    /// makes the inserts bigger.
+    ///
+    /// if `max_ops` can't be met even after merging everything, the operations are replaced by a
+    /// single insert of the whole new buffer plus the remove of the whole original buffer, or a
+    /// single [`Operation::Replace`] of it if that is still too many.
+    ///
+    /// the operations of a signature made of parts (see [`IndexedSignature::from_parts`]) are
+    /// copies, the shortest ones are turned into literals first and merged with the inserts
+    /// around them.
+    ///
+    /// ### Panics
+    /// if `max_ops` is zero, any change takes at least one operation.
+    pub fn diff_capped(&mut self, buf: impl AsRef<[u8]>, max_ops: usize) -> io::Result<()> {
+        assert!(max_ops != 0, "max ops must be > 0");
+        let buf = buf.as_ref();
+        let first_op = self.ops.len();
+        let first_match = self.matches.len();
+        self.diff(buf)?;
+        if self.ops.len() - first_op <= max_ops {
+            return Ok(());
+        }
+        if let Chunking::Parts(_) = self.sig.chunking {
+            self.cap_copies(buf, first_op, first_match, max_ops);
+            self.cap_replace(first_op, max_ops);
+            return Ok(());
+        }
+        let hunks = Hunk::from_operations(&self.ops[first_op..]);
+        let mut gaps: Vec<_> = hunks
+            .windows(2)
+            .map(|h| h[1].new.start - h[0].new.end)
+            .collect();
+        gaps.sort_unstable();
+        gaps.dedup();
+        // find the smallest gap that if we merged everything up to it, we meet the cap.
+        let idx =
+            gaps.partition_point(|gap| Hunk::op_count(&Hunk::coalesce(&hunks, *gap)) > max_ops);
+        let hunks = match gaps.get(idx) {
+            Some(gap) => Hunk::coalesce(&hunks, *gap),
+            None => vec![Hunk {
+                new: 0..buf.len(),
+                original: 0..self.sig.original_buffer_len,
+            }],
+        };
+        trace!(
+            "capped {} ops into {} hunks",
+            self.ops.len() - first_op,
+            hunks.len()
+        );
+        self.ops.truncate(first_op);
+        // the matches that got merged are not matches anymore.
+        let mut i = first_match;
+        while i < self.matches.len() {
+            let offset = self.matches[i].offset;
+            if hunks.iter().any(|h| h.new.contains(&offset)) {
+                self.matches.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        for hunk in hunks {
+            if !hunk.new.is_empty() {
+                self.add_insert_op(hunk.new.start, buf[hunk.new.clone()].to_vec());
+            }
+            if !hunk.original.is_empty() {
+                self.add_remove_op(hunk.new.end, hunk.original.len());
+            }
+        }
+        self.cap_replace(first_op, max_ops);
+        Ok(())
+    }
+
+    /// Turn the insert and remove of the whole buffer after `first_op` into a single replace if
+    /// they are more than `max_ops` operations.
+    fn cap_replace(&mut self, first_op: usize, max_ops: usize) {
+        if self.ops.len() - first_op <= max_ops {
+            return;
+        }
+        if let [Operation::Insert { buffer, .. }, Operation::Remove { len, .. }] =
+            &mut self.ops[first_op..]
+        {
+            let op = Operation::Replace {
+                offset: 0,
+                old_len: *len,
+                buffer: mem::take(buffer),
+            };
+            self.ops.truncate(first_op);
+            self.ops.push(op);
+        }
+    }
+
+    /// Turn the shortest copies of `buf` after `first_op` into literals until there is at most
+    /// `max_ops` operations, or none of them is left.
+    fn cap_copies(&mut self, buf: &[u8], first_op: usize, first_match: usize, max_ops: usize) {
+        let ops = self.ops.split_off(first_op);
+        let mut lens: Vec<_> = ops
+            .iter()
+            .filter_map(|op| match op {
+                Operation::Copy { len, .. } => Some(*len),
+                _ => None,
+            })
+            .collect();
+        lens.sort_unstable();
+        lens.dedup();
+        let original_len = self.sig.original_buffer_len;
+        let idx = lens
+            .partition_point(|len| inline_copies(&ops, buf, *len, original_len).len() > max_ops);
+        let capped = inline_copies(
+            &ops,
+            buf,
+            lens.get(idx).copied().unwrap_or(usize::MAX),
+            original_len,
+        );
+        trace!("capped {} ops into {}", ops.len(), capped.len());
+        // the matches that got inlined are not matches anymore.
+        let literals: Vec<_> = capped
+            .iter()
+            .filter_map(|op| match op {
+                Operation::Insert { offset, buffer } => Some(*offset..*offset + buffer.len()),
+                _ => None,
+            })
+            .collect();
+        let mut i = first_match;
+        while i < self.matches.len() {
+            let offset = self.matches[i].offset;
+            if literals.iter().any(|range| range.contains(&offset)) {
+                self.matches.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        self.ops.extend(capped);
+    }
+
+    /// A second pass over the last [`Delta::diff`] of `new` that tries to match the literal bytes
+    /// right before every matched block with the blocks right before it in the original buffer,
+    /// which makes the inserts smaller.
+    ///
+    /// the forward pass misses such blocks when they were skipped by
+    /// [`DiffOptions::resync_after`], this costs a hash of every block it tries.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, DiffOptions, Signature};
+    ///
+    /// let original = "i saw a red fox";
+    /// let new = "xyzi saw a red fox";
+    /// let mut signature = Signature::with_block_size(4, original);
+    /// signature.calculate();
+    /// let mut delta = Delta::new(signature.to_indexed());
+    /// let options = DiffOptions { resync_after: Some(2), ..Default::default() };
+    /// delta.diff_with_options(new, options).unwrap();
+    /// assert!(rsdiff::inserted_bytes(delta.operations()) > 3);
+    /// delta.optimize(new);
+    /// assert_eq!(rsdiff::inserted_bytes(delta.operations()), 3);
+    /// ```
+    pub fn optimize(&mut self, new: impl AsRef<[u8]>) {
+        let new = new.as_ref();
+        let explicit = matches!(self.sig.chunking, Chunking::Parts(_));
+        let hashes: HashMap<usize, BlockHash> = self
+            .sig
+            .blocks
+            .values()
+            .flatten()
+            .map(|(idx, block)| (*idx, *block))
+            .collect();
+        let mut hasher = CryptoHasher::new(self.sig.hash);
+        let mut matches = Vec::with_capacity(self.matches.len());
+        let mut extended = false;
+        // where the last match ends, in the new and the original buffers.
+        let (mut new_end, mut original_end) = (0, 0);
+        for m in mem::take(&mut self.matches) {
+            let mut found = Vec::new();
+            let (mut offset, mut index) = (m.offset, m.index);
+            while index > 0 {
+                let prev = index - 1;
+                let start = match offset.checked_sub(self.sig.block_len(prev)) {
+                    Some(start) if start >= new_end => start,
+                    _ => break,
+                };
+                if !explicit && self.sig.block_offset(prev) < original_end {
+                    break;
+                }
+                let hash = BlockHash::calculate(&mut hasher, &new[start..offset]);
+                if hashes.get(&prev) != Some(&self.sig.salt_block(hash)) {
+                    break;
+                }
+                trace!("block {} extended backward to offset {}", prev, start);
+                found.push(BlockMatch {
+                    index: prev,
+                    offset: start,
+                });
+                offset = start;
+                index = prev;
+            }
+            extended |= !found.is_empty();
+            matches.extend(found.into_iter().rev());
+            new_end = m.offset + self.sig.block_len(m.index);
+            original_end = self.sig.block_offset(m.index) + self.sig.block_len(m.index);
+            matches.push(m);
+        }
+        self.matches = matches;
+        if extended {
+            self.rebuild_operations(new, explicit);
+        }
+    }
+
+    /// Calculate the operations again from the matched blocks.
+    fn rebuild_operations(&mut self, new: &[u8], explicit: bool) {
+        self.ops.clear();
+        self.first_op = 0;
+        let matches = mem::take(&mut self.matches);
+        self.push_matched_operations(new, matches, explicit);
+    }
+
+    /// Add the operations that turn the original buffer into `new` given the `matches` in it,
+    /// the matched blocks are copied if `explicit`, otherwise the rest of the original buffer
+    /// is removed.
+    fn push_matched_operations(&mut self, new: &[u8], matches: Vec<BlockMatch>, explicit: bool) {
+        let mut pos = 0;
+        let mut next_offset = 0;
+        for m in matches {
+            if m.offset > pos {
+                self.add_insert_op(pos, new[pos..m.offset].to_vec());
+            }
+            let src_offset = self.sig.block_offset(m.index);
+            let len = self.sig.block_len(m.index);
+            if explicit {
+                match self.ops[self.first_op..].last_mut() {
+                    Some(Operation::Copy {
+                        src_offset: last,
+                        len: last_len,
+                    }) if *last + *last_len == src_offset => *last_len += len,
+                    _ => self.ops.push(Operation::Copy { src_offset, len }),
+                }
+            } else {
+                if src_offset > next_offset {
+                    self.add_remove_op(m.offset, src_offset - next_offset);
+                }
+                next_offset = src_offset + len;
+            }
+            pos = m.offset + len;
+            self.matches.push(m);
+        }
+        if pos < new.len() {
+            self.add_insert_op(pos, new[pos..].to_vec());
+        }
+        let has_copies = self.ops[self.first_op..].iter().any(Operation::is_copy);
+        if !has_copies && next_offset < self.sig.original_buffer_len {
+            self.add_remove_op(new.len(), self.sig.original_buffer_len - next_offset);
+        }
+    }
+
+    /// Explain why each operation would be emitted when diffing `new` against the signature.
+    ///
+    /// this is like the trace logs of [`Delta::diff`] but aimed at humans, every line is either
+    /// a block of the original buffer that was found in `new` or an [`Operation`] followed by
+    /// the reason it was emitted.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, Delta};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let delta = Delta::new(signature.to_indexed());
+    /// let explanation = delta.explain(b"i saw a red box");
+    /// assert!(explanation.contains("block 2 matched at offset 8"));
+    /// ```
+    pub fn explain(&self, new: &[u8]) -> String {
+        let mut delta = Delta::new(self.sig.clone());
+        if let Err(e) = delta.diff(new) {
+            return format!("failed to diff: {}", e);
+        }
+        let mut out = String::new();
+        let mut matches = delta.matches.iter().peekable();
+        // the next block we expect from the original buffer.
+        let mut next_block = 0;
+        for op in &delta.ops {
+            while let Some(m) = matches.next_if(|m| m.offset < op.offset()) {
+                out.push_str(&format!(
+                    "block {} matched at offset {}\n",
+                    m.index, m.offset
+                ));
+                next_block = m.index + 1;
+            }
+            match op {
+                Operation::Insert { buffer, .. } => out.push_str(&format!(
+                    "{}: no match, literal insert of {} bytes\n",
+                    op,
+                    buffer.len()
+                )),
+                Operation::Remove { len, .. } => {
+                    let end = delta.sig.block_offset(next_block) + len;
+                    let mut last_block = next_block;
+                    while delta.sig.block_offset(last_block + 1) < end {
+                        last_block += 1;
+                    }
+                    let blocks = if last_block == next_block {
+                        format!("block {}", next_block)
+                    } else {
+                        format!("blocks {} to {}", next_block, last_block)
+                    };
+                    out.push_str(&format!(
+                        "{}: {} not found, remove of {} bytes\n",
+                        op, blocks, len
+                    ));
+                    next_block = last_block + 1;
+                }
+                Operation::Copy { len, .. } => out.push_str(&format!(
+                    "{}: copy of {} bytes from the original\n",
+                    op, len
+                )),
+                Operation::Replace { buffer, .. } => out.push_str(&format!(
+                    "{}: no match, literal replace of {} bytes\n",
+                    op,
+                    buffer.len()
+                )),
+            }
+        }
+        for m in matches {
+            out.push_str(&format!(
+                "block {} matched at offset {}\n",
+                m.index, m.offset
+            ));
+        }
+        out
+    }
+
+    /// Same as [`Delta::diff`] but for signatures calculated using
+    /// [`crate::Signature::calculate_lines`], here the new buffer is divided into lines and every
+    /// line is either matched as a whole or inserted as a whole.
+    ///
+    /// the `lines` keep their `\n`, and all of them together make up the new buffer.
+    fn diff_lines<L: AsRef<[u8]>, O: Observer>(
+        &mut self,
+        lines: impl Iterator<Item = io::Result<L>>,
+        observer: &mut O,
+        cancelled: Option<&AtomicBool>,
+    ) -> io::Result<()> {
+        trace!("starting new line diff");
+        let mut hasher = CryptoHasher::new(self.sig.hash);
+        let mut ins_buffer = Vec::new();
+        let mut offset = 0;
+        // the next block we expect from the original buffer.
+        let mut next_block = 0;
+        for line in lines {
+            let line = line?;
+            let line = line.as_ref();
+            if cancelled.is_some_and(|cancelled| cancelled.load(Ordering::Relaxed)) {
+                return Err(differ::cancelled());
+            }
+            let hash = self.sig.salt_block(BlockHash::calculate(&mut hasher, line));
+            let blocks = self
+                .sig
+                .blocks
+                .get(&hash.weak_hash)
+                .map_or(&[][..], |b| &b[..]);
+            // the last line with the same hashes wins, like in the other diffs.
+            let matched = blocks.iter().rev().find_map(|(idx, block)| {
+                observer.observe(ObserverEvent::WeakHit { index: *idx });
+                if block.crypto_hash == hash.crypto_hash {
+                    observer.observe(ObserverEvent::StrongMatch { index: *idx });
+                    Some(*idx).filter(|idx| *idx >= next_block)
+                } else {
+                    observer.observe(ObserverEvent::StrongMiss { index: *idx });
+                    None
+                }
+            });
+            if let Some(idx) = matched {
+                trace!("line {} matched at offset {}", idx, offset);
+                if !ins_buffer.is_empty() {
+                    self.add_insert_op(offset - ins_buffer.len(), mem::take(&mut ins_buffer));
+                }
+                if idx > next_block {
+                    let len = self.sig.block_offset(idx) - self.sig.block_offset(next_block);
+                    self.add_remove_op(offset, len);
+                }
+                self.matches.push(BlockMatch { index: idx, offset });
+                next_block = idx + 1;
+            } else {
+                ins_buffer.extend_from_slice(line);
+                for _ in line {
+                    observer.observe(ObserverEvent::LiteralByte);
+                }
+            }
+            offset += line.len();
+        }
+        if !ins_buffer.is_empty() {
+            self.add_insert_op(offset - ins_buffer.len(), ins_buffer);
+        }
+        if next_block < self.sig.block_count() {
+            let len = self.sig.original_buffer_len - self.sig.block_offset(next_block);
+            self.add_remove_op(offset, len);
+        }
+        Ok(())
+    }
+
+    /// Same as [`Delta::diff`] but the matched blocks become [`Operation::Copy`]s, so they can
+    /// come from anywhere in the original buffer, used for signatures made of several parts.
+    fn diff_copies<W: Frames, O: Observer>(
+        &mut self,
+        differ: Differ<W>,
+        observer: &mut O,
+        cancelled: Option<&AtomicBool>,
+    ) -> io::Result<()> {
+        let mut differ = differ.with_cost_model(self.cost_model.clone());
+        while let Some(event) = next_event(&mut differ, &mut self.sig, observer, cancelled)? {
+            match event {
+                Event::Op(Operation::Insert { offset, buffer }) => {
+                    self.add_insert_op(offset, buffer)
+                }
+                Event::Op(op) => self.ops.push(op),
+                Event::Match(m) => {
+                    let src_offset = self.sig.block_offset(m.index);
+                    let len = self.sig.block_len(m.index);
+                    trace!("Copy: from {} with len {}", src_offset, len);
+                    // extend the last copy of this diff if the block comes right after it.
+                    match self.ops[self.first_op..].last_mut() {
+                        Some(Operation::Copy {
+                            src_offset: last,
+                            len: last_len,
+                        }) if *last + *last_len == src_offset => *last_len += len,
+                        _ => self.ops.push(Operation::Copy { src_offset, len }),
+                    }
+                    self.matches.push(m);
+                }
+            }
+        }
+        // without any copy these are plain inserts, so the original buffer has to go.
+        if !self.ops[self.first_op..].iter().any(Operation::is_copy)
+            && self.sig.original_buffer_len > 0
+        {
+            self.add_remove_op(differ.bytes_read(), self.sig.original_buffer_len);
+        }
+        Ok(())
+    }
+
+    fn add_insert_op(&mut self, offset: usize, buffer: Vec<u8>) {
+        debug_assert!(
+            offset >= self.new_cursor(),
+            "insert at {} before the end of the last operation at {}",
+            offset,
+            self.new_cursor()
+        );
+        trace!(
+            "Insert: at {} with len {} and buf = {} {:?}",
+            offset,
+            buffer.len(),
+            String::from_utf8_lossy(&buffer),
+            buffer
+        );
+        self.ops.push(Operation::Insert { offset, buffer });
+    }
+
+    fn add_remove_op(&mut self, offset: usize, len: usize) {
+        debug_assert!(
+            offset >= self.new_cursor(),
+            "remove at {} before the end of the last operation at {}",
+            offset,
+            self.new_cursor()
+        );
+        debug_assert!(
+            len <= self.sig.original_buffer_len,
+            "remove of {} bytes from an original buffer of {} bytes",
+            len,
+            self.sig.original_buffer_len
+        );
+        trace!("Remove: at {} with len {}", offset, len,);
+        self.ops.push(Operation::Remove { offset, len });
+    }
+
+    /// Where the last insert or remove of the current diff ends in the new buffer, the next one
+    /// can't start before that, copies are skipped since their offset is in the original buffer.
+    fn new_cursor(&self) -> usize {
+        match self.ops[self.first_op..]
+            .iter()
+            .rev()
+            .find(|op| !op.is_copy())
+        {
+            Some(Operation::Remove { offset, .. }) => *offset,
+            Some(op) => op.offset() + op.len(),
+            None => 0,
+        }
+    }
+}
+
+/// Where [`Delta::iter_operations`] is at.
+enum LazyOps<'a> {
+    Lazy(Differ<Window<&'a [u8]>>, &'a mut IndexedSignature),
+    Eager(vec::IntoIter<Operation>),
+    Failed(io::Error),
+    Done,
+}
+
+/// The next event of the `differ`, it fails once the `cancelled` flag is set.
+fn next_event<W: Frames, O: Observer>(
+    differ: &mut Differ<W>,
+    sig: &mut IndexedSignature,
+    observer: &mut O,
+    cancelled: Option<&AtomicBool>,
+) -> io::Result<Option<Event>> {
+    match cancelled {
+        Some(cancelled) => differ.next_event(
+            &mut Cancellable {
+                source: sig,
+                cancelled,
+            },
+            observer,
+        ),
+        None => differ.next_event(sig, observer),
+    }
+}
+
+/// A changed region, where the `new` range of the new buffer replaces the `original` range of
+/// the original buffer.
+#[derive(Debug, Clone)]
+struct Hunk {
+    new: Range<usize>,
+    original: Range<usize>,
+}
+
+impl Hunk {
+    /// Group the insert/remove operations into hunks, an insert directly followed by a remove is
+    /// a single hunk.
+    fn from_operations(ops: &[Operation]) -> Vec<Hunk> {
+        let mut hunks: Vec<Hunk> = Vec::new();
+        // where we are in the new and the original buffers.
+        let mut new = 0;
+        let mut original = 0;
+        for op in ops {
+            let gap = op.offset().saturating_sub(new);
+            new += gap;
+            original += gap;
+            match hunks.last_mut() {
+                Some(hunk) if gap == 0 && hunk.new.end == new => {}
+                _ => hunks.push(Hunk {
+                    new: new..new,
+                    original: original..original,
+                }),
+            }
+            let hunk = hunks.last_mut().unwrap();
+            match op {
+                Operation::Insert { buffer, .. } => {
+                    new += buffer.len();
+                    hunk.new.end = new;
+                }
+                Operation::Remove { len, .. } => {
+                    original += len;
+                    hunk.original.end = original;
+                }
+                Operation::Replace {
+                    old_len, buffer, ..
+                } => {
+                    new += buffer.len();
+                    original += old_len;
+                    hunk.new.end = new;
+                    hunk.original.end = original;
+                }
+                Operation::Copy { .. } => unreachable!("diff never emits copies"),
+            }
+        }
+        hunks
+    }
+
+    /// Merge the neighbour hunks that are at most `max_gap` bytes apart.
+    fn coalesce(hunks: &[Hunk], max_gap: usize) -> Vec<Hunk> {
+        let mut merged: Vec<Hunk> = Vec::with_capacity(hunks.len());
+        for hunk in hunks {
+            match merged.last_mut() {
+                Some(last) if hunk.new.start - last.new.end <= max_gap => {
+                    last.new.end = hunk.new.end;
+                    last.original.end = hunk.original.end;
+                }
+                _ => merged.push(hunk.clone()),
+            }
+        }
+        merged
+    }
+
+    /// How many operations are needed to express the `hunks`.
+    fn op_count(hunks: &[Hunk]) -> usize {
+        hunks
+            .iter()
+            .map(|h| !h.new.is_empty() as usize + !h.original.is_empty() as usize)
+            .sum()
+    }
+}
+
+/// Turn the copies of at most `max_len` bytes of explicit `ops` into literal bytes of `new`,
+/// merged with the inserts around them.
+///
+/// when no copy is left, the original buffer of `original_len` bytes is removed.
+fn inline_copies(
+    ops: &[Operation],
+    new: &[u8],
+    max_len: usize,
+    original_len: usize,
+) -> Vec<Operation> {
+    let mut inlined: Vec<Operation> = Vec::with_capacity(ops.len());
+    // where we are in the new buffer.
+    let mut offset = 0;
+    for op in ops {
+        let len = match op {
+            Operation::Copy { len, .. } if *len > max_len => {
+                inlined.push(op.clone());
+                offset += len;
+                continue;
+            }
+            Operation::Copy { len, .. } => *len,
+            Operation::Insert { buffer, .. } => buffer.len(),
+            // the remove of the original buffer, added back below if needed.
+            _ => continue,
+        };
+        let bytes = &new[offset..offset + len];
+        match inlined.last_mut() {
+            Some(Operation::Insert {
+                offset: start,
+                buffer,
+            }) if *start + buffer.len() == offset => buffer.extend_from_slice(bytes),
+            _ => inlined.push(Operation::Insert {
+                offset,
+                buffer: bytes.to_vec(),
+            }),
+        }
+        offset += len;
+    }
+    if !inlined.iter().any(Operation::is_copy) && original_len > 0 {
+        inlined.push(Operation::Remove {
+            offset: new.len(),
+            len: original_len,
+        });
+    }
+    inlined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Signature;
+    use crate::rng::{random_bytes, Rng};
+
+    fn delta(block_size: usize, original: &str) -> Delta {
+        let mut signature = Signature::with_block_size(block_size, original);
+        signature.calculate();
+        Delta::new(signature.to_indexed())
+    }
+
+    #[test]
+    fn explain() {
+        let delta = delta(5, "hello there, do you know rust?");
+        let explanation = delta.explain(b"hi, do you know about rustlang?");
+        let lines: Vec<_> = explanation.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "+ 0..6 = hi, do: no match, literal insert of 6 bytes",
+                "- 6..-15: blocks 0 to 2 not found, remove of 15 bytes",
+                "block 3 matched at offset 6",
+                "block 4 matched at offset 11",
+                "+ 16..31 = about rustlang?: no match, literal insert of 15 bytes",
+                "- 31..-5: block 5 not found, remove of 5 bytes",
+            ]
+        );
+    }
+
+    fn line_delta(original: &str) -> Delta {
+        let mut signature = Signature::new(original);
+        signature.calculate_lines();
+        Delta::new(signature.to_indexed())
+    }
+
+    #[test]
+    fn lines() {
+        let original = "first line\nsecond\nthird line\n";
+        let mut delta = line_delta(original);
+        delta
+            .diff("first line\nsecond\nthird line\nfourth\n")
+            .unwrap();
+        assert_eq!(
+            delta.operations(),
+            &[Operation::Insert {
+                offset: 29,
+                buffer: b"fourth\n".to_vec()
+            }]
+        );
+
+        let mut delta = line_delta(original);
+        delta.diff("first line\nthird line\n").unwrap();
+        assert_eq!(
+            delta.operations(),
+            &[Operation::Remove { offset: 11, len: 7 }]
+        );
+
+        let mut delta = line_delta(original);
+        delta.diff("zero\nfirst line\nthird line\n").unwrap();
+        assert_eq!(
+            delta.operations(),
+            &[
+                Operation::Insert {
+                    offset: 0,
+                    buffer: b"zero\n".to_vec()
+                },
+                Operation::Remove { offset: 16, len: 7 },
+            ]
+        );
+
+        let mut delta = line_delta(original);
+        delta.diff("first line\n").unwrap();
+        assert_eq!(
+            delta.operations(),
+            &[Operation::Remove {
+                offset: 11,
+                len: 18
+            }]
+        );
+        assert!(delta
+            .explain(b"first line\n")
+            .contains("blocks 1 to 2 not found"));
+    }
+
+    #[test]
+    fn stride() {
+        let original = "0123456789abcdefghijklmnopqrstuv";
+        let new = "01234567_9abcdefghijklmnopqrstuv";
+        let inserted = |signature: Signature<&str>| {
+            let mut signature = signature;
+            signature.calculate();
+            let mut delta = Delta::new(signature.to_indexed());
+            delta.diff(new).unwrap();
+            let ops = delta.into_operations();
+            let mut patch = crate::patch::Patch::new(&ops);
+            patch.apply(original);
+            assert_eq!(patch.buffer(), new.as_bytes());
+            ops.iter()
+                .filter_map(|op| op.buffer())
+                .map(|b| b.len())
+                .sum::<usize>()
+        };
+        let fixed = inserted(Signature::with_block_size(8, original));
+        let overlapping = inserted(Signature::with_stride(8, 4, original));
+        assert_eq!(fixed, 8);
+        assert_eq!(overlapping, 4);
+    }
+
+    #[test]
+    fn aligned() {
+        // 16 sectors of 512 bytes, random so none are alike.
+        let original = random_bytes(0x2545F4914F6CDD1D, 16 * 512);
+        let mut sectors: Vec<_> = original.chunks(512).map(<[u8]>::to_vec).collect();
+        // one sector changed, one added and one dropped.
+        sectors[3][100] ^= 0xff;
+        sectors.insert(7, vec![b'!'; 512]);
+        sectors.remove(12);
+        let new = sectors.concat();
+        let inserted = |signature: Signature<&[u8]>| {
+            let mut signature = signature;
+            signature.calculate();
+            let mut delta = Delta::new(signature.to_indexed());
+            delta.diff(&new).unwrap();
+            let ops = delta.into_operations();
+            assert_eq!(patched(&ops, &original), new);
+            crate::inserted_bytes(&ops)
+        };
+        let mut signature = Signature::with_alignment(100, 512, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+        for idx in 0..signature.block_count() {
+            assert_eq!(signature.block_offset(idx) % 512, 0);
+        }
+        // only the changed and the added sectors are inserted.
+        assert_eq!(
+            inserted(Signature::with_alignment(500, 512, &original)),
+            1024
+        );
+        assert_eq!(inserted(Signature::with_alignment(1, 512, &original)), 1024);
+        assert!(inserted(Signature::with_block_size(500, &original)) > 512);
+    }
+
+    #[test]
+    fn sampled() {
+        let original: Vec<u8> = (0..64u8).flat_map(|i| [i, b'a', b'b', i ^ 0x55]).collect();
+        let mut new = original.clone();
+        new[100] = b'!';
+        new.splice(200..200, b"inserted".iter().copied());
+        // (signature size, inserted bytes) of a signature.
+        let sizes = |mut signature: Signature<&[u8]>| {
+            signature.calculate();
+            let indexed = signature.to_indexed();
+            let mut delta = Delta::new(indexed.clone());
+            delta.diff(&new).unwrap();
+            let ops = delta.into_operations();
+            assert_eq!(patched(&ops, &original), new);
+            (indexed.to_bytes().len(), crate::inserted_bytes(&ops))
+        };
+        let full = sizes(Signature::with_block_size(8, &original));
+        let sparse = sizes(Signature::sampled(8, 4, &original));
+        assert!(sparse.0 * 3 < full.0, "{:?} vs {:?}", sparse, full);
+        assert!(sparse.1 > full.1, "{:?} vs {:?}", sparse, full);
+        // every block sampled is the same as the full signature.
+        assert_eq!(sizes(Signature::sampled(8, 1, &original)).1, full.1);
+    }
+
+    #[test]
+    fn buffer_str() {
+        let op = Operation::Insert {
+            offset: 0,
+            buffer: "rust 🦀".as_bytes().to_vec(),
+        };
+        assert_eq!(op.buffer_str(), Some(Ok("rust 🦀")));
+        let op = Operation::Insert {
+            offset: 0,
+            buffer: vec![b'a', 0xff, 0xfe],
+        };
+        assert!(matches!(op.buffer_str(), Some(Err(_))));
+        let op = Operation::Remove { offset: 0, len: 3 };
+        assert_eq!(op.buffer_str(), None);
+    }
+
+    #[test]
+    fn capped() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = "the quick red fox jumps over the lazy cat";
+        let apply = |ops: &[Operation]| {
+            let mut patch = crate::patch::Patch::new(ops);
+            patch.apply(original);
+            patch.buffer().to_vec()
+        };
+        let mut uncapped = delta(4, original);
+        uncapped.diff(new).unwrap();
+        assert!(uncapped.operations().len() > 2);
+
+        let mut capped = delta(4, original);
+        capped.diff_capped(new, 2).unwrap();
+        assert!(capped.operations().len() <= 2);
+        assert_eq!(apply(capped.operations()), new.as_bytes());
+
+        // can't be done, so it falls back to replacing the whole buffer.
+        let mut capped = delta(4, original);
+        capped.diff_capped(new, 1).unwrap();
+        assert_eq!(
+            capped.operations(),
+            &[Operation::Replace {
+                offset: 0,
+                old_len: original.len(),
+                buffer: new.as_bytes().to_vec()
+            }]
+        );
+        assert_eq!(apply(capped.operations()), new.as_bytes());
+        // a single insert or remove is already within the cap.
+        let mut capped = delta(4, original);
+        capped.diff_capped("", 1).unwrap();
+        assert_eq!(
+            capped.operations(),
+            &[Operation::Remove {
+                offset: 0,
+                len: original.len()
+            }]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "max ops must be > 0")]
+    fn capped_zero() {
+        delta(4, "i saw a red fox")
+            .diff_capped("i saw a red box", 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn capped_parts() {
+        let parts: &[&[u8]] = &[b"the quick brown fox ", b"jumps over the lazy dog"];
+        let original = parts.concat();
+        let new = "the quick red fox jumps over the old lazy dog!";
+        let apply = |ops: &[Operation]| {
+            let mut patch = crate::patch::Patch::new(ops);
+            patch.apply(&original);
+            patch.buffer().to_vec()
+        };
+        let delta = || Delta::new(IndexedSignature::from_parts(parts, 4));
+        let mut uncapped = delta();
+        uncapped.diff(new).unwrap();
+        let count = uncapped.operations().len();
+        assert!(count > 3);
+
+        for max_ops in 2..count {
+            let mut capped = delta();
+            capped.diff_capped(new, max_ops).unwrap();
+            assert!(capped.operations().len() <= max_ops, "{}", max_ops);
+            assert_eq!(apply(capped.operations()), new.as_bytes());
+            // the matched blocks that got inlined are gone.
+            for m in &capped.matches {
+                assert!(!capped.operations().iter().any(|op| match op {
+                    Operation::Insert { offset, buffer } => {
+                        (*offset..*offset + buffer.len()).contains(&m.offset)
+                    }
+                    _ => false,
+                }));
+            }
+        }
+
+        // can't be done, so it falls back to replacing the whole buffer.
+        let mut capped = delta();
+        capped.diff_capped(new, 1).unwrap();
+        assert_eq!(
+            capped.operations(),
+            &[Operation::Replace {
+                offset: 0,
+                old_len: original.len(),
+                buffer: new.as_bytes().to_vec()
+            }]
+        );
+        assert_eq!(apply(capped.operations()), new.as_bytes());
+        assert!(capped.matches.is_empty());
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(
+            Operation::insert(Offset(12), b"box".to_vec()),
+            Some(Operation::Insert {
+                offset: 12,
+                buffer: b"box".to_vec()
+            })
+        );
+        assert_eq!(
+            Operation::remove(Offset(15), ByteLen(3)),
+            Some(Operation::Remove { offset: 15, len: 3 })
+        );
+        assert_eq!(
+            Operation::copy(Offset(0), ByteLen(12)),
+            Some(Operation::Copy {
+                src_offset: 0,
+                len: 12
+            })
+        );
+        assert_eq!(Operation::insert(Offset(12), Vec::new()), None);
+        assert_eq!(Operation::remove(Offset(15), ByteLen(0)), None);
+        assert_eq!(Operation::copy(Offset(0), ByteLen(0)), None);
+        // plain numbers go through `From`.
+        assert_eq!(
+            Operation::remove(15.into(), 3.into()),
+            Operation::remove(Offset(15), ByteLen(3))
+        );
+        assert_eq!(usize::from(Offset(15)), 15);
+        assert_eq!(usize::from(ByteLen(3)), 3);
+    }
+
+    #[test]
+    fn hash_set() {
+        use std::collections::HashSet;
+
+        let ops = vec![
+            Operation::Insert {
+                offset: 12,
+                buffer: b"box".to_vec(),
+            },
+            Operation::Remove { offset: 15, len: 3 },
+            Operation::Insert {
+                offset: 12,
+                buffer: b"box".to_vec(),
+            },
+            Operation::Remove { offset: 15, len: 3 },
+            Operation::Remove { offset: 15, len: 4 },
+        ];
+        let set: HashSet<_> = ops.into_iter().collect();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(&Operation::Remove { offset: 15, len: 4 }));
+    }
+
+    #[test]
+    fn clone() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let mut delta = delta(5, original);
+        let mut before = delta.clone();
+        delta.diff(new).unwrap();
+        before.diff(new).unwrap();
+        assert_eq!(delta.operations(), before.operations());
+
+        // a clone after diffing carries the operations, and diffs on its own.
+        let mut after = delta.clone();
+        assert_eq!(after.operations(), delta.operations());
+        after.diff("hello").unwrap();
+        assert_ne!(after.operations(), delta.operations());
+        assert_eq!(delta.operations(), before.operations());
+    }
+
+    #[test]
+    fn matched_spans() {
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("i saw a red box").unwrap();
+        assert_eq!(d.matched_spans(), vec![(0..12, 0..12)]);
+
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let mut d = delta(5, original);
+        d.diff(new).unwrap();
+        let spans = d.matched_spans();
+        assert_eq!(spans, vec![(15..25, 6..16)]);
+        for (o, n) in spans {
+            assert_eq!(original[o].as_bytes(), new[n].as_bytes());
+        }
+
+        let mut d = line_delta("first line\nsecond\nthird line\n");
+        d.diff("zero\nfirst line\nthird line\n").unwrap();
+        assert_eq!(d.matched_spans(), vec![(0..11, 5..16), (18..29, 16..27)]);
+    }
+
+    #[test]
+    fn required_ranges() {
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("i saw a red box").unwrap();
+        assert_eq!(d.required_ranges(), vec![12..15]);
+
+        let mut d = delta(5, "hello there, do you know rust?");
+        d.diff("hi, do you know about rustlang?").unwrap();
+        assert_eq!(d.required_ranges(), vec![0..15, 25..30]);
+
+        let mut d = line_delta("first line\nsecond\nthird line\n");
+        d.diff("zero\nfirst line\nthird line\n").unwrap();
+        assert_eq!(d.required_ranges(), vec![11..18]);
+
+        // nothing matched, everything is needed, and nothing when it all matched.
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("something else").unwrap();
+        assert_eq!(d.required_ranges(), vec![0..15]);
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("i saw a red fox").unwrap();
+        assert!(d.required_ranges().is_empty());
+
+        // the same block copied twice.
+        let original = "abcdefgh";
+        let mut d = Delta::new(IndexedSignature::from_parts(&[original.as_bytes()], 2));
+        d.diff("cdcdgh").unwrap();
+        assert_eq!(d.required_ranges(), vec![0..2, 4..6]);
+    }
+
+    #[test]
+    fn diff_report() {
+        let original: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let mut d = delta(8, &original);
+        d.diff(original.replace("line 50\n", "line fifty\n"))
+            .unwrap();
+        assert_eq!(d.diff_report(), DiffReport::default());
+
+        let new: String = (0..100).map(|i| format!("{} enil\n", i)).collect();
+        let mut d = delta(8, &original);
+        d.diff(&new).unwrap();
+        let report = d.diff_report();
+        assert!(report.suspicious);
+        assert!(report.reason.unwrap().contains("literal insert"));
+
+        // short enough inserts, but nothing matched.
+        let mut d = delta(64, &original);
+        d.diff(&new[..500]).unwrap();
+        let report = d.diff_report();
+        assert!(report.suspicious);
+        assert_eq!(
+            report.reason.unwrap(),
+            "only 0 of 500 bytes matched the original, try a smaller block size"
+        );
+    }
+
+    #[test]
+    fn sequenced() {
+        let mut d = delta(5, "hello there, do you know rust?");
+        d.diff("hi, do you know about rustlang?").unwrap();
+        // diffing again keeps counting.
+        d.diff("hello there, do you know c?").unwrap();
+        let sequenced = d.sequenced_operations();
+        assert_eq!(sequenced.len(), d.operations().len());
+        for (i, s) in sequenced.iter().enumerate() {
+            assert_eq!(s.id, i);
+            assert_eq!(&s.op, &d.operations()[i]);
+        }
+    }
+
+    #[test]
+    fn parts() {
+        let parts: &[&[u8]] = &[b"the quick brown fox ", b"jumps over the lazy dog"];
+        let signature = IndexedSignature::from_parts(parts, 4);
+        let mut d = Delta::new(signature.clone());
+        let new = "jumps over the lazy dog, and the quick brown fox!";
+        d.diff(new).unwrap();
+        let ops = d.operations();
+        assert!(ops.iter().any(Operation::is_copy));
+        assert!(!ops.iter().any(Operation::is_remove));
+        // the copies come from both parts.
+        let copied_parts: Vec<_> = ops
+            .iter()
+            .filter(|op| op.is_copy())
+            .map(|op| signature.locate(op.offset()).unwrap().0)
+            .collect();
+        assert!(copied_parts.contains(&0) && copied_parts.contains(&1));
+
+        let original = parts.concat();
+        let mut patch = crate::patch::Patch::new(ops);
+        patch.apply(&original);
+        assert_eq!(patch.buffer(), new.as_bytes());
+    }
+
+    #[test]
+    fn bad_signatures() {
+        let error = |sig: IndexedSignature| {
+            let mut d = Delta::new(sig.clone());
+            let err = d.diff("i saw a red box").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(d.operations().is_empty());
+            let mut d = Delta::new(sig.clone());
+            assert!(d
+                .diff_with_options("i saw a red box", DiffOptions::default())
+                .is_err());
+            let mut d = Delta::new(sig.clone());
+            assert!(d.diff_with_copy("i saw a red box").is_err());
+            let mut d = Delta::new(sig.clone());
+            assert_eq!(
+                d.diff_from("i saw a red box", 4).unwrap_err().kind(),
+                err.kind()
+            );
+            assert!(d.operations().is_empty());
+            let mut d = Delta::new(sig.clone());
+            assert_eq!(
+                d.diff_parallel("i saw a red box", 2).unwrap_err().kind(),
+                err.kind()
+            );
+            assert!(d.operations().is_empty());
+            let mut d = Delta::new(sig);
+            let mut ops = d.iter_operations(b"i saw a red box");
+            let lazy = ops.next().unwrap().unwrap_err();
+            assert_eq!(lazy.kind(), err.kind());
+            assert!(ops.next().is_none());
+            DiffError::from(err)
+        };
+        let mut signature = Signature::with_block_size(4, "i saw a red fox");
+        signature.calculate();
+        // a block size of zero, like a decoded one could have.
+        let mut zero = signature.to_indexed();
+        zero.block_size = 0;
+        // an empty new buffer too, which has no segments to split.
+        let mut d = Delta::new(zero.clone());
+        assert!(matches!(
+            DiffError::from(d.diff_parallel("", 2).unwrap_err()),
+            DiffError::InvalidBlockSize(0)
+        ));
+        assert!(matches!(error(zero), DiffError::InvalidBlockSize(0)));
+        let mut zero = signature.to_indexed();
+        zero.chunking = Chunking::Strided(0);
+        assert!(matches!(error(zero), DiffError::InvalidBlockSize(0)));
+        // never calculated.
+        let empty = Signature::with_block_size(4, "i saw a red fox").to_indexed();
+        assert!(matches!(error(empty), DiffError::EmptySignature));
+        // but an empty original buffer has no blocks to begin with.
+        let mut d = Delta::new(Signature::with_block_size(4, "").to_indexed());
+        d.diff("i saw a red box").unwrap();
+        assert_eq!(crate::inserted_bytes(d.operations()), 15);
+        // other io errors are kept as they are.
+        let err = io::Error::new(io::ErrorKind::UnexpectedEof, "eof");
+        assert!(matches!(DiffError::from(err), DiffError::Io(_)));
+    }
+
+    #[test]
+    fn parts_twice() {
+        let mut d = Delta::new(IndexedSignature::from_parts(&[b"abcd", b"efgh"], 4));
+        d.diff("abcd").unwrap();
+        d.diff("efgh").unwrap();
+        // the second diff starts its own copies.
+        assert_eq!(
+            d.operations(),
+            &[
+                Operation::Copy {
+                    src_offset: 0,
+                    len: 4
+                },
+                Operation::Copy {
+                    src_offset: 4,
+                    len: 4
+                },
+            ]
+        );
+        // and without any copy of its own it still removes the original buffer.
+        d.diff("zzzz").unwrap();
+        assert_eq!(
+            &d.operations()[2..],
+            &[
+                Operation::Insert {
+                    offset: 0,
+                    buffer: b"zzzz".to_vec()
+                },
+                Operation::Remove { offset: 4, len: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn affects() {
+        let insert = Operation::Insert {
+            offset: 10,
+            buffer: b"abcde".to_vec(),
+        };
+        // overlapping on either side, inside and around.
+        for range in &[8..11, 14..20, 11..12, 0..100, 10..15] {
+            assert!(insert.affects(range.clone()), "{:?}", range);
+        }
+        for range in &[0..10, 15..20, 12..12, 0..0] {
+            assert!(!insert.affects(range.clone()), "{:?}", range);
+        }
+
+        let remove = Operation::Remove { offset: 10, len: 5 };
+        assert!(remove.affects(9..11));
+        assert!(remove.affects(0..100));
+        // the removed bytes were right before or after the range.
+        assert!(!remove.affects(10..20));
+        assert!(!remove.affects(0..10));
+        assert!(!remove.affects(10..10));
+
+        let replace = Operation::Replace {
+            offset: 10,
+            old_len: 2,
+            buffer: b"ab".to_vec(),
+        };
+        assert!(replace.affects(11..12));
+        assert!(!replace.affects(12..20));
+        let replace = Operation::Replace {
+            offset: 10,
+            old_len: 2,
+            buffer: Vec::new(),
+        };
+        assert!(replace.affects(9..11));
+        assert!(!replace.affects(10..11));
+
+        let copy = Operation::Copy {
+            src_offset: 0,
+            len: 100,
+        };
+        assert!(!copy.affects(0..100));
+
+        // the ops that touch the visible part of a real diff.
+        let new = "the quick red fox jumps over the lazy cat";
+        let ops =
+            crate::diff_with_block_size(4, "the quick brown fox jumps over the lazy dog", new)
+                .unwrap();
+        assert!(ops.iter().any(|op| op.affects(10..13)));
+        assert!(!ops.iter().any(|op| op.affects(20..30)));
+    }
+
+    #[test]
+    fn snapshot_string() {
+        let ops = crate::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+        let lines: Vec<_> = ops.iter().map(Operation::snapshot_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "insert offset=12 len=3 buffer=626f78",
+                "remove offset=15 len=3"
+            ]
+        );
+        let op = Operation::Copy {
+            src_offset: 15,
+            len: 10,
+        };
+        assert_eq!(op.snapshot_string(), "copy src_offset=15 len=10");
+        let op = Operation::Replace {
+            offset: 0,
+            old_len: 2,
+            buffer: (0..17).collect(),
+        };
+        assert_eq!(
+            op.snapshot_string(),
+            "replace offset=0 old_len=2 len=17 buffer=000102030405060708090a0b0c0d0e0f.."
+        );
+        let op = Operation::Insert {
+            offset: 0,
+            buffer: (0..16).collect(),
+        };
+        assert!(op.snapshot_string().ends_with("0e0f"));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn diff_bytes() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = Bytes::from_static(b"the quick red fox jumps over the lazy cat!");
+        let parts: &[&[u8]] = &[original.as_bytes()];
+        let deltas = vec![
+            delta(4, original),
+            Delta::new(IndexedSignature::from_parts(parts, 4)),
+        ];
+        for mut d in deltas {
+            let ops = d.diff_bytes(new.clone()).unwrap();
+            assert!(d.operations().is_empty());
+            let mut inserted = 0;
+            for op in &ops {
+                // the inserted bytes point into `new` itself.
+                if let BytesOperation::Insert { offset, buffer } = op {
+                    assert_eq!(buffer.as_ptr(), new[*offset..].as_ptr());
+                    inserted += buffer.len();
+                }
+            }
+            assert!(inserted > 0);
+            let ops: Vec<Operation> = ops.into_iter().map(Operation::from).collect();
+            let mut patch = crate::patch::Patch::new(&ops);
+            patch.apply(original);
+            assert_eq!(patch.buffer(), &new[..]);
+        }
+    }
+
+    #[test]
+    fn buffer_range() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = "the quick red fox jumps over the lazy cat!";
+        let check = |ops: &[Operation]| {
+            assert!(ops.iter().any(|op| op.buffer().is_some()));
+            for op in ops {
+                match op.buffer_range() {
+                    Some(range) => assert_eq!(op.buffer(), Some(&new.as_bytes()[range])),
+                    None => assert!(op.buffer().is_none()),
+                }
+            }
+        };
+        let ops = crate::diff_with_block_size(4, original, new).unwrap();
+        check(&ops);
+        check(&crate::coalesce_replacements(&ops));
+        // explicit, with copies.
+        let parts: &[&[u8]] = &[original.as_bytes()];
+        let mut d = Delta::new(IndexedSignature::from_parts(parts, 4));
+        d.diff(new).unwrap();
+        assert!(d.operations().iter().any(Operation::is_copy));
+        check(d.operations());
+    }
+
+    #[test]
+    fn ops_in_order() {
+        // none of the ways to diff trips the debug checks on the order of the operations.
+        let original = "the quick brown fox jumps over the lazy dog\nand runs away\n";
+        let news = [
+            "",
+            "the quick red fox jumps over the lazy cat\nand runs away\n",
+            "and runs away\nthe quick brown fox jumps over the lazy dog\n",
+            "a slow brown fox\nwalks over the lazy dog\n",
+        ];
+        for new in &news {
+            for block_size in 1..8 {
+                let mut d = delta(block_size, original);
+                d.diff(new).unwrap();
+                d.diff(original).unwrap();
+                d.optimize(original);
+                d.diff_capped(new, 2).unwrap();
+                d.diff_from(new, 10).unwrap();
+                let parts: &[&[u8]] = &[original.as_bytes()];
+                let mut d = Delta::new(IndexedSignature::from_parts(parts, block_size));
+                d.diff(new).unwrap();
+                d.diff(new).unwrap();
+            }
+            let mut d = line_delta(original);
+            d.diff(new).unwrap();
+            d.diff(new).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "before the end of the last operation")]
+    fn ops_out_of_order() {
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("i saw a red box").unwrap();
+        d.add_insert_op(4, b"bad".to_vec());
+    }
+
+    #[test]
+    fn cost_model() {
+        // removes cost a lot more than inserts.
+        let cheap_inserts = |op: &Operation| match op {
+            Operation::Remove { len, .. } => len * 10,
+            op => op.len(),
+        };
+        // inserts cost a lot more than removes.
+        let cheap_removes = |op: &Operation| match op {
+            Operation::Remove { .. } => 1,
+            op => op.len() * 10,
+        };
+        let original = "abcdefghijklmnop";
+        let new = "mnopabcd";
+        let diff = |d: Delta| {
+            let mut d = d;
+            d.diff(new).unwrap();
+            assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+            d.into_operations()
+        };
+        let default = diff(delta(4, original));
+        assert_eq!(
+            diff(delta(4, original).with_cost_model(cheap_removes)),
+            default
+        );
+        let ops = diff(delta(4, original).with_cost_model(cheap_inserts));
+        assert_ne!(ops, default);
+        // "mnop" is inserted instead of skipping to it.
+        assert_eq!(
+            ops[0],
+            Operation::Insert {
+                offset: 0,
+                buffer: b"mnop".to_vec()
+            }
+        );
+
+        // copies, the same block is at the start and the end of the original buffer.
+        let parts: &[&[u8]] = &[b"abcdxxxx", b"yyyyabcd"];
+        let copies = |cost: fn(&Operation) -> usize| {
+            let mut d = Delta::new(IndexedSignature::from_parts(parts, 4)).with_cost_model(cost);
+            d.diff("abcd").unwrap();
+            d.into_operations()
+        };
+        let near = |op: &Operation| match op {
+            Operation::Copy { src_offset, .. } => *src_offset,
+            op => op.len() * 100,
+        };
+        let far = |op: &Operation| match op {
+            Operation::Copy { src_offset, .. } => 100 - src_offset,
+            op => op.len() * 100,
+        };
+        assert_eq!(
+            copies(near),
+            vec![Operation::Copy {
+                src_offset: 0,
+                len: 4
+            }]
+        );
+        assert_eq!(
+            copies(far),
+            vec![Operation::Copy {
+                src_offset: 12,
+                len: 4
+            }]
+        );
+        // copies so expensive that the block is inserted.
+        assert!(copies(|op| if op.is_copy() { 1000 } else { op.len() })
+            .iter()
+            .all(|op| !op.is_copy()));
+    }
+
+    #[test]
+    fn cancellable() {
+        let original: Vec<u8> = (0..1 << 16).map(|i| (i % 251) as u8).collect();
+        // nothing in here matches, so the diff slides one byte at a time.
+        let new: Vec<u8> = (0..1 << 18).map(|i| (i % 241) as u8 ^ 0x80).collect();
+        let mut signature = crate::Signature::with_block_size(1024, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+
+        let cancelled = AtomicBool::new(true);
+        let mut d = Delta::new(signature.clone());
+        let err = d.diff_cancellable(&new, &cancelled).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(d.operations().is_empty());
+
+        // in the middle of the diff, the flag is set after some literal bytes.
+        let cancelled = AtomicBool::new(false);
+        let mut literals = 0;
+        let mut d = Delta::new(signature.clone());
+        let err = d
+            .diff_inner(
+                &new,
+                DiffOptions::default(),
+                &mut |event| {
+                    if event == ObserverEvent::LiteralByte {
+                        literals += 1;
+                        if literals == 1000 {
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                    }
+                },
+                Some(&cancelled),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(literals < new.len(), "{}", literals);
+
+        // lines and parts too.
+        let cancelled = AtomicBool::new(true);
+        let mut d = line_delta("a\nb\n");
+        assert!(d.diff_cancellable("a\nc\n", &cancelled).is_err());
+        let parts: &[&[u8]] = &[b"abcd"];
+        let mut d = Delta::new(IndexedSignature::from_parts(parts, 4));
+        assert!(d.diff_cancellable("abcd", &cancelled).is_err());
+        // and a flag that is never set changes nothing.
+        let mut d = delta(4, "i saw a red fox");
+        d.diff_cancellable("i saw a red box", &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(
+            d.operations(),
+            &crate::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap()[..]
+        );
+    }
+
+    fn patched(ops: &[Operation], original: &[u8]) -> Vec<u8> {
+        let mut patch = crate::patch::Patch::new(ops);
+        patch.apply(original);
+        patch.buffer().to_vec()
+    }
+
+    #[test]
+    fn resync() {
+        let original: String = (0..200).map(|i| format!("line number {}\n", i)).collect();
+        let new = format!(
+            "{}{}",
+            "garbage at the start of the buffer that matches nothing ".repeat(4),
+            original.replace("line number 100\n", "line one hundred\n")
+        );
+        for resync_after in &[1, 2, 8, 64, 1000] {
+            let options = DiffOptions {
+                resync_after: Some(*resync_after),
+                ..Default::default()
+            };
+            let mut d = delta(8, &original);
+            d.diff_with_options(&new, options).unwrap();
+            assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+        }
+        // without a resync it is the same as a plain diff.
+        let mut plain = delta(8, &original);
+        plain.diff(&new).unwrap();
+        let mut d = delta(8, &original);
+        d.diff_with_options(&new, DiffOptions::default()).unwrap();
+        assert_eq!(d.operations(), plain.operations());
+    }
+
+    #[test]
+    fn diff_with_hint() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let mut d = delta(5, original);
+        d.diff_with_hint(new, new.len()).unwrap();
+        assert_eq!(
+            d.operations(),
+            &crate::diff_with_block_size(5, original, new).unwrap()[..]
+        );
+        assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+
+        // a truncated read, and one with too much.
+        for (buf, kind) in &[
+            (&new[..10], io::ErrorKind::UnexpectedEof),
+            ("", io::ErrorKind::UnexpectedEof),
+            (
+                "hi, do you know about rustlang? and more",
+                io::ErrorKind::InvalidData,
+            ),
+        ] {
+            let mut d = delta(5, original);
+            let err = d.diff_with_hint(buf, new.len()).unwrap_err();
+            assert_eq!(err.kind(), *kind);
+            assert!(d.operations().is_empty());
+        }
+
+        // lines and parts too.
+        let mut signature = Signature::new(original);
+        signature.calculate_lines();
+        let mut d = Delta::new(signature.to_indexed());
+        d.diff_with_hint(new, new.len()).unwrap();
+        let parts = IndexedSignature::from_parts(&[original.as_bytes()], 5);
+        Delta::new(parts.clone())
+            .diff_with_hint(new, new.len())
+            .unwrap();
+        Delta::new(parts).diff_with_hint("", 0).unwrap();
+    }
+
+    #[test]
+    fn diff_with_copy() {
+        let original = "the quick brown fox jumps over the lazy dog\nand runs away\n";
+        let news = [
+            "",
+            original,
+            "the quick red fox jumps over the lazy cat\nand runs away\n",
+            "and runs away\nthe quick brown fox jumps over the lazy dog\nand runs away\n",
+        ];
+        let mut lines = Signature::new(original);
+        lines.calculate_lines();
+        let mut signatures = vec![
+            lines.to_indexed(),
+            IndexedSignature::from_parts(
+                &[&original.as_bytes()[..20], &original.as_bytes()[20..]],
+                4,
+            ),
+        ];
+        for block_size in &[1, 4, 8] {
+            signatures.push(delta(*block_size, original).sig);
+        }
+        for signature in signatures {
+            for new in &news {
+                let mut d = Delta::new(signature.clone());
+                d.diff_with_copy(new).unwrap();
+                let ops = d.operations();
+                // without any copy the original buffer has to be removed.
+                let copies = ops.iter().any(Operation::is_copy);
+                assert!(ops
+                    .iter()
+                    .all(|op| op.is_copy() || op.buffer().is_some() || !copies));
+                assert_eq!(patched(ops, original.as_bytes()), new.as_bytes());
+                // a matched block is a copy.
+                if new.len() > 40 {
+                    assert!(copies, "{:?}", ops);
+                }
+            }
+        }
+
+        // the block moved to the front is copied, not inserted.
+        let mut d = delta(4, "abcdefgh");
+        d.diff_with_copy("efghabcd").unwrap();
+        assert_eq!(
+            d.operations(),
+            &[
+                Operation::Copy {
+                    src_offset: 4,
+                    len: 4
+                },
+                Operation::Copy {
+                    src_offset: 0,
+                    len: 4
+                },
+            ]
+        );
+        assert_eq!(d.operations()[0].to_string(), "= 4..8");
+
+        // every diff has its own copies, even if they follow each other in the original.
+        let mut d = delta(4, "abcdefgh");
+        d.diff_with_copy("abcd").unwrap();
+        d.diff_with_copy("efgh").unwrap();
+        assert_eq!(
+            d.operations(),
+            &[
+                Operation::Copy {
+                    src_offset: 0,
+                    len: 4
+                },
+                Operation::Copy {
+                    src_offset: 4,
+                    len: 4
+                },
+            ]
+        );
+        // and one without any copy removes the original buffer, whatever came before it.
+        let mut d = delta(4, "abcdefgh");
+        d.diff_with_copy("abcd").unwrap();
+        d.diff_with_copy("zzzz").unwrap();
+        assert_eq!(
+            &d.operations()[1..],
+            &[
+                Operation::Insert {
+                    offset: 0,
+                    buffer: b"zzzz".to_vec()
+                },
+                Operation::Remove { offset: 4, len: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_reader() {
+        use crate::window::tests::ChunkedReader;
+
+        let original: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+        let new = original
+            .replace("line 5\n", "line five\n")
+            .replace("line 70\n", "")
+            + "no newline at the end";
+        let mut lines = Signature::new(&original);
+        lines.calculate_lines();
+        let mut signatures = vec![
+            lines.to_indexed(),
+            IndexedSignature::from_parts(&[&original.as_bytes()[..500], b"line 70\n"], 8),
+        ];
+        for block_size in &[1, 3, 8, 64] {
+            signatures.push(delta(*block_size, &original).sig);
+        }
+        for signature in signatures {
+            for new in &[&new[..], "", &original[..]] {
+                let mut expected = Delta::new(signature.clone());
+                expected.diff(new).unwrap();
+                let mut d = Delta::new(signature.clone());
+                d.diff_reader(ChunkedReader::new(new.as_bytes())).unwrap();
+                assert_eq!(d.operations(), expected.operations());
+                assert_eq!(d.matches, expected.matches);
+            }
+        }
+    }
+
+    #[test]
+    fn match_window() {
+        // every line is a block, and the fourth one shows up again near the end.
+        let lines: Vec<String> = (0..12).map(|i| format!("line {:02}\n", i)).collect();
+        let mut lines_with_repeat = lines.clone();
+        lines_with_repeat[9] = lines[3].clone();
+        let original = lines_with_repeat.concat();
+        // a local edit of the second line.
+        let new = original.replace("line 01\n", "line XX\n");
+        let options = |match_window| DiffOptions {
+            match_window,
+            ..Default::default()
+        };
+        let diff = |options: DiffOptions| {
+            let mut d = delta(8, &original);
+            d.diff_with_options(&new, options).unwrap();
+            assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+            d.into_operations()
+        };
+        let plain = diff(DiffOptions::default());
+        // the repeated line far away wins without a window.
+        assert!(plain.len() > 2, "{:?}", plain);
+        let windowed = diff(options(Some(2)));
+        assert_eq!(
+            windowed,
+            vec![
+                Operation::Insert {
+                    offset: 8,
+                    buffer: b"line XX\n".to_vec()
+                },
+                Operation::Remove { offset: 16, len: 8 },
+            ]
+        );
+        assert!(diff(options(Some(0))).len() <= windowed.len());
+
+        // a removed region bigger than the window turns into inserts.
+        let mut d = delta(8, &original);
+        d.diff_with_options(&original[40..], options(Some(2)))
+            .unwrap();
+        assert_eq!(
+            patched(d.operations(), original.as_bytes()),
+            &original.as_bytes()[40..]
+        );
+        assert_eq!(crate::inserted_bytes(d.operations()), original.len() - 40);
+
+        // explicit copies too.
+        let parts = IndexedSignature::from_parts(&[original.as_bytes()], 8);
+        let mut d = Delta::new(parts.clone());
+        d.diff_with_options(&new, options(Some(2))).unwrap();
+        assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+        assert!(d.operations().iter().all(|op| match op {
+            Operation::Copy { src_offset, .. } => *src_offset != 72,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_resync() {
+        use std::time::Instant;
+        let original: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        // nothing in here matches.
+        let new: Vec<u8> = (0..1 << 20).map(|i| (i % 241) as u8 ^ 0x80).collect();
+        let mut signature = crate::Signature::with_block_size(1024, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+        for resync_after in &[None, Some(4096), Some(1024), Some(1)] {
+            let start = Instant::now();
+            let mut d = Delta::new(signature.clone());
+            let options = DiffOptions {
+                resync_after: *resync_after,
+                ..Default::default()
+            };
+            d.diff_with_options(&new, options).unwrap();
+            println!("resync_after = {:?}: {:?}", resync_after, start.elapsed());
+            assert_eq!(patched(d.operations(), &original), new);
+        }
+    }
+
+    #[test]
+    fn single_byte_blocks() {
+        let inputs = [
+            "",
+            "a",
+            "aaaa",
+            "abcd",
+            "i saw a red fox",
+            "i saw a red box",
+            "xoxoxoxo",
+            "the quick brown fox jumps over the lazy dog",
+        ];
+        for original in &inputs {
+            for new in &inputs {
+                let mut d = delta(1, original);
+                d.diff(new).unwrap();
+                let ops = d.operations();
+                assert_eq!(
+                    patched(ops, original.as_bytes()),
+                    new.as_bytes(),
+                    "{:?} -> {:?}, ops = {:?}",
+                    original,
+                    new,
+                    ops
+                );
+                // every byte is a block, so there is nothing to insert if the byte is there.
+                if new.bytes().all(|b| original.as_bytes().contains(&b)) {
+                    let mut d = Delta::new(crate::IndexedSignature::from_parts(
+                        &[original.as_bytes()],
+                        1,
+                    ));
+                    d.diff(new).unwrap();
+                    assert!(!d.operations().iter().any(Operation::is_insert));
+                    assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+                }
+                let options = DiffOptions {
+                    resync_after: Some(1),
+                    ..Default::default()
+                };
+                let mut d = delta(1, original);
+                d.diff_with_options(new, options).unwrap();
+                assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+
+                let mut signature = Signature::with_stride(1, 1, *original);
+                signature.calculate();
+                let mut d = Delta::new(signature.to_indexed());
+                d.diff(new).unwrap();
+                assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn weak_hash_collision() {
+        // "babe" and "abcd" have the same weak hash but not the same crypto hash.
+        assert_eq!(crate::weak_hash("babe"), crate::weak_hash("abcd"));
+        let original = "babeabcd";
+        let mut d = delta(4, original);
+        assert_eq!(d.sig.weak_hash_collision_count(), 2);
+        for new in &["babeabcd", "x babe y abcd z", "babe"] {
+            let mut d = delta(4, original);
+            d.diff(new).unwrap();
+            assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+            let found: Vec<_> = d.matches.iter().map(|m| m.index).collect();
+            let expected: Vec<_> = (0..2)
+                .filter(|i| new.contains(&original[i * 4..][..4]))
+                .collect();
+            assert_eq!(found, expected, "{}", new);
+        }
+        // both ways around, and with lines too.
+        d.diff_with_copy("abcdbabe").unwrap();
+        assert_eq!(
+            d.matches.iter().map(|m| m.index).collect::<Vec<_>>(),
+            [1, 0]
+        );
+        let mut lines = Signature::new("babe\nabcd\n");
+        lines.calculate_lines();
+        let lines = lines.to_indexed();
+        assert_eq!(lines.weak_hash_collision_count(), 2);
+        let mut d = Delta::new(lines);
+        d.diff("babe\nabcd\n").unwrap();
+        assert!(d.operations().is_empty());
+    }
+
+    #[test]
+    fn optimize() {
+        // the first block collides with the second one, the forward pass finds both anyway.
+        let original = "babeabcdwxyz";
+        let new = "hi babeabcdwxyz!";
+        let mut d = delta(4, original);
+        d.diff(new).unwrap();
+        assert_eq!(crate::inserted_bytes(d.operations()), 4);
+        d.optimize(new);
+        assert_eq!(crate::inserted_bytes(d.operations()), 4);
+        assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+
+        // blocks skipped by a resync come back.
+        let original: String = (0..50).map(|i| format!("line {:02}\n", i)).collect();
+        let new = format!("xyz{}", original);
+        let mut d = delta(8, &original);
+        let options = DiffOptions {
+            resync_after: Some(2),
+            ..Default::default()
+        };
+        d.diff_with_options(&new, options).unwrap();
+        assert!(crate::inserted_bytes(d.operations()) > 3);
+        d.optimize(&new);
+        assert_eq!(crate::inserted_bytes(d.operations()), 3);
+        assert_eq!(patched(d.operations(), original.as_bytes()), new.as_bytes());
+
+        // nothing to do.
+        let mut d = delta(4, "i saw a red fox");
+        d.diff("i saw a red box").unwrap();
+        let ops = d.operations().to_vec();
+        d.optimize("i saw a red box");
+        assert_eq!(d.operations(), &ops[..]);
+    }
+
+    #[test]
+    fn diff_cached() {
+        let mut d = delta(4, "i saw a red fox");
+        d.diff_cached("i saw a red box").unwrap();
+        let ops = d.operations().to_vec();
+        assert_eq!(patched(&ops, b"i saw a red fox"), b"i saw a red box");
+        // poison the cache, a cache hit returns it as is.
+        let poisoned = vec![Operation::Remove { offset: 0, len: 1 }];
+        d.cache.as_mut().unwrap().1 = poisoned.clone();
+        d.diff_cached("i saw a red box").unwrap();
+        assert_eq!(d.operations(), &poisoned[..]);
+        // a different buffer is calculated again.
+        d.diff_cached("i saw a red cat").unwrap();
+        assert_eq!(
+            patched(d.operations(), b"i saw a red fox"),
+            b"i saw a red cat"
+        );
+        d.diff_cached("i saw a red box").unwrap();
+        assert_eq!(d.operations(), &ops[..]);
+    }
+
+    #[test]
+    fn iter_operations() {
+        let original = "the quick brown fox jumps over the lazy dog\nand runs away\n";
+        let news = [
+            "the quick red fox jumps over the lazy cat\nand runs away\n",
+            "",
+            "and runs away\nthe quick brown fox jumps over the lazy dog\n",
+            original,
+        ];
+        for block_size in 1..8 {
+            let mut signature = Signature::with_block_size(block_size, original);
+            signature.calculate();
+            let fixed = signature.to_indexed();
+            let mut signature = Signature::with_stride(block_size, block_size + 1, original);
+            signature.calculate();
+            let strided = signature.to_indexed();
+            signature.calculate_lines();
+            let lines = signature.to_indexed();
+            let parts = IndexedSignature::from_parts(&[original.as_bytes()], block_size);
+            for signature in &[fixed, strided, lines, parts] {
+                let mut lazy = Delta::new(signature.clone());
+                for new in &news {
+                    let mut eager = Delta::new(signature.clone());
+                    eager.diff(new).unwrap();
+                    let ops: Vec<_> = lazy
+                        .iter_operations(new.as_bytes())
+                        .collect::<io::Result<_>>()
+                        .unwrap();
+                    assert_eq!(ops, eager.into_operations());
+                }
+                // nothing is kept.
+                assert!(lazy.operations().is_empty());
+                assert!(lazy.matches.is_empty());
+            }
+        }
+        // the operations come one at a time.
+        let mut d = delta(4, "i saw a red fox");
+        let mut ops = d.iter_operations(b"i saw a red box");
+        assert!(ops.next().unwrap().unwrap().is_insert());
+        assert!(ops.next().unwrap().unwrap().is_remove());
+        assert!(ops.next().is_none());
+        assert!(ops.next().is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        // no byte repeats, so every block is where it is in the original buffer.
+        let original: Vec<u8> = (0..=255).collect();
+        for block_size in &[1, 3, 4, 7, 16, 100] {
+            for cut in 0..=original.len() {
+                let mut signature = Signature::with_block_size(*block_size, &original);
+                signature.calculate();
+                let mut d = Delta::new(signature.to_indexed());
+                d.diff(&original[..cut]).unwrap();
+                // the bytes of the block that got cut are inserted, the original ones are
+                // removed from the start of that block.
+                let boundary = cut / block_size * block_size;
+                let mut expected = Vec::new();
+                // the last block of the original buffer can be short, it matches when nothing
+                // is cut.
+                if boundary < cut && cut < original.len() {
+                    expected.push(Operation::Insert {
+                        offset: boundary,
+                        buffer: original[boundary..cut].to_vec(),
+                    });
+                }
+                if cut < original.len() {
+                    expected.push(Operation::Remove {
+                        offset: cut,
+                        len: original.len() - boundary,
+                    });
+                }
+                assert_eq!(
+                    d.operations(),
+                    &expected[..],
+                    "bs = {}, cut = {}",
+                    block_size,
+                    cut
+                );
+                assert_eq!(patched(d.operations(), &original), &original[..cut]);
+            }
+        }
+    }
+
+    #[test]
+    fn diff_parallel() {
+        // random, so no block repeats.
+        let mut rng = Rng::new(0x9E3779B97F4A7C15);
+        let mut random = |len| rng.bytes(len);
+        let original = random(16 * 8);
+        let mut signature = Signature::with_block_size(16, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+
+        // two segments of 48 bytes, block 3 sits right across the boundary.
+        let new = [random(40), original[48..64].to_vec(), random(40)].concat();
+        let mut d = Delta::new(signature.clone());
+        d.diff_parallel(&new, 2).unwrap();
+        assert_eq!(
+            d.operations(),
+            &[
+                Operation::Insert {
+                    offset: 0,
+                    buffer: new[..40].to_vec()
+                },
+                Operation::Copy {
+                    src_offset: 48,
+                    len: 16
+                },
+                Operation::Insert {
+                    offset: 56,
+                    buffer: new[56..].to_vec()
+                },
+            ]
+        );
+        assert_eq!(d.matches.len(), 1);
+
+        // the same as diffing on one thread, wherever the boundaries fall.
+        let new = [
+            &original[..30],
+            &random(5)[..],
+            &original[20..100],
+            &random(50)[..],
+            &original[64..],
+        ]
+        .concat();
+        let mut one = Delta::new(IndexedSignature::from_parts(&[&original], 16));
+        one.diff(&new).unwrap();
+        for segments in 1..=12 {
+            let mut d = Delta::new(signature.clone());
+            d.diff_parallel(&new, segments).unwrap();
+            assert_eq!(d.operations(), one.operations(), "{} segments", segments);
+            assert_eq!(patched(d.operations(), &original), new);
+        }
+
+        // nothing matches, or nothing at all.
+        for new in &[random(100), vec![]] {
+            let mut d = Delta::new(signature.clone());
+            d.diff_parallel(new, 4).unwrap();
+            assert_eq!(&patched(d.operations(), &original), new);
+        }
+    }
+
+    #[test]
+    fn diff_from() {
+        // random, so no block repeats.
+        let original = random_bytes(0x2545F4914F6CDD1D, 4096);
+        let known = 3000;
+        let new = [
+            &original[..known],
+            b"changed",
+            &original[known + 10..],
+            b"appended",
+        ]
+        .concat();
+        for block_size in &[16, 33, 64] {
+            for prefix in &[0, 1, known / 2, known] {
+                let mut signature = Signature::with_block_size(*block_size, &original);
+                signature.calculate();
+                let mut full = Delta::new(signature.to_indexed());
+                full.diff(&new).unwrap();
+                let mut from = Delta::new(signature.to_indexed());
+                from.diff_from(&new, *prefix).unwrap();
+                // the full diff could trip on a weak hash collision inside the prefix.
+                let start = prefix / block_size * block_size;
+                let tail: Vec<_> = full
+                    .operations()
+                    .iter()
+                    .filter(|op| op.offset() >= start)
+                    .cloned()
+                    .collect();
+                assert_eq!(from.operations(), &tail[..], "bs = {}", block_size);
+                assert_eq!(patched(from.operations(), &original), new);
             }
-            None => None,
         }
+        // a prefix longer than the buffers.
+        let mut d = delta(16, "short");
+        d.diff_from(b"short", 100).unwrap();
+        assert!(d.operations().is_empty());
     }
 }
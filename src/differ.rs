@@ -0,0 +1,660 @@
+//! The rolling diff loop as a resumable state machine.
+//!
+//! [`Differ`] slides a [`Window`] over the new buffer and asks a [`BlockSource`] for a block of
+//! the original buffer that matches the current frame, every call to [`Differ::next_event`]
+//! runs the loop only until the next [`Event`] is ready.
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::trace;
+
+use crate::delta::{BlockMatch, DiffOptions, Operation};
+use crate::hash::{CryptoHash, CryptoHasher, HashKind, IndexedSignature, RollingHasher};
+use crate::observer::{Observer, ObserverEvent};
+use crate::window::{Frames, ReaderWindow, Window};
+
+/// Where the blocks of the original buffer come from.
+pub(crate) trait BlockSource {
+    /// Find a block that matches the current `frame` with the given `weak_hash` and starts at or
+    /// after `next_offset` in the original buffer, returns the block index.
+    fn find_match<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Option<usize>>;
+
+    /// Same as [`BlockSource::find_match`] but returns every block that matches, in the order
+    /// of their index, by default only the one [`BlockSource::find_match`] finds.
+    fn find_candidates<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Vec<usize>> {
+        let idx = self.find_match(weak_hash, frame, next_offset, observer)?;
+        Ok(idx.into_iter().collect())
+    }
+
+    /// Where the block at `idx` starts in the original buffer.
+    fn block_offset(&self, idx: usize) -> usize;
+
+    /// The length of the block at `idx`.
+    fn block_len(&self, idx: usize) -> usize;
+
+    /// The length of the original buffer, only called once the new buffer is done.
+    fn original_len(&mut self) -> io::Result<usize>;
+}
+
+impl BlockSource for IndexedSignature {
+    fn find_match<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Option<usize>> {
+        (&*self).find_match(weak_hash, frame, next_offset, observer)
+    }
+
+    fn find_candidates<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Vec<usize>> {
+        (&*self).find_candidates(weak_hash, frame, next_offset, observer)
+    }
+
+    fn block_offset(&self, idx: usize) -> usize {
+        IndexedSignature::block_offset(self, idx)
+    }
+
+    fn block_len(&self, idx: usize) -> usize {
+        IndexedSignature::block_len(self, idx)
+    }
+
+    fn original_len(&mut self) -> io::Result<usize> {
+        Ok(self.original_buffer_len)
+    }
+}
+
+/// the signature never changes while diffing, so a shared one works too.
+impl BlockSource for &IndexedSignature {
+    fn find_match<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Option<usize>> {
+        trace!("weak_hash of the current frame = 0x{:0x}", weak_hash);
+        // blocks can share a weak hash, the last one that really matches wins.
+        let blocks = match self.blocks.get(&self.salt_weak_hash(weak_hash)) {
+            Some(blocks) => blocks,
+            None => return Ok(None),
+        };
+        trace!("found {} blocks with the weak hash !!!", blocks.len());
+        let crypto_hash = self.salt_crypto_hash(frame_crypto_hash(self.hash, frame));
+        let mut idx = None;
+        for (i, block) in blocks.iter().rev() {
+            observer.observe(ObserverEvent::WeakHit { index: *i });
+            let crypto_match = block.crypto_hash.ct_eq(&crypto_hash);
+            observer.observe(if crypto_match {
+                ObserverEvent::StrongMatch { index: *i }
+            } else {
+                ObserverEvent::StrongMiss { index: *i }
+            });
+            let new_idx = self.block_offset(*i) >= next_offset;
+            trace!(
+                "block {}: crypto_match ? {}, new_idx ? {}",
+                i,
+                crypto_match,
+                new_idx
+            );
+            if crypto_match && new_idx {
+                trace!("all matched !!!");
+                idx = Some(*i);
+                break;
+            }
+        }
+        Ok(idx)
+    }
+
+    fn find_candidates<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Vec<usize>> {
+        let blocks = match self.blocks.get(&self.salt_weak_hash(weak_hash)) {
+            Some(blocks) => blocks,
+            None => return Ok(Vec::new()),
+        };
+        let crypto_hash = self.salt_crypto_hash(frame_crypto_hash(self.hash, frame));
+        let mut candidates = Vec::with_capacity(blocks.len());
+        for (idx, block) in blocks {
+            observer.observe(ObserverEvent::WeakHit { index: *idx });
+            if block.crypto_hash.ct_eq(&crypto_hash) {
+                observer.observe(ObserverEvent::StrongMatch { index: *idx });
+                if self.block_offset(*idx) >= next_offset {
+                    candidates.push(*idx);
+                }
+            } else {
+                observer.observe(ObserverEvent::StrongMiss { index: *idx });
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn block_offset(&self, idx: usize) -> usize {
+        IndexedSignature::block_offset(self, idx)
+    }
+
+    fn block_len(&self, idx: usize) -> usize {
+        IndexedSignature::block_len(self, idx)
+    }
+
+    fn original_len(&mut self) -> io::Result<usize> {
+        Ok(self.original_buffer_len)
+    }
+}
+
+/// A block source that fails as soon as the `cancelled` flag is set, the diff asks it for a
+/// match on every step so it stops right away.
+pub(crate) struct Cancellable<'a, S> {
+    pub(crate) source: &'a mut S,
+    pub(crate) cancelled: &'a AtomicBool,
+}
+
+impl<S> Cancellable<'_, S> {
+    fn check(&self) -> io::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(cancelled())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: BlockSource> BlockSource for Cancellable<'_, S> {
+    fn find_match<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Option<usize>> {
+        self.check()?;
+        self.source
+            .find_match(weak_hash, frame, next_offset, observer)
+    }
+
+    fn find_candidates<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        observer: &mut O,
+    ) -> io::Result<Vec<usize>> {
+        self.check()?;
+        self.source
+            .find_candidates(weak_hash, frame, next_offset, observer)
+    }
+
+    fn block_offset(&self, idx: usize) -> usize {
+        self.source.block_offset(idx)
+    }
+
+    fn block_len(&self, idx: usize) -> usize {
+        self.source.block_len(idx)
+    }
+
+    fn original_len(&mut self) -> io::Result<usize> {
+        self.source.original_len()
+    }
+}
+
+/// The error of a cancelled diff.
+pub(crate) fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "diff cancelled")
+}
+
+/// Calculate the crypto hash of the frame, as if the front and back were one buffer.
+pub(crate) fn frame_crypto_hash(kind: HashKind, frame: (&[u8], &[u8])) -> CryptoHash {
+    CryptoHasher::new(kind).hash_frame(frame)
+}
+
+/// Something that happened while diffing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// An operation is ready.
+    Op(Operation),
+    /// A block of the original buffer was found in the new buffer.
+    Match(BlockMatch),
+}
+
+/// How much an operation costs, see [`crate::Delta::with_cost_model`].
+#[derive(Clone)]
+pub(crate) struct CostModel(pub(crate) Arc<dyn Fn(&Operation) -> usize + Send + Sync>);
+
+impl fmt::Debug for CostModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CostModel")
+    }
+}
+
+/// The buffers a [`Differ`] needs, they can be handed over from one diff to the next so they
+/// only grow once.
+#[derive(Debug, Default)]
+pub(crate) struct Scratch {
+    ins_buffer: Vec<u8>,
+    events: VecDeque<Event>,
+}
+
+/// The state of an ongoing diff.
+pub(crate) struct Differ<W: Frames> {
+    window: W,
+    hasher: RollingHasher,
+    block_size: usize,
+    /// The bytes that did not match anything so far.
+    ins_buffer: Vec<u8>,
+    /// Where we are in the original buffer, everything before it is either matched or removed.
+    next_offset: usize,
+    /// The events that are ready but not yet consumed.
+    events: VecDeque<Event>,
+    /// Are we done with the new buffer?
+    finished: bool,
+    /// Blocks can match in any order and nothing is removed, the matches become copies.
+    explicit: bool,
+    options: DiffOptions,
+    /// How many bytes in a row did not match since the last match or resync.
+    unmatched: usize,
+    /// Keep the capacity of the `ins_buffer`, the inserts get a copy of it instead.
+    reuse_ins_buffer: bool,
+    /// Picks the cheapest of the matching blocks, if any.
+    cost_model: Option<CostModel>,
+    /// The last block that matched, see [`DiffOptions::match_window`].
+    last_block: Option<usize>,
+}
+
+impl<B: AsRef<[u8]>> Differ<Window<B>> {
+    /// Start a new diff of `buf` using blocks of `block_size` bytes.
+    pub(crate) fn new(buf: B, block_size: usize) -> io::Result<Self> {
+        trace!("starting new diff");
+        Ok(Self::with_window(Window::new(buf, block_size)?, block_size))
+    }
+
+    /// Same as [`Differ::new`] but the diff starts `start` bytes into `buf`, everything before
+    /// it is taken as matched up to `next_offset` in the original buffer.
+    pub(crate) fn new_at(
+        buf: B,
+        block_size: usize,
+        start: usize,
+        next_offset: usize,
+    ) -> io::Result<Self> {
+        trace!("starting new diff at {}", start);
+        let window = Window::new_at(buf, block_size, start)?;
+        let mut differ = Self::with_window(window, block_size);
+        differ.next_offset = next_offset;
+        Ok(differ)
+    }
+
+    /// Same as [`Differ::new`] but any block can match at any time, the caller turns the
+    /// matches into [`Operation::Copy`]s, and there are no removes.
+    pub(crate) fn explicit(buf: B, block_size: usize) -> io::Result<Self> {
+        Ok(Self::new(buf, block_size)?.into_explicit())
+    }
+}
+
+impl<R: Read> Differ<ReaderWindow<R>> {
+    /// Same as [`Differ::new`] but the new buffer is read from the `reader` as the diff goes.
+    pub(crate) fn from_reader(reader: R, block_size: usize) -> io::Result<Self> {
+        trace!("starting new diff from a reader");
+        Ok(Self::with_window(
+            ReaderWindow::new(reader, block_size)?,
+            block_size,
+        ))
+    }
+}
+
+impl<W: Frames> Differ<W> {
+    fn with_window(window: W, block_size: usize) -> Self {
+        trace!("block_size = {}", block_size);
+        let mut hasher = RollingHasher::new();
+        hasher.update(window.frame().0);
+        Self {
+            window,
+            hasher,
+            block_size,
+            ins_buffer: Vec::new(),
+            next_offset: 0,
+            events: VecDeque::with_capacity(3),
+            finished: false,
+            explicit: false,
+            options: DiffOptions::default(),
+            unmatched: 0,
+            reuse_ins_buffer: false,
+            cost_model: None,
+            last_block: None,
+        }
+    }
+
+    /// Choose between the matching blocks using the `cost_model`, see [`Differ::choose`].
+    pub(crate) fn with_cost_model(mut self, cost_model: Option<CostModel>) -> Self {
+        self.cost_model = cost_model;
+        self
+    }
+
+    /// Pick the block of the `candidates` whose operation costs the least, the last one wins a
+    /// tie, or none of them if taking the current frame as literal bytes is even cheaper.
+    ///
+    /// the operation of a candidate is the copy of it in an explicit diff, otherwise the remove
+    /// of the bytes skipped to get to it (which is free if nothing is skipped).
+    fn choose<S: BlockSource>(
+        &self,
+        candidates: &[usize],
+        source: &S,
+        cost: &CostModel,
+    ) -> Option<usize> {
+        let bytes_read = self.window.bytes_read();
+        let op_cost = |idx: usize| {
+            let offset = source.block_offset(idx);
+            let op = if self.explicit {
+                Some(Operation::Copy {
+                    src_offset: offset,
+                    len: source.block_len(idx),
+                })
+            } else if offset > self.next_offset {
+                Some(Operation::Remove {
+                    offset: bytes_read,
+                    len: offset - self.next_offset,
+                })
+            } else {
+                None
+            };
+            op.map_or(0, |op| (cost.0)(&op))
+        };
+        let (idx, best) = candidates
+            .iter()
+            .rev()
+            .map(|idx| (*idx, op_cost(*idx)))
+            .min_by_key(|(_, cost)| *cost)?;
+        let (front, back) = self.window.frame();
+        let literal = (cost.0)(&Operation::Insert {
+            offset: bytes_read,
+            buffer: [front, back].concat(),
+        });
+        trace!("cheapest block {} costs {}, literal {}", idx, best, literal);
+        Some(idx).filter(|_| best <= literal)
+    }
+
+    /// Use the buffers of the `scratch` instead of new ones, see [`Differ::into_scratch`].
+    pub(crate) fn with_scratch(mut self, mut scratch: Scratch) -> Self {
+        scratch.ins_buffer.clear();
+        scratch.events.clear();
+        self.ins_buffer = scratch.ins_buffer;
+        self.events = scratch.events;
+        self.reuse_ins_buffer = true;
+        self
+    }
+
+    /// Give back the buffers of this diff, so the next one can use them.
+    pub(crate) fn into_scratch(self) -> Scratch {
+        Scratch {
+            ins_buffer: self.ins_buffer,
+            events: self.events,
+        }
+    }
+
+    /// The insert of the bytes that did not match so far, ending where we are now.
+    fn flush_insert(&mut self) -> Event {
+        let buffer = if self.reuse_ins_buffer {
+            let buffer = self.ins_buffer.clone();
+            self.ins_buffer.clear();
+            buffer
+        } else {
+            mem::take(&mut self.ins_buffer)
+        };
+        Event::Op(Operation::Insert {
+            offset: self.window.bytes_read() - buffer.len(),
+            buffer,
+        })
+    }
+
+    /// Make this an explicit diff, see [`Differ::explicit`].
+    pub(crate) fn into_explicit(mut self) -> Self {
+        self.explicit = true;
+        self
+    }
+
+    /// How many bytes of the new buffer went through the window so far.
+    pub(crate) fn bytes_read(&self) -> usize {
+        self.window.bytes_read()
+    }
+
+    /// Use the given `options` for this diff.
+    pub(crate) fn with_options(mut self, options: DiffOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Run the diff loop until the next event is ready, returns [`None`] once the diff is done.
+    pub(crate) fn next_event<S: BlockSource, O: Observer>(
+        &mut self,
+        source: &mut S,
+        observer: &mut O,
+    ) -> io::Result<Option<Event>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if self.finished {
+                return Ok(None);
+            }
+            if self.window.has_frame() {
+                self.step(source, observer)?;
+            } else {
+                self.finish(source)?;
+            }
+        }
+    }
+
+    /// A single iteration of the diff loop, either a block matched and we skip it, or we slide
+    /// the window one byte.
+    fn step<S: BlockSource, O: Observer>(
+        &mut self,
+        source: &mut S,
+        observer: &mut O,
+    ) -> io::Result<()> {
+        let block_idx = if self.cost_model.is_some() || self.options.match_window.is_some() {
+            let mut candidates = source.find_candidates(
+                self.hasher.digest(),
+                self.window.frame(),
+                self.next_offset,
+                observer,
+            )?;
+            if let Some(window) = self.options.match_window {
+                let expected = self.last_block.map_or(0, |idx| idx + 1);
+                let distance = |idx: &usize| cmp::max(*idx, expected) - cmp::min(*idx, expected);
+                candidates.retain(|idx| distance(idx) <= window);
+                // the nearest one, which is the last one to go if there is a cost model.
+                candidates.sort_by_key(|idx| cmp::Reverse(distance(idx)));
+            }
+            match &self.cost_model {
+                Some(cost) => self.choose(&candidates, source, cost),
+                None => candidates.last().copied(),
+            }
+        } else {
+            source.find_match(
+                self.hasher.digest(),
+                self.window.frame(),
+                self.next_offset,
+                observer,
+            )?
+        };
+        trace!("block_idx = {:?}", block_idx);
+        trace!("current total bytes read: {}", self.window.bytes_read());
+        let bytes_read = self.window.bytes_read();
+        if let Some(block_idx) = block_idx {
+            if !self.ins_buffer.is_empty() {
+                trace!(
+                    "insert buffer is not empty, add insert op with len: {}",
+                    self.ins_buffer.len()
+                );
+                let insert = self.flush_insert();
+                self.events.push_back(insert);
+            }
+            let block_offset = source.block_offset(block_idx);
+            trace!("check if the matched block starts after the last matched one");
+            if self.explicit {
+                trace!(
+                    "explicit diff, the next_offset stays at {}",
+                    self.next_offset
+                );
+            } else {
+                if block_offset > self.next_offset {
+                    trace!("okay, it is, add a remove op");
+                    self.events.push_back(Event::Op(Operation::Remove {
+                        offset: bytes_read,
+                        len: block_offset - self.next_offset,
+                    }));
+                }
+                self.next_offset = block_offset + source.block_len(block_idx);
+                trace!("update next_offset to {}", self.next_offset);
+            }
+            self.unmatched = 0;
+            self.last_block = Some(block_idx);
+            self.events.push_back(Event::Match(BlockMatch {
+                index: block_idx,
+                offset: bytes_read,
+            }));
+            trace!("move a block forword with block_size = {}", self.block_size);
+            for _ in 0..self.block_size {
+                trace!("current total bytes read: {}", self.window.bytes_read());
+                if self.window.on_boundry() && self.window.frame_size() == 0 {
+                    trace!("we hit the bounds and current frame size is zero; break");
+                    break;
+                }
+                trace!("move the window one byte forword ..");
+                let (tail, head) = self.window.move_forword()?;
+                if let Some(tail) = tail {
+                    trace!("rolling out the hash ..");
+                    self.hasher.remove(tail);
+                }
+
+                if let Some(head) = head {
+                    trace!("rolling in the hash ..");
+                    self.hasher.insert(head);
+                }
+            }
+            trace!(
+                "moved a block, current total bytes read so far: {}",
+                self.window.bytes_read()
+            );
+        } else {
+            self.unmatched += 1;
+            // skip a whole block without looking for a match.
+            let resync = matches!(self.options.resync_after, Some(n) if self.unmatched >= n);
+            let steps = if resync {
+                trace!("no match for {} bytes, resync ..", self.unmatched);
+                self.unmatched = 0;
+                self.block_size
+            } else {
+                1
+            };
+            for _ in 0..steps {
+                trace!("no match found, moving the window forword one byte ..");
+                let (tail, head) = self.window.move_forword()?;
+                trace!("current total bytes read: {}", self.window.bytes_read());
+                if let Some(tail) = tail {
+                    trace!("rolling out the hash ..");
+                    self.hasher.remove(tail);
+                    trace!("add the current tail to the insert buffer ..");
+                    self.ins_buffer.push(tail);
+                    observer.observe(ObserverEvent::LiteralByte);
+                }
+                if let Some(head) = head {
+                    trace!("rolling in the hash ..");
+                    self.hasher.insert(head);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The new buffer is done, flush the remaining inserts and remove whatever is left in the
+    /// original buffer.
+    fn finish<S: BlockSource>(&mut self, source: &mut S) -> io::Result<()> {
+        trace!("diff loop ended.");
+        let bytes_read = self.window.bytes_read();
+        trace!("current total bytes read: {}", bytes_read);
+        trace!(
+            "check the insert buffer for any remaining bytes, len = {}",
+            self.ins_buffer.len()
+        );
+        if !self.ins_buffer.is_empty() {
+            let insert = self.flush_insert();
+            self.events.push_back(insert);
+        }
+
+        trace!("checking if there is anything left in the original buffer which means a remove op should be added!");
+        trace!("next_offset = {}", self.next_offset);
+        let original_len = source.original_len()?;
+        if !self.explicit && self.next_offset < original_len {
+            self.events.push_back(Event::Op(Operation::Remove {
+                offset: bytes_read,
+                len: original_len - self.next_offset,
+            }));
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::NoopObserver;
+    use crate::window::tests::ChunkedReader;
+    use crate::Signature;
+
+    fn events<W: Frames>(mut differ: Differ<W>, signature: &mut IndexedSignature) -> Vec<Event> {
+        let mut events = Vec::new();
+        while let Some(event) = differ.next_event(signature, &mut NoopObserver).unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn from_reader() {
+        let original: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let new = original
+            .replace("line 5", "line five")
+            .replace("line 70\n", "");
+        for block_size in &[1, 3, 8, 64] {
+            let mut signature = Signature::with_block_size(*block_size, &original);
+            signature.calculate();
+            let mut signature = signature.to_indexed();
+            let expected = events(Differ::new(&new, *block_size).unwrap(), &mut signature);
+            let reader = ChunkedReader::new(new.as_bytes());
+            let differ = Differ::from_reader(reader, *block_size).unwrap();
+            assert_eq!(
+                events(differ, &mut signature),
+                expected,
+                "bs = {}",
+                block_size
+            );
+        }
+    }
+}
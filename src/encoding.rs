@@ -0,0 +1,1225 @@
+//! Compact binary encoding of [`IndexedSignature`] and [`Operation`]s.
+//!
+//! everything is encoded in little-endian and starts with a small magic header followed by a
+//! version byte, so that future format changes can be detected by older decoders.
+//!
+//! since version 2 a signature ends with a list of extra fields, each one is a tag byte, a
+//! `u64` length and then the value, so new fields can be added without a new version. fields
+//! with the highest bit (`0x80`) set in their tag can't be ignored, a decoder that does not
+//! know about them refuses the whole signature, the rest are skipped.
+//!
+//! since version 3 a signature describes its hashes right after the version byte, the kind of
+//! the weak hash, the kind of the crypto hash and its length, a decoder refuses the hashes it
+//! doesn't know instead of matching garbage. with the block size and the chunking mode that
+//! follow, everything needed to diff against it is in the signature itself.
+//!
+//! since version 2 the operations use LEB128 varints instead of fixed size integers.
+//!
+//! since version 3 the operations end with the length of the patched buffer plus one, or zero
+//! if it is not known, so a stream cut right after an operation is still detected.
+//!
+//! since version 4 the inserts with long runs of the same byte (like the padding of a binary)
+//! can be run-length encoded, only when it takes less bytes.
+//!
+//! operations written one by one with a [`DeltaWriter`] use their own header since their count
+//! is not known up front, each operation is prefixed with its length, and a zero length ends
+//! them followed by their count and a checksum of everything before it.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use blake2::{Blake2b, Digest};
+
+use crate::delta::Operation;
+use crate::hash::{BlockHash, Chunking, CryptoHash, HashKind, IndexedSignature};
+
+/// The magic header of an encoded [`IndexedSignature`].
+const SIGNATURE_MAGIC: &[u8; 4] = b"RSDS";
+/// The magic header of encoded [`Operation`]s.
+const OPERATIONS_MAGIC: &[u8; 4] = b"RSDD";
+/// The magic header of [`Operation`]s written by a [`DeltaWriter`].
+const STREAM_MAGIC: &[u8; 4] = b"RSDW";
+/// The current version of the signature encoding format, version 2 has extra fields and version
+/// 3 describes its hashes.
+const SIGNATURE_VERSION: u8 = 3;
+/// The current version of the operations encoding format, version 2 uses varints, version 3
+/// ends with the length of the patched buffer and version 4 has run-length encoded inserts.
+const OPERATIONS_VERSION: u8 = 4;
+/// The current version of the [`DeltaWriter`] format, the operations in it are encoded the same
+/// as in the current version of the operations format.
+const STREAM_VERSION: u8 = 1;
+
+/// The tag bit of the signature fields that must be understood to decode the signature.
+const REQUIRED_FIELD: u8 = 0x80;
+/// The salt of a salted signature, it can't be ignored since nothing would match without it.
+const SALT_FIELD: u8 = REQUIRED_FIELD | 1;
+
+/// The weak hash of [`crate::RollingHasher`].
+const ROLLING_WEAK_HASH: u8 = 0;
+/// The crypto hash of [`CryptoHash::calculate`], kept whole for [`HashKind::Blake2b`] or the
+/// first 16 bytes of it for [`HashKind::Blake2b128`].
+const BLAKE2B_CRYPTO_HASH: u8 = 0;
+/// The crypto hash of [`HashKind::Blake2s`].
+const BLAKE2S_CRYPTO_HASH: u8 = 1;
+/// The crypto hash of `HashKind::Blake3`, behind the `blake3` feature.
+#[cfg(feature = "blake3")]
+const BLAKE3_CRYPTO_HASH: u8 = 2;
+
+const FIXED_CHUNKING: u8 = 0;
+const LINES_CHUNKING: u8 = 1;
+const STRIDED_CHUNKING: u8 = 2;
+const PARTS_CHUNKING: u8 = 3;
+
+const INSERT_TAG: u8 = 0;
+const REMOVE_TAG: u8 = 1;
+const COPY_TAG: u8 = 2;
+/// A remove with its length in blocks.
+const REMOVE_BLOCKS_TAG: u8 = 3;
+const REPLACE_TAG: u8 = 4;
+/// An insert with its buffer run-length encoded.
+const INSERT_RLE_TAG: u8 = 5;
+
+/// runs of the same byte at least this long are run-length encoded.
+const MIN_RUN: usize = 8;
+
+/// Errors that could happen while decoding a signature or operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer does not start with the expected magic header.
+    BadMagic,
+    /// The buffer was encoded with a version of the format we don't know about.
+    UnsupportedVersion(u8),
+    /// The buffer ended before we could read everything.
+    UnexpectedEof,
+    /// Found an unknown operation tag.
+    UnknownOperation(u8),
+    /// Found an unknown chunking mode of a signature.
+    UnknownChunking(u8),
+    /// Found a required signature field we don't know about.
+    UnknownField(u8),
+    /// A length or offset does not fit in this platform `usize`.
+    Overflow,
+    /// The `line` of an edit script is not a valid command.
+    BadScript { line: usize },
+    /// A protobuf field has the wrong wire type or a bad value.
+    BadField(u32),
+    /// A signature uses a weak hash, or a crypto hash or length of it, we don't know about.
+    UnsupportedHash { weak: u8, crypto: u8, len: u8 },
+    /// A signature has a block size or stride of zero.
+    BadBlockSize,
+    /// The line or part offsets of a signature go past its buffer or are out of order.
+    BadOffsets,
+    /// A protobuf message could not be decoded, with the reason why.
+    Protobuf(String),
+    /// The length, count or checksum of operations written by a [`DeltaWriter`] does not match
+    /// their bytes.
+    BadChecksum,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadMagic => write!(f, "bad magic header"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            ParseError::UnexpectedEof => write!(f, "unexpected end of buffer"),
+            ParseError::UnknownOperation(tag) => write!(f, "unknown operation tag {}", tag),
+            ParseError::UnknownChunking(mode) => write!(f, "unknown chunking mode {}", mode),
+            ParseError::UnknownField(tag) => write!(f, "unknown required field {}", tag),
+            ParseError::Overflow => write!(f, "value does not fit in usize"),
+            ParseError::BadScript { line } => write!(f, "bad edit script at line {}", line),
+            ParseError::BadField(field) => write!(f, "bad protobuf field {}", field),
+            ParseError::UnsupportedHash { weak, crypto, len } => write!(
+                f,
+                "unsupported hashes, weak {}, crypto {} of {} bytes",
+                weak, crypto, len
+            ),
+            ParseError::BadChecksum => write!(f, "checksum mismatch"),
+            ParseError::BadBlockSize => write!(f, "block size of zero"),
+            ParseError::BadOffsets => write!(f, "offsets out of order or out of bounds"),
+            ParseError::Protobuf(reason) => write!(f, "bad protobuf message: {}", reason),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl IndexedSignature {
+    /// Encode the signature into a compact binary format.
+    ///
+    /// the blocks are written in the order of their index, so the output is stable for the
+    /// same signature.
+    ///
+    /// see [`IndexedSignature::from_bytes`] for decoding it back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut blocks: Vec<_> = self.blocks.values().flatten().collect();
+        blocks.sort_by_key(|(idx, _)| *idx);
+        let hash_len = self.hash.size();
+        let mut out = Vec::with_capacity(4 + 1 + 3 + 8 * 3 + blocks.len() * (8 + 4 + hash_len));
+        out.extend_from_slice(SIGNATURE_MAGIC);
+        out.push(SIGNATURE_VERSION);
+        let crypto = match self.hash {
+            HashKind::Blake2b | HashKind::Blake2b128 => BLAKE2B_CRYPTO_HASH,
+            HashKind::Blake2s => BLAKE2S_CRYPTO_HASH,
+            #[cfg(feature = "blake3")]
+            HashKind::Blake3 => BLAKE3_CRYPTO_HASH,
+        };
+        out.extend_from_slice(&[ROLLING_WEAK_HASH, crypto, hash_len as u8]);
+        put_u64(&mut out, self.original_buffer_len);
+        put_u64(&mut out, self.block_size);
+        match &self.chunking {
+            Chunking::Fixed => out.push(FIXED_CHUNKING),
+            Chunking::Strided(stride) => {
+                out.push(STRIDED_CHUNKING);
+                put_u64(&mut out, *stride);
+            }
+            Chunking::Lines(offsets) => {
+                out.push(LINES_CHUNKING);
+                put_offsets(&mut out, offsets);
+            }
+            Chunking::Parts(starts) => {
+                out.push(PARTS_CHUNKING);
+                put_offsets(&mut out, starts);
+            }
+        }
+        put_u64(&mut out, blocks.len());
+        for (idx, block) in blocks {
+            put_u64(&mut out, *idx);
+            out.extend_from_slice(&block.weak_hash.to_le_bytes());
+            out.extend_from_slice(&block.crypto_hash[..hash_len]);
+        }
+        if let Some(salt) = &self.salt {
+            out.push(SALT_FIELD);
+            put_u64(&mut out, salt.len());
+            out.extend_from_slice(salt);
+        }
+        out
+    }
+
+    /// Decode a signature previously encoded using [`IndexedSignature::to_bytes`].
+    ///
+    /// older versions of the format are still accepted, any field they don't have gets its
+    /// default value.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.header(SIGNATURE_MAGIC, SIGNATURE_VERSION)?;
+        // older versions only had the default hashes.
+        let mut hash = HashKind::default();
+        if version >= 3 {
+            let hashes = reader.bytes(3)?;
+            hash = match hashes {
+                [ROLLING_WEAK_HASH, BLAKE2B_CRYPTO_HASH, 32] => HashKind::Blake2b,
+                [ROLLING_WEAK_HASH, BLAKE2B_CRYPTO_HASH, 16] => HashKind::Blake2b128,
+                [ROLLING_WEAK_HASH, BLAKE2S_CRYPTO_HASH, 32] => HashKind::Blake2s,
+                #[cfg(feature = "blake3")]
+                [ROLLING_WEAK_HASH, BLAKE3_CRYPTO_HASH, 32] => HashKind::Blake3,
+                _ => {
+                    return Err(ParseError::UnsupportedHash {
+                        weak: hashes[0],
+                        crypto: hashes[1],
+                        len: hashes[2],
+                    })
+                }
+            };
+        }
+        let original_buffer_len = reader.usize()?;
+        let block_size = reader.usize()?;
+        let chunking = match reader.u8()? {
+            FIXED_CHUNKING => Chunking::Fixed,
+            STRIDED_CHUNKING => Chunking::Strided(reader.usize()?),
+            LINES_CHUNKING => Chunking::Lines(reader.offsets()?),
+            PARTS_CHUNKING => Chunking::Parts(reader.offsets()?),
+            mode => return Err(ParseError::UnknownChunking(mode)),
+        };
+        check_layout(original_buffer_len, block_size, &chunking)?;
+        let count = reader.usize()?;
+        // don't trust the count blindly, every block takes 12 bytes and its crypto hash.
+        let mut blocks = HashMap::with_capacity(count.min(reader.remaining() / (12 + hash.size())));
+        for _ in 0..count {
+            let idx = reader.usize()?;
+            let weak_hash = reader.u32()?;
+            let crypto_hash = CryptoHash::truncated(reader.bytes(hash.size())?, hash.size());
+            let block = BlockHash {
+                weak_hash,
+                crypto_hash,
+            };
+            blocks
+                .entry(weak_hash)
+                .or_insert_with(Vec::new)
+                .push((idx, block));
+        }
+        let mut salt = None;
+        if version >= 2 {
+            while reader.remaining() > 0 {
+                let tag = reader.u8()?;
+                let len = reader.usize()?;
+                let value = reader.bytes(len)?;
+                match tag {
+                    SALT_FIELD => {
+                        salt = Some(
+                            value
+                                .try_into()
+                                .map_err(|_| ParseError::UnknownField(tag))?,
+                        )
+                    }
+                    tag if tag & REQUIRED_FIELD != 0 => return Err(ParseError::UnknownField(tag)),
+                    // an optional field from a newer version, skip it.
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            original_buffer_len,
+            block_size,
+            blocks,
+            chunking,
+            salt,
+            hash,
+        })
+    }
+}
+
+/// Check that the `block_size` and `chunking` of a decoded signature of a buffer of
+/// `original_buffer_len` bytes can be diffed against.
+///
+/// every line must start before the next one and before the end of the buffer, parts could be
+/// empty so they only have to start no later than that.
+pub(crate) fn check_layout(
+    original_buffer_len: usize,
+    block_size: usize,
+    chunking: &Chunking,
+) -> Result<(), ParseError> {
+    let (offsets, strict) = match chunking {
+        Chunking::Fixed => (&[][..], false),
+        Chunking::Strided(0) => return Err(ParseError::BadBlockSize),
+        Chunking::Strided(_) => (&[][..], false),
+        Chunking::Lines(offsets) => (&offsets[..], true),
+        Chunking::Parts(starts) => (&starts[..], false),
+    };
+    if block_size == 0 {
+        return Err(ParseError::BadBlockSize);
+    }
+    let ends = offsets.iter().skip(1).chain(Some(&original_buffer_len));
+    let ordered = offsets
+        .iter()
+        .zip(ends)
+        .all(|(start, end)| start < end || (!strict && start == end));
+    if !ordered {
+        return Err(ParseError::BadOffsets);
+    }
+    Ok(())
+}
+
+/// Encode the `ops` into a compact binary format.
+///
+/// see [`decode_operations`] for decoding it back.
+pub fn encode_operations(ops: &[Operation]) -> Vec<u8> {
+    encode_operations_aligned(ops, 0)
+}
+
+/// Same as [`encode_operations`] but removes of whole blocks of `block_size` bytes store the
+/// number of blocks instead of their length, which takes less bytes.
+///
+/// the `block_size` is stored too, so [`decode_operations`] decodes it back the same.
+///
+/// ### Example
+/// ```
+/// use rsdiff::Operation;
+///
+/// let ops = vec![
+///     Operation::Remove { offset: 0, len: 4096 },
+///     Operation::Remove { offset: 100, len: 8192 },
+/// ];
+/// let aligned = rsdiff::encode_operations_aligned(&ops, 1024);
+/// assert!(aligned.len() < rsdiff::encode_operations(&ops).len());
+/// assert_eq!(rsdiff::decode_operations(&aligned), Ok(ops));
+/// ```
+pub fn encode_operations_aligned(ops: &[Operation], block_size: usize) -> Vec<u8> {
+    encode(ops, block_size, None)
+}
+
+/// Same as [`encode_operations`] but with the length of the patched buffer, so a patch can tell
+/// if the operations were cut short, see [`crate::Patch::decode`].
+///
+/// ### Example
+/// ```
+/// let new = "i saw a red box";
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", new).unwrap();
+/// let bytes = rsdiff::encode_operations_with_len(&ops, new.len());
+/// assert_eq!(rsdiff::decode_operations_with_len(&bytes), Ok((ops, Some(new.len()))));
+/// ```
+pub fn encode_operations_with_len(ops: &[Operation], new_len: usize) -> Vec<u8> {
+    encode(ops, 0, Some(new_len))
+}
+
+fn encode(ops: &[Operation], block_size: usize, new_len: Option<usize>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(OPERATIONS_MAGIC);
+    out.push(OPERATIONS_VERSION);
+    put_varint(&mut out, block_size);
+    put_varint(&mut out, ops.len());
+    for op in ops {
+        put_op(&mut out, op, block_size);
+    }
+    put_varint(&mut out, new_len.map_or(0, |len| len + 1));
+    out
+}
+
+/// Write a single operation, removes of whole blocks are written as blocks if `block_size` is not
+/// zero.
+fn put_op(out: &mut Vec<u8>, op: &Operation, block_size: usize) {
+    match op {
+        Operation::Insert { offset, buffer } => match rle(buffer) {
+            Some(encoded) => {
+                out.push(INSERT_RLE_TAG);
+                put_varint(out, *offset);
+                put_varint(out, buffer.len());
+                out.extend_from_slice(&encoded);
+            }
+            None => {
+                out.push(INSERT_TAG);
+                put_varint(out, *offset);
+                put_varint(out, buffer.len());
+                out.extend_from_slice(buffer);
+            }
+        },
+        Operation::Remove { offset, len } if block_size != 0 && len % block_size == 0 => {
+            out.push(REMOVE_BLOCKS_TAG);
+            put_varint(out, *offset);
+            put_varint(out, len / block_size);
+        }
+        Operation::Remove { offset, len } => {
+            out.push(REMOVE_TAG);
+            put_varint(out, *offset);
+            put_varint(out, *len);
+        }
+        Operation::Copy { src_offset, len } => {
+            out.push(COPY_TAG);
+            put_varint(out, *src_offset);
+            put_varint(out, *len);
+        }
+        Operation::Replace {
+            offset,
+            old_len,
+            buffer,
+        } => {
+            out.push(REPLACE_TAG);
+            put_varint(out, *offset);
+            put_varint(out, buffer.len());
+            put_varint(out, *old_len);
+            out.extend_from_slice(buffer);
+        }
+    }
+}
+
+/// Decode operations previously encoded using [`encode_operations`] or
+/// [`encode_operations_aligned`].
+pub fn decode_operations(bytes: &[u8]) -> Result<Vec<Operation>, ParseError> {
+    decode_operations_with_len(bytes).map(|(ops, _)| ops)
+}
+
+/// Same as [`decode_operations`] but also returns the length of the patched buffer, if it was
+/// encoded with [`encode_operations_with_len`].
+pub fn decode_operations_with_len(
+    bytes: &[u8],
+) -> Result<(Vec<Operation>, Option<usize>), ParseError> {
+    let mut reader = Reader::new(bytes);
+    let version = reader.header(OPERATIONS_MAGIC, OPERATIONS_VERSION)?;
+    // version 1 used fixed size integers and had no block size.
+    let (block_size, count) = if version == 1 {
+        (0, reader.usize()?)
+    } else {
+        (reader.varint()?, reader.varint()?)
+    };
+    // don't trust the count blindly, every operation takes at least 3 bytes.
+    let mut ops = Vec::with_capacity(count.min(reader.remaining() / 3));
+    for _ in 0..count {
+        ops.push(reader.operation(version, block_size)?);
+    }
+    let new_len = if version >= 3 {
+        reader.varint()?.checked_sub(1)
+    } else {
+        None
+    };
+    Ok((ops, new_len))
+}
+
+/// Writes operations one by one to the underlying writer, the streaming counterpart of
+/// [`encode_operations`], nothing is buffered but the operation being written.
+///
+/// the operations must be ended with [`DeltaWriter::finish`], see [`decode_operations_stream`]
+/// for decoding them back.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{DeltaWriter, Operation};
+///
+/// let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+/// writer.write_op(&Operation::Insert { offset: 12, buffer: b"box".to_vec() }).unwrap();
+/// writer.write_op(&Operation::Remove { offset: 15, len: 3 }).unwrap();
+/// let bytes = writer.finish().unwrap();
+/// assert_eq!(rsdiff::decode_operations_stream(&bytes).unwrap().len(), 2);
+/// ```
+pub struct DeltaWriter<W: Write> {
+    writer: W,
+    /// The checksum of everything written so far.
+    hasher: Blake2b,
+    count: usize,
+    /// The bytes waiting to be written.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Create a new writer, the header is written right away.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut this = Self {
+            writer,
+            hasher: Blake2b::new(),
+            count: 0,
+            buf: Vec::new(),
+        };
+        this.buf.extend_from_slice(STREAM_MAGIC);
+        this.buf.push(STREAM_VERSION);
+        this.flush_buf()?;
+        Ok(this)
+    }
+
+    /// Write a single operation prefixed with its length.
+    pub fn write_op(&mut self, op: &Operation) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        put_op(&mut encoded, op, 0);
+        put_varint(&mut self.buf, encoded.len());
+        self.buf.extend_from_slice(&encoded);
+        self.flush_buf()?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// How many operations were written so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// End the operations with their count and checksum, and flush the underlying writer.
+    ///
+    /// returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        put_varint(&mut self.buf, 0);
+        put_varint(&mut self.buf, self.count);
+        self.flush_buf()?;
+        self.writer.write_all(&self.hasher.finalize_reset()[..32])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        self.hasher.update(&self.buf);
+        self.writer.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+/// Decode operations written by a [`DeltaWriter`].
+///
+/// fails with [`ParseError::BadChecksum`] if the bytes were changed, and with
+/// [`ParseError::UnexpectedEof`] if the writer was never finished.
+pub fn decode_operations_stream(bytes: &[u8]) -> Result<Vec<Operation>, ParseError> {
+    let mut reader = Reader::new(bytes);
+    reader.header(STREAM_MAGIC, STREAM_VERSION)?;
+    let mut ops = Vec::new();
+    loop {
+        let len = reader.varint()?;
+        if len == 0 {
+            break;
+        }
+        let mut op = Reader::new(reader.bytes(len)?);
+        ops.push(op.operation(OPERATIONS_VERSION, 0)?);
+        if op.remaining() != 0 {
+            return Err(ParseError::BadChecksum);
+        }
+    }
+    if reader.varint()? != ops.len() {
+        return Err(ParseError::BadChecksum);
+    }
+    let checked = bytes.len() - reader.remaining();
+    let checksum = CryptoHash::new(reader.bytes(32)?).map_err(|_| ParseError::UnexpectedEof)?;
+    if !CryptoHash::from_digest(&Blake2b::digest(&bytes[..checked])).ct_eq(&checksum) {
+        return Err(ParseError::BadChecksum);
+    }
+    Ok(ops)
+}
+
+/// Run-length encode the `buffer`, as pieces that start with their length shifted left by one,
+/// with the low bit set for a run of the byte that follows, or clear for that many literal
+/// bytes.
+///
+/// returns [`None`] if it does not take less bytes than the `buffer` itself.
+fn rle(buffer: &[u8]) -> Option<Vec<u8>> {
+    if buffer.len() < MIN_RUN {
+        return None;
+    }
+    let mut out = Vec::new();
+    let mut literal = 0;
+    let mut i = 0;
+    while i < buffer.len() {
+        let run = buffer[i..].iter().take_while(|b| **b == buffer[i]).count();
+        if run >= MIN_RUN {
+            if literal < i {
+                put_varint(&mut out, (i - literal) << 1);
+                out.extend_from_slice(&buffer[literal..i]);
+            }
+            put_varint(&mut out, run << 1 | 1);
+            out.push(buffer[i]);
+            literal = i + run;
+        }
+        i += run;
+        if out.len() >= buffer.len() {
+            return None;
+        }
+    }
+    if literal < buffer.len() {
+        put_varint(&mut out, (buffer.len() - literal) << 1);
+        out.extend_from_slice(&buffer[literal..]);
+    }
+    Some(out).filter(|out| out.len() < buffer.len())
+}
+
+fn put_u64(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn put_offsets(out: &mut Vec<u8>, offsets: &[usize]) {
+    put_u64(out, offsets.len());
+    for offset in offsets {
+        put_u64(out, *offset);
+    }
+}
+
+/// Write `value` as a LEB128 varint, 7 bits per byte with the high bit set on all but the last.
+pub(crate) fn put_varint(out: &mut Vec<u8>, value: usize) {
+    let mut value = value as u64;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A simple cursor over the encoded bytes.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Read a single operation encoded with the given `version` of the format.
+    fn operation(&mut self, version: u8, block_size: usize) -> Result<Operation, ParseError> {
+        let tag = self.u8()?;
+        let (offset, len) = if version == 1 {
+            (self.usize()?, self.usize()?)
+        } else {
+            (self.varint()?, self.varint()?)
+        };
+        Ok(match tag {
+            INSERT_TAG => Operation::Insert {
+                offset,
+                buffer: self.bytes(len)?.to_vec(),
+            },
+            INSERT_RLE_TAG if version >= 4 => Operation::Insert {
+                offset,
+                buffer: self.rle(len)?,
+            },
+            REMOVE_TAG => Operation::Remove { offset, len },
+            REMOVE_BLOCKS_TAG if block_size != 0 => Operation::Remove {
+                offset,
+                len: len.checked_mul(block_size).ok_or(ParseError::Overflow)?,
+            },
+            COPY_TAG => Operation::Copy {
+                src_offset: offset,
+                len,
+            },
+            REPLACE_TAG if version >= 2 => Operation::Replace {
+                offset,
+                old_len: self.varint()?,
+                buffer: self.bytes(len)?.to_vec(),
+            },
+            tag => return Err(ParseError::UnknownOperation(tag)),
+        })
+    }
+
+    /// Read a run-length encoded buffer of `len` bytes, see [`rle`].
+    fn rle(&mut self, len: usize) -> Result<Vec<u8>, ParseError> {
+        let mut buffer = Vec::new();
+        while buffer.len() < len {
+            let piece = self.varint()?;
+            let count = piece >> 1;
+            // every piece has at least one byte, and they can't overflow the buffer.
+            if count == 0 || count > len - buffer.len() {
+                return Err(ParseError::Overflow);
+            }
+            if piece & 1 == 1 {
+                let byte = self.u8()?;
+                buffer.resize(buffer.len() + count, byte);
+            } else {
+                buffer.extend_from_slice(self.bytes(count)?);
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Check the magic header and the version byte, any version from 1 up to `version` is
+    /// accepted, returns the version.
+    fn header(&mut self, magic: &[u8; 4], version: u8) -> Result<u8, ParseError> {
+        if self.bytes(4)? != magic {
+            return Err(ParseError::BadMagic);
+        }
+        match self.u8()? {
+            v if (1..=version).contains(&v) => Ok(v),
+            v => Err(ParseError::UnsupportedVersion(v)),
+        }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.buf.len() < len {
+            return Err(ParseError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn offsets(&mut self) -> Result<Vec<usize>, ParseError> {
+        let count = self.usize()?;
+        let mut offsets = Vec::with_capacity(count.min(self.remaining() / 8));
+        for _ in 0..count {
+            offsets.push(self.usize()?);
+        }
+        Ok(offsets)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<usize, ParseError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                return Err(ParseError::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return value.try_into().map_err(|_| ParseError::Overflow);
+            }
+        }
+        Err(ParseError::Overflow)
+    }
+
+    fn usize(&mut self) -> Result<usize, ParseError> {
+        let bytes = self.bytes(8)?;
+        u64::from_le_bytes(bytes.try_into().unwrap())
+            .try_into()
+            .map_err(|_| ParseError::Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Signature;
+
+    #[test]
+    fn signature_roundtrip() {
+        let mut signature = Signature::with_block_size(4, "my name\nis shady\nkhalifa");
+        signature.calculate();
+        let indexed = signature.to_indexed();
+        let bytes = indexed.to_bytes();
+        assert_eq!(IndexedSignature::from_bytes(&bytes), Ok(indexed));
+
+        signature.calculate_lines();
+        let indexed = signature.to_indexed();
+        let bytes = indexed.to_bytes();
+        assert_eq!(IndexedSignature::from_bytes(&bytes), Ok(indexed));
+
+        let indexed = IndexedSignature::from_parts(&[b"my name", b"is shady"], 4);
+        let bytes = indexed.to_bytes();
+        assert_eq!(IndexedSignature::from_bytes(&bytes), Ok(indexed));
+    }
+
+    #[test]
+    fn salted_signature() {
+        let mut signature = Signature::with_block_size(4, "i saw a red fox");
+        signature.calculate();
+        let salted = signature.to_indexed().with_salt(*b"0123456789abcdef");
+        let bytes = salted.to_bytes();
+        assert!(bytes.ends_with(b"0123456789abcdef"));
+        let decoded = IndexedSignature::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.salt(), Some(b"0123456789abcdef"));
+        assert_eq!(decoded, salted);
+        // a salt of the wrong size.
+        let mut bad = signature.to_indexed().to_bytes();
+        bad.push(SALT_FIELD);
+        bad.extend_from_slice(&3u64.to_le_bytes());
+        bad.extend_from_slice(b"abc");
+        assert_eq!(
+            IndexedSignature::from_bytes(&bad),
+            Err(ParseError::UnknownField(SALT_FIELD))
+        );
+        // swapping the salt for another one, nothing matches anymore.
+        let len = bytes.len();
+        let mut swapped = bytes;
+        swapped[len - 16..].copy_from_slice(b"fedcba9876543210");
+        let mut delta = crate::Delta::new(IndexedSignature::from_bytes(&swapped).unwrap());
+        delta.diff("i saw a red fox").unwrap();
+        assert_eq!(crate::inserted_bytes(delta.operations()), 15);
+    }
+
+    #[test]
+    fn operations_roundtrip() {
+        let mut ops = crate::diff_with_block_size(
+            5,
+            "hello there, do you know rust?",
+            "hi, do you know about rustlang?",
+        )
+        .unwrap();
+        ops.push(Operation::Copy {
+            src_offset: 4,
+            len: 12,
+        });
+        ops.push(Operation::Replace {
+            offset: 40,
+            old_len: 3,
+            buffer: b"fox".to_vec(),
+        });
+        let bytes = encode_operations(&ops);
+        assert_eq!(decode_operations(&bytes), Ok(ops));
+    }
+
+    #[test]
+    fn operations_len() {
+        let ops = crate::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+        let bytes = encode_operations_with_len(&ops, 15);
+        assert_eq!(
+            decode_operations_with_len(&bytes),
+            Ok((ops.clone(), Some(15)))
+        );
+        assert_eq!(decode_operations(&bytes), Ok(ops.clone()));
+        let bytes = encode_operations(&ops);
+        assert_eq!(decode_operations_with_len(&bytes), Ok((ops.clone(), None)));
+        // version 2 had nothing after the operations.
+        let mut v2 = bytes;
+        v2[4] = 2;
+        assert_eq!(v2.pop(), Some(0));
+        assert_eq!(decode_operations_with_len(&v2), Ok((ops, None)));
+    }
+
+    #[test]
+    fn delta_writer() {
+        let mut ops = crate::diff_with_block_size(
+            5,
+            "hello there, do you know rust?",
+            "hi, do you know about rustlang?",
+        )
+        .unwrap();
+        ops.push(Operation::Copy {
+            src_offset: 4,
+            len: 12,
+        });
+        ops.push(Operation::Insert {
+            offset: 50,
+            buffer: vec![0; 100],
+        });
+        ops.push(Operation::Replace {
+            offset: 40,
+            old_len: 3,
+            buffer: b"fox".to_vec(),
+        });
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        for op in &ops {
+            writer.write_op(op).unwrap();
+        }
+        assert_eq!(writer.count(), ops.len());
+        let bytes = writer.finish().unwrap();
+        assert_eq!(decode_operations_stream(&bytes), Ok(ops.clone()));
+        let empty = DeltaWriter::new(Vec::new()).unwrap().finish().unwrap();
+        assert_eq!(decode_operations_stream(&empty), Ok(vec![]));
+
+        // every operation is written as soon as it comes.
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        let mut written = writer.writer.len();
+        for op in &ops {
+            writer.write_op(op).unwrap();
+            assert!(writer.writer.len() > written);
+            written = writer.writer.len();
+        }
+
+        // any change is caught.
+        for at in 5..bytes.len() {
+            let mut bad = bytes.clone();
+            bad[at] ^= 0x20;
+            assert!(decode_operations_stream(&bad).is_err(), "{}", at);
+        }
+        let mut bad = bytes.clone();
+        bad.pop();
+        assert_eq!(
+            decode_operations_stream(&bad),
+            Err(ParseError::UnexpectedEof)
+        );
+        // not finished.
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        writer.write_op(&ops[0]).unwrap();
+        assert_eq!(
+            decode_operations_stream(&writer.writer),
+            Err(ParseError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode_operations_stream(&encode_operations(&ops)),
+            Err(ParseError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(decode_operations(b"RSD"), Err(ParseError::UnexpectedEof));
+        assert_eq!(decode_operations(b"RSDS\x01"), Err(ParseError::BadMagic));
+        assert_eq!(
+            decode_operations(b"RSDD\x09"),
+            Err(ParseError::UnsupportedVersion(9))
+        );
+        let ops = vec![Operation::Remove { offset: 0, len: 3 }];
+        let mut bytes = encode_operations(&ops);
+        bytes.pop();
+        assert_eq!(decode_operations(&bytes), Err(ParseError::UnexpectedEof));
+    }
+
+    #[test]
+    fn self_describing() {
+        let buffer = "my name\nis shady\nkhalifa, my name is shady";
+        let mut signatures = Vec::new();
+        for block_size in &[1, 4, 7, 64] {
+            let mut signature = Signature::with_block_size(*block_size, buffer);
+            signature.calculate();
+            signatures.push(signature.to_indexed());
+            signatures.push(signature.to_indexed().with_salt([7; 16]));
+            let mut strided = Signature::with_stride(*block_size, 2, buffer);
+            strided.calculate();
+            signatures.push(strided.to_indexed());
+            for kind in &HashKind::all()[1..] {
+                let mut signature =
+                    Signature::with_block_size(*block_size, buffer).with_hash(*kind);
+                signature.calculate();
+                signatures.push(signature.to_indexed());
+                signatures.push(signature.to_indexed().with_salt([7; 16]));
+            }
+            signatures.push(IndexedSignature::from_parts(
+                &[b"my name\n", b"is shady"],
+                *block_size,
+            ));
+        }
+        let mut lines = Signature::new(buffer);
+        lines.calculate_lines();
+        signatures.push(lines.to_indexed());
+        for signature in signatures {
+            let bytes = signature.to_bytes();
+            let (crypto, len) = match signature.hash_kind() {
+                HashKind::Blake2b => (BLAKE2B_CRYPTO_HASH, 32),
+                HashKind::Blake2s => (BLAKE2S_CRYPTO_HASH, 32),
+                HashKind::Blake2b128 => (BLAKE2B_CRYPTO_HASH, 16),
+                #[cfg(feature = "blake3")]
+                HashKind::Blake3 => (BLAKE3_CRYPTO_HASH, 32),
+            };
+            assert_eq!(&bytes[5..8], &[ROLLING_WEAK_HASH, crypto, len]);
+            let decoded = IndexedSignature::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, signature);
+            // a shorter hash makes a smaller signature.
+            if signature.hash_kind() == HashKind::Blake2b128 {
+                let mut blake2b = signature.clone();
+                blake2b.hash = HashKind::Blake2b;
+                assert!(bytes.len() < blake2b.to_bytes().len());
+            }
+            // nothing else is needed to diff against it.
+            let new = "my name is shady\nkhalifa";
+            let mut expected = crate::Delta::new(signature);
+            expected.diff(new).unwrap();
+            let mut delta = crate::Delta::new(decoded);
+            delta.diff(new).unwrap();
+            assert_eq!(delta.operations(), expected.operations());
+
+            // hashes we don't know.
+            for (at, value) in &[(5, 1), (6, 3), (7, 20)] {
+                let mut other = bytes.clone();
+                other[*at] = *value;
+                assert!(matches!(
+                    IndexedSignature::from_bytes(&other),
+                    Err(ParseError::UnsupportedHash { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn signature_versions() {
+        let mut signature = Signature::with_block_size(4, "my name\nis shady\nkhalifa");
+        signature.calculate();
+        let indexed = signature.to_indexed();
+        let v3 = indexed.to_bytes();
+        assert_eq!(v3[4], 3);
+
+        // version 2 is the same without the hashes.
+        let mut v2 = v3.clone();
+        v2[4] = 2;
+        v2.drain(5..8);
+        assert_eq!(IndexedSignature::from_bytes(&v2), Ok(indexed.clone()));
+
+        // version 1 is the same without the fields section.
+        let mut v1 = v2.clone();
+        v1[4] = 1;
+        assert_eq!(IndexedSignature::from_bytes(&v1), Ok(indexed.clone()));
+        // but anything after the blocks is not a field in version 1.
+        v1.extend_from_slice(&[0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(IndexedSignature::from_bytes(&v1), Ok(indexed.clone()));
+
+        // a signature from a newer encoder with an optional field we don't know.
+        let mut newer = v2.clone();
+        newer.push(0x01);
+        newer.extend_from_slice(&3u64.to_le_bytes());
+        newer.extend_from_slice(b"abc");
+        assert_eq!(IndexedSignature::from_bytes(&newer), Ok(indexed));
+
+        // and with a required one.
+        let mut newer = v2.clone();
+        newer.push(REQUIRED_FIELD | 0x7f);
+        newer.extend_from_slice(&0u64.to_le_bytes());
+        assert_eq!(
+            IndexedSignature::from_bytes(&newer),
+            Err(ParseError::UnknownField(0xff))
+        );
+
+        // a truncated field.
+        let mut truncated = v2.clone();
+        truncated.push(0x01);
+        truncated.extend_from_slice(&8u64.to_le_bytes());
+        assert_eq!(
+            IndexedSignature::from_bytes(&truncated),
+            Err(ParseError::UnexpectedEof)
+        );
+
+        let mut future = v3;
+        future[4] = 4;
+        assert_eq!(
+            IndexedSignature::from_bytes(&future),
+            Err(ParseError::UnsupportedVersion(4))
+        );
+    }
+
+    #[test]
+    fn huge_block_count() {
+        let mut signature = Signature::with_block_size(4, "i saw a red fox");
+        signature.calculate();
+        let mut bytes = signature.to_indexed().to_bytes();
+        // the count of blocks comes right after the fixed chunking mode.
+        bytes[25..33].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            IndexedSignature::from_bytes(&bytes),
+            Err(ParseError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn zero_block_size() {
+        let mut signature = Signature::with_block_size(4, "i saw a red fox");
+        signature.calculate();
+        let mut bytes = signature.to_indexed().to_bytes();
+        bytes[16..24].copy_from_slice(&0u64.to_le_bytes());
+        assert_eq!(
+            IndexedSignature::from_bytes(&bytes),
+            Err(ParseError::BadBlockSize)
+        );
+
+        let mut strided = Signature::with_stride(4, 2, "i saw a red fox");
+        strided.calculate();
+        let mut bytes = strided.to_indexed().to_bytes();
+        bytes[25..33].copy_from_slice(&0u64.to_le_bytes());
+        assert_eq!(
+            IndexedSignature::from_bytes(&bytes),
+            Err(ParseError::BadBlockSize)
+        );
+    }
+
+    #[test]
+    fn bad_offsets() {
+        let buffer = "my name\nis shady\nkhalifa";
+        let mut signature = Signature::new(buffer);
+        signature.calculate_lines();
+        let lines = signature.to_indexed().to_bytes();
+        let parts = IndexedSignature::from_parts(&[b"my name\n", b"", b"is shady"], 4).to_bytes();
+        assert!(IndexedSignature::from_bytes(&parts).is_ok());
+        // the offsets come after the chunking mode and their count, the second one is changed.
+        let bad = |bytes: &[u8], offset: u64| {
+            let mut bytes = bytes.to_vec();
+            bytes[41..49].copy_from_slice(&offset.to_le_bytes());
+            IndexedSignature::from_bytes(&bytes)
+        };
+        // a line past the end of the buffer, or starting with the one before it.
+        assert_eq!(bad(&lines, 100), Err(ParseError::BadOffsets));
+        assert_eq!(
+            bad(&lines, buffer.len() as u64),
+            Err(ParseError::BadOffsets)
+        );
+        assert_eq!(bad(&lines, 0), Err(ParseError::BadOffsets));
+        // a part past the end of the buffer, or starting after the one after it.
+        assert_eq!(bad(&parts, 100), Err(ParseError::BadOffsets));
+        assert_eq!(bad(&parts, 9), Err(ParseError::BadOffsets));
+    }
+
+    #[test]
+    fn aligned_removes() {
+        let ops = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"hi".to_vec(),
+            },
+            Operation::Remove {
+                offset: 2,
+                len: 1024,
+            },
+            Operation::Remove {
+                offset: 300,
+                len: 2048,
+            },
+            // not aligned.
+            Operation::Remove {
+                offset: 600,
+                len: 1000,
+            },
+        ];
+        let aligned = encode_operations_aligned(&ops, 512);
+        let unaligned = encode_operations(&ops);
+        assert!(aligned.len() < unaligned.len());
+        assert_eq!(decode_operations(&aligned), Ok(ops.clone()));
+        assert_eq!(decode_operations(&unaligned), Ok(ops));
+
+        // a remove in blocks without a block size.
+        let mut bytes = b"RSDD\x02\x00\x01".to_vec();
+        bytes.extend_from_slice(&[REMOVE_BLOCKS_TAG, 0, 1]);
+        assert_eq!(
+            decode_operations(&bytes),
+            Err(ParseError::UnknownOperation(REMOVE_BLOCKS_TAG))
+        );
+    }
+
+    #[test]
+    fn rle_inserts() {
+        let padded = [&b"header"[..], &[0; 4096], b"body", &[0xff; 100], b"end"].concat();
+        let random: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let buffers: &[&[u8]] = &[
+            &padded,
+            &random,
+            &[7; 8],
+            &[7; 7],
+            &[0; 1 << 20],
+            b"aaaaaaaabbbbbbbbcccccccc",
+            b"",
+        ];
+        for buffer in buffers {
+            let ops = vec![
+                Operation::Insert {
+                    offset: 3,
+                    buffer: buffer.to_vec(),
+                },
+                Operation::Remove { offset: 5, len: 2 },
+            ];
+            let bytes = encode_operations(&ops);
+            assert_eq!(decode_operations(&bytes), Ok(ops));
+            // never bigger than the plain buffer.
+            assert!(bytes.len() <= buffer.len() + 16, "{}", bytes.len());
+        }
+        let ops = vec![Operation::Insert {
+            offset: 0,
+            buffer: padded.clone(),
+        }];
+        assert!(encode_operations(&ops).len() < 64);
+        // nothing to gain, so it is a plain insert.
+        let ops = vec![Operation::Insert {
+            offset: 0,
+            buffer: random.clone(),
+        }];
+        assert_eq!(encode_operations(&ops)[7], INSERT_TAG);
+
+        // a run longer than the buffer.
+        let mut bytes = b"RSDD\x04\x00\x01".to_vec();
+        bytes.extend_from_slice(&[INSERT_RLE_TAG, 0, 4, 5 << 1 | 1, b'a', 0]);
+        assert_eq!(decode_operations(&bytes), Err(ParseError::Overflow));
+        // and an empty piece.
+        let mut bytes = b"RSDD\x04\x00\x01".to_vec();
+        bytes.extend_from_slice(&[INSERT_RLE_TAG, 0, 4, 0, 0]);
+        assert_eq!(decode_operations(&bytes), Err(ParseError::Overflow));
+        // version 3 had no run-length encoded inserts.
+        let mut bytes = b"RSDD\x03\x00\x01".to_vec();
+        bytes.extend_from_slice(&[INSERT_RLE_TAG, 0, 4, 4 << 1 | 1, b'a', 0]);
+        assert_eq!(
+            decode_operations(&bytes),
+            Err(ParseError::UnknownOperation(INSERT_RLE_TAG))
+        );
+        let mut bytes = b"RSDD\x04\x00\x01".to_vec();
+        bytes.extend_from_slice(&[INSERT_RLE_TAG, 0, 4, 4 << 1 | 1, b'a', 0]);
+        assert_eq!(
+            decode_operations(&bytes),
+            Ok(vec![Operation::Insert {
+                offset: 0,
+                buffer: b"aaaa".to_vec()
+            }])
+        );
+    }
+
+    #[test]
+    fn operations_v1() {
+        let mut bytes = b"RSDD\x01".to_vec();
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.push(INSERT_TAG);
+        bytes.extend_from_slice(&12u64.to_le_bytes());
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(b"box");
+        bytes.push(REMOVE_TAG);
+        bytes.extend_from_slice(&15u64.to_le_bytes());
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        assert_eq!(
+            decode_operations(&bytes),
+            Ok(crate::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap())
+        );
+    }
+
+    #[test]
+    fn varint() {
+        for value in &[0, 1, 127, 128, 300, u32::MAX as usize, usize::MAX] {
+            let mut out = Vec::new();
+            put_varint(&mut out, *value);
+            let mut reader = Reader::new(&out);
+            assert_eq!(reader.varint(), Ok(*value));
+            assert_eq!(reader.remaining(), 0);
+        }
+        assert_eq!(Reader::new(&[0xff; 11]).varint(), Err(ParseError::Overflow));
+        assert_eq!(
+            Reader::new(&[0x80]).varint(),
+            Err(ParseError::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Diffing many buffers without allocating the same scratch buffers over and over.
+use std::mem;
+
+use crate::delta::{check_signature, Delta, DiffError, Operation};
+use crate::differ::{Differ, Event, Scratch};
+use crate::hash::{Chunking, IndexedSignature};
+use crate::observer::NoopObserver;
+
+/// A reusable diff, it keeps the scratch buffers of the diff loop (the bytes that did not match
+/// yet and the pending operations) between calls, so they only grow once.
+///
+/// the operations are the same as the ones of [`Delta::diff`], handy for a server that diffs
+/// thousands of buffers.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{DiffEngine, Operation, Signature};
+///
+/// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+/// signature.calculate();
+/// let signature = signature.to_indexed();
+/// let mut engine = DiffEngine::new();
+/// for new in &["i saw a red box", "i saw a red fox!"] {
+///     let ops = engine.diff(&signature, new).unwrap();
+///     assert!(!ops.is_empty());
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct DiffEngine {
+    scratch: Scratch,
+}
+
+impl DiffEngine {
+    /// Create a new engine, the scratch buffers are allocated by the first diff.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calculate the operations to get from the original buffer of the `signature` to `new`.
+    ///
+    /// only signatures of fixed or strided blocks use the scratch buffers, the others are
+    /// diffed the same as [`Delta::diff`], and it fails the same way.
+    pub fn diff(
+        &mut self,
+        signature: &IndexedSignature,
+        new: impl AsRef<[u8]>,
+    ) -> Result<Vec<Operation>, DiffError> {
+        let new = new.as_ref();
+        check_signature(signature)?;
+        if let Chunking::Lines(_) | Chunking::Parts(_) = signature.chunking {
+            let mut delta = Delta::new(signature.clone());
+            delta.diff(new)?;
+            return Ok(delta.into_operations());
+        }
+        let mut differ =
+            Differ::new(new, signature.block_size)?.with_scratch(mem::take(&mut self.scratch));
+        let mut ops = Vec::new();
+        let mut source = signature;
+        let result = loop {
+            match differ.next_event(&mut source, &mut NoopObserver) {
+                Ok(Some(Event::Op(op))) => ops.push(op),
+                Ok(Some(Event::Match(_))) => {}
+                Ok(None) => break Ok(ops),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        // keep the scratch buffers even if the diff failed.
+        self.scratch = differ.into_scratch();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Signature;
+
+    #[test]
+    fn same_as_delta() {
+        let original = "the quick brown fox jumps over the lazy dog\nand runs away\n";
+        let news = [
+            "the quick red fox jumps over the lazy cat\nand runs away\n",
+            "",
+            "and runs away\nthe quick brown fox jumps over the lazy dog\n",
+            original,
+            "a slow brown fox\nwalks over the lazy dog\n",
+        ];
+        let mut engine = DiffEngine::new();
+        for block_size in 1..8 {
+            let mut signature = Signature::with_block_size(block_size, original);
+            signature.calculate();
+            let fixed = signature.to_indexed();
+            signature.calculate_lines();
+            let lines = signature.to_indexed();
+            for signature in &[fixed, lines] {
+                // the same engine over and over.
+                for new in &news {
+                    let mut delta = Delta::new(signature.clone());
+                    delta.diff(new).unwrap();
+                    assert_eq!(engine.diff(signature, new).unwrap(), delta.operations());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bad_signature() {
+        let mut engine = DiffEngine::new();
+        let mut signature = Signature::with_block_size(4, "i saw a red fox");
+        signature.calculate();
+        let mut zero = signature.to_indexed();
+        zero.block_size = 0;
+        assert!(matches!(
+            engine.diff(&zero, "i saw a red box"),
+            Err(DiffError::InvalidBlockSize(0))
+        ));
+        let empty = Signature::with_block_size(4, "i saw a red fox").to_indexed();
+        assert!(matches!(
+            engine.diff(&empty, "i saw a red box"),
+            Err(DiffError::EmptySignature)
+        ));
+        // still usable afterwards.
+        assert!(engine
+            .diff(&signature.to_indexed(), "i saw a red box")
+            .is_ok());
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_engine() {
+        use std::time::Instant;
+        let original: Vec<u8> = (0..1 << 16).map(|i| (i % 251) as u8).collect();
+        let mut signature = Signature::with_block_size(64, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+        // small edits every few blocks, so there are many inserts.
+        let news: Vec<Vec<u8>> = (0..64)
+            .map(|seed| {
+                let mut new = original.clone();
+                for i in (seed..new.len()).step_by(300) {
+                    new[i] ^= 0xff;
+                }
+                new
+            })
+            .collect();
+
+        let start = Instant::now();
+        for new in &news {
+            let mut delta = Delta::new(signature.clone());
+            delta.diff(new).unwrap();
+        }
+        println!("delta per call: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mut engine = DiffEngine::new();
+        for new in &news {
+            engine.diff(&signature, new).unwrap();
+        }
+        println!("reused engine: {:?}", start.elapsed());
+    }
+}
@@ -1,14 +1,30 @@
 //! Rolling hash and Crypto hash.
 //!
+use std::array::TryFromSliceError;
+use std::cmp;
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::ops::Deref;
+use std::io::{self, Read};
+use std::mem;
+use std::ops::{Deref, Range};
 
-use blake2::{Blake2b, Digest};
+use blake2::{Blake2b, Blake2s, Digest};
+
+use crate::observer::{NoopObserver, Observer, ObserverEvent};
+use crate::window::hash_frame;
+
+/// Tells the salted crypto hashes apart from any other keyed [`Blake2b`] hash.
+const SALT_PERSONA: &[u8] = b"rsdiff salt";
+
+/// How many places of the new buffer [`IndexedSignature::estimate_delta_size`] looks at.
+const ESTIMATE_SAMPLES: usize = 64;
+
+/// About how many bytes an encoded operation costs besides its data.
+const ESTIMATE_OP_OVERHEAD: usize = 4;
 
 /// An Adler-32 checksum modification with rolling operation.
 /// it is not the same algorithm as Adler-32, but acts similarly.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct RollingHasher {
     a: u32,
     b: u32,
@@ -69,6 +85,19 @@ impl RollingHasher {
         self.count -= 1;
     }
 
+    /// Export the internal state of the hasher as `(a, b, count)`.
+    ///
+    /// this could be used to checkpoint a long running diff and later resume it using
+    /// [`RollingHasher::from_state`].
+    pub const fn state(&self) -> (u32, u32, usize) {
+        (self.a, self.b, self.count)
+    }
+
+    /// Restore a hasher from a state previously exported by [`RollingHasher::state`].
+    pub const fn from_state(a: u32, b: u32, count: usize) -> Self {
+        Self { a, b, count }
+    }
+
     /// Reset hasher instance to its initial state.
     pub fn reset(&mut self) {
         self.a = 0;
@@ -84,6 +113,89 @@ impl Default for RollingHasher {
     }
 }
 
+/// The largest prime smaller than 2^16, the modulus of Adler-32.
+const ADLER32_MOD: u32 = 65521;
+
+/// The real Adler-32 checksum with rolling operation, as described in RFC 1950.
+///
+/// the diff uses [`RollingHasher`], this one is for when the standard checksum is needed.
+///
+/// ### Example
+/// ```
+/// use rsdiff::Adler32RollingHasher;
+///
+/// let mut hasher = Adler32RollingHasher::new();
+/// hasher.update("Wikipedia");
+/// assert_eq!(hasher.digest(), 0x11E6_0398);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Adler32RollingHasher {
+    a: u32,
+    b: u32,
+    count: usize,
+}
+
+impl Adler32RollingHasher {
+    /// Create a new `Adler32RollingHasher`, `a` starts at one as in Adler-32.
+    pub const fn new() -> Self {
+        Self {
+            a: 1,
+            b: 0,
+            count: 0,
+        }
+    }
+
+    /// return the current checksum digest calculated so far.
+    #[inline]
+    pub const fn digest(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+
+    /// returns how many bytes we rolled in so far.
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Adds `bytes` to the checksum and update the internal calculated checksum.
+    pub fn update(&mut self, bytes: impl AsRef<[u8]>) {
+        for b in bytes.as_ref() {
+            self.insert(*b);
+        }
+    }
+
+    /// Rolling in a `byte`.
+    #[inline(always)]
+    pub fn insert(&mut self, byte: u8) {
+        self.a = (self.a + byte as u32) % ADLER32_MOD;
+        self.b = (self.b + self.a) % ADLER32_MOD;
+        self.count += 1;
+    }
+
+    /// Rolling out a `byte`.
+    /// Removes the given `byte` that was fed to the algorithm `count` bytes ago.
+    pub fn remove(&mut self, byte: u8) {
+        let m = ADLER32_MOD as u64;
+        let byte = byte as u64;
+        // the byte was added to `b` once for every byte since, and so was the initial one of `a`.
+        let weight = (self.count as u64 % m) * byte % m;
+        self.a = ((self.a as u64 + m - byte) % m) as u32;
+        self.b = ((self.b as u64 + 2 * m - weight - 1) % m) as u32;
+        self.count -= 1;
+    }
+
+    /// Reset hasher instance to its initial state.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Adler32RollingHasher {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Convenience function to compute `hash` of the `bytes`.
 /// It will handle hasher creation, data feeding and finalization.
 pub fn weak_hash(bytes: impl AsRef<[u8]>) -> u32 {
@@ -92,19 +204,59 @@ pub fn weak_hash(bytes: impl AsRef<[u8]>) -> u32 {
     hasher.digest()
 }
 
-/// A [`Blake2b`] Crypto hash, with only the first 32 bytes of the result.
+/// A 256 bit crypto hash, the blocks of a [`Signature`] use the first 32 bytes of their
+/// [`Blake2b`] hash by default (see [`HashKind`]), but any other 256 bit hash (sha256, blake3)
+/// fits too, shorter hashes are padded with zeros.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct CryptoHash([u8; 32]);
 
 impl CryptoHash {
-    /// creates a new crypto hash from a given `hash`.
+    /// Calculate the crypto hash of a `block`, the same way the blocks of a [`Signature`] are
+    /// hashed with the default [`HashKind::Blake2b`].
+    pub fn calculate(block: impl AsRef<[u8]>) -> Self {
+        Self::from_digest(&hash_frame((block.as_ref(), &[]), &mut Blake2b::new()))
+    }
+
+    /// Create a crypto hash from the 32 bytes of a 256 bit `hash`, fails if it is not 32 bytes.
     ///
-    /// ### Panics
-    /// if the given `hash` bytes is not 32 bytes.
+    /// ### Example
+    /// ```
+    /// use rsdiff::CryptoHash;
     ///
-    /// for internal use only.
-    pub(crate) fn new(hash: &[u8]) -> Self {
-        Self(hash.try_into().expect("hash.len() >= 32 byte"))
+    /// assert!(CryptoHash::new(&[7; 32]).is_ok());
+    /// assert!(CryptoHash::new(&[7; 20]).is_err());
+    /// ```
+    pub fn new(hash: &[u8]) -> Result<Self, TryFromSliceError> {
+        hash.try_into().map(Self)
+    }
+
+    /// the first 32 bytes of a longer `digest`, like the 64 bytes of [`Blake2b`].
+    pub(crate) fn from_digest(digest: &[u8]) -> Self {
+        Self::truncated(digest, 32)
+    }
+
+    /// the first `len` bytes of the `digest`, the rest of the hash is zeros.
+    pub(crate) fn truncated(digest: &[u8], len: usize) -> Self {
+        let mut hash = [0; 32];
+        hash[..len].copy_from_slice(&digest[..len]);
+        Self(hash)
+    }
+
+    /// Compare two hashes in constant time, unlike `==` it does not stop at the first byte
+    /// that differs, so the time it takes tells nothing about the hashes.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        let diff = self
+            .0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        std::hint::black_box(diff) == 0
+    }
+}
+
+impl From<[u8; 32]> for CryptoHash {
+    fn from(hash: [u8; 32]) -> Self {
+        Self(hash)
     }
 }
 
@@ -115,6 +267,108 @@ impl Deref for CryptoHash {
     }
 }
 
+/// The algorithm of the crypto hashes of the blocks of a [`Signature`], see
+/// [`Signature::with_hash`].
+///
+/// it is kept in the [`IndexedSignature`] and encoded along with it, so [`crate::Delta`]
+/// always hashes the new buffer the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HashKind {
+    /// The first 32 bytes of Blake2b, the default.
+    #[default]
+    Blake2b,
+    /// Blake2s-256, faster than Blake2b on 32 bit targets.
+    Blake2s,
+    /// The first 16 bytes of Blake2b, for signatures that have to be small, the encoded
+    /// blocks are a third smaller but a collision is a lot more likely, don't use it if the
+    /// new buffer could be crafted to collide with the original one.
+    Blake2b128,
+    /// Blake3, a lot faster than Blake2 on CPUs with SIMD, behind the `blake3` feature.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashKind {
+    /// How many bytes of the hash are kept, the rest of the [`CryptoHash`] is zeros.
+    pub fn size(self) -> usize {
+        match self {
+            HashKind::Blake2b | HashKind::Blake2s => 32,
+            HashKind::Blake2b128 => 16,
+            #[cfg(feature = "blake3")]
+            HashKind::Blake3 => 32,
+        }
+    }
+
+    /// Every kind, the default first.
+    #[cfg(test)]
+    pub(crate) fn all() -> Vec<HashKind> {
+        vec![
+            HashKind::Blake2b,
+            HashKind::Blake2s,
+            HashKind::Blake2b128,
+            #[cfg(feature = "blake3")]
+            HashKind::Blake3,
+        ]
+    }
+}
+
+/// Calculates the crypto hashes of a [`HashKind`].
+pub(crate) enum CryptoHasher {
+    Blake2b(Blake2b),
+    Blake2s(Blake2s),
+    Blake2b128(Blake2b),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl CryptoHasher {
+    pub(crate) fn new(kind: HashKind) -> Self {
+        match kind {
+            HashKind::Blake2b => CryptoHasher::Blake2b(Blake2b::new()),
+            HashKind::Blake2s => CryptoHasher::Blake2s(Blake2s::new()),
+            HashKind::Blake2b128 => CryptoHasher::Blake2b128(Blake2b::new()),
+            #[cfg(feature = "blake3")]
+            HashKind::Blake3 => CryptoHasher::Blake3(Box::default()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            CryptoHasher::Blake2b(hasher) | CryptoHasher::Blake2b128(hasher) => {
+                hasher.update(bytes)
+            }
+            CryptoHasher::Blake2s(hasher) => hasher.update(bytes),
+            #[cfg(feature = "blake3")]
+            CryptoHasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    /// The hash of everything so far, the hasher is reset after that.
+    pub(crate) fn finalize_reset(&mut self) -> CryptoHash {
+        match self {
+            CryptoHasher::Blake2b(hasher) => CryptoHash::from_digest(&hasher.finalize_reset()),
+            CryptoHasher::Blake2s(hasher) => CryptoHash::from_digest(&hasher.finalize_reset()),
+            CryptoHasher::Blake2b128(hasher) => CryptoHash::truncated(&hasher.finalize_reset(), 16),
+            #[cfg(feature = "blake3")]
+            CryptoHasher::Blake3(hasher) => {
+                let hash = CryptoHash::from_digest(hasher.finalize().as_bytes());
+                hasher.reset();
+                hash
+            }
+        }
+    }
+
+    /// Same as [`hash_frame`], with the hash of this kind.
+    pub(crate) fn hash_frame(&mut self, frame: (&[u8], &[u8])) -> CryptoHash {
+        self.update(frame.0);
+        self.update(frame.1);
+        self.finalize_reset()
+    }
+}
+
 /// A Buffer Signature.
 ///
 /// This represents a signature of a given buffer that can be used to calculate any changes
@@ -134,25 +388,526 @@ pub struct Signature<B: AsRef<[u8]>> {
     ///
     /// used to be handed over to the [`IndexedSignature`].
     original_buffer_len: usize,
+    /// How the buffer is divided into blocks.
+    chunking: Chunking,
+    /// The crypto hash of the blocks.
+    hash: HashKind,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct BlockHash {
+/// The hashes of a block of the original buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockHash {
     pub(crate) weak_hash: u32,
     pub(crate) crypto_hash: CryptoHash,
 }
 
+impl BlockHash {
+    /// The rolling hash of the block, see [`weak_hash`].
+    pub fn weak_hash(&self) -> u32 {
+        self.weak_hash
+    }
+
+    /// The crypto hash of the block, see [`CryptoHash::calculate`].
+    pub fn crypto_hash(&self) -> &CryptoHash {
+        &self.crypto_hash
+    }
+
+    /// Calculate both hashes of the `block`, the `hasher` is reset after that.
+    pub(crate) fn calculate(hasher: &mut CryptoHasher, block: &[u8]) -> Self {
+        Self {
+            weak_hash: weak_hash(block),
+            crypto_hash: hasher.hash_frame((block, &[])),
+        }
+    }
+}
+
 /// A Small representation of the orignal [`Signature`].
 /// this only holds the Hash blocks calculated using [`Signature::calculate`].
 ///
 /// this signature can be serialized into any format and saved to local disk or sent over the
 /// network to be then used to calculate the diff between a given buffer and the orignal one
 /// without the need to have the original buffer itself.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::serialize::RawSignature"))]
 pub struct IndexedSignature {
     pub(crate) original_buffer_len: usize,
     pub(crate) block_size: usize,
-    pub(crate) blocks: HashMap<u32, (usize, BlockHash)>,
+    /// The blocks by their weak hash, blocks with the same weak hash are kept in the order of
+    /// their index.
+    pub(crate) blocks: HashMap<u32, Vec<(usize, BlockHash)>>,
+    pub(crate) chunking: Chunking,
+    /// The secret the hashes of the blocks are salted with, see [`IndexedSignature::with_salt`].
+    pub(crate) salt: Option<[u8; 16]>,
+    /// The crypto hash of the blocks, signatures from before it could be chosen are Blake2b.
+    pub(crate) hash: HashKind,
+}
+
+/// How a buffer is divided into blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Chunking {
+    /// Blocks of `block_size` bytes each, except maybe the last one.
+    Fixed,
+    /// Blocks of `block_size` bytes starting every `stride` bytes, they overlap if the stride
+    /// is smaller than the block size.
+    Strided(usize),
+    /// Every line is a block, holds the offset where each line starts in the buffer.
+    Lines(Vec<usize>),
+    /// The buffer is a concatenation of parts, holds the offset where each part starts, every
+    /// part is divided into blocks of `block_size` bytes on its own.
+    Parts(Vec<usize>),
+}
+
+impl Chunking {
+    /// The number of blocks a buffer of `len` bytes is divided into.
+    fn block_count(&self, len: usize, block_size: usize) -> usize {
+        match self {
+            Chunking::Fixed => len.div_ceil(block_size),
+            // the blocks near the end are shorter, so the tail of the buffer could still match.
+            Chunking::Strided(stride) => len.div_ceil(*stride),
+            Chunking::Lines(offsets) => offsets.len(),
+            Chunking::Parts(starts) => Self::parts(starts, len)
+                .map(|part| part.len().div_ceil(block_size))
+                .sum(),
+        }
+    }
+
+    /// The ranges of the parts of a buffer of `len` bytes that starts at `starts`.
+    fn parts(starts: &[usize], len: usize) -> impl Iterator<Item = Range<usize>> + '_ {
+        starts.iter().enumerate().map(move |(i, start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(len);
+            *start..end
+        })
+    }
+
+    /// Where the block at `idx` starts in a buffer of `len` bytes.
+    fn block_offset(&self, idx: usize, len: usize, block_size: usize) -> usize {
+        match self {
+            Chunking::Fixed => std::cmp::min(idx * block_size, len),
+            Chunking::Strided(stride) => std::cmp::min(idx * stride, len),
+            Chunking::Lines(offsets) => offsets.get(idx).copied().unwrap_or(len),
+            Chunking::Parts(starts) => {
+                let mut idx = idx;
+                for part in Self::parts(starts, len) {
+                    let count = part.len().div_ceil(block_size);
+                    if idx < count {
+                        return part.start + idx * block_size;
+                    }
+                    idx -= count;
+                }
+                len
+            }
+        }
+    }
+
+    /// The length of the block at `idx` in a buffer of `len` bytes.
+    fn block_len(&self, idx: usize, len: usize, block_size: usize) -> usize {
+        let offset = self.block_offset(idx, len, block_size);
+        match self {
+            Chunking::Lines(_) => self.block_offset(idx + 1, len, block_size) - offset,
+            // the last block of a part ends with the part.
+            Chunking::Parts(starts) => {
+                let end = starts
+                    .iter()
+                    .find(|start| **start > offset)
+                    .copied()
+                    .unwrap_or(len);
+                std::cmp::min(block_size, end - offset)
+            }
+            _ => std::cmp::min(block_size, len - offset),
+        }
+    }
+}
+
+impl IndexedSignature {
+    /// The number of blocks the original buffer was divided into.
+    pub(crate) fn block_count(&self) -> usize {
+        self.chunking
+            .block_count(self.original_buffer_len, self.block_size)
+    }
+
+    /// Where the block at `idx` starts in the original buffer.
+    ///
+    /// `idx` could be equal to the block count, in that case it returns the buffer length.
+    pub(crate) fn block_offset(&self, idx: usize) -> usize {
+        self.chunking
+            .block_offset(idx, self.original_buffer_len, self.block_size)
+    }
+
+    /// The length of the block at `idx` in the original buffer.
+    pub(crate) fn block_len(&self, idx: usize) -> usize {
+        self.chunking
+            .block_len(idx, self.original_buffer_len, self.block_size)
+    }
+
+    /// Where the block at `idx` is in the original buffer, returns [`None`] if there is no such
+    /// block.
+    ///
+    /// blocks don't have to be `block_size` bytes each, a line signature has a block per line
+    /// and the blocks of a signature made of parts end with their part, the indices of the
+    /// [`IndexedSignature::buckets`] map to their ranges here.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let mut signature = Signature::new("a\nbig\nfox\n");
+    /// signature.calculate_lines();
+    /// let signature = signature.to_indexed();
+    /// assert_eq!(signature.block_range(1), Some(2..6));
+    /// assert_eq!(signature.block_range(3), None);
+    /// ```
+    pub fn block_range(&self, idx: usize) -> Option<Range<usize>> {
+        if idx >= self.block_count() {
+            return None;
+        }
+        let offset = self.block_offset(idx);
+        Some(offset..offset + self.block_len(idx))
+    }
+
+    /// Salt the hashes of the blocks with a secret `salt`, like one per tenant of a sync
+    /// service, so the signature can't be used to find out if someone else has some content
+    /// without knowing their salt.
+    ///
+    /// the weak hashes are mixed with the salt and the crypto hashes are hashed again with a
+    /// [`Blake2b`] keyed with it, [`crate::Delta`] salts the hashes of the new buffer the same
+    /// way, so the diff is the same as without a salt. the salt is encoded along with the
+    /// signature.
+    ///
+    /// ### Panics
+    /// if the signature is already salted.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let plain = signature.to_indexed();
+    /// let salted = plain.clone().with_salt(*b"tenant 42 secret");
+    /// assert_ne!(salted, plain);
+    /// let mut delta = Delta::new(salted);
+    /// delta.diff("i saw a red box").unwrap();
+    /// assert_eq!(
+    ///     delta.operations(),
+    ///     rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap()
+    /// );
+    /// ```
+    pub fn with_salt(mut self, salt: [u8; 16]) -> Self {
+        assert!(self.salt.is_none(), "signature is already salted");
+        self.salt = Some(salt);
+        let blocks = mem::take(&mut self.blocks);
+        for (idx, block) in blocks.into_values().flatten() {
+            let block = self.salt_block(block);
+            self.blocks
+                .entry(block.weak_hash)
+                .or_default()
+                .push((idx, block));
+        }
+        for blocks in self.blocks.values_mut() {
+            blocks.sort_by_key(|(idx, _)| *idx);
+        }
+        self
+    }
+
+    /// The salt of the signature, if it is salted.
+    pub fn salt(&self) -> Option<&[u8; 16]> {
+        self.salt.as_ref()
+    }
+
+    /// The crypto hash of the blocks, see [`Signature::with_hash`].
+    pub fn hash_kind(&self) -> HashKind {
+        self.hash
+    }
+
+    /// The weak hash of a block as it is stored in this signature.
+    pub(crate) fn salt_weak_hash(&self, weak_hash: u32) -> u32 {
+        match &self.salt {
+            Some(salt) => {
+                let key = |i: usize| u32::from_le_bytes(salt[i..i + 4].try_into().unwrap());
+                // a bijection, so salting adds no collisions.
+                (weak_hash ^ key(0))
+                    .wrapping_mul(key(4) | 1)
+                    .rotate_left(key(8) % 32)
+                    ^ key(12)
+            }
+            None => weak_hash,
+        }
+    }
+
+    /// The crypto hash of a block as it is stored in this signature.
+    pub(crate) fn salt_crypto_hash(&self, crypto_hash: CryptoHash) -> CryptoHash {
+        match &self.salt {
+            Some(salt) => {
+                let mut blake2 = Blake2b::with_params(salt, &[], SALT_PERSONA);
+                let len = self.hash.size();
+                CryptoHash::truncated(&hash_frame((&crypto_hash[..len], &[]), &mut blake2), len)
+            }
+            None => crypto_hash,
+        }
+    }
+
+    /// Both hashes of a block as they are stored in this signature.
+    pub(crate) fn salt_block(&self, block: BlockHash) -> BlockHash {
+        BlockHash {
+            weak_hash: self.salt_weak_hash(block.weak_hash),
+            crypto_hash: self.salt_crypto_hash(block.crypto_hash),
+        }
+    }
+
+    /// How many blocks share their weak hash with at least one other block, identical blocks
+    /// included.
+    ///
+    /// when diffing, a window that hits such a weak hash costs a crypto hash and a comparison
+    /// against every block sharing it, a high count compared to the number of blocks means the
+    /// block size or the weak hash is a poor fit for the data.
+    pub fn weak_hash_collision_count(&self) -> usize {
+        self.blocks
+            .values()
+            .filter(|blocks| blocks.len() > 1)
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// The index of every block with the given `weak` and `strong` hashes, in order.
+    ///
+    /// a buffer that repeats itself has many blocks with the same content, all of them are
+    /// returned, not only the one [`crate::Delta`] matches against.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{weak_hash, CryptoHash, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "fox fox fox box ");
+    /// signature.calculate();
+    /// let signature = signature.to_indexed();
+    /// let all = signature.find_all(weak_hash("fox "), &CryptoHash::calculate("fox "));
+    /// assert_eq!(all, vec![0, 1, 2]);
+    /// ```
+    ///
+    /// the hashes are salted first if the signature is, see [`IndexedSignature::with_salt`].
+    pub fn find_all(&self, weak: u32, strong: &CryptoHash) -> Vec<usize> {
+        let strong = self.salt_crypto_hash(*strong);
+        self.blocks
+            .get(&self.salt_weak_hash(weak))
+            .into_iter()
+            .flatten()
+            .filter(|(_, block)| block.crypto_hash.ct_eq(&strong))
+            .map(|(idx, _)| *idx)
+            .collect()
+    }
+
+    /// Every weak hash of the blocks along with the blocks that share it (and their index), in
+    /// the order of their index, the weak hashes come in no particular order.
+    ///
+    /// a bucket with more than one block is a weak hash collision, see
+    /// [`IndexedSignature::weak_hash_collision_count`].
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let mut signature = Signature::with_block_size(4, "fox fox box ");
+    /// signature.calculate();
+    /// let signature = signature.to_indexed();
+    /// let mut sizes: Vec<_> = signature.buckets().map(|(_, blocks)| blocks.len()).collect();
+    /// sizes.sort();
+    /// assert_eq!(sizes, vec![1, 2]);
+    /// ```
+    pub fn buckets(&self) -> impl Iterator<Item = (u32, &[(usize, BlockHash)])> + '_ {
+        self.blocks
+            .iter()
+            .map(|(weak, blocks)| (*weak, blocks.as_slice()))
+    }
+
+    /// Create one signature of several `parts`, as if they were a single buffer made of all of
+    /// them one after the other.
+    ///
+    /// the parts are divided into blocks of `block_size` bytes on their own, so no block spans
+    /// two parts. diffing against such a signature is done using [`crate::Operation::Copy`]s so
+    /// the blocks can come from any part in any order, see [`IndexedSignature::locate`] for
+    /// finding which part a copy comes from.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, IndexedSignature, Operation};
+    ///
+    /// let parts: &[&[u8]] = &[b"first log file\n", b"second log file\n"];
+    /// let signature = IndexedSignature::from_parts(parts, 4);
+    /// let mut delta = Delta::new(signature.clone());
+    /// delta.diff("second log file\n").unwrap();
+    /// assert_eq!(delta.operations(), &[Operation::Copy { src_offset: 15, len: 16 }]);
+    /// assert_eq!(signature.locate(15), Some((1, 0)));
+    /// ```
+    pub fn from_parts(parts: &[&[u8]], block_size: usize) -> Self {
+        assert!(block_size != 0, "block size must be > 0");
+        let mut starts = Vec::with_capacity(parts.len());
+        let mut blocks = HashMap::new();
+        let mut hasher = CryptoHasher::new(HashKind::default());
+        let mut len = 0;
+        let mut idx = 0;
+        for part in parts {
+            starts.push(len);
+            len += part.len();
+            for block in part.chunks(block_size) {
+                let block = BlockHash::calculate(&mut hasher, block);
+                blocks
+                    .entry(block.weak_hash)
+                    .or_insert_with(Vec::new)
+                    .push((idx, block));
+                idx += 1;
+            }
+        }
+        Self {
+            original_buffer_len: len,
+            block_size,
+            blocks,
+            chunking: Chunking::Parts(starts),
+            salt: None,
+            hash: HashKind::default(),
+        }
+    }
+
+    /// Find where the byte at `offset` in the original buffer comes from, as
+    /// `(part_index, offset_in_part)`.
+    ///
+    /// the whole buffer is a single part, unless this signature was created using
+    /// [`IndexedSignature::from_parts`].
+    ///
+    /// returns [`None`] if the `offset` is past the end of the original buffer.
+    pub fn locate(&self, offset: usize) -> Option<(usize, usize)> {
+        if offset >= self.original_buffer_len {
+            return None;
+        }
+        match &self.chunking {
+            Chunking::Parts(starts) => {
+                // the last part that starts at or before the offset, skipping empty parts.
+                let part = starts.partition_point(|start| *start <= offset) - 1;
+                Some((part, offset - starts[part]))
+            }
+            _ => Some((0, offset)),
+        }
+    }
+
+    /// Check if the `buffer` is the same one this signature was calculated from.
+    ///
+    /// this calculates the signature of `buffer` with the same block size, chunking and salt,
+    /// and compares it with this one.
+    pub fn verify(&self, buffer: &[u8]) -> bool {
+        if buffer.len() != self.original_buffer_len {
+            return false;
+        }
+        let mut signature =
+            Signature::with_block_size(self.block_size, buffer).with_hash(self.hash);
+        match &self.chunking {
+            Chunking::Lines(_) => signature.calculate_lines(),
+            chunking => {
+                signature.chunking = chunking.clone();
+                signature.calculate();
+            }
+        }
+        let indexed = signature.to_indexed();
+        match self.salt {
+            Some(salt) => indexed.with_salt(salt) == *self,
+            None => indexed == *self,
+        }
+    }
+
+    /// Guess how many bytes the encoded delta between the original buffer and `new` would take,
+    /// without diffing them.
+    ///
+    /// only a few places spread over `new` are looked at, a place counts as matching if a block
+    /// starting anywhere in it has the weak hash of some block of the original, the rest of the
+    /// bytes are expected to be inserted. no crypto hash is calculated so it is cheap even for a
+    /// big `new`, but it is only a ballpark, use it to decide if a delta is worth it at all.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let original = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+    /// let mut signature = Signature::with_block_size(16, &original);
+    /// signature.calculate();
+    /// let signature = signature.to_indexed();
+    /// assert!(signature.estimate_delta_size(original.as_bytes()) < original.len() / 10);
+    /// ```
+    pub fn estimate_delta_size(&self, new: &[u8]) -> usize {
+        if new.is_empty() {
+            return ESTIMATE_OP_OVERHEAD;
+        }
+        let contains = |weak_hash| self.blocks.contains_key(&self.salt_weak_hash(weak_hash));
+        let samples = cmp::min(ESTIMATE_SAMPLES, new.len());
+        let mut missed = 0;
+        for i in 0..samples {
+            let start = i * new.len() / samples;
+            let hit = match &self.chunking {
+                // the line the place falls in.
+                Chunking::Lines(_) => {
+                    let line_start = new[..start]
+                        .iter()
+                        .rposition(|b| *b == b'\n')
+                        .map_or(0, |at| at + 1);
+                    let line_end = new[start..]
+                        .iter()
+                        .position(|b| *b == b'\n')
+                        .map_or(new.len(), |at| start + at + 1);
+                    contains(weak_hash(&new[line_start..line_end]))
+                }
+                _ => {
+                    let len = cmp::min(self.block_size, new.len());
+                    let start = cmp::min(start, new.len() - len);
+                    let end = cmp::min(start + self.block_size, new.len() - len + 1);
+                    let mut hasher = RollingHasher::new();
+                    hasher.update(&new[start..start + len]);
+                    let mut hit = contains(hasher.digest());
+                    for at in start + 1..end {
+                        if hit {
+                            break;
+                        }
+                        hasher.remove(new[at - 1]);
+                        hasher.insert(new[at + len - 1]);
+                        hit = contains(hasher.digest());
+                    }
+                    hit
+                }
+            };
+            if !hit {
+                missed += 1;
+            }
+        }
+        let inserted = new.len() * missed / samples;
+        inserted + (missed + 1) * ESTIMATE_OP_OVERHEAD
+    }
+}
+
+/// Options for creating a [`Signature`], see [`Signature::with_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignatureOptions {
+    /// The block size, if not set it depends on the buffer size, see [`Signature::new`].
+    pub block_size: Option<usize>,
+    /// The max number of blocks, the block size is raised until the blocks fit.
+    ///
+    /// this keeps the signature of a huge buffer small, but bigger blocks means coarser
+    /// matching, a small change makes a whole block (and so a bigger delta) different.
+    pub max_blocks: Option<usize>,
+}
+
+impl SignatureOptions {
+    /// The block size to use for a buffer of `len` bytes.
+    ///
+    /// ### Panics
+    /// if the `block_size` or `max_blocks` is set to zero.
+    pub fn block_size_for(&self, len: usize) -> usize {
+        assert!(self.block_size != Some(0), "block size must be > 0");
+        assert!(self.max_blocks != Some(0), "max blocks must be > 0");
+        let block_size = self.block_size.unwrap_or_else(|| calculate_block_size(len));
+        match self.max_blocks {
+            Some(max_blocks) if len.div_ceil(block_size) > max_blocks => len.div_ceil(max_blocks),
+            _ => block_size,
+        }
+    }
 }
 
 impl<B: AsRef<[u8]>> Signature<B> {
@@ -175,9 +930,119 @@ impl<B: AsRef<[u8]>> Signature<B> {
             blocks: Vec::with_capacity(buffer.as_ref().len() / block_size),
             original_buffer_len: buffer.as_ref().len(),
             buffer,
+            chunking: Chunking::Fixed,
+            hash: HashKind::default(),
         }
     }
 
+    /// Create a new Signature using the given `options`.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Signature, SignatureOptions};
+    ///
+    /// let options = SignatureOptions { max_blocks: Some(4), ..Default::default() };
+    /// let signature = Signature::with_options(options, "my name is shady khalifa");
+    /// assert_eq!(signature.block_size(), 6);
+    /// ```
+    pub fn with_options(options: SignatureOptions, buffer: B) -> Self {
+        let block_size = options.block_size_for(buffer.as_ref().len());
+        Self::with_block_size(block_size, buffer)
+    }
+
+    /// Create a new Signature with about `target_blocks` blocks, whatever the buffer size is.
+    ///
+    /// the block size is `len / target_blocks` rounded up, so there are never more blocks than
+    /// the target, and at most one less as long as the buffer is bigger than the target squared,
+    /// for smaller buffers the block sizes are too coarse to get that close.
+    pub fn with_block_count(target_blocks: usize, buffer: B) -> Self {
+        assert!(target_blocks != 0, "target blocks must be > 0");
+        let len = buffer.as_ref().len();
+        let block_size = cmp::max(len.div_ceil(target_blocks), 1);
+        Self::with_block_size(block_size, buffer)
+    }
+
+    /// Create a new Signature with blocks of `block_size` bytes starting every `stride` bytes.
+    ///
+    /// with a `stride` smaller than the `block_size` the blocks overlap, which makes it more
+    /// likely for a shifted region of the buffer to still match some block, at the cost of a
+    /// bigger signature.
+    pub fn with_stride(block_size: usize, stride: usize, buffer: B) -> Self {
+        assert!(stride != 0, "stride must be > 0");
+        let mut signature = Self::with_block_size(block_size, buffer);
+        signature.chunking = Chunking::Strided(stride);
+        signature
+    }
+
+    /// Create a new Signature for a buffer of `alignment` bytes records (like the 512 bytes
+    /// sectors of a disk image), every block starts at a multiple of `alignment`.
+    ///
+    /// the `block_size` is rounded up to a multiple of `alignment`, so a block never spans part
+    /// of a record, and a record that changed or moved doesn't spoil the blocks of the others.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let signature = Signature::with_alignment(500, 512, vec![0; 4096]);
+    /// assert_eq!(signature.block_size(), 512);
+    /// let signature = Signature::with_alignment(1000, 512, vec![0; 4096]);
+    /// assert_eq!(signature.block_size(), 1024);
+    /// ```
+    pub fn with_alignment(block_size: usize, alignment: usize, buffer: B) -> Self {
+        assert!(alignment != 0, "alignment must be > 0");
+        let block_size = cmp::max(block_size.div_ceil(alignment), 1) * alignment;
+        Self::with_block_size(block_size, buffer)
+    }
+
+    /// Create a new sparse Signature, only every `stride_blocks`th block of `block_size` bytes
+    /// is hashed.
+    ///
+    /// the signature is about `stride_blocks` times smaller, but the bytes between the sampled
+    /// blocks can't be matched, so they end up inserted in the delta, handy for huge buffers
+    /// where a full signature is too big to send around.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let buffer = "0123456789abcdef";
+    /// let mut full = Signature::with_block_size(4, buffer);
+    /// full.calculate();
+    /// let mut sampled = Signature::sampled(4, 2, buffer);
+    /// sampled.calculate();
+    /// assert!(sampled.to_indexed().to_bytes().len() < full.to_indexed().to_bytes().len());
+    /// ```
+    pub fn sampled(block_size: usize, stride_blocks: usize, buffer: B) -> Self {
+        assert!(stride_blocks != 0, "stride blocks must be > 0");
+        Self::with_stride(block_size, block_size * stride_blocks, buffer)
+    }
+
+    /// Use the `hash` for the crypto hashes of the blocks instead of the default Blake2b, it has
+    /// to be set before calculating the signature.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Delta, HashKind, Signature};
+    ///
+    /// let mut signature =
+    ///     Signature::with_block_size(4, "i saw a red fox").with_hash(HashKind::Blake2b128);
+    /// signature.calculate();
+    /// let indexed = signature.to_indexed();
+    /// assert_eq!(indexed.hash_kind(), HashKind::Blake2b128);
+    /// let mut delta = Delta::new(indexed);
+    /// delta.diff("i saw a red box").unwrap();
+    /// assert_eq!(
+    ///     delta.operations(),
+    ///     rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap()
+    /// );
+    /// ```
+    pub fn with_hash(mut self, hash: HashKind) -> Self {
+        assert!(self.blocks.is_empty(), "signature is already calculated");
+        self.hash = hash;
+        self
+    }
+
     /// get the block size used by this signature.
     pub fn block_size(&self) -> usize {
         self.block_size
@@ -188,19 +1053,76 @@ impl<B: AsRef<[u8]>> Signature<B> {
     /// this will divide the current buffer into small chunks each at least `block_size` of bytes.
     /// and then calculate for each block of them the crypto hash and the rolling hash.
     pub fn calculate(&mut self) {
-        let buf = &self.buffer;
-        let mut blake2 = Blake2b::new();
-        let chunks = buf.as_ref().chunks(self.block_size);
-        for chunk in chunks {
-            let weak_hash = weak_hash(&chunk);
-            blake2.update(&chunk);
-            let blake2_hash = blake2.finalize_reset();
-            let crypto_hash = CryptoHash::new(&blake2_hash[..32]);
-            self.blocks.push(BlockHash {
-                weak_hash,
-                crypto_hash,
-            });
+        self.calculate_observed(&mut NoopObserver)
+    }
+
+    /// Same as [`Signature::calculate`] but the `observer` sees every hashed block.
+    pub fn calculate_observed<O: Observer>(&mut self, observer: &mut O) {
+        let buf = self.buffer.as_ref();
+        let mut hasher = CryptoHasher::new(self.hash);
+        let block_count = self.chunking.block_count(buf.len(), self.block_size);
+        for idx in 0..block_count {
+            let offset = self.chunking.block_offset(idx, buf.len(), self.block_size);
+            let len = self.chunking.block_len(idx, buf.len(), self.block_size);
+            let chunk = &buf[offset..offset + len];
+            self.blocks.push(BlockHash::calculate(&mut hasher, chunk));
+            observer.observe(ObserverEvent::BlockHashed { index: idx, len });
+        }
+    }
+
+    /// Same as [`IndexedSignature::weak_hash_collision_count`], for the blocks calculated so
+    /// far.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let mut signature = Signature::with_block_size(4, "abcdabcdabcd1234");
+    /// signature.calculate();
+    /// assert_eq!(signature.weak_hash_collision_count(), 3);
+    /// assert_eq!(signature.to_indexed().weak_hash_collision_count(), 3);
+    /// ```
+    pub fn weak_hash_collision_count(&self) -> usize {
+        let mut counts: HashMap<u32, usize> = HashMap::with_capacity(self.blocks.len());
+        for block in &self.blocks {
+            *counts.entry(block.weak_hash).or_insert(0) += 1;
+        }
+        counts.values().filter(|count| **count > 1).sum()
+    }
+
+    /// Hash every block of the buffer again and check that the stored blocks match them.
+    ///
+    /// this is a debugging tool, it returns `false` if the signature was not calculated yet, was
+    /// calculated more than once, or if the hashing changed since it was calculated.
+    pub fn self_check(&self) -> bool {
+        let buf = self.buffer.as_ref();
+        let mut hasher = CryptoHasher::new(self.hash);
+        let block_count = self.chunking.block_count(buf.len(), self.block_size);
+        self.blocks.len() == block_count
+            && self.blocks.iter().enumerate().all(|(idx, block)| {
+                let offset = self.chunking.block_offset(idx, buf.len(), self.block_size);
+                let len = self.chunking.block_len(idx, buf.len(), self.block_size);
+                BlockHash::calculate(&mut hasher, &buf[offset..offset + len]) == *block
+            })
+    }
+
+    /// Calculate the signature for the current buffer using lines as blocks.
+    ///
+    /// this is useful for append-only buffers like logs, every line (including its `\n`) is
+    /// hashed on its own regardless of its length, so lines are matched as a whole no matter
+    /// where they end up in the new buffer. the `block_size` is ignored in this mode.
+    pub fn calculate_lines(&mut self) {
+        let buf = self.buffer.as_ref();
+        let mut hasher = CryptoHasher::new(self.hash);
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        self.blocks.clear();
+        for line in buf.split_inclusive(|b| *b == b'\n') {
+            offsets.push(offset);
+            offset += line.len();
+            self.blocks.push(BlockHash::calculate(&mut hasher, line));
         }
+        self.chunking = Chunking::Lines(offsets);
     }
 
     /// Convert the current Signature into the indexed one.
@@ -211,34 +1133,137 @@ impl<B: AsRef<[u8]>> Signature<B> {
     pub fn to_indexed(&self) -> IndexedSignature {
         let mut blocks = HashMap::with_capacity(self.blocks.len());
         for (i, block) in self.blocks.iter().enumerate() {
-            blocks.insert(block.weak_hash, (i, *block));
+            blocks
+                .entry(block.weak_hash)
+                .or_insert_with(Vec::new)
+                .push((i, *block));
         }
 
         IndexedSignature {
             block_size: self.block_size,
             blocks,
             original_buffer_len: self.original_buffer_len,
+            chunking: self.chunking.clone(),
+            salt: None,
+            hash: self.hash,
         }
     }
 }
 
-/// The recommended block_size is sqrt(original_buffer_len) with a 32 min size rounded
-/// down to a multiple of the 128 byte.
+impl Signature<Vec<u8>> {
+    /// Calculate the signature of everything the `reader` has, one block at a time, so only a
+    /// single block is in memory at once.
+    ///
+    /// the result is the same as [`Signature::calculate`] over the same bytes.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let signature = Signature::from_reader(4, &b"i saw a red fox"[..]).unwrap();
+    /// let mut expected = Signature::with_block_size(4, "i saw a red fox");
+    /// expected.calculate();
+    /// assert_eq!(signature, expected.to_indexed());
+    /// ```
+    pub fn from_reader<R: Read>(block_size: usize, mut reader: R) -> io::Result<IndexedSignature> {
+        let mut signature = Self::with_block_size(block_size, Vec::with_capacity(block_size));
+        let mut hasher = CryptoHasher::new(signature.hash);
+        loop {
+            signature.buffer.clear();
+            // `read_to_end` keeps reading after short reads, so only the last block is short.
+            let len = (&mut reader)
+                .take(block_size as u64)
+                .read_to_end(&mut signature.buffer)?;
+            if len == 0 {
+                break;
+            }
+            let block = BlockHash::calculate(&mut hasher, &signature.buffer);
+            signature.blocks.push(block);
+            signature.original_buffer_len += len;
+        }
+        Ok(signature.to_indexed())
+    }
+
+    /// Calculate the signature of a buffer stored as `segments`, the same as the one of their
+    /// concatenation, without copying them into one buffer.
+    ///
+    /// blocks that span two or more segments are hashed piece by piece.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::Signature;
+    ///
+    /// let signature = Signature::from_segments(4, &[b"i saw ", b"a red", b" fox"]);
+    /// let mut expected = Signature::with_block_size(4, "i saw a red fox");
+    /// expected.calculate();
+    /// assert_eq!(signature, expected.to_indexed());
+    /// ```
+    pub fn from_segments(block_size: usize, segments: &[&[u8]]) -> IndexedSignature {
+        let mut signature = Self::with_block_size(block_size, Vec::new());
+        signature.calculate_segments(segments);
+        signature.to_indexed()
+    }
+
+    /// Calculate the blocks of the concatenation of `segments` instead of the buffer, with the
+    /// chunking of the signature.
+    fn calculate_segments(&mut self, segments: &[&[u8]]) {
+        let mut starts = Vec::with_capacity(segments.len());
+        let mut len = 0;
+        for segment in segments {
+            starts.push(len);
+            len += segment.len();
+        }
+        self.original_buffer_len = len;
+        self.blocks.clear();
+        let mut crypto_hasher = CryptoHasher::new(self.hash);
+        let block_count = self.chunking.block_count(len, self.block_size);
+        for idx in 0..block_count {
+            let offset = self.chunking.block_offset(idx, len, self.block_size);
+            let end = offset + self.chunking.block_len(idx, len, self.block_size);
+            let mut hasher = RollingHasher::new();
+            // the last segment that starts at or before the block.
+            let first = starts
+                .partition_point(|start| *start <= offset)
+                .saturating_sub(1);
+            for (segment, start) in segments[first..].iter().zip(&starts[first..]) {
+                if *start >= end {
+                    break;
+                }
+                let piece =
+                    &segment[offset.saturating_sub(*start)..cmp::min(segment.len(), end - start)];
+                hasher.update(piece);
+                crypto_hasher.update(piece);
+            }
+            self.blocks.push(BlockHash {
+                weak_hash: hasher.digest(),
+                crypto_hash: crypto_hasher.finalize_reset(),
+            });
+        }
+    }
+}
+
+/// The recommended block_size is sqrt(original_buffer_len) rounded down to a multiple of the
+/// 128 byte, with a 32 min size.
 ///
 /// similar to the original one in `rsync` code.
 ///
+/// small buffers (up to 1KiB) use sqrt(original_buffer_len) as is, with a 4 min size, a 32 bytes
+/// block in a buffer of a few dozen bytes would never match anything.
+///
 /// see: https://github.com/librsync/librsync/blob/1fd391c50719773bed09ad23013cd920f7606c47/src/sumset.c#L138
 pub(crate) fn calculate_block_size(len: usize) -> usize {
+    let sqrt = (len as f64).sqrt() as usize;
     if len <= 32usize.pow(2) {
-        32
+        cmp::max(sqrt, 4)
     } else {
-        ((len as f64).sqrt()) as usize & !127
+        cmp::max(sqrt & !127, 32)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rng::{random_bytes, Rng};
 
     #[test]
     fn simple() {
@@ -265,6 +1290,57 @@ mod tests {
         assert_eq!(hasher.digest(), weak_hash("kohex"));
     }
 
+    #[test]
+    fn rolling_inverse() {
+        let bytes = random_bytes(0x2545F4914F6CDD1D, 2048);
+        for window in (1..=64).chain(vec![255, 1000]) {
+            // roll in then out, and out then in, both must end up as if the window was hashed
+            // from scratch.
+            let mut in_out = RollingHasher::new();
+            in_out.update(&bytes[..window]);
+            let mut out_in = in_out;
+            for start in 1..bytes.len() - window {
+                let head = bytes[start + window - 1];
+                let tail = bytes[start - 1];
+                in_out.insert(head);
+                in_out.remove(tail);
+                out_in.remove(tail);
+                out_in.insert(head);
+                let mut fresh = RollingHasher::new();
+                fresh.update(&bytes[start..start + window]);
+                assert_eq!(in_out, fresh, "window = {}, start = {}", window, start);
+                assert_eq!(out_in, fresh, "window = {}, start = {}", window, start);
+            }
+        }
+    }
+
+    #[test]
+    fn state_checkpoint() {
+        let mut hasher = RollingHasher::new();
+        hasher.update("shekohex");
+        let (a, b, count) = hasher.state();
+        hasher.remove(b's');
+        hasher.insert(b'!');
+
+        let mut restored = RollingHasher::from_state(a, b, count);
+        assert_eq!(restored.count(), 8);
+        restored.remove(b's');
+        restored.insert(b'!');
+        assert_eq!(restored, hasher);
+        assert_eq!(restored.digest(), weak_hash("hekohex!"));
+    }
+
+    #[test]
+    fn signature_clone() {
+        let mut signature = Signature::with_block_size(4, "my name is shady khalifa");
+        let mut cloned = signature.clone();
+        signature.calculate();
+        cloned.calculate();
+        assert_eq!(signature.to_indexed(), cloned.to_indexed());
+        // cloning after calculating keeps the blocks.
+        assert_eq!(signature.clone().to_indexed(), signature.to_indexed());
+    }
+
     #[test]
     fn signature() {
         let buf = b"my name is shady khalifa";
@@ -273,4 +1349,529 @@ mod tests {
         let indexed = signature.to_indexed();
         println!("{:#?}", indexed);
     }
+
+    #[test]
+    fn block_count() {
+        let buf = vec![7u8; 1 << 20];
+        for target in &[1, 3, 10, 100, 777, 1000] {
+            let mut signature = Signature::with_block_count(*target, &buf);
+            signature.calculate();
+            let count = signature.blocks.len();
+            assert!(count <= *target && count + 1 >= *target, "{} blocks", count);
+        }
+        let signature = Signature::with_block_count(10, b"");
+        assert_eq!(signature.block_size(), 1);
+    }
+
+    #[test]
+    fn dynamic_block_size() {
+        assert_eq!(calculate_block_size(0), 4);
+        assert_eq!(calculate_block_size(32), 5);
+        assert_eq!(calculate_block_size(1024), 32);
+        // used to be zero between 1KiB and 16KiB.
+        assert_eq!(calculate_block_size(1025), 32);
+        assert_eq!(calculate_block_size(16383), 32);
+        assert_eq!(calculate_block_size(16384), 128);
+        assert_eq!(calculate_block_size(1 << 20), 1024);
+        let mut signature = Signature::new(vec![0; 2000]);
+        signature.calculate();
+        assert_eq!(signature.block_size(), 32);
+    }
+
+    #[test]
+    fn crypto_hash_new() {
+        // blake2s is 256 bit.
+        let blake2s = blake2::Blake2s::digest(b"abc");
+        assert_eq!(*CryptoHash::new(&blake2s).unwrap(), blake2s[..]);
+        // sha256 of "abc".
+        let sha256 = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad,
+        ];
+        assert_eq!(CryptoHash::new(&sha256).unwrap(), CryptoHash::from(sha256));
+        // sha1 is too short, and the whole blake2b is too long.
+        assert!(CryptoHash::new(&[0; 20]).is_err());
+        assert!(CryptoHash::new(&Blake2b::digest(b"abc")).is_err());
+        assert!(CryptoHash::new(&[]).is_err());
+        assert_eq!(
+            CryptoHash::from_digest(&Blake2b::digest(b"abc")),
+            CryptoHash::calculate(b"abc")
+        );
+    }
+
+    #[test]
+    fn crypto_hash_ct_eq() {
+        let a = CryptoHash::from([7; 32]);
+        let mut bytes = [7; 32];
+        assert!(a.ct_eq(&CryptoHash::from(bytes)));
+        for i in &[0, 15, 31] {
+            bytes[*i] ^= 1;
+            let b = CryptoHash::from(bytes);
+            assert_eq!(a.ct_eq(&b), a == b);
+            assert!(!a.ct_eq(&b));
+            bytes[*i] ^= 1;
+        }
+    }
+
+    #[test]
+    fn adler32() {
+        let adler32 = |bytes: &[u8]| {
+            let mut hasher = Adler32RollingHasher::new();
+            hasher.update(bytes);
+            hasher.digest()
+        };
+        assert_eq!(adler32(b""), 0x0000_0001);
+        assert_eq!(adler32(b"a"), 0x0062_0062);
+        assert_eq!(adler32(b"abc"), 0x024d_0127);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // big enough to wrap around the modulus.
+        assert_eq!(adler32(&[0xff; 5552]), 0xf18f_9b8c);
+
+        let buf: Vec<u8> = (0..10_000u32).map(|i| (i * 131 % 256) as u8).collect();
+        let window = 16;
+        let mut hasher = Adler32RollingHasher::new();
+        hasher.update(&buf[..window]);
+        for i in window..buf.len() {
+            hasher.remove(buf[i - window]);
+            hasher.insert(buf[i]);
+            assert_eq!(hasher.digest(), adler32(&buf[i + 1 - window..=i]));
+        }
+    }
+
+    #[test]
+    fn parts() {
+        let parts: &[&[u8]] = &[b"hello", b"", b"world!", b"abc"];
+        let signature = IndexedSignature::from_parts(parts, 4);
+        assert_eq!(signature.original_buffer_len, 14);
+        assert_eq!(signature.block_count(), 5);
+        let blocks: Vec<_> = (0..5)
+            .map(|i| (signature.block_offset(i), signature.block_len(i)))
+            .collect();
+        assert_eq!(blocks, vec![(0, 4), (4, 1), (5, 4), (9, 2), (11, 3)]);
+        assert_eq!(signature.locate(0), Some((0, 0)));
+        assert_eq!(signature.locate(5), Some((2, 0)));
+        assert_eq!(signature.locate(10), Some((2, 5)));
+        assert_eq!(signature.locate(13), Some((3, 2)));
+        assert_eq!(signature.locate(14), None);
+        assert!(signature.verify(b"helloworld!abc"));
+        assert!(!signature.verify(b"hellowORld!abc"));
+        // shorter or longer than the parts.
+        assert!(!signature.verify(b"hello"));
+        assert!(!signature.verify(b"helloworld!abcd"));
+        let signature = IndexedSignature::from_parts(&[b"hello", b"world"], 4);
+        let mut patch = crate::Patch::new(vec![crate::Operation::Remove { offset: 0, len: 7 }]);
+        assert!(!patch.apply_verified("helloworld", &signature));
+    }
+
+    #[test]
+    fn self_check() {
+        let mut signature = Signature::with_block_size(4, "my name\nis shady\nkhalifa");
+        assert!(!signature.self_check());
+        signature.calculate();
+        assert!(signature.self_check());
+        signature.blocks[2].weak_hash ^= 1;
+        assert!(!signature.self_check());
+        signature.blocks[2].weak_hash ^= 1;
+        signature.blocks[3].crypto_hash = CryptoHash::from([0; 32]);
+        assert!(!signature.self_check());
+
+        signature.calculate_lines();
+        assert!(signature.self_check());
+        // calculated twice.
+        signature.calculate();
+        assert!(!signature.self_check());
+    }
+
+    #[test]
+    fn max_blocks() {
+        let options = SignatureOptions {
+            max_blocks: Some(10_000),
+            ..Default::default()
+        };
+        for len in &[0, 1, 1 << 20, 1 << 30, 10 << 30, usize::MAX / 2] {
+            let block_size = options.block_size_for(*len);
+            assert!(len.div_ceil(block_size) <= 10_000, "len = {}", len);
+            // never smaller than without the cap.
+            assert!(block_size >= calculate_block_size(*len));
+        }
+        let options = SignatureOptions {
+            block_size: Some(4),
+            max_blocks: Some(3),
+        };
+        let mut signature = Signature::with_options(options, "my name is shady khalifa");
+        signature.calculate();
+        assert!(signature.blocks.len() <= 3);
+        assert!(signature.self_check());
+    }
+
+    #[test]
+    #[should_panic(expected = "block size must be > 0")]
+    fn zero_block_size_option() {
+        let options = SignatureOptions {
+            block_size: Some(0),
+            max_blocks: Some(3),
+        };
+        options.block_size_for(16);
+    }
+
+    #[test]
+    #[should_panic(expected = "max blocks must be > 0")]
+    fn zero_max_blocks() {
+        let options = SignatureOptions {
+            block_size: None,
+            max_blocks: Some(0),
+        };
+        options.block_size_for(0);
+    }
+
+    #[test]
+    fn find_all() {
+        let block = "0123456789abcdef";
+        let buffer = format!("{0}xxxxxxxxxxxxxxxx{0}{0}yyyyyyyyyyyyyyyy", block);
+        let mut signature = Signature::with_block_size(16, &buffer);
+        signature.calculate();
+        let signature = signature.to_indexed();
+        let strong = CryptoHash::calculate(block);
+        assert_eq!(signature.find_all(weak_hash(block), &strong), vec![0, 2, 3]);
+        // the weak hash alone is not enough.
+        let other = CryptoHash::calculate("xxxxxxxxxxxxxxxx");
+        assert!(signature.find_all(weak_hash(block), &other).is_empty());
+        assert!(signature.find_all(weak_hash("nothing"), &strong).is_empty());
+        // the duplicates survive encoding.
+        let decoded = IndexedSignature::from_bytes(&signature.to_bytes()).unwrap();
+        assert_eq!(decoded.find_all(weak_hash(block), &strong), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn from_reader() {
+        use crate::window::tests::ChunkedReader;
+
+        let buffer: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        for len in &[0, 1, 7, 8, 9, buffer.len()] {
+            let buffer = &buffer.as_bytes()[..*len];
+            for block_size in &[1, 3, 8, 64] {
+                let mut expected = Signature::with_block_size(*block_size, buffer);
+                expected.calculate();
+                let reader = ChunkedReader::new(buffer);
+                let signature = Signature::from_reader(*block_size, reader).unwrap();
+                assert_eq!(signature, expected.to_indexed(), "{} {}", len, block_size);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_kinds() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = "the quick red fox jumps over the lazy cat";
+        let expected = crate::diff_with_block_size(4, original, new).unwrap();
+        for kind in &HashKind::all() {
+            let mut signature = Signature::with_block_size(4, original).with_hash(*kind);
+            signature.calculate();
+            assert!(signature.self_check());
+            let indexed = signature.to_indexed();
+            assert_eq!(indexed.hash_kind(), *kind);
+            assert!(indexed.verify(original.as_bytes()));
+            let (_, blocks) = indexed.buckets().next().unwrap();
+            let hash = blocks[0].1.crypto_hash();
+            assert!(hash[kind.size()..].iter().all(|b| *b == 0));
+            assert!(hash[..kind.size()].iter().any(|b| *b != 0));
+            // the new buffer is hashed the same way, salted or not.
+            for indexed in [indexed.clone(), indexed.with_salt([7; 16])] {
+                let mut delta = crate::Delta::new(indexed);
+                delta.diff(new).unwrap();
+                assert_eq!(delta.operations(), &expected[..], "{:?}", kind);
+            }
+        }
+        let mut signature = Signature::with_block_size(4, "abcd").with_hash(HashKind::Blake2s);
+        signature.calculate();
+        assert_eq!(
+            signature.to_indexed().find_all(
+                weak_hash("abcd"),
+                &CryptoHash::new(&Blake2s::digest(b"abcd")).unwrap()
+            ),
+            vec![0]
+        );
+        #[cfg(feature = "blake3")]
+        {
+            let mut signature = Signature::with_block_size(4, "abcd").with_hash(HashKind::Blake3);
+            signature.calculate();
+            let hash = CryptoHash::new(blake3::hash(b"abcd").as_bytes()).unwrap();
+            assert_eq!(
+                signature.to_indexed().find_all(weak_hash("abcd"), &hash),
+                vec![0]
+            );
+        }
+        assert_eq!(HashKind::default(), HashKind::Blake2b);
+    }
+
+    #[test]
+    fn vectored() {
+        let buffer = b"my name is shady khalifa, and this is rsdiff";
+        let expected = |mut signature: Signature<&[u8]>| {
+            signature.calculate();
+            signature.to_indexed()
+        };
+        // every way to split the buffer in three, some segments empty.
+        for a in 0..=buffer.len() {
+            for b in a..=buffer.len() {
+                let segments: &[&[u8]] = &[&buffer[..a], &buffer[a..b], &buffer[b..], b""];
+                for block_size in &[1, 3, 8, 64] {
+                    assert_eq!(
+                        Signature::from_segments(*block_size, segments),
+                        expected(Signature::with_block_size(*block_size, &buffer[..]))
+                    );
+                }
+                let mut strided = Signature::with_stride(8, 3, Vec::new());
+                strided.calculate_segments(segments);
+                assert_eq!(
+                    strided.to_indexed(),
+                    expected(Signature::with_stride(8, 3, &buffer[..]))
+                );
+            }
+        }
+        assert_eq!(Signature::from_segments(4, &[]).block_count(), 0);
+    }
+
+    #[test]
+    fn variable_blocks() {
+        let ranges = |signature: &IndexedSignature| {
+            (0..signature.block_count())
+                .map(|idx| signature.block_range(idx).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut signature = Signature::new("a\n\nbig line\nfox");
+        signature.calculate_lines();
+        let lines = signature.to_indexed();
+        assert_eq!(ranges(&lines), vec![0..2, 2..3, 3..12, 12..15]);
+        assert_eq!(lines.block_range(4), None);
+
+        // every part ends with a short block.
+        let parts: &[&[u8]] = &[b"abcdef", b"", b"ghi", b"jklmnopqr"];
+        let signature = IndexedSignature::from_parts(parts, 4);
+        assert_eq!(
+            ranges(&signature),
+            vec![0..4, 4..6, 6..9, 9..13, 13..17, 17..18]
+        );
+        // the hashes of the blocks are the ones of their ranges.
+        let original = parts.concat();
+        for (_, blocks) in signature.buckets() {
+            for (idx, block) in blocks {
+                let range = signature.block_range(*idx).unwrap();
+                assert_eq!(
+                    block.crypto_hash(),
+                    &CryptoHash::calculate(&original[range])
+                );
+            }
+        }
+        // and the copies come from there.
+        let mut delta = crate::Delta::new(signature);
+        delta.diff("nopqabcdghi").unwrap();
+        assert_eq!(
+            delta.operations(),
+            &[
+                crate::Operation::Copy {
+                    src_offset: 13,
+                    len: 4
+                },
+                crate::Operation::Copy {
+                    src_offset: 0,
+                    len: 4
+                },
+                crate::Operation::Copy {
+                    src_offset: 6,
+                    len: 3
+                },
+            ][..]
+        );
+    }
+
+    #[test]
+    fn finalize_reset() {
+        let buffer: Vec<u8> = (0..1000).map(|i| (i * 31 % 251) as u8).collect();
+        for block_size in &[1, 7, 64, 1000, 2000] {
+            // the signature hashes every block with the same hasher.
+            let mut signature = Signature::with_block_size(*block_size, &buffer);
+            signature.calculate();
+            let chunks: Vec<_> = buffer.chunks(*block_size).collect();
+            assert_eq!(signature.blocks.len(), chunks.len());
+            for (block, chunk) in signature.blocks.iter().zip(&chunks) {
+                // and the diff with a new one for every frame, in one or two pieces.
+                let (front, back) = chunk.split_at(chunk.len() / 2);
+                assert_eq!(
+                    block.crypto_hash,
+                    crate::differ::frame_crypto_hash(HashKind::Blake2b, (chunk, &[]))
+                );
+                assert_eq!(
+                    block.crypto_hash,
+                    crate::differ::frame_crypto_hash(HashKind::Blake2b, (front, back))
+                );
+                assert_eq!(block.crypto_hash, CryptoHash::calculate(chunk));
+            }
+        }
+        // a hasher left with some bytes in it is reset too.
+        let mut hasher = CryptoHasher::new(HashKind::Blake2b);
+        hasher.update(b"left over");
+        hasher.finalize_reset();
+        let block = BlockHash::calculate(&mut hasher, b"abcd");
+        assert_eq!(block.crypto_hash, CryptoHash::calculate(b"abcd"));
+        assert_eq!(BlockHash::calculate(&mut hasher, b"abcd"), block);
+    }
+
+    #[test]
+    fn salted() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = "the quick red fox jumps over the lazy cat";
+        let mut signature = Signature::with_block_size(4, original);
+        signature.calculate();
+        let plain = signature.to_indexed();
+        let a = plain.clone().with_salt(*b"tenant a secret!");
+        let b = plain.clone().with_salt(*b"tenant b secret!");
+        assert_eq!(a.salt(), Some(b"tenant a secret!"));
+        assert_eq!(plain.salt(), None);
+        // the buffer is hashed with the same salt to verify it.
+        assert!(a.verify(original.as_bytes()));
+        assert!(!a.verify(new.as_bytes()));
+        let mut patch = crate::Patch::new(crate::diff_with_block_size(4, new, original).unwrap());
+        assert!(patch.apply_verified(new, &a));
+        // no hash is shared between the salts.
+        let hashes = |signature: &IndexedSignature| {
+            signature
+                .buckets()
+                .flat_map(|(_, blocks)| blocks.iter().map(|(_, block)| *block))
+                .collect::<Vec<_>>()
+        };
+        for block in hashes(&a) {
+            for other in hashes(&b).into_iter().chain(hashes(&plain)) {
+                assert_ne!(block.weak_hash(), other.weak_hash());
+                assert_ne!(block.crypto_hash(), other.crypto_hash());
+            }
+        }
+        // the same blocks are found with the plain hashes.
+        let block = &original.as_bytes()[4..8];
+        let strong = CryptoHash::calculate(block);
+        assert_eq!(a.find_all(weak_hash(block), &strong), vec![1]);
+        assert_eq!(plain.find_all(weak_hash(block), &strong), vec![1]);
+
+        // the same diff with the matching salt.
+        let diff = |signature: &IndexedSignature| {
+            let mut delta = crate::Delta::new(signature.clone());
+            delta.diff(new).unwrap();
+            delta.into_operations()
+        };
+        assert_eq!(diff(&a), diff(&plain));
+        signature.calculate_lines();
+        let lines = signature.to_indexed();
+        assert_eq!(diff(&lines.clone().with_salt([1; 16])), diff(&lines));
+        let parts = IndexedSignature::from_parts(&[original.as_bytes()], 4);
+        assert_eq!(diff(&parts.clone().with_salt([1; 16])), diff(&parts));
+        // and nothing matches with another one.
+        let mut forged = a;
+        forged.salt = b.salt;
+        let ops = diff(&forged);
+        assert_eq!(crate::inserted_bytes(&ops), new.len());
+    }
+
+    #[test]
+    fn buckets() {
+        // "babe" has the same weak hash as "abcd".
+        let mut signature = Signature::with_block_size(4, "abcdwxyzbabe");
+        signature.calculate();
+        let signature = signature.to_indexed();
+        assert_eq!(signature.buckets().count(), 2);
+        let (weak, blocks) = signature
+            .buckets()
+            .find(|(_, blocks)| blocks.len() > 1)
+            .unwrap();
+        assert_eq!(weak, weak_hash("abcd"));
+        assert_eq!(
+            blocks.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(blocks[0].1.crypto_hash(), &CryptoHash::calculate("abcd"));
+        assert_eq!(blocks[1].1.crypto_hash(), &CryptoHash::calculate("babe"));
+        assert!(blocks.iter().all(|(_, block)| block.weak_hash() == weak));
+    }
+
+    #[test]
+    fn weak_hash_collisions() {
+        let repetitive = "0123456789abcdef".repeat(64);
+        let mut signature = Signature::with_block_size(16, &repetitive);
+        signature.calculate();
+        assert_eq!(signature.weak_hash_collision_count(), 64);
+        // a block size that doesn't divide the period still repeats every 16 blocks.
+        let mut signature = Signature::with_block_size(12, &repetitive);
+        signature.calculate();
+        assert!(signature.weak_hash_collision_count() > 64);
+        assert_eq!(
+            signature.to_indexed().weak_hash_collision_count(),
+            signature.weak_hash_collision_count()
+        );
+
+        // random, nothing repeats.
+        let random = random_bytes(0x9E3779B97F4A7C15, 1024);
+        let mut signature = Signature::with_block_size(16, &random);
+        signature.calculate();
+        assert_eq!(signature.weak_hash_collision_count(), 0);
+        assert_eq!(signature.to_indexed().weak_hash_collision_count(), 0);
+    }
+
+    #[test]
+    fn estimate_delta_size() {
+        let mut rng = Rng::new(0x2545F4914F6CDD1D);
+        let mut random = |len| rng.bytes(len);
+        let original = random(64 * 1024);
+        let mut signature = Signature::with_block_size(64, &original);
+        signature.calculate();
+        let signature = signature.to_indexed();
+        let actual = |new: &[u8]| {
+            let mut delta = crate::Delta::new(signature.clone());
+            delta.diff(new).unwrap();
+            crate::encode_operations(delta.operations()).len()
+        };
+
+        // a quarter of the buffer rewritten, at a few places.
+        let mut edited = original.clone();
+        for at in &[1000, 20_000, 40_000, 60_000] {
+            let len = 4 * 1024;
+            edited[*at..*at + len].copy_from_slice(&random(len));
+        }
+        // and shifted by a few bytes.
+        edited.splice(5000..5000, random(7));
+        let unrelated = random(original.len());
+        for &(new, (low, high)) in &[
+            (&original, (0, 1024)),
+            (&edited, (8 * 1024, 32 * 1024)),
+            (&unrelated, (56 * 1024, 80 * 1024)),
+        ] {
+            let estimate = signature.estimate_delta_size(new);
+            let actual = actual(new);
+            assert!(low <= actual && actual <= high, "{}", actual);
+            assert!(
+                low <= estimate && estimate <= high,
+                "{} vs {}",
+                estimate,
+                actual
+            );
+        }
+        assert!(signature.estimate_delta_size(&[]) < 16);
+        assert_eq!(
+            signature.estimate_delta_size(&edited),
+            signature
+                .clone()
+                .with_salt([7; 16])
+                .estimate_delta_size(&edited)
+        );
+
+        // lines.
+        let original = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let mut signature = Signature::new(&original);
+        signature.calculate_lines();
+        let signature = signature.to_indexed();
+        assert!(signature.estimate_delta_size(original.as_bytes()) < 1024);
+        let unrelated = original.replace("line", "LINE");
+        assert!(signature.estimate_delta_size(unrelated.as_bytes()) >= unrelated.len());
+    }
 }
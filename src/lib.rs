@@ -7,7 +7,10 @@
 //! signature of a given buffer and then calculating the delta between the original buffer and a
 //! modified one with onenly the signature.
 //!
-//! patching is implemented, but not ready yet!
+//! the operations are applied back on the original buffer using a [`Patch`].
+//!
+//! all the types are `Send + Sync` as long as the buffers they hold are, so a signature can be
+//! shared between threads and deltas can be calculated on any of them.
 //!
 //! here is a simple examples of how it works.
 //!
@@ -22,7 +25,7 @@
 //! // now we will make a small edit to it.
 //! let new = "i saw a red box";
 //! // in this example we tell it to use 4 bytes as a block size.
-//! let ops = rsdiff::diff_with_block_size(4, original, new);
+//! let ops = rsdiff::diff_with_block_size(4, original, new).unwrap();
 //! for op in &ops {
 //!  println!("{}", op);
 //! }
@@ -49,7 +52,7 @@
 //!
 //! let original = "hello there, do you know rust?";
 //! let new = "hi, do you know about rustlang?";
-//! let ops = rsdiff::diff_with_block_size(5, original, new);
+//! let ops = rsdiff::diff_with_block_size(5, original, new).unwrap();
 //!
 //! assert_eq!(
 //!     ops,
@@ -108,69 +111,183 @@
 //! ```
 //!
 
+mod change;
 mod delta;
+mod differ;
+mod encoding;
+mod engine;
 mod hash;
+#[cfg(all(test, feature = "librsync-bench"))]
+mod librsync;
+mod manifest;
+mod observer;
+mod operations;
+mod patch;
+/// The messages of `proto/rsdiff.proto` generated by prost, they convert from and to our types
+/// with `From` and `TryFrom`, see [`IndexedSignature::to_protobuf`] and
+/// [`encode_operations_protobuf`] to go straight to bytes.
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+#[cfg(test)]
+mod quality;
+mod report;
+#[cfg(test)]
+mod rng;
+mod script;
+#[cfg(feature = "serde")]
+mod serialize;
+mod streaming;
+mod vcdiff;
 mod window;
 
-#[doc(hidden)]
-#[allow(dead_code)]
-mod patch; // not ready yet.
+pub use change::{Change, ChangeKind};
+#[cfg(feature = "bytes")]
+pub use delta::BytesOperation;
+pub use delta::{
+    ByteLen, Delta, DiffError, DiffOptions, DiffReport, Offset, Operation, SequencedOperation,
+};
+pub use encoding::{
+    decode_operations, decode_operations_stream, decode_operations_with_len, encode_operations,
+    encode_operations_aligned, encode_operations_with_len, DeltaWriter, ParseError,
+};
+pub use engine::DiffEngine;
+pub use hash::{
+    weak_hash, Adler32RollingHasher, BlockHash, CryptoHash, HashKind, IndexedSignature,
+    RollingHasher, Signature, SignatureOptions,
+};
+pub use manifest::{Manifest, ManifestEntry};
+pub use observer::{NoopObserver, Observer, ObserverEvent};
+pub use operations::{
+    coalesce_replacements, compact_noop_churn, compose, flatten, inserted_bytes, merge3,
+    removed_bytes, snapshot_operations, to_new_offsets, to_original_offsets, Conflict,
+};
+pub use patch::{apply_chunked, Patch, PatchError};
+#[cfg(feature = "protobuf")]
+pub use protobuf::{decode_operations_protobuf, encode_operations_protobuf};
+pub use report::{compression_report, estimate_block_size, Report};
+pub use script::{from_ed_script, to_ed_script};
+pub use streaming::StreamingDiff;
+pub use vcdiff::encode_vcdiff;
 
-pub use delta::{Delta, Operation};
-pub use hash::{IndexedSignature, RollingHasher, Signature};
+use std::cmp;
 
 /// Convenience function to compute [`Delta`] between two buffers.
 /// it will handle the creation of the [`Signature`] and the [`Delta`].
 ///
 /// returns the total operations needed to upgrade `a` to `b`.
-pub fn diff(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> Vec<Operation> {
+pub fn diff(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> Result<Vec<Operation>, DiffError> {
     let len = std::cmp::max(a.as_ref().len(), b.as_ref().len());
     let block_size = hash::calculate_block_size(len);
     diff_with_block_size(block_size, a, b)
 }
 
 /// Same as [`diff`]. but with more control over the `block_size`.
+///
+/// the common prefix and suffix of both buffers are skipped first, only the middle that changed
+/// is diffed, so small edits in big buffers are cheap.
+///
+/// fails with [`DiffError::InvalidBlockSize`] if the `block_size` is zero.
 pub fn diff_with_block_size(
     block_size: usize,
     a: impl AsRef<[u8]>,
     b: impl AsRef<[u8]>,
-) -> Vec<Operation> {
-    let mut signature = Signature::with_block_size(block_size, a);
+) -> Result<Vec<Operation>, DiffError> {
+    if block_size == 0 {
+        return Err(DiffError::InvalidBlockSize(block_size));
+    }
+    let (a, b) = (a.as_ref(), b.as_ref());
+    let (prefix, suffix) = anchors(block_size, a, b);
+    let mut signature = Signature::with_block_size(block_size, &a[prefix..a.len() - suffix]);
     signature.calculate();
     let mut delta = Delta::new(signature.to_indexed());
-    delta.diff(b).unwrap();
-    delta.into_operations()
+    delta.diff(&b[prefix..b.len() - suffix])?;
+    let mut ops = delta.into_operations();
+    for op in &mut ops {
+        match op {
+            Operation::Insert { offset, .. }
+            | Operation::Remove { offset, .. }
+            | Operation::Replace { offset, .. } => *offset += prefix,
+            Operation::Copy { src_offset, .. } => *src_offset += prefix,
+        }
+    }
+    Ok(ops)
+}
+
+/// Same as [`diff`] but for text, where blocks match regardless of the ASCII letters casing.
+///
+/// the inserted bytes keep the casing of `b`, but the matched blocks are copied from `a` when
+/// patching, so a change in casing alone is not a change.
+///
+/// ### Example
+/// ```
+/// let a = "The quick brown fox jumps over the lazy dog, again and again and again and again.";
+/// let b = "THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG, again and again and again and again.";
+/// assert!(rsdiff::diff_text_ci(a, b).unwrap().is_empty());
+/// ```
+pub fn diff_text_ci(a: &str, b: &str) -> Result<Vec<Operation>, DiffError> {
+    let len = cmp::max(a.len(), b.len());
+    let block_size = hash::calculate_block_size(len);
+    let mut ops = diff_with_block_size(block_size, a.to_ascii_lowercase(), b.to_ascii_lowercase())?;
+    // the lowercase view has the same length, so the offsets are the same in `b`.
+    for op in &mut ops {
+        if let Operation::Insert { offset, buffer } = op {
+            let end = *offset + buffer.len();
+            buffer.copy_from_slice(&b.as_bytes()[*offset..end]);
+        }
+    }
+    Ok(ops)
+}
+
+/// The length of the common prefix and suffix of `a` and `b` that can be skipped while diffing.
+///
+/// both are trimmed to whole blocks of `a`, so the blocks of the middle are the same ones we
+/// would get from the whole buffer.
+fn anchors(block_size: usize, a: &[u8], b: &[u8]) -> (usize, usize) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let prefix = prefix - prefix % block_size;
+    let max_suffix = cmp::min(a.len(), b.len()) - prefix;
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take(max_suffix)
+        .take_while(|(x, y)| x == y)
+        .count();
+    // the middle of `a` must end on a block boundary, or we keep the whole suffix.
+    let middle = a.len() - prefix - suffix;
+    let padding = (block_size - middle % block_size) % block_size;
+    let suffix = suffix.saturating_sub(padding);
+    (prefix, suffix)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rng::random_bytes;
     macro_rules! test_diff {
         (
             v1 = $v1: expr, v2 = $v2: expr, bs = $bs: expr,
             +[$(($ioffset: expr, $buf: expr)),*],
             -[$(($doffset: expr, $len: expr)),*],
         ) => {{
-            let mut ops = diff_with_block_size($bs, $v1, $v2);
+            let mut ops = diff_with_block_size($bs, $v1, $v2).unwrap();
             ops.sort_by_key(|op| op.is_insert());
-            #[allow(unused_mut)]
-            let mut expected_ops: Vec<Operation> = Vec::new();
+            let mut expected_ops: Vec<Operation> = vec![
                 $(
-                    expected_ops.push(
-                        Operation::Insert {
-                            offset: $ioffset,
-                            buffer: $buf.bytes().collect()
-                        }
-                    );
+                    Operation::Insert {
+                        offset: $ioffset,
+                        buffer: $buf.bytes().collect()
+                    },
                 )*
                 $(
-                    expected_ops.push(
-                        Operation::Remove {
-                            offset: $doffset,
-                            len: $len
-                        }
-                    );
+                    Operation::Remove {
+                        offset: $doffset,
+                        len: $len
+                    },
                 )*
+            ];
             expected_ops.sort_by_key(|op| op.is_insert());
             assert_eq!(ops, expected_ops);
 
@@ -272,8 +389,173 @@ mod tests {
             v1 = "hello there, do you know rust?",
             v2 = "hi, do you know about rustlang?",
             bs = hash::calculate_block_size(32),
-            +[(0, "hi, do you know about rustlang?")],
-            -[(31, 30)],
+            +[(0, "hi, do"), (16, "about rustlang?")],
+            -[(6, 15), (31, 5)],
+        );
+    }
+
+    #[test]
+    fn test_dynamic_block_size_small_edits() {
+        init();
+        for (original, new) in PAIRS {
+            let ops = diff(original, new).unwrap();
+            assert_eq!(patched(&ops, original), new.as_bytes());
+        }
+        // short strings with a small edit don't turn into a whole insert anymore.
+        let ops = diff("i saw a red fox", "i saw a red box").unwrap();
+        assert_eq!(inserted_bytes(&ops), 3);
+        let ops = diff(
+            "the quick brown fox jumps over the lazy dog",
+            "the quick brown fox jumped over the lazy dog",
+        )
+        .unwrap();
+        assert!(inserted_bytes(&ops) < 10, "{:?}", ops);
+    }
+
+    /// a fixed set of (original, new) pairs used to check `diff` + `patch` round-trips.
+    const PAIRS: &[(&str, &str)] = &[
+        ("i saw a red fox", "i saw a red box"),
+        ("i saw a red fox", "i saw a green fox"),
+        (
+            "hello there, do you know rust?",
+            "hi, do you know about rustlang?",
+        ),
+        ("hello fox", "hello fox and friends"),
+        ("hello fox and friends", "hello fox"),
+        ("wow there is no updates", "wow there is no updates"),
+        ("", "something from nothing"),
+        ("something to nothing", ""),
+        ("aaaaaaaaaaaaaaaaaaaaaaaa", "aaaaaaaabbbbaaaaaaaaaaaa"),
+        ("abcabcabcabcabcabc", "abcabcXabcabcabcabcY"),
+        (
+            "the quick brown fox jumps over the lazy dog",
+            "a quick brown dog jumps over the lazy fox!",
+        ),
+    ];
+
+    fn patched(ops: &[Operation], original: impl AsRef<[u8]>) -> Vec<u8> {
+        let mut patch = patch::Patch::new(ops);
+        patch.apply(original);
+        patch.buffer().to_vec()
+    }
+
+    #[test]
+    fn test_roundtrip_block_sizes() {
+        init();
+        let block_sizes = (1..=64).chain(vec![128, 4096]);
+        for bs in block_sizes {
+            for (v1, v2) in PAIRS {
+                let ops = diff_with_block_size(bs, v1, v2).unwrap();
+                assert_eq!(
+                    patched(&ops, v1),
+                    v2.as_bytes(),
+                    "round-trip failed for bs = {}, {:?} -> {:?}, ops = {:?}",
+                    bs,
+                    v1,
+                    v2,
+                    ops
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        init();
+        let mut original = random_bytes(0x5eed, 4096);
+        // make sure the edges of the byte range are there.
+        original[..64].iter_mut().for_each(|b| *b = 0x00);
+        original[64..128].iter_mut().for_each(|b| *b = 0xFF);
+        let mut new = original.clone();
+        new[10] = 0xFF;
+        new.splice(1000..1010, vec![0x00; 32]);
+        new.splice(2000..2000, random_bytes(7, 100));
+        new.drain(3000..3100);
+        new.extend_from_slice(&[0xFF, 0x00, 0xFE, 0x80]);
+        for bs in &[1, 7, 16, 64, 256] {
+            let ops = diff_with_block_size(*bs, &original, &new).unwrap();
+            let mut patch = patch::Patch::new(&ops);
+            patch.apply(&original);
+            assert_eq!(patch.buffer(), &new[..], "bs = {}", bs);
+            let decoded = decode_operations(&encode_operations(&ops)).unwrap();
+            assert_eq!(decoded, ops);
+        }
+    }
+
+    #[test]
+    fn test_anchored() {
+        init();
+        let prefix = random_bytes(1, 10_000);
+        let suffix = random_bytes(2, 10_000);
+        let original = [&prefix[..], b"a red fox", &suffix[..]].concat();
+        let new = [&prefix[..], b"a blue box", &suffix[..]].concat();
+        // both are trimmed so that the middle of the original is a single block.
+        assert_eq!(anchors(64, &original, &new), (9984, 9961));
+        let ops = diff_with_block_size(64, &original, &new).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Operation::Insert {
+                    offset: 9984,
+                    buffer: new[9984..new.len() - 9961].to_vec()
+                },
+                Operation::Remove {
+                    offset: 10_049,
+                    len: 64
+                },
+            ]
         );
+        assert_eq!(patched(&ops, &original), new);
+
+        // nothing in common.
+        assert_eq!(anchors(4, b"abc", b"xyz"), (0, 0));
+        // one is a prefix of the other.
+        assert_eq!(anchors(4, b"i saw a red fox", b"i saw a red"), (8, 0));
+        assert_eq!(anchors(4, b"i saw a red", b"i saw a red fox"), (8, 0));
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<Signature<Vec<u8>>>();
+        assert_send_sync::<Signature<&[u8]>>();
+        assert_send_sync::<IndexedSignature>();
+        assert_send_sync::<Delta>();
+        assert_send_sync::<Operation>();
+        assert_send_sync::<window::Window<Vec<u8>>>();
+    }
+
+    #[test]
+    fn test_diff_text_ci() {
+        init();
+        let a: String = (0..20).map(|i| format!("Line number {}\n", i)).collect();
+        let b = a.to_uppercase();
+        assert!(diff_text_ci(&a, &b).unwrap().is_empty());
+        assert!(!diff(&a, &b).unwrap().is_empty());
+
+        let b = a.replace("Line number 10\n", "LINE NUMBER TEN\n");
+        let ops = diff_text_ci(&a, &b).unwrap();
+        assert!(!ops.is_empty());
+        for op in &ops {
+            if let Operation::Insert { offset, buffer } = op {
+                assert_eq!(&b.as_bytes()[*offset..*offset + buffer.len()], &buffer[..]);
+            }
+        }
+        assert!(String::from_utf8(patched(&ops, &a))
+            .unwrap()
+            .contains("LINE NUMBER TEN"));
+    }
+
+    #[test]
+    fn test_diff_error() {
+        assert!(matches!(
+            diff_with_block_size(0, "i saw a red fox", "i saw a red box"),
+            Err(DiffError::InvalidBlockSize(0))
+        ));
+        let err = DiffError::from(std::io::Error::other("disk on fire"));
+        assert_eq!(err.to_string(), "io error: disk on fire");
+        assert!(std::error::Error::source(&err).is_some());
+        assert_send_sync::<DiffError>();
     }
 }
@@ -0,0 +1,115 @@
+//! Compare rsdiff against librsync on the same inputs, using `fast_rsync`, a port of librsync
+//! that reads and writes its signature and delta formats.
+//!
+//! run with `cargo test --release --features librsync-bench against_librsync -- --nocapture`.
+use std::time::{Duration, Instant};
+
+use fast_rsync::{Signature as RsyncSignature, SignatureOptions};
+
+use crate::rng::random_bytes;
+use crate::{encode_operations, Delta, Patch, Signature};
+
+const BLOCK_SIZE: usize = 2048;
+/// How many bytes of the MD4 hash of every block the librsync signature keeps.
+const CRYPTO_HASH_SIZE: u32 = 8;
+
+/// The new version of `original`, a few bytes changed every `every` bytes and a chunk moved.
+fn edited(original: &[u8], every: usize) -> Vec<u8> {
+    let mut new = original.to_vec();
+    for i in (every / 2..new.len()).step_by(every) {
+        new[i] ^= 0xff;
+    }
+    let moved: Vec<u8> = new.drain(..new.len() / 10).collect();
+    new.extend_from_slice(&moved);
+    new
+}
+
+/// The time and the signature plus delta size.
+struct Run {
+    signature: Duration,
+    delta: Duration,
+    signature_len: usize,
+    delta_len: usize,
+}
+
+fn rsdiff(original: &[u8], new: &[u8]) -> Run {
+    let start = Instant::now();
+    let mut signature = Signature::with_block_size(BLOCK_SIZE, original);
+    signature.calculate();
+    let signature = signature.to_indexed();
+    let signature_time = start.elapsed();
+    let signature_len = signature.to_bytes().len();
+    let start = Instant::now();
+    let mut delta = Delta::new(signature);
+    delta.diff(new).unwrap();
+    let delta_time = start.elapsed();
+    let ops = delta.into_operations();
+    let mut patch = Patch::new(&ops);
+    patch.apply(original);
+    assert_eq!(patch.buffer(), new);
+    Run {
+        signature: signature_time,
+        delta: delta_time,
+        signature_len,
+        delta_len: encode_operations(&ops).len(),
+    }
+}
+
+fn librsync(original: &[u8], new: &[u8]) -> Run {
+    let start = Instant::now();
+    let options = SignatureOptions {
+        block_size: BLOCK_SIZE as u32,
+        crypto_hash_size: CRYPTO_HASH_SIZE,
+    };
+    let signature = RsyncSignature::calculate(original, options);
+    let signature_time = start.elapsed();
+    let start = Instant::now();
+    let mut delta = Vec::new();
+    fast_rsync::diff(&signature.index(), new, &mut delta).unwrap();
+    let delta_time = start.elapsed();
+    let mut out = Vec::new();
+    fast_rsync::apply(original, &delta, &mut out).unwrap();
+    assert_eq!(out, new);
+    Run {
+        signature: signature_time,
+        delta: delta_time,
+        signature_len: signature.serialized().len(),
+        delta_len: delta.len(),
+    }
+}
+
+#[test]
+fn against_librsync() {
+    println!(
+        "{:>10} {:>8} | {:>10} {:>10} {:>10} {:>10} | {:>10} {:>10} {:>10} {:>10}",
+        "size",
+        "every",
+        "sig",
+        "delta",
+        "sig len",
+        "delta len",
+        "rsync sig",
+        "delta",
+        "sig len",
+        "delta len"
+    );
+    for (len, every) in &[(1 << 20, 4096), (1 << 20, 65536), (8 << 20, 65536)] {
+        let original = random_bytes(*len as u64, *len);
+        let new = edited(&original, *every);
+        let ours = rsdiff(&original, &new);
+        let theirs = librsync(&original, &new);
+        println!(
+            "{:>10} {:>8} | {:>10.2?} {:>10.2?} {:>10} {:>10} | {:>10.2?} {:>10.2?} {:>10} {:>10}",
+            len,
+            every,
+            ours.signature,
+            ours.delta,
+            ours.signature_len,
+            ours.delta_len,
+            theirs.signature,
+            theirs.delta,
+            theirs.signature_len,
+            theirs.delta_len
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! The new buffer as a list of content-addressed blocks, for deduplicating storage.
+use crate::hash::CryptoHash;
+
+/// A piece of the new buffer, see [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEntry {
+    /// Bytes that are not in the original buffer.
+    Literal(Vec<u8>),
+    /// A block of the original buffer, referenced by its crypto hash.
+    BlockRef(CryptoHash),
+}
+
+/// The new buffer described as literals and references to the blocks of the original buffer,
+/// made by [`crate::Delta::into_manifest`].
+///
+/// unlike the operations, a manifest does not depend on where the blocks were in the original
+/// buffer, only on their content, so a block store can fetch whatever blocks it is missing.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// The hashes of the referenced blocks, in order, with duplicates.
+    pub fn block_refs(&self) -> impl Iterator<Item = &CryptoHash> {
+        self.entries.iter().filter_map(|entry| match entry {
+            ManifestEntry::BlockRef(hash) => Some(hash),
+            ManifestEntry::Literal(_) => None,
+        })
+    }
+
+    /// Rebuild the new buffer, `fetch` returns the content of a block by its hash, returns
+    /// [`None`] if any block is missing.
+    pub fn reconstruct<F, T>(&self, mut fetch: F) -> Option<Vec<u8>>
+    where
+        F: FnMut(&CryptoHash) -> Option<T>,
+        T: AsRef<[u8]>,
+    {
+        let mut buffer = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                ManifestEntry::Literal(bytes) => buffer.extend_from_slice(bytes),
+                ManifestEntry::BlockRef(hash) => buffer.extend_from_slice(fetch(hash)?.as_ref()),
+            }
+        }
+        Some(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{Delta, Signature};
+
+    #[test]
+    fn reconstruct() {
+        let original = "the quick brown fox jumps over the lazy dog";
+        let new = "the lazy dog jumps over the quick brown fox!";
+        // the store only knows the blocks of the original buffer.
+        let store: HashMap<CryptoHash, &[u8]> = original
+            .as_bytes()
+            .chunks(4)
+            .map(|block| (CryptoHash::calculate(block), block))
+            .collect();
+        let mut signature = Signature::with_block_size(4, original);
+        signature.calculate();
+        let mut delta = Delta::new(signature.to_indexed());
+        delta.diff(new).unwrap();
+        let manifest = delta.into_manifest();
+        assert!(manifest.block_refs().count() > 0);
+        assert!(manifest.block_refs().all(|hash| store.contains_key(hash)));
+        let rebuilt = manifest.reconstruct(|hash| store.get(hash)).unwrap();
+        assert_eq!(rebuilt, new.as_bytes());
+        // a missing block.
+        assert_eq!(manifest.reconstruct(|_| None::<&[u8]>), None);
+    }
+}
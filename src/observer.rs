@@ -0,0 +1,94 @@
+//! Hooks to see what happens while hashing and diffing, useful for profiling and tuning.
+
+/// Something that happened while calculating a signature or a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObserverEvent {
+    /// A block of the original buffer was hashed.
+    BlockHashed { index: usize, len: usize },
+    /// The weak hash of the current frame matched the block at `index`.
+    WeakHit { index: usize },
+    /// The crypto hash matched too, the block is used unless it is behind the last matched one.
+    StrongMatch { index: usize },
+    /// The crypto hash did not match, the weak hash was a collision.
+    StrongMiss { index: usize },
+    /// A byte of the new buffer did not match anything and becomes part of an insert.
+    LiteralByte,
+}
+
+/// Receives the [`ObserverEvent`]s, see [`crate::Signature::calculate_observed`] and
+/// [`crate::Delta::diff_observed`].
+///
+/// any `FnMut(ObserverEvent)` closure is an observer.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{Delta, ObserverEvent, Signature};
+///
+/// let mut hashed = 0;
+/// let mut signature = Signature::with_block_size(4, b"i saw a red fox");
+/// signature.calculate_observed(&mut |event| {
+///     if let ObserverEvent::BlockHashed { .. } = event {
+///         hashed += 1;
+///     }
+/// });
+/// assert_eq!(hashed, 4);
+///
+/// let mut literals = 0;
+/// let mut delta = Delta::new(signature.to_indexed());
+/// delta
+///     .diff_observed(b"i saw a red box", &mut |event| {
+///         if event == ObserverEvent::LiteralByte {
+///             literals += 1;
+///         }
+///     })
+///     .unwrap();
+/// assert_eq!(literals, 3);
+/// ```
+pub trait Observer {
+    fn observe(&mut self, event: ObserverEvent);
+}
+
+impl<F: FnMut(ObserverEvent)> Observer for F {
+    fn observe(&mut self, event: ObserverEvent) {
+        self(event)
+    }
+}
+
+/// The observer used when nobody is watching, it does nothing and costs nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {
+    #[inline(always)]
+    fn observe(&mut self, _: ObserverEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Delta, Signature};
+
+    #[test]
+    fn strong_misses() {
+        // "babe" has the same weak hash as "abcd", every block of the new buffer collides.
+        let original = "abcd".repeat(16);
+        let new = "babe".repeat(16);
+        let mut signature = Signature::with_block_size(4, &original);
+        signature.calculate();
+        let mut delta = Delta::new(signature.to_indexed());
+        let (mut weak_hits, mut strong_matches, mut strong_misses, mut literals) = (0, 0, 0, 0);
+        delta
+            .diff_observed(&new, &mut |event| match event {
+                ObserverEvent::WeakHit { .. } => weak_hits += 1,
+                ObserverEvent::StrongMatch { .. } => strong_matches += 1,
+                ObserverEvent::StrongMiss { .. } => strong_misses += 1,
+                ObserverEvent::LiteralByte => literals += 1,
+                ObserverEvent::BlockHashed { .. } => unreachable!(),
+            })
+            .unwrap();
+        assert!(strong_misses >= 16);
+        assert_eq!(strong_misses, weak_hits);
+        assert_eq!(strong_matches, 0);
+        assert_eq!(literals, new.len());
+    }
+}
@@ -0,0 +1,1012 @@
+//! Helpers that work on any list of [`Operation`]s, calculated or hand-built.
+use std::cmp;
+use std::mem;
+use std::ops::Range;
+
+use crate::delta::Operation;
+
+/// Remove the changes that do nothing, like removing some bytes from the original buffer and
+/// inserting the very same bytes back in the same place.
+///
+/// a change here is a group of operations with no unchanged bytes between them, the whole group
+/// is dropped if the inserted bytes are equal to the removed ones, so the `original` buffer is
+/// needed to compare them.
+///
+/// operations that contain any [`Operation::Copy`] are returned as is.
+///
+/// ### Example
+/// ```
+/// use rsdiff::Operation;
+///
+/// let ops = vec![
+///     Operation::Remove { offset: 2, len: 3 },
+///     Operation::Insert { offset: 2, buffer: b"saw".to_vec() },
+///     Operation::Insert { offset: 12, buffer: b"box".to_vec() },
+///     Operation::Remove { offset: 15, len: 3 },
+/// ];
+/// let ops = rsdiff::compact_noop_churn(&ops, b"i saw a red fox");
+/// assert_eq!(
+///     ops,
+///     vec![
+///         Operation::Insert { offset: 12, buffer: b"box".to_vec() },
+///         Operation::Remove { offset: 15, len: 3 },
+///     ],
+/// );
+/// ```
+pub fn compact_noop_churn(ops: &[Operation], original: &[u8]) -> Vec<Operation> {
+    if ops.iter().any(Operation::is_copy) {
+        return ops.to_vec();
+    }
+    let mut compacted = Vec::with_capacity(ops.len());
+    // the operations of the current change.
+    let mut change: Vec<&Operation> = Vec::new();
+    let mut inserted = Vec::new();
+    // where the current change starts in the original buffer and how much it removes.
+    let mut start = 0;
+    let mut removed = 0;
+    // where we are in the new and the original buffers.
+    let mut new = 0;
+    let mut cursor = 0;
+    let mut flush = |change: &mut Vec<&Operation>, inserted: &mut Vec<u8>, start, removed| {
+        let churn = original.get(start..start + removed) == Some(&inserted[..]);
+        if !churn {
+            compacted.extend(change.iter().map(|op| (*op).clone()));
+        }
+        change.clear();
+        inserted.clear();
+    };
+    for op in ops {
+        let gap = op.offset().saturating_sub(new);
+        if gap > 0 || change.is_empty() {
+            flush(&mut change, &mut inserted, start, removed);
+            new += gap;
+            cursor += gap;
+            start = cursor;
+            removed = 0;
+        }
+        change.push(op);
+        match op {
+            Operation::Insert { buffer, .. } => {
+                new += buffer.len();
+                inserted.extend_from_slice(buffer);
+            }
+            Operation::Remove { len, .. } => {
+                cursor += len;
+                removed += len;
+            }
+            Operation::Replace {
+                old_len, buffer, ..
+            } => {
+                new += buffer.len();
+                inserted.extend_from_slice(buffer);
+                cursor += old_len;
+                removed += old_len;
+            }
+            Operation::Copy { .. } => unreachable!("copies are handled above"),
+        }
+    }
+    flush(&mut change, &mut inserted, start, removed);
+    compacted
+}
+
+/// Collapse every remove and insert at the same place into a single [`Operation::Replace`],
+/// which is more compact and easier to read.
+///
+/// both a remove followed by an insert at the same offset and an insert followed by a remove
+/// right after the inserted bytes (the way [`crate::Delta`] emits them) are collapsed.
+///
+/// operations that contain any [`Operation::Copy`] are returned as is.
+///
+/// ### Example
+/// ```
+/// use rsdiff::Operation;
+///
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// assert_eq!(
+///     rsdiff::coalesce_replacements(&ops),
+///     vec![Operation::Replace { offset: 12, old_len: 3, buffer: b"box".to_vec() }],
+/// );
+/// ```
+pub fn coalesce_replacements(ops: &[Operation]) -> Vec<Operation> {
+    if ops.iter().any(Operation::is_copy) {
+        return ops.to_vec();
+    }
+    let mut coalesced: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in ops {
+        let replace =
+            match (coalesced.last(), op) {
+                (
+                    Some(Operation::Remove { offset, len }),
+                    Operation::Insert { offset: at, buffer },
+                ) if at == offset => Operation::Replace {
+                    offset: *offset,
+                    old_len: *len,
+                    buffer: buffer.clone(),
+                },
+                (
+                    Some(Operation::Insert { offset, buffer }),
+                    Operation::Remove { offset: at, len },
+                ) if *at == offset + buffer.len() => Operation::Replace {
+                    offset: *offset,
+                    old_len: *len,
+                    buffer: buffer.clone(),
+                },
+                _ => {
+                    coalesced.push(op.clone());
+                    continue;
+                }
+            };
+        coalesced.pop();
+        coalesced.push(replace);
+    }
+    coalesced
+}
+
+/// Convert the offsets of the `ops` from positions in the new buffer (what [`crate::Delta`]
+/// calculates) to positions in the original buffer, for annotating the original buffer.
+///
+/// in the original buffer an insert goes right before the byte at `offset`, and a remove
+/// starts at `offset` and removes the `len` bytes after it.
+///
+/// the [`Operation::Copy`] offsets are already in the original buffer, they are kept as is.
+///
+/// ### Example
+/// ```
+/// use rsdiff::Operation;
+///
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// assert_eq!(
+///     rsdiff::to_original_offsets(&ops),
+///     vec![
+///         Operation::Insert { offset: 12, buffer: b"box".to_vec() },
+///         // remove "fox" which starts at 12 in the original buffer.
+///         Operation::Remove { offset: 12, len: 3 },
+///     ],
+/// );
+/// ```
+pub fn to_original_offsets(ops: &[Operation]) -> Vec<Operation> {
+    let mut new = 0;
+    let mut original = 0;
+    ops.iter()
+        .map(|op| {
+            if op.is_copy() {
+                return op.clone();
+            }
+            // the unchanged bytes since the last operation.
+            original += op.offset().saturating_sub(new);
+            new = op.offset();
+            with_offset(op, original, &mut new, &mut original)
+        })
+        .collect()
+}
+
+/// The opposite of [`to_original_offsets`], convert the offsets of the `ops` from positions in
+/// the original buffer back to positions in the new buffer.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(
+///     5,
+///     "hello there, do you know rust?",
+///     "hi, do you know about rustlang?",
+/// ).unwrap();
+/// let original_offsets = rsdiff::to_original_offsets(&ops);
+/// assert_eq!(rsdiff::to_new_offsets(&original_offsets), ops);
+/// ```
+pub fn to_new_offsets(ops: &[Operation]) -> Vec<Operation> {
+    let mut new = 0;
+    let mut original = 0;
+    ops.iter()
+        .map(|op| {
+            if op.is_copy() {
+                return op.clone();
+            }
+            new += op.offset().saturating_sub(original);
+            original = op.offset();
+            with_offset(op, new, &mut new, &mut original)
+        })
+        .collect()
+}
+
+/// Clone `op` with the given `offset` and move the `new` and `original` positions past it.
+fn with_offset(op: &Operation, offset: usize, new: &mut usize, original: &mut usize) -> Operation {
+    match op {
+        Operation::Insert { buffer, .. } => {
+            *new += buffer.len();
+            Operation::Insert {
+                offset,
+                buffer: buffer.clone(),
+            }
+        }
+        Operation::Remove { len, .. } => {
+            *original += len;
+            Operation::Remove { offset, len: *len }
+        }
+        Operation::Replace {
+            old_len, buffer, ..
+        } => {
+            *new += buffer.len();
+            *original += old_len;
+            Operation::Replace {
+                offset,
+                old_len: *old_len,
+                buffer: buffer.clone(),
+            }
+        }
+        Operation::Copy { .. } => op.clone(),
+    }
+}
+
+/// Fold a chain of deltas into a single one, the first delta upgrades the base buffer to the
+/// first version, the second upgrades that to the second version and so on, the result
+/// upgrades the base buffer straight to the last version.
+///
+/// see [`compose`].
+///
+/// ### Example
+/// ```
+/// let v0 = "i saw a red fox";
+/// let v1 = "i saw a red box";
+/// let v2 = "i saw a big red box!";
+/// let deltas = vec![
+///     rsdiff::diff_with_block_size(4, v0, v1).unwrap(),
+///     rsdiff::diff_with_block_size(4, v1, v2).unwrap(),
+/// ];
+/// let flat = rsdiff::flatten(&deltas);
+/// assert_eq!(flat, rsdiff::compose(&deltas[0], &deltas[1]));
+/// ```
+pub fn flatten(deltas: &[Vec<Operation>]) -> Vec<Operation> {
+    let mut deltas = deltas.iter();
+    let first = match deltas.next() {
+        Some(first) => Pieces::from_operations(first),
+        None => return Vec::new(),
+    };
+    deltas
+        .fold(first, |pieces, ops| {
+            Pieces::from_operations(ops).compose(&pieces)
+        })
+        .into_operations()
+}
+
+/// Combine two deltas, `first` upgrades a buffer to a middle version and `second` upgrades the
+/// middle version, the result upgrades the buffer straight to the last version without the
+/// middle one.
+///
+/// the result is made of [`Operation::Copy`]s if any of the deltas is, otherwise it is made of
+/// inserts and removes just like the ones calculated by [`crate::Delta`]. when none of the
+/// copied bytes are left, the result inserts the last version and removes the whole buffer.
+pub fn compose(first: &[Operation], second: &[Operation]) -> Vec<Operation> {
+    Pieces::from_operations(second)
+        .compose(&Pieces::from_operations(first))
+        .into_operations()
+}
+
+/// A piece of the buffer produced by applying some operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    /// Bytes that were inserted.
+    Literal(Vec<u8>),
+    /// A range of the input buffer.
+    Source(Range<usize>),
+    /// Everything from this offset to the end of the input buffer, the length of the input is
+    /// not known.
+    Tail(usize),
+}
+
+/// The buffer produced by applying some operations, as a list of pieces.
+#[derive(Debug, Default)]
+struct Pieces(Vec<Piece>);
+
+impl Pieces {
+    fn from_operations(ops: &[Operation]) -> Self {
+        let mut pieces = Self::default();
+        if ops.iter().any(Operation::is_copy) {
+            for op in ops {
+                match op {
+                    Operation::Insert { buffer, .. } | Operation::Replace { buffer, .. } => {
+                        pieces.push(Piece::Literal(buffer.clone()))
+                    }
+                    Operation::Copy { src_offset, len } => {
+                        pieces.push(Piece::Source(*src_offset..src_offset + len))
+                    }
+                    Operation::Remove { .. } => {}
+                }
+            }
+            return pieces;
+        }
+        let mut new = 0;
+        let mut cursor = 0;
+        for op in ops {
+            let gap = op.offset().saturating_sub(new);
+            pieces.push(Piece::Source(cursor..cursor + gap));
+            new += gap;
+            cursor += gap;
+            match op {
+                Operation::Insert { buffer, .. } => {
+                    new += buffer.len();
+                    pieces.push(Piece::Literal(buffer.clone()));
+                }
+                Operation::Remove { len, .. } => cursor += len,
+                Operation::Replace {
+                    old_len, buffer, ..
+                } => {
+                    cursor += old_len;
+                    new += buffer.len();
+                    pieces.push(Piece::Literal(buffer.clone()));
+                }
+                Operation::Copy { .. } => unreachable!("copies are handled above"),
+            }
+        }
+        pieces.push(Piece::Tail(cursor));
+        pieces
+    }
+
+    /// Add a piece, merging it with the last one if they are next to each other.
+    fn push(&mut self, piece: Piece) {
+        match (self.0.last_mut(), piece) {
+            (_, Piece::Literal(bytes)) if bytes.is_empty() => {}
+            (_, Piece::Source(range)) if range.is_empty() => {}
+            (Some(Piece::Literal(last)), Piece::Literal(bytes)) => last.extend_from_slice(&bytes),
+            (Some(Piece::Source(last)), Piece::Source(range)) if last.end == range.start => {
+                last.end = range.end
+            }
+            (Some(Piece::Source(last)), Piece::Tail(start)) if last.end == start => {
+                let start = last.start;
+                self.0.pop();
+                self.0.push(Piece::Tail(start));
+            }
+            (_, piece) => self.0.push(piece),
+        }
+    }
+
+    /// Express these pieces, which are taken from the output of `input`, in terms of the
+    /// input of `input`.
+    fn compose(&self, input: &Pieces) -> Pieces {
+        let mut pieces = Pieces::default();
+        for piece in &self.0 {
+            match piece {
+                Piece::Literal(bytes) => pieces.push(Piece::Literal(bytes.clone())),
+                Piece::Source(range) => input.slice(range.start, Some(range.end), &mut pieces),
+                Piece::Tail(start) => input.slice(*start, None, &mut pieces),
+            }
+        }
+        pieces
+    }
+
+    /// Push the pieces that make the `start..end` range of this buffer into `out`, up to the
+    /// end of the buffer if there is no `end`.
+    fn slice(&self, start: usize, end: Option<usize>, out: &mut Pieces) {
+        let mut offset = 0;
+        for piece in &self.0 {
+            if end.is_some_and(|end| offset >= end) {
+                break;
+            }
+            let len = match piece {
+                Piece::Literal(bytes) => bytes.len(),
+                Piece::Source(range) => range.len(),
+                Piece::Tail(_) => usize::MAX - offset,
+            };
+            let from = start.saturating_sub(offset);
+            let to = end.map_or(len, |end| cmp::min(end - offset, len));
+            if from < to {
+                match piece {
+                    Piece::Literal(bytes) => out.push(Piece::Literal(bytes[from..to].to_vec())),
+                    Piece::Source(range) => {
+                        out.push(Piece::Source(range.start + from..range.start + to))
+                    }
+                    Piece::Tail(tail) if end.is_none() => out.push(Piece::Tail(tail + from)),
+                    Piece::Tail(tail) => out.push(Piece::Source(tail + from..tail + to)),
+                }
+            }
+            offset = offset.saturating_add(len);
+        }
+    }
+
+    fn into_operations(self) -> Vec<Operation> {
+        let mut ops = Vec::new();
+        let mut new = 0;
+        if !matches!(self.0.last(), Some(Piece::Tail(_))) {
+            // the end of the input is not known, so only copies can describe it.
+            for piece in self.0 {
+                match piece {
+                    Piece::Literal(buffer) => {
+                        let offset = new;
+                        new += buffer.len();
+                        ops.push(Operation::Insert { offset, buffer });
+                    }
+                    Piece::Source(range) => {
+                        new += range.len();
+                        ops.push(Operation::Copy {
+                            src_offset: range.start,
+                            len: range.len(),
+                        });
+                    }
+                    Piece::Tail(_) => unreachable!("only the last piece is a tail"),
+                }
+            }
+            if !ops.iter().any(Operation::is_copy) {
+                // plain inserts keep the original buffer, remove all of it whatever its length.
+                ops.push(Operation::Remove {
+                    offset: new,
+                    len: usize::MAX - new,
+                });
+            }
+            return ops;
+        }
+        let mut cursor = 0;
+        for piece in self.0 {
+            let start = match piece {
+                Piece::Literal(buffer) => {
+                    let offset = new;
+                    new += buffer.len();
+                    ops.push(Operation::Insert { offset, buffer });
+                    continue;
+                }
+                Piece::Source(ref range) => range.start,
+                Piece::Tail(start) => start,
+            };
+            if start > cursor {
+                ops.push(Operation::Remove {
+                    offset: new,
+                    len: start - cursor,
+                });
+            }
+            if let Piece::Source(range) = piece {
+                new += range.len();
+                cursor = range.end;
+            }
+        }
+        ops
+    }
+}
+
+/// Two deltas of the same base buffer that change the same bytes, see [`merge3`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// The bytes of the base buffer both sides changed.
+    pub base: Range<usize>,
+    /// What the first delta turns these bytes into.
+    pub a: Vec<u8>,
+    /// What the second delta turns these bytes into.
+    pub b: Vec<u8>,
+}
+
+/// A three-way merge, apply both `ops_a` and `ops_b`, which are two independent deltas of the
+/// same `base` buffer, and return the merged buffer.
+///
+/// the changes of both sides are merged as long as they touch different bytes of the base
+/// buffer, the same change on both sides is taken once, anything else that overlaps (including
+/// two inserts at the same place) is a [`Conflict`], and all of them are returned.
+///
+/// ### Example
+/// ```
+/// let base = "i saw a red fox";
+/// let a = rsdiff::diff_with_block_size(4, base, "you saw a red fox").unwrap();
+/// let b = rsdiff::diff_with_block_size(4, base, "i saw a red box").unwrap();
+/// assert_eq!(rsdiff::merge3(base.as_bytes(), &a, &b).unwrap(), b"you saw a red box");
+///
+/// let c = rsdiff::diff_with_block_size(4, base, "i saw a red cat").unwrap();
+/// let conflicts = rsdiff::merge3(base.as_bytes(), &b, &c).unwrap_err();
+/// assert_eq!(conflicts[0].a, b"box");
+/// assert_eq!(conflicts[0].b, b"cat");
+/// ```
+pub fn merge3(
+    base: &[u8],
+    ops_a: &[Operation],
+    ops_b: &[Operation],
+) -> Result<Vec<u8>, Vec<Conflict>> {
+    let a = Edit::from_operations(ops_a, base);
+    let b = Edit::from_operations(ops_b, base);
+    let mut merged = Vec::with_capacity(base.len());
+    let mut conflicts = Vec::new();
+    // everything before it is already merged.
+    let mut cursor = 0;
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let edit = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x == y => {
+                i += 1;
+                j += 1;
+                x
+            }
+            (Some(x), Some(y)) if overlaps(&x.range, &y.range) => {
+                // grow the conflict until nothing else on either side overlaps it.
+                let mut range =
+                    cmp::min(x.range.start, y.range.start)..cmp::max(x.range.end, y.range.end);
+                let (first_a, first_b) = (i, j);
+                i += 1;
+                j += 1;
+                loop {
+                    if let Some(x) = a.get(i).filter(|x| overlaps(&range, &x.range)) {
+                        range.end = cmp::max(range.end, x.range.end);
+                        i += 1;
+                    } else if let Some(y) = b.get(j).filter(|y| overlaps(&range, &y.range)) {
+                        range.end = cmp::max(range.end, y.range.end);
+                        j += 1;
+                    } else {
+                        break;
+                    }
+                }
+                merged.extend_from_slice(&base[cursor..range.start]);
+                cursor = range.end;
+                conflicts.push(Conflict {
+                    a: Edit::apply(&a[first_a..i], base, range.clone()),
+                    b: Edit::apply(&b[first_b..j], base, range.clone()),
+                    base: range,
+                });
+                continue;
+            }
+            (Some(x), Some(y)) if y.range.start < x.range.start => {
+                j += 1;
+                y
+            }
+            (Some(x), _) => {
+                i += 1;
+                x
+            }
+            (None, Some(y)) => {
+                j += 1;
+                y
+            }
+            (None, None) => unreachable!("the loop stops when both sides are done"),
+        };
+        merged.extend_from_slice(&base[cursor..edit.range.start]);
+        merged.extend_from_slice(&edit.bytes);
+        cursor = edit.range.end;
+    }
+    merged.extend_from_slice(&base[cursor..]);
+    if conflicts.is_empty() {
+        Ok(merged)
+    } else {
+        Err(conflicts)
+    }
+}
+
+/// Do two changes of the base buffer get in each other's way? changes that start at the same
+/// place do, since there is no way to tell which one goes first.
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start == b.start || (a.start < b.end && b.start < a.end)
+}
+
+/// A change to the base buffer, the `range` of the base buffer is replaced by the `bytes`.
+#[derive(Debug, PartialEq, Eq)]
+struct Edit {
+    range: Range<usize>,
+    bytes: Vec<u8>,
+}
+
+impl Edit {
+    /// The changes the `ops` make to the `base` buffer, in order.
+    fn from_operations(ops: &[Operation], base: &[u8]) -> Vec<Edit> {
+        let mut edits = Vec::new();
+        let mut cursor = 0;
+        let mut bytes = Vec::new();
+        for piece in Pieces::from_operations(ops).0 {
+            let range = match piece {
+                Piece::Literal(literal) => {
+                    bytes.extend_from_slice(&literal);
+                    continue;
+                }
+                Piece::Source(range) => range,
+                Piece::Tail(start) => start..base.len(),
+            };
+            let range = cmp::min(range.start, base.len())..cmp::min(range.end, base.len());
+            if range.start < cursor {
+                // taken from behind, as far as the base buffer goes these are new bytes.
+                bytes.extend_from_slice(&base[range]);
+                continue;
+            }
+            if range.start > cursor || !bytes.is_empty() {
+                edits.push(Edit {
+                    range: cursor..range.start,
+                    bytes: mem::take(&mut bytes),
+                });
+            }
+            cursor = range.end;
+        }
+        if cursor < base.len() || !bytes.is_empty() {
+            edits.push(Edit {
+                range: cursor..base.len(),
+                bytes,
+            });
+        }
+        edits
+    }
+
+    /// The `range` of the `base` buffer after the `edits`, which are all inside it.
+    fn apply(edits: &[Edit], base: &[u8], range: Range<usize>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cursor = range.start;
+        for edit in edits {
+            out.extend_from_slice(&base[cursor..edit.range.start]);
+            out.extend_from_slice(&edit.bytes);
+            cursor = edit.range.end;
+        }
+        out.extend_from_slice(&base[cursor..range.end]);
+        out
+    }
+}
+
+/// How many literal bytes the `ops` insert.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(
+///     5,
+///     "hello there, do you know rust?",
+///     "hi, do you know about rustlang?",
+/// ).unwrap();
+/// // "hi, do" and "about rustlang?".
+/// assert_eq!(rsdiff::inserted_bytes(&ops), 21);
+/// ```
+pub fn inserted_bytes(ops: &[Operation]) -> usize {
+    ops.iter()
+        .filter(|op| op.is_insert() || op.is_replace())
+        .map(Operation::len)
+        .sum()
+}
+
+/// How many bytes of the original buffer the `ops` remove.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(
+///     5,
+///     "hello there, do you know rust?",
+///     "hi, do you know about rustlang?",
+/// ).unwrap();
+/// // "hello there, " and "rust?".
+/// assert_eq!(rsdiff::removed_bytes(&ops), 20);
+/// ```
+pub fn removed_bytes(ops: &[Operation]) -> usize {
+    ops.iter()
+        .map(|op| match op {
+            Operation::Remove { len, .. } => *len,
+            Operation::Replace { old_len, .. } => *old_len,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The [`Operation::snapshot_string`] of every operation, one per line, for golden tests.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// assert_eq!(
+///     rsdiff::snapshot_operations(&ops),
+///     "insert offset=12 len=3 buffer=626f78\nremove offset=15 len=3\n",
+/// );
+/// ```
+pub fn snapshot_operations(ops: &[Operation]) -> String {
+    let mut snapshot = String::new();
+    for op in ops {
+        snapshot.push_str(&op.snapshot_string());
+        snapshot.push('\n');
+    }
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn() {
+        let original = b"hello there, do you know rust?";
+        // insert then remove the same bytes, in the middle of a real change.
+        let ops = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"hi, do".to_vec(),
+            },
+            Operation::Remove { offset: 6, len: 15 },
+            Operation::Insert {
+                offset: 11,
+                buffer: b"know".to_vec(),
+            },
+            Operation::Remove { offset: 15, len: 4 },
+            Operation::Insert {
+                offset: 16,
+                buffer: b"about rustlang?".to_vec(),
+            },
+            Operation::Remove { offset: 31, len: 5 },
+        ];
+        let compacted = compact_noop_churn(&ops, original);
+        assert_eq!(
+            compacted,
+            vec![
+                ops[0].clone(),
+                ops[1].clone(),
+                ops[4].clone(),
+                ops[5].clone()
+            ]
+        );
+        let apply = |ops: &[Operation]| {
+            let mut patch = crate::patch::Patch::new(ops);
+            patch.apply(original);
+            patch.buffer().to_vec()
+        };
+        assert_eq!(apply(&compacted), apply(&ops));
+    }
+
+    #[test]
+    fn not_churn() {
+        let ops = crate::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+        assert_eq!(compact_noop_churn(&ops, b"i saw a red fox"), ops);
+    }
+
+    #[test]
+    fn offsets() {
+        let original = "hello there, do you know rust?";
+        let ops =
+            crate::diff_with_block_size(5, original, "hi, do you know about rustlang?").unwrap();
+        let original_offsets = to_original_offsets(&ops);
+        assert_eq!(
+            original_offsets,
+            vec![
+                Operation::Insert {
+                    offset: 0,
+                    buffer: b"hi, do".to_vec(),
+                },
+                Operation::Remove { offset: 0, len: 15 },
+                Operation::Insert {
+                    offset: 25,
+                    buffer: b"about rustlang?".to_vec(),
+                },
+                Operation::Remove { offset: 25, len: 5 },
+            ]
+        );
+        assert_eq!(&original[0..15], "hello there, do");
+        assert_eq!(&original[25..30], "rust?");
+        assert_eq!(to_new_offsets(&original_offsets), ops);
+
+        for (original, new) in &[
+            ("i saw a red fox", "i saw a green fox"),
+            ("hello fox", "hello fox and friends"),
+            ("abcabcabcabcabcabc", "abcabcXabcabcabcabcY"),
+        ] {
+            let ops = crate::diff_with_block_size(3, original, new).unwrap();
+            assert_eq!(to_new_offsets(&to_original_offsets(&ops)), ops);
+        }
+    }
+
+    fn apply(ops: &[Operation], original: &[u8]) -> Vec<u8> {
+        let mut patch = crate::patch::Patch::new(ops);
+        patch.apply(original);
+        patch.buffer().to_vec()
+    }
+
+    #[test]
+    fn flatten_chain() {
+        let versions = [
+            "the quick brown fox jumps over the lazy dog",
+            "the quick brown fox jumped over the lazy dog",
+            "a quick brown fox jumped over the lazy dog!",
+            "a quick fox jumped over the very lazy dog!",
+            "",
+            "something from nothing",
+        ];
+        for bs in 1..8 {
+            let deltas: Vec<_> = versions
+                .windows(2)
+                .map(|v| crate::diff_with_block_size(bs, v[0], v[1]).unwrap())
+                .collect();
+            for n in 1..=deltas.len() {
+                let flat = flatten(&deltas[..n]);
+                let mut sequential = versions[0].as_bytes().to_vec();
+                for delta in &deltas[..n] {
+                    sequential = apply(delta, &sequential);
+                }
+                assert_eq!(sequential, versions[n].as_bytes());
+                assert_eq!(
+                    apply(&flat, versions[0].as_bytes()),
+                    sequential,
+                    "bs = {}",
+                    bs
+                );
+                assert!(!flat.iter().any(Operation::is_copy));
+            }
+        }
+        assert!(flatten(&[]).is_empty());
+    }
+
+    #[test]
+    fn flatten_copies() {
+        let base = b"the quick brown fox jumps over the lazy dog";
+        let first = vec![
+            Operation::Copy {
+                src_offset: 4,
+                len: 6,
+            },
+            Operation::Insert {
+                offset: 6,
+                buffer: b"lazy ".to_vec(),
+            },
+            Operation::Copy {
+                src_offset: 16,
+                len: 3,
+            },
+        ];
+        let middle = apply(&first, base);
+        assert_eq!(middle, b"quick lazy fox");
+        let second = crate::diff_with_block_size(3, &middle, "a quick fox!").unwrap();
+        let flat = compose(&first, &second);
+        assert_eq!(apply(&flat, base), b"a quick fox!");
+        assert!(flat.iter().any(Operation::is_copy));
+    }
+
+    #[test]
+    fn flatten_all_literal() {
+        let base = b"\nbz1\n\nxb";
+        let deltas = vec![
+            vec![Operation::Remove { offset: 0, len: 8 }],
+            vec![Operation::Insert {
+                offset: 0,
+                buffer: b"0yy".to_vec(),
+            }],
+            vec![Operation::Copy {
+                src_offset: 0,
+                len: 3,
+            }],
+        ];
+        let flat = flatten(&deltas);
+        assert!(!flat.iter().any(Operation::is_copy));
+        assert_eq!(apply(&flat, base), b"0yy");
+        // nothing at all is left.
+        let empty = compose(&deltas[2], &[Operation::Remove { offset: 0, len: 3 }]);
+        assert_eq!(apply(&empty, base), b"");
+        // a first delta made of copies, all of them replaced by the second one.
+        let mut sig = crate::Signature::with_block_size(3, &base[..]);
+        sig.calculate();
+        let mut delta = crate::Delta::new(sig.to_indexed());
+        delta.diff_with_copy("1\n\n\nbz").unwrap();
+        let first = delta.operations().to_vec();
+        assert!(first.iter().any(Operation::is_copy));
+        let second = crate::diff_with_block_size(3, "1\n\n\nbz", "zzz").unwrap();
+        let composed = compose(&first, &second);
+        assert_eq!(apply(&composed, base), b"zzz");
+    }
+
+    #[test]
+    fn merge3_clean() {
+        let base = "the quick brown fox jumps over the lazy dog";
+        let a = crate::diff_with_block_size(4, base, "the quick red fox jumps over the lazy dog")
+            .unwrap();
+        let b =
+            crate::diff_with_block_size(4, base, "the quick brown fox jumps over the lazy cat!")
+                .unwrap();
+        let merged = merge3(base.as_bytes(), &a, &b).unwrap();
+        assert_eq!(merged, b"the quick red fox jumps over the lazy cat!");
+        // the other way around is the same.
+        assert_eq!(merge3(base.as_bytes(), &b, &a).unwrap(), merged);
+        // the same change on both sides is taken once.
+        assert_eq!(
+            merge3(base.as_bytes(), &a, &a).unwrap(),
+            apply(&a, base.as_bytes())
+        );
+        // nothing on one side.
+        assert_eq!(
+            merge3(base.as_bytes(), &a, &[]).unwrap(),
+            apply(&a, base.as_bytes())
+        );
+        // explicit copies work too.
+        let copies = vec![
+            Operation::Copy {
+                src_offset: 0,
+                len: 20,
+            },
+            Operation::Insert {
+                offset: 20,
+                buffer: b"leaps".to_vec(),
+            },
+            Operation::Copy {
+                src_offset: 25,
+                len: 18,
+            },
+        ];
+        assert_eq!(
+            merge3(base.as_bytes(), &a, &copies).unwrap(),
+            b"the quick red fox leaps over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn merge3_conflicts() {
+        let base = "the quick brown fox jumps over the lazy dog";
+        let a = crate::diff_with_block_size(4, base, "the quick red fox jumps over the lazy dog")
+            .unwrap();
+        let b = crate::diff_with_block_size(4, base, "the quick blue fox jumps over the lazy cat")
+            .unwrap();
+        let conflicts = merge3(base.as_bytes(), &a, &b).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert!(conflict.base.start <= 10 && conflict.base.end >= 15);
+        assert_eq!(
+            [
+                &base.as_bytes()[..conflict.base.start],
+                &conflict.a[..],
+                &base.as_bytes()[conflict.base.end..]
+            ]
+            .concat(),
+            b"the quick red fox jumps over the lazy dog"
+        );
+        assert_eq!(
+            [
+                &base.as_bytes()[..conflict.base.start],
+                &conflict.b[..],
+                &base.as_bytes()[conflict.base.end..]
+            ]
+            .concat(),
+            b"the quick blue fox jumps over the lazy dog"
+        );
+
+        // two inserts at the same place.
+        let a = vec![Operation::Insert {
+            offset: 3,
+            buffer: b"!".to_vec(),
+        }];
+        let b = vec![Operation::Insert {
+            offset: 3,
+            buffer: b"?".to_vec(),
+        }];
+        let conflicts = merge3(base.as_bytes(), &a, &b).unwrap_err();
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                base: 3..3,
+                a: b"!".to_vec(),
+                b: b"?".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn replacements() {
+        let original = b"hello there, do you know rust?";
+        let ops = vec![
+            Operation::Remove { offset: 0, len: 5 },
+            Operation::Insert {
+                offset: 0,
+                buffer: b"howdy".to_vec(),
+            },
+            Operation::Insert {
+                offset: 25,
+                buffer: b"go".to_vec(),
+            },
+            Operation::Remove { offset: 27, len: 4 },
+        ];
+        let coalesced = coalesce_replacements(&ops);
+        assert_eq!(
+            coalesced,
+            vec![
+                Operation::Replace {
+                    offset: 0,
+                    old_len: 5,
+                    buffer: b"howdy".to_vec(),
+                },
+                Operation::Replace {
+                    offset: 25,
+                    old_len: 4,
+                    buffer: b"go".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(apply(&coalesced, original), apply(&ops, original));
+        assert_eq!(apply(&coalesced, original), b"howdy there, do you know go?");
+        assert_eq!(inserted_bytes(&coalesced), inserted_bytes(&ops));
+        assert_eq!(removed_bytes(&coalesced), removed_bytes(&ops));
+        // the offsets helpers and composing understand them too.
+        assert_eq!(to_new_offsets(&to_original_offsets(&coalesced)), coalesced);
+        assert_eq!(compose(&coalesced, &[]), compose(&ops, &[]));
+        // a remove and an insert in different places stay apart.
+        let apart = vec![
+            Operation::Remove { offset: 0, len: 5 },
+            Operation::Insert {
+                offset: 3,
+                buffer: b"!".to_vec(),
+            },
+        ];
+        assert_eq!(coalesce_replacements(&apart), apart);
+    }
+}
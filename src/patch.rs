@@ -1,90 +1,511 @@
 //! Patching buffer using operations.
-//! this is not ready yet!
-//! it is a bit messy so never mind reading it.
+//!
+//! the patched buffer is written in a single pass over the operations in order, see
+//! [`Patch::apply`] for how each one is applied.
 use log::trace;
-use std::mem;
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::iter::FromIterator;
 use std::str::Utf8Error;
 
 use crate::delta::Operation;
+use crate::encoding::{decode_operations_with_len, ParseError};
+use crate::hash::IndexedSignature;
 
+/// Applies [`Operation`]s on an original buffer to get the new one.
 #[derive(Debug, Clone)]
 pub struct Patch<O: AsRef<[Operation]>> {
     buffer: Vec<u8>,
     ops: O,
+    /// The length of the patched buffer, if the operations came with it.
+    expected_len: Option<usize>,
 }
 
 impl<O: AsRef<[Operation]>> Patch<O> {
+    /// Create a new patch of the given `ops`, see [`Patch::apply`].
     pub fn new(ops: O) -> Self {
         Self {
             buffer: Vec::new(),
             ops,
+            expected_len: None,
         }
     }
 
+    /// Apply the operations on the `original` buffer in the same order they were generated.
+    ///
+    /// the offsets of the operations are positions in the patched buffer, so any bytes between
+    /// two operations are copied as is from the `original` buffer.
+    ///
+    /// if there is any [`Operation::Copy`] the operations are treated as explicit, the patched
+    /// buffer is only made of the copied ranges and the inserted bytes.
+    ///
+    /// returns `true` if there was any operation applied.
     pub fn apply(&mut self, original: impl AsRef<[u8]>) -> bool {
-        trace!("starting new patch with {} op", self.ops.as_ref().len());
-        // noting to patch
-        if self.ops.as_ref().is_empty() {
-            trace!("noting here to patch !");
-            return false;
-        }
-        let mut original_buffer = original.as_ref().iter();
-        trace!("creating new empty buffer for the patched buffer");
-        let mut patched = Vec::new();
-        // seprate the operations.
-        let inserts = self.ops.as_ref().iter().filter(|op| op.is_insert());
-        let removes = self.ops.as_ref().iter().filter(|op| op.is_remove());
-        let mut idx = 0;
-        trace!("starting by the inserts ops first ..");
-        for op in inserts {
-            trace!("current idx: {}", idx);
-            trace!("Insert {}", op);
-            while idx < op.offset() {
-                if let Some(b) = original_buffer.next() {
-                    patched.push(*b);
-                    idx += 1;
-                } else {
-                    break;
-                }
-            }
-            let changes = op.buffer().unwrap();
-            patched.extend(changes);
-            idx += changes.len();
+        let original = original.as_ref();
+        let mut patched = Vec::with_capacity(original.len());
+        let result: Result<(), PatchError> = patch(self.ops.as_ref(), original, |bytes| {
+            patched.extend_from_slice(bytes);
+            Ok(())
+        });
+        debug_assert!(result.is_ok());
+        self.buffer = patched;
+        !self.ops.as_ref().is_empty()
+    }
+
+    /// Same as [`Patch::apply`] but fails with [`PatchError::Truncated`] if the patched buffer
+    /// does not have the length the operations were made for, see [`Patch::decode`].
+    ///
+    /// a patch without an expected length never fails.
+    pub fn apply_checked(&mut self, original: impl AsRef<[u8]>) -> Result<(), PatchError> {
+        self.apply(original);
+        match self.expected_len {
+            Some(len) if len != self.buffer.len() => Err(PatchError::Truncated),
+            _ => Ok(()),
         }
-        trace!("done with inserts ops ..");
-        patched.extend(original_buffer);
-        trace!("switching buffers (original <-> patched)");
-        let original_buffer = mem::replace(&mut patched, Vec::new());
-        let mut original_buffer = original_buffer.iter();
-        idx = 0;
-        trace!("starting removes ops ..");
-        for op in removes {
-            trace!("current idx: {}", idx);
-            trace!("Remove {}", op);
-            while idx < op.offset() {
-                if let Some(b) = original_buffer.next() {
-                    patched.push(*b);
-                    idx += 1;
-                } else {
-                    break;
-                }
-            }
-            trace!("skipping {} bytes..", op.len());
-            for _ in 0..op.len() {
-                original_buffer.next();
+    }
+
+    /// Same as [`Patch::apply`] but writes the patched buffer into `out` instead.
+    ///
+    /// returns how many bytes were written, or [`PatchError::OutputTooSmall`] if the patched
+    /// buffer does not fit, in that case the content of `out` is unspecified.
+    pub fn apply_into(&self, original: &[u8], out: &mut [u8]) -> Result<usize, PatchError> {
+        let mut written = 0;
+        patch(self.ops.as_ref(), original, |bytes| {
+            let end = written + bytes.len();
+            match out.get_mut(written..end) {
+                Some(dst) => dst.copy_from_slice(bytes),
+                None => return Err(PatchError::OutputTooSmall { len: out.len() }),
             }
-        }
+            written = end;
+            Ok(())
+        })?;
+        Ok(written)
+    }
 
-        patched.extend(original_buffer);
-        self.buffer = patched;
-        self.buffer.is_empty()
+    /// Same as [`Patch::apply`] but then checks the patched buffer against the `expected`
+    /// signature of the new buffer.
+    ///
+    /// returns `true` only if the patched buffer matches the `expected` signature, this catches
+    /// both corrupted operations and patching the wrong original buffer.
+    pub fn apply_verified(
+        &mut self,
+        original: impl AsRef<[u8]>,
+        expected: &IndexedSignature,
+    ) -> bool {
+        self.apply(original);
+        expected.verify(&self.buffer)
     }
 
+    /// The patched buffer, empty until the patch is applied.
     pub fn buffer(&self) -> &[u8] {
         &self.buffer
     }
 
+    /// Same as [`Patch::buffer`] but as a `str`.
     pub fn buffer_utf8(&self) -> Result<&str, Utf8Error> {
         std::str::from_utf8(&self.buffer)
     }
 }
+
+/// Collect operations into a patch, handy with decoders that produce them one by one.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{Operation, Patch};
+///
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// let script = rsdiff::to_ed_script(&ops);
+/// let mut patch: Patch<Vec<Operation>> = rsdiff::from_ed_script(&script)
+///     .unwrap()
+///     .into_iter()
+///     .collect();
+/// patch.apply("i saw a red fox");
+/// assert_eq!(patch.buffer(), b"i saw a red box");
+/// ```
+impl Patch<Vec<Operation>> {
+    /// Decode a patch from operations encoded with [`crate::encode_operations_with_len`] (or
+    /// any other encoder, without the length check then).
+    ///
+    /// returns [`PatchError::Truncated`] if the `bytes` end before all the operations and the
+    /// length of the patched buffer are there, [`Patch::apply_checked`] then checks the length.
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{Patch, PatchError};
+    ///
+    /// let new = "i saw a red box";
+    /// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", new).unwrap();
+    /// let bytes = rsdiff::encode_operations_with_len(&ops, new.len());
+    /// let mut patch = Patch::decode(&bytes).unwrap();
+    /// patch.apply_checked("i saw a red fox").unwrap();
+    /// assert_eq!(patch.buffer(), new.as_bytes());
+    /// // cut in transfer.
+    /// assert!(matches!(Patch::decode(&bytes[..bytes.len() - 1]), Err(PatchError::Truncated)));
+    /// ```
+    pub fn decode(bytes: &[u8]) -> Result<Self, PatchError> {
+        let (ops, expected_len) = decode_operations_with_len(bytes).map_err(|e| match e {
+            ParseError::UnexpectedEof => PatchError::Truncated,
+            e => PatchError::Parse(e),
+        })?;
+        let mut patch = Self::new(ops);
+        patch.expected_len = expected_len;
+        Ok(patch)
+    }
+}
+
+impl FromIterator<Operation> for Patch<Vec<Operation>> {
+    fn from_iter<I: IntoIterator<Item = Operation>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+/// Errors that could happen while patching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The output buffer of `len` bytes can't hold the patched buffer.
+    OutputTooSmall { len: usize },
+    /// The operations were cut short, or the patched buffer is not as long as the one they
+    /// were made for.
+    Truncated,
+    /// The encoded operations are not valid.
+    Parse(ParseError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::OutputTooSmall { len } => {
+                write!(f, "output buffer of {} bytes is too small", len)
+            }
+            PatchError::Truncated => write!(f, "the operations are truncated"),
+            PatchError::Parse(e) => write!(f, "bad operations: {}", e),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+/// Apply the `ops` on the `original` buffer without holding the whole patched buffer, instead
+/// `sink` is called with the patched bytes in order, `chunk_size` bytes at a time (the last
+/// chunk could be smaller).
+///
+/// see [`Patch::apply`] for how the operations are applied.
+///
+/// ### Example
+/// ```
+/// let original = "i saw a red fox";
+/// let ops = rsdiff::diff_with_block_size(4, original, "i saw a red box").unwrap();
+/// let mut patched = Vec::new();
+/// rsdiff::apply_chunked(original, &ops, 4, |chunk| patched.extend_from_slice(chunk));
+/// assert_eq!(patched, b"i saw a red box");
+/// ```
+pub fn apply_chunked<F: FnMut(&[u8])>(
+    original: impl AsRef<[u8]>,
+    ops: &[Operation],
+    chunk_size: usize,
+    mut sink: F,
+) {
+    assert!(chunk_size != 0, "chunk size must be > 0");
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let result: Result<(), PatchError> = patch(ops, original.as_ref(), |mut bytes| {
+        while !bytes.is_empty() {
+            let n = cmp::min(chunk_size - chunk.len(), bytes.len());
+            chunk.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if chunk.len() == chunk_size {
+                sink(&chunk);
+                chunk.clear();
+            }
+        }
+        Ok(())
+    });
+    debug_assert!(result.is_ok());
+    if !chunk.is_empty() {
+        sink(&chunk);
+    }
+}
+
+/// Apply the `ops` on the `original` buffer, every piece of the patched buffer is passed to
+/// `write` in order.
+fn patch(
+    ops: &[Operation],
+    original: &[u8],
+    mut write: impl FnMut(&[u8]) -> Result<(), PatchError>,
+) -> Result<(), PatchError> {
+    trace!("starting new patch with {} op", ops.len());
+    let explicit = ops.iter().any(Operation::is_copy);
+    trace!("explicit = {}", explicit);
+    // where we are in the original buffer.
+    let mut cursor = 0;
+    // how many bytes we wrote so far.
+    let mut written = 0;
+    for op in ops {
+        trace!("{}", op);
+        if !explicit {
+            // copy the unchanged bytes up to the operation offset.
+            let gap = op.offset().saturating_sub(written);
+            let end = cmp::min(cursor + gap, original.len());
+            write(&original[cursor..end])?;
+            written += end - cursor;
+            cursor = end;
+        }
+        match op {
+            Operation::Insert { buffer, .. } => {
+                write(buffer)?;
+                written += buffer.len();
+            }
+            Operation::Remove { len, .. } => {
+                trace!("skipping {} bytes..", len);
+                cursor = cmp::min(cursor + len, original.len());
+            }
+            Operation::Copy { src_offset, len } => {
+                let start = cmp::min(*src_offset, original.len());
+                let end = cmp::min(start + len, original.len());
+                write(&original[start..end])?;
+                written += end - start;
+                cursor = end;
+            }
+            Operation::Replace {
+                old_len, buffer, ..
+            } => {
+                trace!("skipping {} bytes..", old_len);
+                cursor = cmp::min(cursor + old_len, original.len());
+                write(buffer)?;
+                written += buffer.len();
+            }
+        }
+    }
+    if !explicit {
+        trace!("copying the remaining {} bytes", original.len() - cursor);
+        write(&original[cursor..])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn apply(ops: &[Operation], original: &str) -> Vec<u8> {
+        let mut patch = Patch::new(ops);
+        patch.apply(original);
+        patch.buffer().to_vec()
+    }
+
+    #[test]
+    fn copy() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let legacy = crate::diff_with_block_size(5, original, new).unwrap();
+        let copies = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"hi, do".to_vec(),
+            },
+            Operation::Copy {
+                src_offset: 15,
+                len: 10,
+            },
+            Operation::Insert {
+                offset: 16,
+                buffer: b"about rustlang?".to_vec(),
+            },
+        ];
+        assert_eq!(apply(&copies, original), new.as_bytes());
+        assert_eq!(apply(&copies, original), apply(&legacy, original));
+    }
+
+    #[test]
+    fn copy_drops_the_rest() {
+        let original = "i saw a red fox";
+        let ops = vec![
+            Operation::Copy {
+                src_offset: 0,
+                len: 5,
+            },
+            // a legacy remove in an explicit stream only moves the cursor.
+            Operation::Remove { offset: 5, len: 3 },
+            Operation::Copy {
+                src_offset: 8,
+                len: 3,
+            },
+        ];
+        assert_eq!(apply(&ops, original), b"i sawred");
+    }
+
+    #[test]
+    fn verified() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let mut signature = crate::Signature::with_block_size(5, new);
+        signature.calculate();
+        let expected = signature.to_indexed();
+
+        let mut ops = crate::diff_with_block_size(5, original, new).unwrap();
+        assert!(Patch::new(&ops).apply_verified(original, &expected));
+        // wrong base.
+        assert!(!Patch::new(&ops).apply_verified("hello there, do you KNOW rust?", &expected));
+        // tampered delta.
+        if let Operation::Insert { buffer, .. } = &mut ops[0] {
+            buffer[0] = b'H';
+        }
+        assert!(!Patch::new(&ops).apply_verified(original, &expected));
+    }
+
+    #[test]
+    fn apply_into() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let ops = crate::diff_with_block_size(5, original, new).unwrap();
+        let patch = Patch::new(&ops);
+
+        let mut out = vec![0; new.len()];
+        assert_eq!(
+            patch.apply_into(original.as_bytes(), &mut out),
+            Ok(new.len())
+        );
+        assert_eq!(out, new.as_bytes());
+
+        let mut out = vec![0; new.len() + 10];
+        assert_eq!(
+            patch.apply_into(original.as_bytes(), &mut out),
+            Ok(new.len())
+        );
+        assert_eq!(&out[..new.len()], new.as_bytes());
+
+        let mut out = vec![0; new.len() - 1];
+        assert_eq!(
+            patch.apply_into(original.as_bytes(), &mut out),
+            Err(PatchError::OutputTooSmall { len: new.len() - 1 })
+        );
+        assert_eq!(
+            patch.apply_into(original.as_bytes(), &mut []),
+            Err(PatchError::OutputTooSmall { len: 0 })
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let ops = crate::diff_with_block_size(5, original, new).unwrap();
+        let bytes = crate::encode_operations_with_len(&ops, new.len());
+        let mut patch = Patch::decode(&bytes).unwrap();
+        assert_eq!(patch.apply_checked(original), Ok(()));
+        assert_eq!(patch.buffer(), new.as_bytes());
+        // cut anywhere.
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(Patch::decode(&bytes[..len]), Err(PatchError::Truncated)),
+                "len = {}",
+                len
+            );
+        }
+        // an operation lost on the way, the rest is still valid.
+        let lost = crate::encode_operations_with_len(&ops[1..], new.len());
+        let mut patch = Patch::decode(&lost).unwrap();
+        assert_eq!(patch.apply_checked(original), Err(PatchError::Truncated));
+        // without the length there is nothing to check.
+        let mut patch = Patch::decode(&crate::encode_operations(&ops[1..])).unwrap();
+        assert_eq!(patch.apply_checked(original), Ok(()));
+        assert!(matches!(
+            Patch::decode(b"RSDS\x03"),
+            Err(PatchError::Parse(ParseError::BadMagic))
+        ));
+    }
+
+    #[test]
+    fn chunked() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let ops = crate::diff_with_block_size(5, original, new).unwrap();
+        for chunk_size in 1..40 {
+            let mut chunks = Vec::new();
+            apply_chunked(original, &ops, chunk_size, |chunk| {
+                chunks.push(chunk.to_vec())
+            });
+            assert_eq!(chunks.concat(), new.as_bytes());
+            assert!(chunks.iter().all(|chunk| chunk.len() <= chunk_size));
+            assert_eq!(chunks.len(), new.len().div_ceil(chunk_size));
+        }
+        let mut called = false;
+        apply_chunked("", &[], 8, |_| called = true);
+        assert!(!called);
+    }
+
+    /// `len` random bytes of a small alphabet, so blocks and lines repeat now and then.
+    fn alphabet_bytes(rng: &mut Rng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ab\nxyz01"[rng.below(8)]).collect()
+    }
+
+    /// Insert, remove and replace random ranges of the `buffer`.
+    fn edit(rng: &mut Rng, buffer: &[u8]) -> Vec<u8> {
+        let mut edited = buffer.to_vec();
+        for _ in 0..rng.below(6) {
+            let at = rng.below(edited.len() + 1);
+            let len = cmp::min(rng.below(20), edited.len() - at);
+            match rng.below(3) {
+                0 => {
+                    let bytes = alphabet_bytes(rng, len + 1);
+                    edited.splice(at..at, bytes);
+                }
+                1 => {
+                    edited.drain(at..at + len);
+                }
+                _ => {
+                    let new_len = rng.below(20);
+                    let bytes = alphabet_bytes(rng, new_len);
+                    edited.splice(at..at + len, bytes);
+                }
+            }
+        }
+        edited
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Rng::new(0x2545F4914F6CDD1D);
+        for _ in 0..100 {
+            let len = rng.below(200);
+            let original = alphabet_bytes(&mut rng, len);
+            let new = edit(&mut rng, &original);
+            for block_size in &[1, 2, 3, 5, 8, 16, 64] {
+                let mut signature = crate::Signature::with_block_size(*block_size, &original);
+                signature.calculate();
+                let mut all =
+                    vec![crate::diff_with_block_size(*block_size, &original, &new).unwrap()];
+                signature.calculate_lines();
+                let mut delta = crate::Delta::new(signature.to_indexed());
+                delta.diff(&new).unwrap();
+                all.push(delta.into_operations());
+                let parts = IndexedSignature::from_parts(&[&original], *block_size);
+                let mut delta = crate::Delta::new(parts);
+                delta.diff(&new).unwrap();
+                all.push(delta.into_operations());
+                for ops in all {
+                    let mut patch = Patch::new(&ops);
+                    patch.apply(&original);
+                    assert_eq!(
+                        patch.buffer(),
+                        &new[..],
+                        "{:?} -> {:?} with {:?}",
+                        String::from_utf8_lossy(&original),
+                        String::from_utf8_lossy(&new),
+                        ops
+                    );
+                    let mut out = vec![0; new.len()];
+                    assert_eq!(patch.apply_into(&original, &mut out), Ok(new.len()));
+                    assert_eq!(out, new);
+                    let mut chunks = Vec::new();
+                    apply_chunked(&original, &ops, 7, |chunk| chunks.extend_from_slice(chunk));
+                    assert_eq!(chunks, new);
+                }
+            }
+            // no change at all.
+            let ops = crate::diff_with_block_size(4, &original, &original).unwrap();
+            assert!(!Patch::new(&ops).apply(&original));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// This file is @generated by prost-build from `proto/rsdiff.proto`, generate it again when the
+// schema changes instead of editing it.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Insert {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub buffer: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Remove {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub len: u64,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Copy {
+    #[prost(uint64, tag = "1")]
+    pub src_offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub len: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replace {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub old_len: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    pub buffer: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Operation {
+    #[prost(oneof = "operation::Op", tags = "1, 2, 3, 4")]
+    pub op: ::core::option::Option<operation::Op>,
+}
+/// Nested message and enum types in `Operation`.
+pub mod operation {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        #[prost(message, tag = "1")]
+        Insert(super::Insert),
+        #[prost(message, tag = "2")]
+        Remove(super::Remove),
+        #[prost(message, tag = "3")]
+        Copy(super::Copy),
+        #[prost(message, tag = "4")]
+        Replace(super::Replace),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Operations {
+    #[prost(message, repeated, tag = "1")]
+    pub operations: ::prost::alloc::vec::Vec<Operation>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Block {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(fixed32, tag = "2")]
+    pub weak_hash: u32,
+    /// as many bytes as the `CryptoHash` of the signature keeps.
+    #[prost(bytes = "vec", tag = "3")]
+    pub crypto_hash: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Offsets {
+    #[prost(uint64, repeated, tag = "1")]
+    pub offsets: ::prost::alloc::vec::Vec<u64>,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Fixed {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Signature {
+    #[prost(uint64, tag = "1")]
+    pub original_buffer_len: u64,
+    #[prost(uint64, tag = "2")]
+    pub block_size: u64,
+    #[prost(message, repeated, tag = "7")]
+    pub blocks: ::prost::alloc::vec::Vec<Block>,
+    /// the 16 bytes secret the hashes of the blocks are salted with, if any.
+    #[prost(bytes = "vec", tag = "8")]
+    pub salt: ::prost::alloc::vec::Vec<u8>,
+    #[prost(enumeration = "CryptoHash", tag = "9")]
+    pub crypto_hash: i32,
+    #[prost(oneof = "signature::Chunking", tags = "3, 4, 5, 6")]
+    pub chunking: ::core::option::Option<signature::Chunking>,
+}
+/// Nested message and enum types in `Signature`.
+pub mod signature {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Chunking {
+        #[prost(message, tag = "3")]
+        Fixed(super::Fixed),
+        #[prost(uint64, tag = "4")]
+        Strided(u64),
+        #[prost(message, tag = "5")]
+        Lines(super::Offsets),
+        #[prost(message, tag = "6")]
+        Parts(super::Offsets),
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CryptoHash {
+    /// the first 32 bytes of Blake2b.
+    Blake2b = 0,
+    /// Blake2s-256.
+    Blake2s = 1,
+    /// the first 16 bytes of Blake2b.
+    Blake2b128 = 2,
+    /// Blake3, only known with the `blake3` feature.
+    Blake3 = 3,
+}
+impl CryptoHash {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Blake2b => "BLAKE2B",
+            Self::Blake2s => "BLAKE2S",
+            Self::Blake2b128 => "BLAKE2B_128",
+            Self::Blake3 => "BLAKE3",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "BLAKE2B" => Some(Self::Blake2b),
+            "BLAKE2S" => Some(Self::Blake2s),
+            "BLAKE2B_128" => Some(Self::Blake2b128),
+            "BLAKE3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+}
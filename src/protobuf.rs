@@ -0,0 +1,471 @@
+//! Protobuf encoding of [`IndexedSignature`] and [`Operation`]s, behind the `protobuf` feature.
+//!
+//! the messages are described in `proto/rsdiff.proto` and generated by prost into
+//! [`crate::proto`], here are the conversions between them and our types, so a gRPC service can
+//! use the messages directly.
+//!
+//! like proto3 does, scalar fields with the default value are not written and missing fields
+//! are decoded as their default value, unknown fields are skipped.
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use prost::Message;
+
+use crate::delta::Operation;
+use crate::encoding::{check_layout, ParseError};
+use crate::hash::{BlockHash, Chunking, CryptoHash, HashKind, IndexedSignature};
+use crate::proto::{self, operation::Op, signature};
+
+// the fields of the messages, for the errors.
+const INSERT: u32 = 1;
+const CRYPTO_HASH: u32 = 3;
+const SALT: u32 = 8;
+const HASH_KIND: u32 = 9;
+
+/// Encode the `ops` as an `Operations` protobuf message.
+///
+/// see [`decode_operations_protobuf`] for decoding it back.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// let bytes = rsdiff::encode_operations_protobuf(&ops);
+/// assert_eq!(rsdiff::decode_operations_protobuf(&bytes), Ok(ops));
+/// ```
+pub fn encode_operations_protobuf(ops: &[Operation]) -> Vec<u8> {
+    proto::Operations {
+        operations: ops.iter().map(proto::Operation::from).collect(),
+    }
+    .encode_to_vec()
+}
+
+/// Decode an `Operations` protobuf message, like the one of [`encode_operations_protobuf`].
+pub fn decode_operations_protobuf(bytes: &[u8]) -> Result<Vec<Operation>, ParseError> {
+    proto::Operations::decode(bytes)?
+        .operations
+        .into_iter()
+        .map(Operation::try_from)
+        .collect()
+}
+
+impl From<prost::DecodeError> for ParseError {
+    fn from(err: prost::DecodeError) -> Self {
+        ParseError::Protobuf(err.to_string())
+    }
+}
+
+impl From<&Operation> for proto::Operation {
+    fn from(op: &Operation) -> Self {
+        let op = match op {
+            Operation::Insert { offset, buffer } => Op::Insert(proto::Insert {
+                offset: *offset as u64,
+                buffer: buffer.clone(),
+            }),
+            Operation::Remove { offset, len } => Op::Remove(proto::Remove {
+                offset: *offset as u64,
+                len: *len as u64,
+            }),
+            Operation::Copy { src_offset, len } => Op::Copy(proto::Copy {
+                src_offset: *src_offset as u64,
+                len: *len as u64,
+            }),
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => Op::Replace(proto::Replace {
+                offset: *offset as u64,
+                old_len: *old_len as u64,
+                buffer: buffer.clone(),
+            }),
+        };
+        proto::Operation { op: Some(op) }
+    }
+}
+
+impl TryFrom<proto::Operation> for Operation {
+    type Error = ParseError;
+
+    fn try_from(op: proto::Operation) -> Result<Self, ParseError> {
+        // an operation without any of the oneof members.
+        Ok(match op.op.ok_or(ParseError::BadField(INSERT))? {
+            Op::Insert(insert) => Operation::Insert {
+                offset: usize(insert.offset)?,
+                buffer: insert.buffer,
+            },
+            Op::Remove(remove) => Operation::Remove {
+                offset: usize(remove.offset)?,
+                len: usize(remove.len)?,
+            },
+            Op::Copy(copy) => Operation::Copy {
+                src_offset: usize(copy.src_offset)?,
+                len: usize(copy.len)?,
+            },
+            Op::Replace(replace) => Operation::Replace {
+                offset: usize(replace.offset)?,
+                old_len: usize(replace.old_len)?,
+                buffer: replace.buffer,
+            },
+        })
+    }
+}
+
+impl From<&IndexedSignature> for proto::Signature {
+    fn from(sig: &IndexedSignature) -> Self {
+        let offsets = |offsets: &[usize]| proto::Offsets {
+            offsets: offsets.iter().map(|offset| *offset as u64).collect(),
+        };
+        let chunking = match &sig.chunking {
+            Chunking::Fixed => signature::Chunking::Fixed(proto::Fixed {}),
+            Chunking::Strided(stride) => signature::Chunking::Strided(*stride as u64),
+            Chunking::Lines(starts) => signature::Chunking::Lines(offsets(starts)),
+            Chunking::Parts(starts) => signature::Chunking::Parts(offsets(starts)),
+        };
+        let mut blocks: Vec<_> = sig.blocks.values().flatten().collect();
+        blocks.sort_by_key(|(idx, _)| *idx);
+        let crypto_hash = match sig.hash {
+            HashKind::Blake2b => proto::CryptoHash::Blake2b,
+            HashKind::Blake2s => proto::CryptoHash::Blake2s,
+            HashKind::Blake2b128 => proto::CryptoHash::Blake2b128,
+            #[cfg(feature = "blake3")]
+            HashKind::Blake3 => proto::CryptoHash::Blake3,
+        };
+        proto::Signature {
+            original_buffer_len: sig.original_buffer_len as u64,
+            block_size: sig.block_size as u64,
+            chunking: Some(chunking),
+            blocks: blocks
+                .into_iter()
+                .map(|(idx, block)| proto::Block {
+                    index: *idx as u64,
+                    weak_hash: block.weak_hash,
+                    crypto_hash: block.crypto_hash[..sig.hash.size()].to_vec(),
+                })
+                .collect(),
+            salt: sig.salt.map(|salt| salt.to_vec()).unwrap_or_default(),
+            crypto_hash: crypto_hash.into(),
+        }
+    }
+}
+
+impl TryFrom<proto::Signature> for IndexedSignature {
+    type Error = ParseError;
+
+    /// fails if the signature can't be diffed against, like a block size of zero or offsets
+    /// out of order, the same as [`IndexedSignature::from_bytes`] does.
+    fn try_from(sig: proto::Signature) -> Result<Self, ParseError> {
+        let offsets = |offsets: proto::Offsets| -> Result<Vec<usize>, ParseError> {
+            offsets.offsets.into_iter().map(usize).collect()
+        };
+        let chunking = match sig.chunking {
+            None | Some(signature::Chunking::Fixed(_)) => Chunking::Fixed,
+            Some(signature::Chunking::Strided(stride)) => Chunking::Strided(usize(stride)?),
+            Some(signature::Chunking::Lines(starts)) => Chunking::Lines(offsets(starts)?),
+            Some(signature::Chunking::Parts(starts)) => Chunking::Parts(offsets(starts)?),
+        };
+        let original_buffer_len = usize(sig.original_buffer_len)?;
+        let block_size = usize(sig.block_size)?;
+        check_layout(original_buffer_len, block_size, &chunking)?;
+        let hash = match proto::CryptoHash::try_from(sig.crypto_hash) {
+            Ok(proto::CryptoHash::Blake2b) => HashKind::Blake2b,
+            Ok(proto::CryptoHash::Blake2s) => HashKind::Blake2s,
+            Ok(proto::CryptoHash::Blake2b128) => HashKind::Blake2b128,
+            #[cfg(feature = "blake3")]
+            Ok(proto::CryptoHash::Blake3) => HashKind::Blake3,
+            #[cfg(not(feature = "blake3"))]
+            Ok(proto::CryptoHash::Blake3) => return Err(ParseError::BadField(HASH_KIND)),
+            Err(_) => return Err(ParseError::BadField(HASH_KIND)),
+        };
+        let salt = match sig.salt.len() {
+            0 => None,
+            _ => Some(
+                sig.salt[..]
+                    .try_into()
+                    .map_err(|_| ParseError::BadField(SALT))?,
+            ),
+        };
+        let mut blocks = HashMap::with_capacity(sig.blocks.len());
+        for block in sig.blocks {
+            if block.crypto_hash.len() != hash.size() {
+                return Err(ParseError::BadField(CRYPTO_HASH));
+            }
+            let hashes = BlockHash {
+                weak_hash: block.weak_hash,
+                crypto_hash: CryptoHash::truncated(&block.crypto_hash, hash.size()),
+            };
+            blocks
+                .entry(block.weak_hash)
+                .or_insert_with(Vec::new)
+                .push((usize(block.index)?, hashes));
+        }
+        // keep the duplicates in the order of their index, like the other decoders do.
+        for candidates in blocks.values_mut() {
+            candidates.sort_by_key(|(idx, _)| *idx);
+        }
+        Ok(Self {
+            original_buffer_len,
+            block_size,
+            blocks,
+            chunking,
+            salt,
+            hash,
+        })
+    }
+}
+
+impl IndexedSignature {
+    /// Encode the signature as a `Signature` protobuf message.
+    ///
+    /// the blocks are written in the order of their index, so the output is stable for the
+    /// same signature.
+    ///
+    /// see [`IndexedSignature::from_protobuf`] for decoding it back.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        proto::Signature::from(self).encode_to_vec()
+    }
+
+    /// Decode a `Signature` protobuf message, like the one of [`IndexedSignature::to_protobuf`].
+    ///
+    /// ### Example
+    /// ```
+    /// use rsdiff::{IndexedSignature, Signature};
+    ///
+    /// let mut signature = Signature::with_block_size(4, "i saw a red fox");
+    /// signature.calculate();
+    /// let indexed = signature.to_indexed();
+    /// let bytes = indexed.to_protobuf();
+    /// assert_eq!(IndexedSignature::from_protobuf(&bytes), Ok(indexed));
+    /// ```
+    pub fn from_protobuf(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::try_from(proto::Signature::decode(bytes)?)
+    }
+}
+
+fn usize(value: u64) -> Result<usize, ParseError> {
+    value.try_into().map_err(|_| ParseError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Signature;
+
+    #[test]
+    fn operations_roundtrip() {
+        let ops = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"hi, do".to_vec(),
+            },
+            Operation::Remove { offset: 6, len: 15 },
+            Operation::Copy {
+                src_offset: 0,
+                len: 300,
+            },
+            Operation::Replace {
+                offset: 16,
+                old_len: 4,
+                buffer: b"rustlang".to_vec(),
+            },
+            // all defaults, so the body is empty.
+            Operation::Insert {
+                offset: 0,
+                buffer: Vec::new(),
+            },
+        ];
+        let bytes = encode_operations_protobuf(&ops);
+        assert_eq!(decode_operations_protobuf(&bytes), Ok(ops.clone()));
+        assert_eq!(decode_operations_protobuf(&[]), Ok(Vec::new()));
+        // the messages are the generated ones.
+        let message = proto::Operations::decode(&bytes[..]).unwrap();
+        assert_eq!(
+            message.operations[1],
+            proto::Operation {
+                op: Some(Op::Remove(proto::Remove { offset: 6, len: 15 }))
+            }
+        );
+        let decoded: Result<Vec<_>, _> = message
+            .operations
+            .into_iter()
+            .map(Operation::try_from)
+            .collect();
+        assert_eq!(decoded, Ok(ops));
+        // what any other protobuf encoder writes for `Remove { offset: 6, len: 15 }`.
+        assert_eq!(
+            encode_operations_protobuf(&[Operation::Remove { offset: 6, len: 15 }]),
+            [0x0a, 0x06, 0x12, 0x04, 0x08, 0x06, 0x10, 0x0f]
+        );
+    }
+
+    #[test]
+    fn signature_roundtrip() {
+        let mut signature = Signature::with_block_size(4, "my name\nis shady\nkhalifa");
+        signature.calculate();
+        let indexed = signature.to_indexed();
+        assert_eq!(
+            IndexedSignature::from_protobuf(&indexed.to_protobuf()),
+            Ok(indexed.clone())
+        );
+        let message = proto::Signature::from(&indexed);
+        assert_eq!(message.block_size, 4);
+        assert_eq!(message.blocks.len(), 6);
+        assert_eq!(
+            message.chunking,
+            Some(signature::Chunking::Fixed(proto::Fixed {}))
+        );
+        assert_eq!(IndexedSignature::try_from(message), Ok(indexed));
+
+        signature.calculate_lines();
+        let indexed = signature.to_indexed();
+        assert_eq!(
+            IndexedSignature::from_protobuf(&indexed.to_protobuf()),
+            Ok(indexed)
+        );
+
+        // duplicated blocks are all kept.
+        let mut signature = Signature::with_block_size(4, "abcdabcdabcd");
+        signature.calculate();
+        let indexed = signature.to_indexed();
+        assert_eq!(
+            IndexedSignature::from_protobuf(&indexed.to_protobuf()),
+            Ok(indexed.clone())
+        );
+
+        let salted = indexed.with_salt([7; 16]);
+        assert_eq!(proto::Signature::from(&salted).salt, [7; 16]);
+        assert_eq!(
+            IndexedSignature::from_protobuf(&salted.to_protobuf()),
+            Ok(salted)
+        );
+
+        // the crypto hash is kept, and only as many bytes of it as it has.
+        for kind in &HashKind::all()[1..] {
+            let mut signature = Signature::with_block_size(4, "abcdabcdabcd").with_hash(*kind);
+            signature.calculate();
+            let indexed = signature.to_indexed().with_salt([7; 16]);
+            let bytes = indexed.to_protobuf();
+            assert_eq!(IndexedSignature::from_protobuf(&bytes), Ok(indexed.clone()));
+            let mut blake2b = indexed.clone();
+            blake2b.hash = HashKind::Blake2b;
+            let other = IndexedSignature::from_protobuf(&blake2b.to_protobuf()).unwrap();
+            assert_eq!(other.hash_kind(), HashKind::Blake2b);
+            if *kind == HashKind::Blake2b128 {
+                assert!(bytes.len() < blake2b.to_protobuf().len());
+            }
+        }
+        // a kind of a newer schema.
+        let newer = proto::Signature {
+            block_size: 4,
+            crypto_hash: 7,
+            ..Default::default()
+        };
+        assert_eq!(
+            IndexedSignature::from_protobuf(&newer.encode_to_vec()),
+            Err(ParseError::BadField(HASH_KIND))
+        );
+        // and blake3 is only known with its feature.
+        #[cfg(not(feature = "blake3"))]
+        {
+            let blake3 = proto::Signature {
+                block_size: 4,
+                crypto_hash: proto::CryptoHash::Blake3 as i32,
+                ..Default::default()
+            };
+            assert_eq!(
+                IndexedSignature::from_protobuf(&blake3.encode_to_vec()),
+                Err(ParseError::BadField(HASH_KIND))
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_and_bad_fields() {
+        let ops = vec![Operation::Remove { offset: 6, len: 15 }];
+        let mut bytes = encode_operations_protobuf(&ops);
+        // an unknown varint, fixed64 and length delimited field of a newer schema.
+        bytes.extend_from_slice(&[0x10, 0x01, 0x19, 0, 0, 0, 0, 0, 0, 0, 0, 0x22, 0x01, 0xff]);
+        assert_eq!(decode_operations_protobuf(&bytes), Ok(ops));
+        // `operations` as a varint.
+        assert!(matches!(
+            decode_operations_protobuf(&[0x08, 0x01]),
+            Err(ParseError::Protobuf(_))
+        ));
+        // an operation without any kind.
+        assert_eq!(
+            decode_operations_protobuf(&[0x0a, 0x00]),
+            Err(ParseError::BadField(INSERT))
+        );
+        assert!(matches!(
+            decode_operations_protobuf(&[0x0a, 0x06, 0x12]),
+            Err(ParseError::Protobuf(_))
+        ));
+        // a crypto hash of 3 bytes.
+        let block = |crypto_hash: Vec<u8>, kind: proto::CryptoHash| proto::Signature {
+            block_size: 4,
+            blocks: vec![proto::Block {
+                index: 0,
+                weak_hash: 1,
+                crypto_hash,
+            }],
+            crypto_hash: kind.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            IndexedSignature::try_from(block(vec![1, 2, 3], proto::CryptoHash::Blake2b)),
+            Err(ParseError::BadField(CRYPTO_HASH))
+        );
+        // 16 bytes, but the signature says blake2b.
+        assert_eq!(
+            IndexedSignature::try_from(block(vec![7; 16], proto::CryptoHash::Blake2b)),
+            Err(ParseError::BadField(CRYPTO_HASH))
+        );
+        assert!(
+            IndexedSignature::try_from(block(vec![7; 16], proto::CryptoHash::Blake2b128)).is_ok()
+        );
+        // a salt of the wrong size.
+        let mut salted = block(vec![7; 32], proto::CryptoHash::Blake2b);
+        salted.salt = b"abc".to_vec();
+        assert_eq!(
+            IndexedSignature::try_from(salted),
+            Err(ParseError::BadField(SALT))
+        );
+    }
+
+    #[test]
+    fn bad_layout() {
+        let signature = |block_size, chunking| proto::Signature {
+            original_buffer_len: 16,
+            block_size,
+            chunking: Some(chunking),
+            ..Default::default()
+        };
+        let offsets = |offsets: &[u64]| proto::Offsets {
+            offsets: offsets.to_vec(),
+        };
+        let fixed = signature::Chunking::Fixed(proto::Fixed {});
+        assert!(IndexedSignature::try_from(signature(4, fixed.clone())).is_ok());
+        assert_eq!(
+            IndexedSignature::try_from(signature(0, fixed)),
+            Err(ParseError::BadBlockSize)
+        );
+        assert_eq!(
+            IndexedSignature::try_from(signature(4, signature::Chunking::Strided(0))),
+            Err(ParseError::BadBlockSize)
+        );
+        // parts could be empty, lines can't.
+        let parts = signature::Chunking::Parts(offsets(&[0, 8, 8]));
+        assert!(IndexedSignature::try_from(signature(4, parts)).is_ok());
+        let lines = signature::Chunking::Lines(offsets(&[0, 8, 8]));
+        assert_eq!(
+            IndexedSignature::try_from(signature(4, lines)),
+            Err(ParseError::BadOffsets)
+        );
+        for starts in &[&[0, 8, 4][..], &[0, 20]] {
+            let parts = signature::Chunking::Parts(offsets(starts));
+            assert_eq!(
+                IndexedSignature::from_protobuf(&signature(4, parts).encode_to_vec()),
+                Err(ParseError::BadOffsets)
+            );
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Diff quality checks, the deltas are compared against the minimal edit script found by the
+//! Myers algorithm.
+//!
+//! the cost of a delta is how many bytes it inserts plus how many it removes, which for the
+//! Myers edit script is the edit distance. a block based delta can't beat that, but it should
+//! stay within [`FACTOR`] times the edit distance plus [`SLACK_BLOCKS`] blocks for every change,
+//! since a single changed byte costs a whole block.
+use crate::delta::Operation;
+use crate::operations::{inserted_bytes, removed_bytes};
+
+/// How many times the minimal cost a delta is allowed to be.
+const FACTOR: usize = 2;
+
+/// How many extra blocks every change is allowed to cost.
+const SLACK_BLOCKS: usize = 2;
+
+/// The minimal number of inserted plus removed bytes to turn `a` into `b`, using the Myers
+/// O(ND) algorithm.
+fn myers(a: &[u8], b: &[u8]) -> usize {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    // the furthest x reached on every diagonal k = x - y.
+    let mut v = vec![0isize; 2 * max + 3];
+    for d in 0..=max as isize {
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                return d as usize;
+            }
+        }
+    }
+    unreachable!("the edit distance is at most n + m")
+}
+
+fn cost(ops: &[Operation]) -> usize {
+    inserted_bytes(ops) + removed_bytes(ops)
+}
+
+/// The number of changes, an insert right before a remove is a single change.
+fn changes(ops: &[Operation]) -> usize {
+    ops.iter()
+        .enumerate()
+        .filter(|(i, op)| !(op.is_remove() && *i > 0 && ops[i - 1].is_insert()))
+        .count()
+}
+
+/// The delta cost and the maximum allowed cost.
+fn check(a: &str, b: &str, block_size: usize) -> (usize, usize) {
+    let ops = crate::diff_with_block_size(block_size, a, b).unwrap();
+    let minimal = myers(a.as_bytes(), b.as_bytes());
+    let cost = cost(&ops);
+    assert!(
+        cost >= minimal,
+        "a delta can't beat the minimal edit script"
+    );
+    (
+        cost,
+        FACTOR * minimal + SLACK_BLOCKS * block_size * changes(&ops),
+    )
+}
+
+#[test]
+fn myers_distance() {
+    assert_eq!(myers(b"", b""), 0);
+    assert_eq!(myers(b"abc", b""), 3);
+    assert_eq!(myers(b"", b"abc"), 3);
+    assert_eq!(myers(b"abcabba", b"cbabac"), 5);
+    assert_eq!(myers(b"i saw a red fox", b"i saw a red box"), 2);
+}
+
+#[test]
+fn within_factor() {
+    let text: String = (0..40).map(|i| format!("line number {}\n", i)).collect();
+    let inputs = [
+        ("i saw a red fox", "i saw a red box"),
+        (
+            "hello there, do you know rust?",
+            "hi, do you know about rustlang?",
+        ),
+        (&text, &text.replace("line number 20\n", "line twenty\n")),
+        (&text, &text.replace("line number 5\n", "")),
+        (
+            &text,
+            &text.replace("line number 30\n", "line number 30\nextra\n"),
+        ),
+    ];
+    for (a, b) in &inputs {
+        for block_size in &[2, 4, 8, 16] {
+            let (cost, max) = check(a, b, *block_size);
+            assert!(
+                cost <= max,
+                "cost {} > {} for bs = {}, {:?} -> {:?}",
+                cost,
+                max,
+                block_size,
+                a,
+                b
+            );
+        }
+    }
+}
+
+/// Inputs that block based diffing is known to handle badly, they are here to keep track of
+/// how bad, not to hold them to the same bar.
+#[test]
+fn known_suboptimal() {
+    let text: String = (0..40).map(|i| format!("line number {}\n", i)).collect();
+    // a byte changed in every block, so no block matches.
+    let every_block: String = text
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| if i % 8 == 0 { 'X' } else { b as char })
+        .collect();
+    let (cost, max) = check(&text, &every_block, 8);
+    assert!(cost > max);
+    assert!(cost <= text.len() + every_block.len());
+
+    // a moved region can't be matched again after the cursor passed it.
+    let moved = format!("{}{}", &text[300..], &text[..300]);
+    let (cost, _) = check(&text, &moved, 8);
+    assert!(cost >= 2 * 300 - 16);
+}
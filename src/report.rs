@@ -0,0 +1,182 @@
+//! Size reports to help picking a good block size.
+
+use std::cmp;
+
+use crate::delta::Delta;
+use crate::encoding::encode_operations_aligned;
+use crate::hash::Signature;
+
+/// How much data a sync would transfer for a given pair of buffers and block size.
+///
+/// see [`compression_report`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    /// The block size used to calculate the signature and the delta.
+    pub block_size: usize,
+    /// Size of the encoded signature of the original buffer in bytes.
+    pub signature_size: usize,
+    /// Size of the encoded delta in bytes.
+    pub delta_size: usize,
+    /// Size of the new buffer, what we would send without any delta.
+    pub whole_size: usize,
+    /// `delta_size / whole_size`, less than `1.0` means the delta is smaller than the new buffer.
+    pub ratio: f64,
+}
+
+/// Calculate the encoded sizes of the signature and the delta between `original` and `new`
+/// using the given `block_size`.
+///
+/// ### Example
+/// ```
+/// let report = rsdiff::compression_report(b"i saw a red fox", b"i saw a red box", 4);
+/// assert_eq!(report.whole_size, 15);
+/// println!("{:#?}", report);
+/// ```
+pub fn compression_report(original: &[u8], new: &[u8], block_size: usize) -> Report {
+    let mut signature = Signature::with_block_size(block_size, original);
+    signature.calculate();
+    let indexed = signature.to_indexed();
+    let signature_size = indexed.to_bytes().len();
+    let mut delta = Delta::new(indexed);
+    delta
+        .diff(new)
+        .expect("diffing in-memory buffers never fails");
+    let delta_size = encode_operations_aligned(delta.operations(), block_size).len();
+    let whole_size = new.len();
+    let ratio = if whole_size == 0 {
+        delta_size as f64
+    } else {
+        delta_size as f64 / whole_size as f64
+    };
+    Report {
+        block_size,
+        signature_size,
+        delta_size,
+        whole_size,
+        ratio,
+    }
+}
+
+/// The block sizes [`estimate_block_size`] tries.
+const CANDIDATE_BLOCK_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// How much of the start of each buffer [`estimate_block_size`] looks at.
+const SAMPLE_LEN: usize = 64 * 1024;
+
+/// Guess a good block size for diffing `a` (the original) and `b` (the new one).
+///
+/// it tries a few block sizes on a sampled prefix of both buffers, and picks the one where the
+/// signature plus the delta is the smallest, see [`compression_report`]. if some of them are
+/// equally small the biggest one wins since it has less blocks to hash, so the same buffers
+/// always get the same block size.
+///
+/// ### Example
+/// ```
+/// let original: String = (0..1000).map(|i| format!("line {}\n", i)).collect();
+/// let new = original.replace("line 500\n", "line five hundred\n");
+/// let block_size = rsdiff::estimate_block_size(original.as_bytes(), new.as_bytes());
+/// let ops = rsdiff::diff_with_block_size(block_size, &original, &new).unwrap();
+/// assert!(ops.len() <= 2);
+/// ```
+pub fn estimate_block_size(a: &[u8], b: &[u8]) -> usize {
+    let a = &a[..a.len().min(SAMPLE_LEN)];
+    let b = &b[..b.len().min(SAMPLE_LEN)];
+    let max_len = a.len().max(b.len());
+    // no need to try blocks bigger than the whole sample.
+    cheapest(
+        CANDIDATE_BLOCK_SIZES
+            .iter()
+            .take_while(|&&block_size| {
+                block_size == CANDIDATE_BLOCK_SIZES[0] || block_size <= max_len
+            })
+            .map(|&block_size| {
+                let report = compression_report(a, b, block_size);
+                (block_size, report.signature_size + report.delta_size)
+            }),
+    )
+    .unwrap_or(CANDIDATE_BLOCK_SIZES[0])
+}
+
+/// The block size with the smallest cost out of `(block_size, cost)` pairs, the biggest block
+/// size wins a tie no matter the order they come in.
+fn cheapest(costs: impl IntoIterator<Item = (usize, usize)>) -> Option<usize> {
+    costs
+        .into_iter()
+        .min_by_key(|(block_size, cost)| (*cost, cmp::Reverse(*block_size)))
+        .map(|(block_size, _)| block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistent() {
+        let original: String = (0..200).map(|i| format!("line number {}\n", i)).collect();
+        let new = original.replace("line number 100\n", "line number one hundred\n");
+        let report = compression_report(original.as_bytes(), new.as_bytes(), 64);
+        assert_eq!(report.block_size, 64);
+        assert_eq!(report.whole_size, new.len());
+        assert!(report.delta_size <= report.whole_size);
+        assert!(report.ratio < 1.0);
+        assert!((report.ratio - report.delta_size as f64 / report.whole_size as f64).abs() < 1e-9);
+        // smaller blocks means more of them in the signature.
+        let small = compression_report(original.as_bytes(), new.as_bytes(), 16);
+        assert!(small.signature_size > report.signature_size);
+    }
+
+    #[test]
+    fn estimate() {
+        let valid = |block_size| CANDIDATE_BLOCK_SIZES.contains(&block_size);
+        assert_eq!(estimate_block_size(b"", b""), CANDIDATE_BLOCK_SIZES[0]);
+        assert!(valid(estimate_block_size(
+            b"i saw a red fox",
+            b"i saw a red box"
+        )));
+
+        // a big file with a single edit wants big blocks.
+        let mut seed = 0x2545_f491u32;
+        let original: Vec<u8> = (0..100_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut new = original.clone();
+        new[50_000] ^= 0xff;
+        let block_size = estimate_block_size(&original, &new);
+        assert!(valid(block_size));
+        assert!(block_size >= 256, "block size = {}", block_size);
+
+        // edits all over the place want small blocks.
+        let mut new = original.clone();
+        for i in (0..new.len()).step_by(1000) {
+            new[i] ^= 0xff;
+        }
+        let small = estimate_block_size(&original, &new);
+        assert!(valid(small));
+        assert!(small < block_size, "block size = {}", small);
+    }
+
+    #[test]
+    fn ties() {
+        assert_eq!(cheapest(vec![]), None);
+        assert_eq!(
+            cheapest(vec![(16, 10), (32, 5), (64, 5), (128, 7)]),
+            Some(64)
+        );
+        assert_eq!(cheapest(vec![(64, 5), (32, 5), (16, 5)]), Some(64));
+        assert_eq!(cheapest(vec![(16, 4), (32, 5), (64, 5)]), Some(16));
+
+        // the same buffers always get the same block size.
+        let original: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        let new = original.replace("line 250\n", "");
+        let block_size = estimate_block_size(original.as_bytes(), new.as_bytes());
+        for _ in 0..3 {
+            assert_eq!(
+                estimate_block_size(original.as_bytes(), new.as_bytes()),
+                block_size
+            );
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Deterministic pseudo random numbers for the tests (xorshift), so they are reproducible.
+
+/// A xorshift generator, the same seed always gives the same numbers, it must not be zero.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        debug_assert!(seed != 0, "a zero seed only gives zeros");
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..below`.
+    pub(crate) fn below(&mut self, below: usize) -> usize {
+        (self.next_u64() % below as u64) as usize
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// `len` bytes of a generator seeded with `seed`.
+pub(crate) fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+    Rng::new(seed).bytes(len)
+}
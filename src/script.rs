@@ -0,0 +1,240 @@
+//! Human readable edit scripts, a text alternative to [`crate::encode_operations`].
+//!
+//! every operation is a line that looks like an `ed(1)` command:
+//!
+//! ```text
+//! 12a box       insert "box" at 12
+//! 15,17d        remove 3 bytes at 15
+//! 15,17c fox    replace the 3 bytes at 15 with "fox"
+//! 15,24t        copy the bytes 15 to 24 of the original buffer
+//! ```
+//!
+//! ranges are inclusive like in `ed(1)`, the inserted bytes are escaped so they fit on a single
+//! line, a `\` is written as `\\`, a new line as `\n`, a tab as `\t`, a carriage return as `\r`,
+//! and anything else that is not printable ASCII as `\xHH`.
+use std::fmt::Write;
+
+use crate::delta::Operation;
+use crate::encoding::ParseError;
+
+/// Write the `ops` as an edit script.
+///
+/// empty operations do nothing and can't be written as a range, so they are skipped.
+///
+/// see [`from_ed_script`] for parsing it back.
+///
+/// ### Example
+/// ```
+/// let ops = rsdiff::diff_with_block_size(4, "i saw a red fox", "i saw a red box").unwrap();
+/// let script = rsdiff::to_ed_script(&ops);
+/// assert_eq!(script, "12a box\n15,17d\n");
+/// assert_eq!(rsdiff::from_ed_script(&script), Ok(ops));
+/// ```
+pub fn to_ed_script(ops: &[Operation]) -> String {
+    let mut script = String::new();
+    for op in ops.iter().filter(|op| !op.is_empty()) {
+        match op {
+            Operation::Insert { offset, buffer } => {
+                let _ = write!(script, "{}a ", offset);
+                escape(&mut script, buffer);
+            }
+            Operation::Remove { offset, len } => {
+                let _ = write!(script, "{},{}d", offset, offset + len - 1);
+            }
+            Operation::Copy { src_offset, len } => {
+                let _ = write!(script, "{},{}t", src_offset, src_offset + len - 1);
+            }
+            // nothing to replace, so it is just an insert.
+            Operation::Replace {
+                offset,
+                old_len: 0,
+                buffer,
+            } => {
+                let _ = write!(script, "{}a ", offset);
+                escape(&mut script, buffer);
+            }
+            Operation::Replace {
+                offset,
+                old_len,
+                buffer,
+            } => {
+                let _ = write!(script, "{},{}c ", offset, offset + old_len - 1);
+                escape(&mut script, buffer);
+            }
+        }
+        script.push('\n');
+    }
+    script
+}
+
+/// Parse an edit script written by [`to_ed_script`], empty lines are ignored.
+pub fn from_ed_script(script: &str) -> Result<Vec<Operation>, ParseError> {
+    let mut ops = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let bad_line = ParseError::BadScript { line: i + 1 };
+        let command = line
+            .find(|c: char| !c.is_ascii_digit() && c != ',')
+            .ok_or_else(|| bad_line.clone())?;
+        let (start, end) = range(&line[..command]).ok_or_else(|| bad_line.clone())?;
+        // the command is a single ascii letter, anything else could split a character.
+        if !line.as_bytes()[command].is_ascii() {
+            return Err(bad_line);
+        }
+        let len = end
+            .checked_sub(start)
+            .and_then(|len| len.checked_add(1))
+            .ok_or_else(|| bad_line.clone())?;
+        let rest = &line[command + 1..];
+        let op = match (&line[command..=command], rest) {
+            ("a", rest) if start == end && rest.starts_with(' ') => Operation::Insert {
+                offset: start,
+                buffer: unescape(&rest[1..]).ok_or_else(|| bad_line.clone())?,
+            },
+            ("c", rest) if rest.starts_with(' ') => Operation::Replace {
+                offset: start,
+                old_len: len,
+                buffer: unescape(&rest[1..]).ok_or_else(|| bad_line.clone())?,
+            },
+            ("d", "") => Operation::Remove { offset: start, len },
+            ("t", "") => Operation::Copy {
+                src_offset: start,
+                len,
+            },
+            _ => return Err(bad_line),
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
+/// Parse an `ed(1)` address, either `start,end` or a single `start`.
+fn range(address: &str) -> Option<(usize, usize)> {
+    let (start, end) = match address.find(',') {
+        Some(comma) => (&address[..comma], &address[comma + 1..]),
+        None => (address, address),
+    };
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn escape(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            0x20..=0x7e => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", b);
+            }
+        }
+    }
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        let b = match iter.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'x' => {
+                let hex = [iter.next()?, iter.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            _ => return None,
+        };
+        bytes.push(b);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let ops = crate::diff_with_block_size(
+            5,
+            "hello there, do you know rust?",
+            "hi, do you know about rustlang?",
+        )
+        .unwrap();
+        let script = to_ed_script(&ops);
+        assert_eq!(script, "0a hi, do\n6,20d\n16a about rustlang?\n31,35d\n");
+        assert_eq!(from_ed_script(&script), Ok(ops.clone()));
+
+        let replaced = crate::coalesce_replacements(&ops);
+        let script = to_ed_script(&replaced);
+        assert_eq!(script, "0,14c hi, do\n16,20c about rustlang?\n");
+        assert_eq!(from_ed_script(&script), Ok(replaced));
+
+        let ops = vec![
+            Operation::Insert {
+                offset: 0,
+                buffer: b"line\n\ttab\\ \x00\xff".to_vec(),
+            },
+            Operation::Copy {
+                src_offset: 4,
+                len: 1,
+            },
+        ];
+        let script = to_ed_script(&ops);
+        assert_eq!(script, "0a line\\n\\ttab\\\\ \\x00\\xff\n4,4t\n");
+        assert_eq!(from_ed_script(&script), Ok(ops));
+    }
+
+    #[test]
+    fn empty() {
+        let ops = vec![
+            Operation::Remove { offset: 0, len: 0 },
+            Operation::Insert {
+                offset: 0,
+                buffer: Vec::new(),
+            },
+        ];
+        assert_eq!(to_ed_script(&ops), "");
+    }
+
+    #[test]
+    fn single_address() {
+        assert_eq!(
+            from_ed_script("15d\n\n3t\n"),
+            Ok(vec![
+                Operation::Remove { offset: 15, len: 1 },
+                Operation::Copy {
+                    src_offset: 3,
+                    len: 1
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let bad = |line| Err(ParseError::BadScript { line });
+        assert_eq!(from_ed_script("12a box\n15,17"), bad(2));
+        assert_eq!(from_ed_script("17,15d"), bad(1));
+        assert_eq!(from_ed_script("12,13a box"), bad(1));
+        assert_eq!(from_ed_script("12abox"), bad(1));
+        assert_eq!(from_ed_script("12a bo\\x"), bad(1));
+        assert_eq!(from_ed_script("15,17d extra"), bad(1));
+        assert_eq!(from_ed_script("a box"), bad(1));
+        assert_eq!(from_ed_script("12é box"), bad(1));
+        assert_eq!(from_ed_script("0,18446744073709551615d"), bad(1));
+    }
+}
@@ -0,0 +1,259 @@
+//! Serde support of [`Operation`]s and [`IndexedSignature`], behind the `serde` feature.
+//!
+//! the types derive `Serialize` and `Deserialize`, only the bytes are done here, they are raw
+//! bytes in binary formats (like MessagePack) and a base64 string in human readable ones (like
+//! JSON), a list of numbers would be a few times bigger. a deserialized signature is checked
+//! like a decoded one, so it can't panic a diff later.
+//!
+//! [`Operation`]: crate::Operation
+//! [`IndexedSignature`]: crate::IndexedSignature
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::encoding::{check_layout, ParseError};
+use crate::hash::{BlockHash, Chunking, CryptoHash, HashKind, IndexedSignature};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `#[serde(with = "...")]` of a byte buffer.
+pub(crate) mod bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&encode_base64(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+impl Serialize for CryptoHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(&self[..], serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CryptoHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = bytes::deserialize(deserializer)?;
+        CryptoHash::new(&bytes).map_err(|_| de::Error::invalid_length(bytes.len(), &"32 bytes"))
+    }
+}
+
+/// The fields of a deserialized [`IndexedSignature`], checked like a decoded one before they
+/// become one, see [`check_layout`].
+#[derive(Deserialize)]
+pub(crate) struct RawSignature {
+    original_buffer_len: usize,
+    block_size: usize,
+    blocks: HashMap<u32, Vec<(usize, BlockHash)>>,
+    chunking: Chunking,
+    salt: Option<[u8; 16]>,
+    /// Signatures from before the crypto hash could be chosen are Blake2b.
+    #[serde(default)]
+    hash: HashKind,
+}
+
+impl TryFrom<RawSignature> for IndexedSignature {
+    type Error = ParseError;
+
+    fn try_from(raw: RawSignature) -> Result<Self, ParseError> {
+        check_layout(raw.original_buffer_len, raw.block_size, &raw.chunking)?;
+        Ok(IndexedSignature {
+            original_buffer_len: raw.original_buffer_len,
+            block_size: raw.block_size,
+            blocks: raw.blocks,
+            chunking: raw.chunking,
+            salt: raw.salt,
+            hash: raw.hash,
+        })
+    }
+}
+
+/// Takes the bytes as they come, a base64 string or a list of numbers too.
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("bytes or a base64 string")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Vec<u8>, E> {
+        decode_base64(s).ok_or_else(|| E::invalid_value(de::Unexpected::Str(s), &self))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Standard base64 with padding.
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The reverse of [`encode_base64`], returns [`None`] if `s` is not valid base64.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = (i + 1) * 4 == s.len();
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            let value = BASE64.iter().position(|b| b == c)?;
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * padding as u32;
+        let group: [u8; 3] = n.to_be_bytes()[1..].try_into().unwrap();
+        out.extend_from_slice(&group[..3 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Delta, HashKind, IndexedSignature, Operation, Signature};
+
+    #[test]
+    fn base64() {
+        for (bytes, encoded) in &[
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\xfe\x00", "//4A"),
+        ] {
+            assert_eq!(encode_base64(bytes), *encoded);
+            assert_eq!(decode_base64(encoded).as_deref(), Some(*bytes));
+        }
+        for bad in &["Zg=", "Zg=a", "Z===", "Zg==Zg==", "Zm9*"] {
+            assert_eq!(decode_base64(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let original = "hello there, do you know rust?";
+        let new = "hi, do you know about rustlang?";
+        let mut signature = Signature::with_block_size(5, original);
+        signature.calculate();
+        let signature = signature.to_indexed().with_salt([7; 16]);
+        let mut delta = Delta::new(signature.clone());
+        delta.diff(new).unwrap();
+        let mut ops = delta.into_operations();
+        ops.push(Operation::Copy {
+            src_offset: 4,
+            len: 12,
+        });
+        ops.push(Operation::Replace {
+            offset: 40,
+            old_len: 3,
+            buffer: b"fox".to_vec(),
+        });
+
+        let json = serde_json::to_string(&ops).unwrap();
+        assert!(json.contains(r#""buffer":"Zm94""#), "{}", json);
+        assert_eq!(serde_json::from_str::<Vec<Operation>>(&json).unwrap(), ops);
+
+        let json = serde_json::to_string(&signature).unwrap();
+        assert_eq!(
+            serde_json::from_str::<IndexedSignature>(&json).unwrap(),
+            signature
+        );
+        // the crypto hash is kept, and signatures from before it are blake2b.
+        let mut blake2s = Signature::with_block_size(5, original).with_hash(HashKind::Blake2s);
+        blake2s.calculate();
+        let blake2s = blake2s.to_indexed();
+        let mut json = serde_json::to_value(&blake2s).unwrap();
+        assert_eq!(
+            serde_json::from_value::<IndexedSignature>(json.clone()).unwrap(),
+            blake2s
+        );
+        json.as_object_mut().unwrap().remove("hash");
+        let old = serde_json::from_value::<IndexedSignature>(json).unwrap();
+        assert_eq!(old.hash_kind(), HashKind::Blake2b);
+
+        let mut lines = Signature::new(original);
+        lines.calculate_lines();
+        let lines = lines.to_indexed();
+        let json = serde_json::to_string(&lines).unwrap();
+        assert_eq!(
+            serde_json::from_str::<IndexedSignature>(&json).unwrap(),
+            lines
+        );
+
+        // the layout is checked like a decoded signature's.
+        let mut json = serde_json::to_value(&lines).unwrap();
+        json["chunking"] = serde_json::json!({ "Lines": [0, 7, 2] });
+        let err = serde_json::from_value::<IndexedSignature>(json).unwrap_err();
+        assert_eq!(err.to_string(), ParseError::BadOffsets.to_string());
+        let mut json = serde_json::to_value(&signature).unwrap();
+        json["block_size"] = 0.into();
+        assert!(serde_json::from_value::<IndexedSignature>(json).is_err());
+
+        // a list of numbers works too, but not a hash of the wrong size.
+        let op = r#"{"Insert":{"buffer":[102,111,120],"offset":12}}"#;
+        assert_eq!(
+            serde_json::from_str::<Operation>(op).unwrap(),
+            Operation::Insert {
+                offset: 12,
+                buffer: b"fox".to_vec()
+            }
+        );
+        assert!(serde_json::from_str::<CryptoHash>(r#""Zm94""#).is_err());
+        assert!(
+            serde_json::from_str::<Operation>(r#"{"Insert":{"buffer":"Zm9","offset":0}}"#).is_err()
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Diffing against an original buffer that is too big to keep its whole signature in memory.
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+
+use log::trace;
+
+use crate::delta::Operation;
+use crate::differ::{frame_crypto_hash, BlockSource, Differ, Event};
+use crate::encoding::DeltaWriter;
+use crate::hash::{BlockHash, CryptoHasher, HashKind};
+use crate::observer::{NoopObserver, Observer};
+use crate::window::Window;
+
+/// A diff that only keeps a bounded window of the original buffer signature in memory.
+///
+/// the signature of the original buffer is calculated lazily from a reader, at most
+/// `window_blocks` blocks starting from the current position in the original buffer are kept,
+/// the blocks before it can't match anymore so they are dropped, and the blocks after it are
+/// read when the window slides forward.
+///
+/// a block that moved further than the window away from its old position is not matched, so
+/// the delta could be bigger than the one from [`crate::Delta`], but for buffers that fit in the
+/// window both produce the same operations.
+///
+/// the operations are produced incrementally by iterating over it.
+///
+/// ### Example
+/// ```
+/// use rsdiff::{Operation, StreamingDiff};
+///
+/// let original = &b"i saw a red fox"[..];
+/// let diff = StreamingDiff::new(original, "i saw a red box", 4, 2).unwrap();
+/// let ops: Vec<_> = diff.collect::<Result<_, _>>().unwrap();
+/// assert_eq!(
+///     ops,
+///     vec![
+///         Operation::Insert { offset: 12, buffer: b"box".to_vec() },
+///         Operation::Remove { offset: 15, len: 3 },
+///     ],
+/// );
+/// ```
+pub struct StreamingDiff<R: Read, B: AsRef<[u8]>> {
+    differ: Differ<Window<B>>,
+    signature: SignatureWindow<R>,
+}
+
+impl<R: Read, B: AsRef<[u8]>> StreamingDiff<R, B> {
+    /// Create a new streaming diff between the `original` reader and the `new` buffer, keeping
+    /// at most `window_blocks` blocks of `block_size` bytes of the original signature.
+    pub fn new(original: R, new: B, block_size: usize, window_blocks: usize) -> io::Result<Self> {
+        assert!(block_size != 0, "block size must be > 0");
+        assert!(window_blocks != 0, "window must hold at least one block");
+        Ok(Self {
+            differ: Differ::new(new, block_size)?,
+            signature: SignatureWindow {
+                reader: original,
+                block_size,
+                capacity: window_blocks,
+                blocks: HashMap::with_capacity(window_blocks),
+                order: VecDeque::with_capacity(window_blocks),
+                next_block: 0,
+                bytes_read: 0,
+                eof: false,
+                hasher: CryptoHasher::new(HashKind::Blake2b),
+                chunk: vec![0; block_size],
+            },
+        })
+    }
+
+    /// Write the operations to the `writer` as they are produced, without collecting them.
+    ///
+    /// the `writer` is not finished, so more operations could be written after them.
+    pub fn write_to<W: Write>(self, writer: &mut DeltaWriter<W>) -> io::Result<()> {
+        for op in self {
+            writer.write_op(&op?)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read, B: AsRef<[u8]>> Iterator for StreamingDiff<R, B> {
+    type Item = io::Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self
+                .differ
+                .next_event(&mut self.signature, &mut NoopObserver)
+            {
+                Ok(Some(Event::Op(op))) => return Some(Ok(op)),
+                Ok(Some(Event::Match(_))) => continue,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A sliding window over the signature of the original buffer.
+struct SignatureWindow<R: Read> {
+    reader: R,
+    block_size: usize,
+    /// The max number of blocks in the window.
+    capacity: usize,
+    blocks: HashMap<u32, (usize, BlockHash)>,
+    /// The blocks in the window ordered by their index, with their weak hash.
+    order: VecDeque<(usize, u32)>,
+    /// The index of the next block to be read from the original buffer.
+    next_block: usize,
+    /// How many bytes we read from the original buffer so far.
+    bytes_read: usize,
+    eof: bool,
+    hasher: CryptoHasher,
+    chunk: Vec<u8>,
+}
+
+impl<R: Read> SignatureWindow<R> {
+    /// Drop the blocks that start before `next_offset` and fill the window with new ones.
+    fn slide(&mut self, next_offset: usize) -> io::Result<()> {
+        while let Some((idx, weak_hash)) = self.order.front().copied() {
+            if idx * self.block_size >= next_offset {
+                break;
+            }
+            self.order.pop_front();
+            // a later block with the same weak hash could have replaced it.
+            if matches!(self.blocks.get(&weak_hash), Some((i, _)) if *i == idx) {
+                self.blocks.remove(&weak_hash);
+            }
+        }
+        while self.order.len() < self.capacity && !self.eof {
+            let len = self.read_chunk()?;
+            if len == 0 {
+                break;
+            }
+            let idx = self.next_block;
+            let block = BlockHash::calculate(&mut self.hasher, &self.chunk[..len]);
+            trace!("read block {} into the signature window", idx);
+            self.blocks.insert(block.weak_hash, (idx, block));
+            self.order.push_back((idx, block.weak_hash));
+            self.next_block += 1;
+        }
+        Ok(())
+    }
+
+    /// Read the next block into `chunk`, returns its length which is less than the block size
+    /// only at the end of the original buffer.
+    fn read_chunk(&mut self) -> io::Result<usize> {
+        let mut len = 0;
+        while len < self.chunk.len() {
+            match self.reader.read(&mut self.chunk[len..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.bytes_read += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read> BlockSource for SignatureWindow<R> {
+    fn find_match<O: Observer>(
+        &mut self,
+        weak_hash: u32,
+        frame: (&[u8], &[u8]),
+        next_offset: usize,
+        _observer: &mut O,
+    ) -> io::Result<Option<usize>> {
+        self.slide(next_offset)?;
+        let idx = match self.blocks.get(&weak_hash) {
+            Some((idx, block))
+                if idx * self.block_size >= next_offset
+                    && block
+                        .crypto_hash
+                        .ct_eq(&frame_crypto_hash(HashKind::Blake2b, frame)) =>
+            {
+                Some(*idx)
+            }
+            _ => None,
+        };
+        Ok(idx)
+    }
+
+    fn block_offset(&self, idx: usize) -> usize {
+        idx * self.block_size
+    }
+
+    fn block_len(&self, idx: usize) -> usize {
+        // only the last block could be shorter.
+        std::cmp::min(self.block_size, self.bytes_read - self.block_offset(idx))
+    }
+
+    fn original_len(&mut self) -> io::Result<usize> {
+        while !self.eof {
+            self.read_chunk()?;
+        }
+        Ok(self.bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streaming(original: &[u8], new: &[u8], block_size: usize, window: usize) -> Vec<Operation> {
+        StreamingDiff::new(original, new, block_size, window)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn same_as_delta() {
+        let pairs: &[(&str, &str)] = &[
+            ("i saw a red fox", "i saw a red box"),
+            (
+                "hello there, do you know rust?",
+                "hi, do you know about rustlang?",
+            ),
+            ("hello fox and friends", "hello fox"),
+            ("", "something from nothing"),
+            ("something to nothing", ""),
+        ];
+        for (original, new) in pairs {
+            for bs in 1..8 {
+                // the window is big enough to hold the whole signature.
+                let ops = streaming(original.as_bytes(), new.as_bytes(), bs, 64);
+                let mut signature = crate::Signature::with_block_size(bs, original);
+                signature.calculate();
+                let mut delta = crate::Delta::new(signature.to_indexed());
+                delta.diff(new).unwrap();
+                assert_eq!(ops, delta.into_operations());
+            }
+        }
+    }
+
+    #[test]
+    fn small_window() {
+        let original: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let new = original
+            .replace("line 10\n", "line ten\n")
+            .replace("line 70\n", "");
+        let ops = streaming(original.as_bytes(), new.as_bytes(), 8, 4);
+        let mut patch = crate::patch::Patch::new(&ops);
+        patch.apply(&original);
+        assert_eq!(patch.buffer(), new.as_bytes());
+    }
+
+    #[test]
+    fn write_to() {
+        let original: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let new = original.replace("line 10\n", "line ten\n");
+        let diff = StreamingDiff::new(original.as_bytes(), new.as_bytes(), 8, 4).unwrap();
+        let mut writer = DeltaWriter::new(Vec::new()).unwrap();
+        diff.write_to(&mut writer).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            crate::decode_operations_stream(&bytes),
+            Ok(streaming(original.as_bytes(), new.as_bytes(), 8, 4))
+        );
+    }
+}
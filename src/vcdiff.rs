@@ -0,0 +1,524 @@
+//! Encode operations as VCDIFF (RFC 3284) deltas, for the decoders out there (open-vcdiff,
+//! xdelta3) to apply them.
+//!
+//! the whole original buffer is the source segment of a single window, the copied bytes become
+//! `COPY` instructions from it, the inserted bytes `ADD`s, and long runs of the same byte `RUN`s.
+//! the default code table and address cache of the RFC are used, nothing is compressed.
+use std::cmp;
+
+use crate::delta::Operation;
+
+/// The magic header, "VCD" with the high bits set, and version 0.
+const MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+/// the window copies from the source segment.
+const VCD_SOURCE: u8 = 0x01;
+
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+const VCD_SELF: u8 = 0;
+const VCD_HERE: u8 = 1;
+
+/// literal runs of the same byte at least this long are `RUN`s.
+const MIN_RUN: usize = 8;
+
+/// Encode the `ops` that upgrade an `original_len` bytes buffer as a VCDIFF delta.
+///
+/// the `ops` are resolved the same way [`crate::Patch::apply`] does, so they can be legacy or
+/// explicit ones (see [`Operation::Copy`]).
+///
+/// ### Example
+/// ```
+/// let original = "i saw a red fox, the red fox saw me";
+/// let new = "i saw a red box, the red fox saw me";
+/// let ops = rsdiff::diff_with_block_size(4, original, new).unwrap();
+/// let delta = rsdiff::encode_vcdiff(&ops, original.len());
+/// assert!(delta.starts_with(&[0xd6, 0xc3, 0xc4, 0x00]));
+/// ```
+pub fn encode_vcdiff(ops: &[Operation], original_len: usize) -> Vec<u8> {
+    let mut window = Window::new(original_len);
+    for segment in segments(ops, original_len) {
+        match segment {
+            Segment::Copy(start, len) => window.copy(start, len),
+            Segment::Literal(bytes) => window.literal(bytes),
+        }
+    }
+    let mut out = MAGIC.to_vec();
+    // no secondary compressor and the default code table.
+    out.push(0);
+    // an empty target has no windows.
+    if window.target_len > 0 {
+        window.finish(&mut out);
+    }
+    out
+}
+
+/// A piece of the new buffer.
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+    /// copied from the original buffer, where and how many bytes.
+    Copy(usize, usize),
+    Literal(&'a [u8]),
+}
+
+/// The pieces of the new buffer, in order, with the contiguous copies merged.
+fn segments(ops: &[Operation], original_len: usize) -> Vec<Segment<'_>> {
+    let explicit = ops.iter().any(Operation::is_copy);
+    let mut segments = Vec::with_capacity(ops.len() * 2 + 1);
+    let push_copy = |segments: &mut Vec<Segment>, start: usize, end: usize| {
+        if start >= end {
+            return;
+        }
+        if let Some(Segment::Copy(last, len)) = segments.last_mut() {
+            if *last + *len == start {
+                *len += end - start;
+                return;
+            }
+        }
+        segments.push(Segment::Copy(start, end - start));
+    };
+    // where we are in the original buffer, and how many bytes we wrote so far.
+    let (mut cursor, mut written) = (0, 0);
+    for op in ops {
+        if !explicit {
+            let gap = op.offset().saturating_sub(written);
+            let end = cmp::min(cursor + gap, original_len);
+            push_copy(&mut segments, cursor, end);
+            written += end - cursor;
+            cursor = end;
+        }
+        match op {
+            Operation::Insert { buffer, .. } => {
+                if !buffer.is_empty() {
+                    segments.push(Segment::Literal(buffer));
+                }
+                written += buffer.len();
+            }
+            Operation::Remove { len, .. } => cursor = cmp::min(cursor + len, original_len),
+            Operation::Copy { src_offset, len } => {
+                let start = cmp::min(*src_offset, original_len);
+                let end = cmp::min(start + len, original_len);
+                push_copy(&mut segments, start, end);
+                written += end - start;
+                cursor = end;
+            }
+            Operation::Replace {
+                old_len, buffer, ..
+            } => {
+                cursor = cmp::min(cursor + old_len, original_len);
+                if !buffer.is_empty() {
+                    segments.push(Segment::Literal(buffer));
+                }
+                written += buffer.len();
+            }
+        }
+    }
+    if !explicit {
+        push_copy(&mut segments, cursor, original_len);
+    }
+    segments
+}
+
+/// The three sections of a window, filled one instruction at a time.
+struct Window {
+    source_len: usize,
+    target_len: usize,
+    data: Vec<u8>,
+    instructions: Vec<u8>,
+    addresses: Vec<u8>,
+    cache: AddressCache,
+}
+
+impl Window {
+    fn new(source_len: usize) -> Self {
+        Self {
+            source_len,
+            target_len: 0,
+            data: Vec::new(),
+            instructions: Vec::new(),
+            addresses: Vec::new(),
+            cache: AddressCache::new(),
+        }
+    }
+
+    fn copy(&mut self, start: usize, len: usize) {
+        let here = self.source_len + self.target_len;
+        let (mode, address) = self.cache.encode(start, here);
+        // the default table has the sizes 4 to 18 of every mode built in.
+        let first = 19 + 16 * mode as usize;
+        match len {
+            4..=18 => self.instructions.push((first + len - 3) as u8),
+            _ => {
+                self.instructions.push(first as u8);
+                put_varint(&mut self.instructions, len);
+            }
+        }
+        match address {
+            Address::Varint(address) => put_varint(&mut self.addresses, address),
+            Address::Byte(address) => self.addresses.push(address),
+        }
+        self.target_len += len;
+    }
+
+    fn literal(&mut self, bytes: &[u8]) {
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let run = bytes[i..].iter().take_while(|b| **b == bytes[i]).count();
+            if run >= MIN_RUN {
+                self.add(&bytes[start..i]);
+                self.instructions.push(0);
+                put_varint(&mut self.instructions, run);
+                self.data.push(bytes[i]);
+                self.target_len += run;
+                start = i + run;
+            }
+            i += run;
+        }
+        self.add(&bytes[start..]);
+    }
+
+    fn add(&mut self, bytes: &[u8]) {
+        match bytes.len() {
+            0 => return,
+            // the default table has the sizes 1 to 17 built in.
+            len @ 1..=17 => self.instructions.push(len as u8 + 1),
+            len => {
+                self.instructions.push(1);
+                put_varint(&mut self.instructions, len);
+            }
+        }
+        self.data.extend_from_slice(bytes);
+        self.target_len += bytes.len();
+    }
+
+    fn finish(self, out: &mut Vec<u8>) {
+        if self.source_len > 0 {
+            out.push(VCD_SOURCE);
+            put_varint(out, self.source_len);
+            put_varint(out, 0);
+        } else {
+            out.push(0);
+        }
+        let mut lengths = Vec::new();
+        put_varint(&mut lengths, self.target_len);
+        // nothing is compressed.
+        lengths.push(0);
+        put_varint(&mut lengths, self.data.len());
+        put_varint(&mut lengths, self.instructions.len());
+        put_varint(&mut lengths, self.addresses.len());
+        let delta_len =
+            lengths.len() + self.data.len() + self.instructions.len() + self.addresses.len();
+        put_varint(out, delta_len);
+        out.extend_from_slice(&lengths);
+        out.extend_from_slice(&self.data);
+        out.extend_from_slice(&self.instructions);
+        out.extend_from_slice(&self.addresses);
+    }
+}
+
+enum Address {
+    Varint(usize),
+    Byte(u8),
+}
+
+/// The `near` and `same` caches of the recently copied addresses, the decoder keeps the same
+/// ones.
+struct AddressCache {
+    near: [usize; NEAR_SIZE],
+    next_slot: usize,
+    same: Vec<usize>,
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; NEAR_SIZE],
+            next_slot: 0,
+            same: vec![0; SAME_SIZE * 256],
+        }
+    }
+
+    /// The mode and the encoded address of `address`, `here` is where the copy goes.
+    fn encode(&mut self, address: usize, here: usize) -> (u8, Address) {
+        let (mut mode, mut best) = (VCD_SELF, address);
+        if here - address < best {
+            mode = VCD_HERE;
+            best = here - address;
+        }
+        for (i, near) in self.near.iter().enumerate() {
+            if address >= *near && address - near < best {
+                mode = i as u8 + 2;
+                best = address - near;
+            }
+        }
+        let slot = address % self.same.len();
+        let encoded = if self.same[slot] == address {
+            mode = (NEAR_SIZE + 2 + slot / 256) as u8;
+            Address::Byte((slot % 256) as u8)
+        } else {
+            Address::Varint(best)
+        };
+        self.update(address);
+        (mode, encoded)
+    }
+
+    fn update(&mut self, address: usize) {
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        let slot = address % self.same.len();
+        self.same[slot] = address;
+    }
+}
+
+/// the VCDIFF integers are big endian base 128, every byte but the last has its high bit set.
+fn put_varint(out: &mut Vec<u8>, value: usize) {
+    let mut bytes = [0u8; 10];
+    let mut i = bytes.len() - 1;
+    let mut value = value;
+    bytes[i] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value > 0 {
+        i -= 1;
+        bytes[i] = (value & 0x7f) as u8 | 0x80;
+        value >>= 7;
+    }
+    out.extend_from_slice(&bytes[i..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Kind {
+        Noop,
+        Add,
+        Run,
+        Copy,
+    }
+
+    /// the default code table of the RFC, (kind, size, mode) twice per entry.
+    fn code_table() -> Vec<[(Kind, usize, u8); 2]> {
+        let noop = (Kind::Noop, 0, 0);
+        let mut table = vec![[(Kind::Run, 0, 0), noop]];
+        for size in 0..=17 {
+            table.push([(Kind::Add, size, 0), noop]);
+        }
+        for mode in 0..9 {
+            table.push([(Kind::Copy, 0, mode), noop]);
+            for size in 4..=18 {
+                table.push([(Kind::Copy, size, mode), noop]);
+            }
+        }
+        for mode in 0..6 {
+            for add in 1..=4 {
+                for copy in 4..=6 {
+                    table.push([(Kind::Add, add, 0), (Kind::Copy, copy, mode)]);
+                }
+            }
+        }
+        for mode in 6..9 {
+            for add in 1..=4 {
+                table.push([(Kind::Add, add, 0), (Kind::Copy, 4, mode)]);
+            }
+        }
+        for mode in 0..9 {
+            table.push([(Kind::Copy, 4, mode), (Kind::Add, 1, 0)]);
+        }
+        assert_eq!(table.len(), 256);
+        table
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn byte(&mut self) -> u8 {
+            let byte = self.0[0];
+            self.0 = &self.0[1..];
+            byte
+        }
+
+        fn varint(&mut self) -> usize {
+            let mut value = 0;
+            loop {
+                let byte = self.byte();
+                value = value << 7 | (byte & 0x7f) as usize;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+
+        fn bytes(&mut self, len: usize) -> &'a [u8] {
+            let (bytes, rest) = self.0.split_at(len);
+            self.0 = rest;
+            bytes
+        }
+    }
+
+    /// A decoder of the deltas we encode, straight from the RFC.
+    fn decode(delta: &[u8], source: &[u8]) -> Vec<u8> {
+        let table = code_table();
+        let mut reader = Reader(delta);
+        assert_eq!(reader.bytes(4), MAGIC);
+        assert_eq!(reader.byte(), 0);
+        let mut target = Vec::new();
+        while !reader.0.is_empty() {
+            let (source_len, source_pos) = match reader.byte() {
+                VCD_SOURCE => (reader.varint(), reader.varint()),
+                0 => (0, 0),
+                indicator => panic!("bad window indicator {}", indicator),
+            };
+            let source = &source[source_pos..source_pos + source_len];
+            let delta_len = reader.varint();
+            let mut window = Reader(reader.bytes(delta_len));
+            let target_len = window.varint();
+            assert_eq!(window.byte(), 0);
+            let (data_len, inst_len, addr_len) =
+                (window.varint(), window.varint(), window.varint());
+            let mut data = Reader(window.bytes(data_len));
+            let mut instructions = Reader(window.bytes(inst_len));
+            let mut addresses = Reader(window.bytes(addr_len));
+            assert!(window.0.is_empty());
+            let (mut near, mut next_slot, mut same) = ([0; NEAR_SIZE], 0, vec![0; SAME_SIZE * 256]);
+            let mut out: Vec<u8> = Vec::with_capacity(target_len);
+            while !instructions.0.is_empty() {
+                for (kind, size, mode) in table[instructions.byte() as usize].iter().copied() {
+                    let size = match (kind, size) {
+                        (Kind::Noop, _) => continue,
+                        (_, 0) => instructions.varint(),
+                        _ => size,
+                    };
+                    match kind {
+                        Kind::Add => out.extend_from_slice(data.bytes(size)),
+                        Kind::Run => {
+                            let byte = data.byte();
+                            out.resize(out.len() + size, byte);
+                        }
+                        Kind::Copy => {
+                            let here = source_len + out.len();
+                            let address = match mode {
+                                VCD_SELF => addresses.varint(),
+                                VCD_HERE => here - addresses.varint(),
+                                m if (m as usize) < NEAR_SIZE + 2 => {
+                                    near[m as usize - 2] + addresses.varint()
+                                }
+                                m => {
+                                    same[(m as usize - NEAR_SIZE - 2) * 256
+                                        + addresses.byte() as usize]
+                                }
+                            };
+                            near[next_slot] = address;
+                            next_slot = (next_slot + 1) % NEAR_SIZE;
+                            same[address % (SAME_SIZE * 256)] = address;
+                            for i in 0..size {
+                                let at = address + i;
+                                let byte = if at < source_len {
+                                    source[at]
+                                } else {
+                                    out[at - source_len]
+                                };
+                                out.push(byte);
+                            }
+                        }
+                        Kind::Noop => unreachable!(),
+                    }
+                }
+            }
+            assert!(data.0.is_empty() && addresses.0.is_empty());
+            assert_eq!(out.len(), target_len);
+            target.extend_from_slice(&out);
+        }
+        target
+    }
+
+    #[test]
+    fn varint() {
+        // the examples of the RFC.
+        let mut out = Vec::new();
+        put_varint(&mut out, 123456789);
+        assert_eq!(out, [0xba, 0xef, 0x9a, 0x15]);
+        out.clear();
+        put_varint(&mut out, 0);
+        put_varint(&mut out, 127);
+        put_varint(&mut out, 128);
+        assert_eq!(out, [0x00, 0x7f, 0x81, 0x00]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs: &[&[u8]] = &[
+            b"",
+            b"a",
+            b"i saw a red fox",
+            b"i saw a red box",
+            b"the quick brown fox jumps over the lazy dog",
+            b"the quick brown fox jumps over the lazy dog, the quick brown fox",
+            b"zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz the lazy dog",
+        ];
+        for original in inputs {
+            for new in inputs {
+                for block_size in &[1, 3, 4, 16] {
+                    let ops = crate::diff_with_block_size(*block_size, original, new).unwrap();
+                    let delta = encode_vcdiff(&ops, original.len());
+                    assert_eq!(decode(&delta, original), *new, "{:?}", ops);
+                    // the explicit copies too.
+                    let signature = crate::IndexedSignature::from_parts(&[*original], *block_size);
+                    let mut d = crate::Delta::new(signature);
+                    d.diff(new).unwrap();
+                    let delta = encode_vcdiff(d.operations(), original.len());
+                    assert_eq!(decode(&delta, original), *new);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let original: Vec<u8> = (0..=255).collect();
+        let new = [
+            &original[100..200],
+            &[b'!'; 20][..],
+            b"abc",
+            &original[..100],
+        ]
+        .concat();
+        let ops = vec![
+            Operation::Copy {
+                src_offset: 100,
+                len: 100,
+            },
+            Operation::Insert {
+                offset: 100,
+                buffer: [&[b'!'; 20][..], b"abc"].concat(),
+            },
+            Operation::Copy {
+                src_offset: 0,
+                len: 100,
+            },
+        ];
+        let delta = encode_vcdiff(&ops, original.len());
+        assert_eq!(decode(&delta, &original), new);
+        assert_eq!(
+            segments(&ops, original.len()),
+            vec![
+                Segment::Copy(100, 100),
+                Segment::Literal(ops[1].buffer().unwrap()),
+                Segment::Copy(0, 100),
+            ]
+        );
+        // a copy, a run of '!', an add of "abc" and a copy.
+        let mut window = Window::new(original.len());
+        window.copy(100, 100);
+        window.literal(ops[1].buffer().unwrap());
+        window.copy(0, 100);
+        assert_eq!(window.data, b"!abc");
+        assert_eq!(window.instructions, [19, 100, 0, 20, 4, 19 + 6 * 16, 100]);
+        // the second copy is at 0, which the same cache starts with.
+        assert_eq!(window.addresses, [100, 0]);
+        // a copy of the whole original buffer and nothing else.
+        let delta = encode_vcdiff(&[], original.len());
+        assert_eq!(decode(&delta, &original), original);
+        assert_eq!(encode_vcdiff(&[], 0), [0xd6, 0xc3, 0xc4, 0x00, 0x00]);
+    }
+}
@@ -1,135 +1,483 @@
-//! Sliding window over immutable buffer.
+//! Sliding window over immutable buffer, or over a reader that can't seek.
 
-use io::Read;
-use std::{cmp, io, mem};
+use std::cmp;
+use std::io::{self, Read};
+
+use blake2::digest::Output;
+use blake2::Digest;
+
+/// A window that slides over the new buffer one byte at a time, the diff only needs this.
+pub(crate) trait Frames {
+    /// Slides the window byte by byte.
+    /// this will change the offset +1 and the total bytes read +1.
+    ///
+    /// returning (tail, head)
+    fn move_forword(&mut self) -> io::Result<(Option<u8>, Option<u8>)>;
+
+    /// Peek the current frame as (front, back) buffers.
+    fn frame(&self) -> (&[u8], &[u8]);
+
+    /// Current frame size.
+    fn frame_size(&self) -> usize {
+        let (front, back) = self.frame();
+        front.len() + back.len()
+    }
+
+    /// are we still in a frame ?
+    fn has_frame(&self) -> bool {
+        self.frame_size() > 0
+    }
+
+    /// are we on the bonds of the current block (frame)?
+    fn on_boundry(&self) -> bool;
+
+    /// get the total bytes read so far.
+    fn bytes_read(&self) -> usize;
+}
+
+/// Hash a `(front, back)` frame with the `hasher` as if it was one slice, the `hasher` is reset
+/// after that.
+///
+/// the blocks of a signature are hashed in one piece and the frames of the diff in two, both go
+/// through here so they always hash the same bytes.
+pub(crate) fn hash_frame<H: Digest>((front, back): (&[u8], &[u8]), hasher: &mut H) -> Output<H> {
+    hasher.update(front);
+    hasher.update(back);
+    hasher.finalize_reset()
+}
 
 /// Sliding window over a buffer.
-/// This maintains an internal buffer read from the original buffer.
+/// it must use the same block size as the signature it is diffed against, that's why it is only
+/// created internally by the diff and never exposed.
+/// This indexes directly into the buffer, the frame is always a sub slice of it.
 pub struct Window<B: AsRef<[u8]>> {
-    /// The front window buffer, contains at most `block_size` of bytes.
-    front: Vec<u8>,
-    /// The back window buffer, contains at most `block_size` of bytes.
-    back: Vec<u8>,
     /// The block size used previously to create the original buffer signature.
     block_size: usize,
-    /// The current read buffer offset (position).
+    /// Where the current frame starts in the buffer, also how much bytes we read so far.
     offset: usize,
-    /// Maintains how much bytes we read so far.
-    bytes_read: usize,
     /// The Window buffer.
-    buffer: io::Cursor<B>,
+    buffer: B,
 }
 
 impl<B: AsRef<[u8]>> Window<B> {
-    /// Create a new window, it will try to fill the front and back buffer with at least size of
-    /// block size bytes, if it fails it will return an io error.
+    /// Create a new window over the buffer with frames of at most block size bytes.
     pub fn new(buffer: B, block_size: usize) -> io::Result<Self> {
-        let mut buffer = io::Cursor::new(buffer);
-        log::trace!("creating new window with block_size = {}", block_size);
-        let mut front = vec![0; block_size];
-        let mut back = vec![0; block_size];
-        let size = buffer.read(&mut front)?;
-        front.truncate(size);
-        let size = buffer.read(&mut back)?;
-        back.truncate(size);
+        Self::new_at(buffer, block_size, 0)
+    }
+
+    /// Same as [`Window::new`] but the window starts at `start_offset` bytes into the buffer,
+    /// as if it was moved forword that many bytes, used to resume a diff.
+    ///
+    /// the rolling hash of the first frame has to be restored or calculated again by the caller.
+    pub fn new_at(buffer: B, block_size: usize, start_offset: usize) -> io::Result<Self> {
+        log::trace!(
+            "creating new window with block_size = {} at {}",
+            block_size,
+            start_offset
+        );
+        let offset = cmp::min(start_offset, buffer.as_ref().len());
         Ok(Window {
-            front,
-            back,
             block_size,
             buffer,
-            offset: 0,
-            bytes_read: 0,
+            offset,
         })
     }
+}
 
-    /// Slides the window byte by byte.
-    /// this will change the offset +1 and the total bytes read +1.
-    ///
-    /// returning (tail, head)
-    pub fn move_forword(&mut self) -> io::Result<(Option<u8>, Option<u8>)> {
-        if self.front.is_empty() {
+impl<B: AsRef<[u8]>> Frames for Window<B> {
+    fn move_forword(&mut self) -> io::Result<(Option<u8>, Option<u8>)> {
+        let buffer = self.buffer.as_ref();
+        let tail = buffer.get(self.offset).copied();
+        if tail.is_none() {
             return Ok((None, None));
         }
-        if self.offset >= self.front.len() {
-            if self.back.is_empty() {
-                return Ok((None, None));
-            }
-            self.read_next()?;
-        }
-        let tail = self.tail();
-        let head = self.head();
+        let head = buffer.get(self.offset + self.block_size).copied();
         self.offset += 1;
-        self.bytes_read += 1;
         Ok((tail, head))
     }
 
-    /// Peek the current frame.
-    /// this will return (front, back) buffers from the current offset.
+    /// the frame is a single slice of the buffer so the back is always empty.
     ///
     ///```text
-    /// Front Buffer:
     ///               [     Current View    ]
     ///               +---------------------+
     ///               |                     |
-    /// +-------------|---------------------|-+
-    /// |  |  |  |  | +  |  |  |  |  |  | + | |
-    /// +-------------------------------------+
-    ///
-    /// Back Buffer:
-    ///   [     Current View    ]
-    ///   +---------------------+
-    ///   |                     |
-    /// +-|---------------------|-------------+
-    /// | + |  |  |  |  |  |  | + |  |  |  |  |
-    /// +-------------------------------------+
+    /// +-------------|---------------------|---------+
+    /// |  |  |  |  | +  |  |  |  |  |  |  | + |  |  |
+    /// +---------------------------------------------+
     ///```
-    pub fn frame(&self) -> (&[u8], &[u8]) {
-        let front_offset = cmp::min(self.offset, self.front.len());
-        let back_offset = cmp::min(self.offset, self.back.len());
-        (&self.front[front_offset..], &self.back[..back_offset])
+    fn frame(&self) -> (&[u8], &[u8]) {
+        let buffer = self.buffer.as_ref();
+        let start = cmp::min(self.offset, buffer.len());
+        let end = cmp::min(start + self.block_size, buffer.len());
+        (&buffer[start..end], &[])
     }
 
-    /// Current frame size.
-    /// Calculated by `front_frame_size` + `back_frame_size` - `current_read_offset`.
-    pub fn frame_size(&self) -> usize {
-        self.front.len() + self.back.len() - self.offset
+    fn on_boundry(&self) -> bool {
+        self.offset == self.buffer.as_ref().len() || self.offset.is_multiple_of(self.block_size)
     }
 
-    /// are we still in a frame ?
-    pub fn has_frame(&self) -> bool {
-        self.frame_size() > 0
+    fn bytes_read(&self) -> usize {
+        self.offset
     }
+}
 
-    /// are we on the bonds of the current block (frame)?
-    pub fn on_boundry(&self) -> bool {
-        self.offset == 0 || self.offset == self.front.len()
+/// Sliding window over a reader that can't seek, like a pipe or a socket.
+///
+/// the bytes are read ahead into a small buffer as the window slides, and the bytes behind the
+/// window are dropped, so it holds about two blocks at most no matter how big the input is.
+pub(crate) struct ReaderWindow<R: Read> {
+    reader: R,
+    block_size: usize,
+    /// The bytes read so far that are still needed.
+    buffer: Vec<u8>,
+    /// Where the buffer starts in the input.
+    start: usize,
+    /// Where the current frame starts in the input.
+    offset: usize,
+    /// Did the reader run out of bytes?
+    eof: bool,
+}
+
+impl<R: Read> ReaderWindow<R> {
+    /// Create a new window over the `reader` with frames of at most block size bytes.
+    pub(crate) fn new(reader: R, block_size: usize) -> io::Result<Self> {
+        log::trace!(
+            "creating new reader window with block_size = {}",
+            block_size
+        );
+        let mut window = ReaderWindow {
+            reader,
+            block_size,
+            buffer: Vec::with_capacity(2 * block_size + 1),
+            start: 0,
+            offset: 0,
+            eof: false,
+        };
+        window.fill()?;
+        Ok(window)
     }
 
-    /// get the total bytes read so far.
-    pub fn bytes_read(&self) -> usize {
-        self.bytes_read
+    /// Read until the buffer holds the current frame and the byte after it, or the reader is
+    /// done.
+    fn fill(&mut self) -> io::Result<()> {
+        let want = self.offset - self.start + self.block_size + 1;
+        while !self.eof && self.buffer.len() < want {
+            let len = self.buffer.len();
+            self.buffer.resize(want, 0);
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(0) => {
+                    self.buffer.truncate(len);
+                    self.eof = true;
+                }
+                Ok(n) => self.buffer.truncate(len + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buffer.truncate(len),
+                Err(e) => {
+                    self.buffer.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
     }
+}
 
-    fn head(&self) -> Option<u8> {
-        let head_idx = self.offset + self.block_size - self.front.len();
-        if head_idx >= self.back.len() {
-            return None;
+impl<R: Read> Frames for ReaderWindow<R> {
+    fn move_forword(&mut self) -> io::Result<(Option<u8>, Option<u8>)> {
+        let idx = self.offset - self.start;
+        let tail = self.buffer.get(idx).copied();
+        if tail.is_none() {
+            return Ok((None, None));
+        }
+        let head = self.buffer.get(idx + self.block_size).copied();
+        self.offset += 1;
+        // drop the bytes behind the window once there is a block of them.
+        if self.offset - self.start >= self.block_size {
+            self.buffer.drain(..self.offset - self.start);
+            self.start = self.offset;
         }
-        Some(self.back[head_idx])
+        self.fill()?;
+        Ok((tail, head))
     }
 
-    fn tail(&self) -> Option<u8> {
-        self.front.get(self.offset).cloned()
+    fn frame(&self) -> (&[u8], &[u8]) {
+        let start = self.offset - self.start;
+        let end = cmp::min(start + self.block_size, self.buffer.len());
+        (&self.buffer[start..end], &[])
     }
 
-    /// Read next block
-    /// replace the current front buffer with the current back buffer.
-    /// and read a new buffer into the back buffer then reset the read offset.
-    fn read_next(&mut self) -> io::Result<()> {
-        self.front = mem::replace(&mut self.back, vec![0; self.block_size]);
-        let size = self.buffer.read(&mut self.back)?;
-        self.back.truncate(size);
-        self.offset = 0;
-        Ok(())
+    fn on_boundry(&self) -> bool {
+        (self.eof && self.offset == self.start + self.buffer.len())
+            || self.offset.is_multiple_of(self.block_size)
+    }
+
+    fn bytes_read(&self) -> usize {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use io::Read;
+    use std::mem;
+    use std::time::Instant;
+
+    /// The old window that reads the buffer through an `io::Cursor` into front and back buffers,
+    /// kept here to compare against.
+    struct CursorWindow<B: AsRef<[u8]>> {
+        front: Vec<u8>,
+        back: Vec<u8>,
+        block_size: usize,
+        offset: usize,
+        bytes_read: usize,
+        buffer: io::Cursor<B>,
+    }
+
+    impl<B: AsRef<[u8]>> CursorWindow<B> {
+        fn new(buffer: B, block_size: usize) -> io::Result<Self> {
+            let mut buffer = io::Cursor::new(buffer);
+            let mut front = vec![0; block_size];
+            let mut back = vec![0; block_size];
+            let size = buffer.read(&mut front)?;
+            front.truncate(size);
+            let size = buffer.read(&mut back)?;
+            back.truncate(size);
+            Ok(CursorWindow {
+                front,
+                back,
+                block_size,
+                buffer,
+                offset: 0,
+                bytes_read: 0,
+            })
+        }
+
+        fn move_forword(&mut self) -> io::Result<(Option<u8>, Option<u8>)> {
+            if self.front.is_empty() {
+                return Ok((None, None));
+            }
+            if self.offset >= self.front.len() {
+                if self.back.is_empty() {
+                    return Ok((None, None));
+                }
+                self.front = mem::replace(&mut self.back, vec![0; self.block_size]);
+                let size = self.buffer.read(&mut self.back)?;
+                self.back.truncate(size);
+                self.offset = 0;
+            }
+            let tail = self.front.get(self.offset).cloned();
+            let head_idx = self.offset + self.block_size - self.front.len();
+            let head = self.back.get(head_idx).cloned();
+            self.offset += 1;
+            self.bytes_read += 1;
+            Ok((tail, head))
+        }
+
+        fn frame(&self) -> Vec<u8> {
+            let front_offset = cmp::min(self.offset, self.front.len());
+            let back_offset = cmp::min(self.offset, self.back.len());
+            let mut frame = self.front[front_offset..].to_vec();
+            frame.extend_from_slice(&self.back[..back_offset]);
+            frame
+        }
+
+        fn has_frame(&self) -> bool {
+            self.front.len() + self.back.len() - self.offset > 0
+        }
+    }
+
+    fn buffer(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn same_as_cursor() {
+        for len in &[0, 1, 5, 16, 17, 100] {
+            let buffer = buffer(*len);
+            for block_size in 1..=20 {
+                let mut window = Window::new(&buffer, block_size).unwrap();
+                let mut cursor = CursorWindow::new(&buffer, block_size).unwrap();
+                for _ in 0..len + 3 {
+                    assert_eq!(window.frame().0, &cursor.frame()[..]);
+                    assert_eq!(window.has_frame(), cursor.has_frame());
+                    assert_eq!(window.bytes_read(), cursor.bytes_read);
+                    assert_eq!(
+                        window.move_forword().unwrap(),
+                        cursor.move_forword().unwrap()
+                    );
+                }
+                assert!(!window.has_frame());
+            }
+        }
+    }
+
+    #[test]
+    fn new_at() {
+        let buffer = buffer(50);
+        for start in &[0, 1, 7, 16, 49, 50] {
+            let mut fresh = Window::new(&buffer, 8).unwrap();
+            for _ in 0..*start {
+                fresh.move_forword().unwrap();
+            }
+            let mut resumed = Window::new_at(&buffer, 8, *start).unwrap();
+            assert_eq!(resumed.bytes_read(), *start);
+            while fresh.has_frame() {
+                assert_eq!(resumed.frame(), fresh.frame());
+                assert_eq!(resumed.bytes_read(), fresh.bytes_read());
+                assert_eq!(
+                    resumed.move_forword().unwrap(),
+                    fresh.move_forword().unwrap()
+                );
+            }
+            assert!(!resumed.has_frame());
+        }
+        // past the end is the end.
+        let window = Window::new_at(&buffer, 8, 100).unwrap();
+        assert_eq!(window.bytes_read(), 50);
+        assert!(!window.has_frame());
+    }
+
+    /// Drive the window to the end and check it stays there.
+    fn drive_to_eof<W: Frames>(mut window: W, buffer: &[u8], block_size: usize) {
+        let len = buffer.len();
+        for i in window.bytes_read()..len {
+            assert!(window.has_frame());
+            assert_eq!(window.bytes_read(), i);
+            assert_eq!(window.frame_size(), cmp::min(block_size, len - i));
+            assert_eq!(
+                window.move_forword().unwrap(),
+                (Some(buffer[i]), buffer.get(i + block_size).copied())
+            );
+        }
+        // the frame is gone exactly at the end, and moving again does nothing.
+        for _ in 0..3 {
+            assert!(!window.has_frame());
+            assert!(window.on_boundry());
+            assert_eq!(window.bytes_read(), len);
+            assert_eq!(window.move_forword().unwrap(), (None, None));
+        }
+    }
+
+    #[test]
+    fn eof() {
+        for len in &[0, 1, 7, 8, 9, 16, 100] {
+            let buffer = buffer(*len);
+            for block_size in &[1, 2, 8, 16, 200] {
+                drive_to_eof(
+                    Window::new(&buffer, *block_size).unwrap(),
+                    &buffer,
+                    *block_size,
+                );
+                drive_to_eof(
+                    ReaderWindow::new(ChunkedReader::new(&buffer), *block_size).unwrap(),
+                    &buffer,
+                    *block_size,
+                );
+                // resumed near or past the end.
+                for start in &[len.saturating_sub(1), *len, len + 1] {
+                    let window = Window::new_at(&buffer, *block_size, *start).unwrap();
+                    drive_to_eof(window, &buffer, *block_size);
+                }
+            }
+        }
+    }
+
+    /// A reader that yields the bytes in small chunks of irregular sizes, like a socket.
+    pub(crate) struct ChunkedReader<'a> {
+        bytes: &'a [u8],
+        reads: usize,
+    }
+
+    impl<'a> ChunkedReader<'a> {
+        pub(crate) fn new(bytes: &'a [u8]) -> Self {
+            Self { bytes, reads: 0 }
+        }
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(5) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let len = cmp::min(cmp::min(self.reads % 7 + 1, buf.len()), self.bytes.len());
+            buf[..len].copy_from_slice(&self.bytes[..len]);
+            self.bytes = &self.bytes[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn hash_frame_in_pieces() {
+        use blake2::Blake2b;
+        let buffer = buffer(100);
+        let mut hasher = Blake2b::new();
+        for block_size in &[1, 3, 16, 33] {
+            let mut window = Window::new(&buffer, *block_size).unwrap();
+            while window.has_frame() {
+                let (frame, _) = window.frame();
+                // split anywhere, the hash is the one of the contiguous frame.
+                for at in 0..=frame.len() {
+                    let (front, back) = frame.split_at(at);
+                    assert_eq!(
+                        hash_frame((front, back), &mut hasher),
+                        Blake2b::digest(frame)
+                    );
+                }
+                window.move_forword().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn reader_window() {
+        for len in &[0, 1, 5, 16, 17, 100] {
+            let buffer = buffer(*len);
+            for block_size in 1..=20 {
+                let mut window = Window::new(&buffer, block_size).unwrap();
+                let mut reader =
+                    ReaderWindow::new(ChunkedReader::new(&buffer), block_size).unwrap();
+                for _ in 0..len + 3 {
+                    assert_eq!(reader.frame(), window.frame());
+                    assert_eq!(reader.has_frame(), window.has_frame());
+                    assert_eq!(reader.on_boundry(), window.on_boundry());
+                    assert_eq!(reader.bytes_read(), window.bytes_read());
+                    assert_eq!(
+                        reader.move_forword().unwrap(),
+                        window.move_forword().unwrap()
+                    );
+                    assert!(reader.buffer.len() <= 2 * block_size + 1);
+                }
+                assert!(!reader.has_frame());
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bench_against_cursor() {
+        let buffer = buffer(8 << 20);
+        let block_size = 1024;
+        let start = Instant::now();
+        let mut window = Window::new(&buffer, block_size).unwrap();
+        let mut sum = 0u64;
+        while let (Some(tail), _) = window.move_forword().unwrap() {
+            sum += u64::from(tail) + window.frame().0.len() as u64;
+        }
+        let slice = start.elapsed();
+        let start = Instant::now();
+        let mut cursor = CursorWindow::new(&buffer, block_size).unwrap();
+        let mut cursor_sum = 0u64;
+        while let (Some(tail), _) = cursor.move_forword().unwrap() {
+            let frame_len = cursor.front.len() + cursor.back.len() - cursor.offset;
+            cursor_sum += u64::from(tail) + cmp::min(frame_len, block_size) as u64;
+        }
+        let cursor_time = start.elapsed();
+        assert_eq!(sum, cursor_sum);
+        println!(
+            "slice window: {:?}, cursor window: {:?}",
+            slice, cursor_time
+        );
     }
 }